@@ -0,0 +1,23 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::Decoder;
+
+use rust_http_tunnel::codec::{HttpCodec, HttpRequest};
+
+// Feed arbitrary bytes to the request decoder: it must never panic, it returns a request, Ok(None) (not enough
+// data) or a DecodeError
+fuzz_target!(|data: &[u8]| {
+    for (default_port, parse_headers, forward_http) in [(None, false, false), (Some(443), false, false), (None, true, false),
+                                                        (None, false, true)] {
+        let mut codec = HttpCodec { default_port, parse_headers, forward_http, ..Default::default() };
+        let mut buffer = BytesMut::from(data);
+        // Note: several requests may be decoded from the same buffer
+        while let Ok(Some(request)) = codec.decode(&mut buffer) {
+            if let HttpRequest::Connect(target) | HttpRequest::Forward { target, .. } = request {
+                assert!(!target.is_empty());
+            }
+        }
+    }
+});
@@ -1,5 +1,5 @@
 use tokio_util::codec::{Decoder, Encoder};
-use bytes::BytesMut;
+use bytes::{Buf, BytesMut};
 
 // traits
 use std::fmt::Write; // for write_fmt()
@@ -9,75 +9,500 @@ use std::fmt::Write; // for write_fmt()
 // The following traits need to be implemented (see tokio util doc)
 // codec.decode() -> Parse bytes (HTTP Connect request)
 // codec.encode() -> Send HTTP response (usually 200 OK)
+//   Note: dst is cleared first (one response per buffer), a reused (pooled) buffer holds no stale bytes
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct HttpCodec {
+    // Proxy-Agent header value sent with 200 responses (no header if None)
+    pub proxy_agent: Option<String>,
+    // port appended to CONNECT targets without one (e.g. "example.com" -> "example.com:443")
+    // if None, such a target is kept as is (and fails to resolve)
+    pub default_port: Option<u16>,
+    // targets with userinfo (e.g. "user@example.com:443") are rejected if set, otherwise the userinfo is stripped
+    pub reject_userinfo: bool,
+    // strict: if set, wait for the whole request head (up to the blank line) and parse the headers
+    // so no header is mistaken for tunnel data (and the other way around)
+    // lenient: otherwise act on the request line alone, only the request line is parsed (and consumed)
+    pub parse_headers: bool,
+    // reject CONNECT requests with a Host header not matching the target (host, and port if any), if parse_headers
+    // e.g. request smuggling through a front proxy routing on Host
+    pub require_host_match: bool,
+    // forwarding mode: also accept absolute-form requests for other methods (e.g. "GET http://host/path HTTP/1.1"),
+    // decoded as Forward with the head rewritten in origin-form (whatever parse_headers, the whole head is read)
+    pub forward_http: bool,
+    // strict version: reject requests of any other HTTP version (e.g. a downgrade from HTTP/1.1 to HTTP/1.0)
+    // if None: CONNECT requests are HTTP/1.1, absolute-form requests HTTP/1.1 or HTTP/1.0
+    pub strict_version: Option<HttpVersion>,
+    // headers of the last decoded request (if parse_headers)
+    pub headers: Vec<(String, String)>,
 }
 
-const MAX_HTTP_CONNECT_SIZE: usize = 1024; // enough for "CONNECT ..."
+impl HttpCodec {
+    // First value of a header (name is case insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// HTTP version of a request line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HttpVersion {
+    Http10,
+    Http11,
+}
+
+impl HttpVersion {
+    // "HTTP/1.1" -> Http11
+    fn from_token(token: &[u8]) -> Option<Self> {
+        match token {
+            b"HTTP/1.0" => Some(HttpVersion::Http10),
+            b"HTTP/1.1" => Some(HttpVersion::Http11),
+            _ => None,
+        }
+    }
+}
+
+impl std::str::FromStr for HttpVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1.0" => Ok(HttpVersion::Http10),
+            "1.1" => Ok(HttpVersion::Http11),
+            _ => Err(format!("unsupported http version: {} (expect 1.0 or 1.1)", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for HttpVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpVersion::Http10 => write!(f, "HTTP/1.0"),
+            HttpVersion::Http11 => write!(f, "HTTP/1.1"),
+        }
+    }
+}
+
+const MAX_HTTP_CONNECT_SIZE: usize = 1024; // enough for the request line: "CONNECT ... HTTP/1.1"
 const HTTP_CONNECT_START: &[u8] = b"CONNECT ";
-const HTTP_CONNECT_END: &[u8] = b" HTTP/1.1";
+const HTTP_LINE_END: &[u8] = b"\r\n";
+const HTTP_HEAD_END: &[u8] = b"\r\n\r\n";
+const MAX_HTTP_HEAD_SIZE: usize = 8 * 1024; // request line + headers (if parse_headers)
 const HTTP_CONNECT_SLICE_START: usize = HTTP_CONNECT_START.len();
-const HTTP_CONNECT_SLICE_END_SIZE: usize = HTTP_CONNECT_END.len();
+const HTTP_OPTIONS_START: &[u8] = b"OPTIONS ";
+const HTTP_OPTIONS_ASTERISK: &[u8] = b"OPTIONS * HTTP/1.1";
+const HTTP_FORWARD_SCHEME: &str = "http://";
+const HTTP_FORWARD_DEFAULT_PORT: u16 = 80;
+// methods advertised in the OPTIONS response
+const ALLOWED_METHODS: &str = "CONNECT, OPTIONS";
+
+// Decoded request
+#[derive(Debug, Clone, PartialEq)]
+pub enum HttpRequest {
+    // CONNECT target, e.g. "example.com:443"
+    Connect(String),
+    // "OPTIONS * HTTP/1.1", e.g. tooling probing the proxy
+    Options,
+    // absolute-form request (if forward_http), e.g. "GET http://example.com/path HTTP/1.1"
+    // target: "example.com:80", head: the request head to send upstream, in origin-form with a matching Host header
+    // e.g. "GET /path HTTP/1.1\r\nHost: example.com\r\n...\r\n\r\n"
+    Forward { target: String, head: Vec<u8> },
+}
+
+impl HttpRequest {
+    pub fn target(&self) -> Option<&str> {
+        match self {
+            HttpRequest::Connect(target) | HttpRequest::Forward { target, .. } => Some(target),
+            HttpRequest::Options => None,
+        }
+    }
+
+    // Target split in host & port (None for a target without port, e.g. no default port)
+    pub fn connect_target(&self) -> Option<ConnectTarget> {
+        self.target().and_then(ConnectTarget::parse)
+    }
+}
+
+// Request target host & port, e.g. "[::1]:443" -> ("::1", 443)
+// Note: the port is validated by the decoder, any decoded target with a port can be split
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectTarget {
+    // without brackets for ipv6
+    pub host: String,
+    pub port: u16,
+}
+
+impl ConnectTarget {
+    // "host:port" or "[ipv6]:port"
+    pub fn parse(target: &str) -> Option<Self> {
+        if !has_port(target) {
+            return None;
+        }
+        let (host, port) = target.rsplit_once(':')?;
+        let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+        Some(Self { host: host.to_string(), port: port.parse().ok()? })
+    }
+}
+
+impl std::fmt::Display for ConnectTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
 
 #[derive(Debug, thiserror::Error)]
 pub enum DecodeError {
     #[error("i/o error: {0}")]
     IO(#[from] std::io::Error),
     #[error("utf8 error: {0}")]
-    UTF8(#[from] std::string::FromUtf8Error)
+    UTF8(#[from] std::string::FromUtf8Error),
+    #[error("invalid target: {0:?}")]
+    InvalidTarget(String),
+    #[error("method not allowed: {0:?}")]
+    MethodNotAllowed(String),
+    #[error("HTTP request line too large: {0}")]
+    TooLarge(usize),
+    #[error("Invalid HTTP request")]
+    InvalidRequest,
+    #[error("Invalid HTTP header: {0:?}")]
+    InvalidHeader(String),
+    // Note: no data, userinfo may hold credentials
+    #[error("userinfo in target")]
+    UserInfo,
+    // e.g. request smuggling attempt
+    #[error("conflicting Host headers: {0:?}")]
+    ConflictingHost(Vec<String>),
+    #[error("Host header {0:?} does not match target {1:?}")]
+    HostMismatch(String, String),
+    // a known version, other than the strict one (see strict_version)
+    #[error("HTTP version not allowed: {0}")]
+    VersionNotAllowed(HttpVersion),
+    // e.g. "HTTP/2.0" (a CONNECT request is HTTP/1.x)
+    #[error("HTTP version not supported: {0:?}")]
+    UnsupportedVersion(String),
+}
+
+impl DecodeError {
+    // Response (status) for a rejected request
+    pub fn response(&self) -> TunnelResult {
+        match self {
+            DecodeError::MethodNotAllowed(_) => TunnelResult::MethodNotAllowed,
+            DecodeError::UnsupportedVersion(_) => TunnelResult::HttpVersionNotSupported,
+            _ => TunnelResult::BadRequest,
+        }
+    }
+
+    // Failure category, safe to send back to the client (no request data)
+    pub fn reason(&self) -> &'static str {
+        match self {
+            DecodeError::IO(_) => "i/o error",
+            DecodeError::UTF8(_) => "request target is not valid utf-8",
+            DecodeError::InvalidTarget(_) => "invalid request target, expected host:port",
+            DecodeError::MethodNotAllowed(_) => "method not allowed, only CONNECT is supported",
+            DecodeError::TooLarge(_) => "request line too large",
+            DecodeError::InvalidRequest => "invalid request line, expected: CONNECT host:port HTTP/1.1",
+            DecodeError::InvalidHeader(_) => "invalid request header, expected: name: value",
+            DecodeError::UserInfo => "request target must not contain userinfo (user@)",
+            DecodeError::ConflictingHost(_) => "conflicting Host headers",
+            DecodeError::HostMismatch(_, _) => "Host header does not match the request target",
+            DecodeError::VersionNotAllowed(_) => "HTTP version not allowed by the proxy",
+            DecodeError::UnsupportedVersion(_) => "HTTP version not supported, expected HTTP/1.1",
+        }
+    }
+}
+
+// "host:port" or "[ipv6]:port"
+fn has_port(target: &str) -> bool {
+    match target.rsplit_once(':') {
+        Some((host, _)) => !host.starts_with('[') || host.ends_with(']'),
+        None => false,
+    }
+}
+
+// Host header value ("host" or "host:port") for target ("host:port"): same host (case insensitive)
+// & same port if the header has one
+fn host_matches(host_header: &str, target: &str) -> bool {
+    let split = |s: &str| -> (String, Option<String>) {
+        let (host, port) = match s.rsplit_once(':') {
+            Some((host, port)) if has_port(s) => (host, Some(port.to_string())),
+            _ => (s, None),
+        };
+        (host.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase(), port)
+    };
+    let ((header_host, header_port), (target_host, target_port)) = (split(host_header), split(target));
+    header_host == target_host && (header_port.is_none() || header_port == target_port)
+}
+
+// "HTTP/2.0" or "HTTP/2" (major[.minor])
+fn is_version_token(token: &[u8]) -> bool {
+    let is_number = |n: &[u8]| !n.is_empty() && n.len() <= 3 && n.iter().all(u8::is_ascii_digit);
+    match token.strip_prefix(b"HTTP/") {
+        Some(version) => version.splitn(2, |b| *b == b'.').all(is_number),
+        None => false,
+    }
 }
 
 fn find_subsequence(haystack: &[u8], needle: &[u8]) -> Option<usize> {
     haystack.windows(needle.len()).position(|window| window == needle)
 }
 
+type Headers = Vec<(String, String)>;
+
+// Request head end (the blank line index) & headers, None if not complete yet
+fn parse_head(src: &[u8], request_line_end: usize) -> Result<Option<(usize, Headers)>, DecodeError> {
+    // Note: the blank line ends the head, search from the request line end ("\r\n\r\n" if no headers)
+    let head_end = match find_subsequence(&src[request_line_end..], HTTP_HEAD_END) {
+        Some(index) => request_line_end + index,
+        None if src.len() >= MAX_HTTP_HEAD_SIZE => return Err(DecodeError::TooLarge(src.len())),
+        None => return Ok(None), // not enough data
+    };
+    if head_end >= MAX_HTTP_HEAD_SIZE {
+        return Err(DecodeError::TooLarge(head_end));
+    }
+
+    let mut headers = Vec::new();
+    let header_lines = &src[request_line_end + HTTP_LINE_END.len()..head_end + HTTP_LINE_END.len()];
+    for line in header_lines.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let line = String::from_utf8_lossy(line);
+        match line.split_once(':') {
+            Some((name, value)) if !name.is_empty() && !name.contains(' ') => {
+                headers.push((name.to_string(), value.trim().to_string()));
+            },
+            _ => return Err(DecodeError::InvalidHeader(line.chars().take(64).collect())),
+        }
+    }
+
+    // Note: several identical Host headers are fine
+    let hosts: Vec<&String> = headers.iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("host"))
+        .map(|(_, value)| value)
+        .collect();
+    if hosts.iter().any(|host| !host.eq_ignore_ascii_case(hosts[0])) {
+        return Err(DecodeError::ConflictingHost(hosts.into_iter().cloned().collect()));
+    }
+    Ok(Some((head_end, headers)))
+}
+
+impl HttpCodec {
+    // "GET http://example.com/path HTTP/1.1\r\n...\r\n\r\n" -> Forward("example.com:80", "GET /path HTTP/1.1\r\n...")
+    // Note: the Host header is replaced by the url authority (RFC 7230 5.4), other headers are sent as is
+    fn decode_forward(&mut self, src: &mut BytesMut, request_line_end: usize) -> Result<Option<HttpRequest>, DecodeError> {
+        let request_line = std::str::from_utf8(&src[..request_line_end]).map_err(|_| DecodeError::InvalidRequest)?;
+        let (method, url, version) = match request_line.split(' ').collect::<Vec<_>>()[..] {
+            [method, url, version] => (method, url, version),
+            _ => return Err(DecodeError::InvalidRequest),
+        };
+        let is_token = !method.is_empty() && method.bytes().all(|b| b.is_ascii_uppercase());
+        // Note: bytes compared, the url may not be ascii (no char boundary at the scheme length)
+        let is_absolute = url.len() > HTTP_FORWARD_SCHEME.len()
+            && url.as_bytes()[..HTTP_FORWARD_SCHEME.len()].eq_ignore_ascii_case(HTTP_FORWARD_SCHEME.as_bytes());
+        if !is_token || !is_absolute {
+            // Note: an origin-form request ("GET /path") is for a server, not a proxy
+            return Err(DecodeError::MethodNotAllowed(method.chars().take(HTTP_CONNECT_START.len()).collect()));
+        }
+        self.check_version(version.as_bytes(), &[HttpVersion::Http11, HttpVersion::Http10])?;
+
+        let url = &url[HTTP_FORWARD_SCHEME.len()..];
+        let (authority, path) = match url.find(['/', '?']) {
+            Some(index) => url.split_at(index),
+            None => (url, ""),
+        };
+        let authority = match authority.rsplit_once('@') {
+            Some(_) if self.reject_userinfo => return Err(DecodeError::UserInfo),
+            Some((_userinfo, authority)) => authority,
+            None => authority,
+        };
+        if authority.is_empty() {
+            return Err(DecodeError::InvalidTarget(authority.to_string()));
+        }
+        let target = if has_port(authority) {
+            authority.to_string()
+        } else {
+            format!("{}:{}", authority, HTTP_FORWARD_DEFAULT_PORT)
+        };
+        if ConnectTarget::parse(&target).is_none() {
+            return Err(DecodeError::InvalidTarget(target));
+        }
+        let path = if path.starts_with('/') { path.to_string() } else { format!("/{}", path) };
+
+        let (head_end, headers) = match parse_head(src, request_line_end)? {
+            Some(head) => head,
+            None => return Ok(None), // not enough data
+        };
+        let mut head = format!("{} {} {}\r\nHost: {}\r\n", method, path, version, authority).into_bytes();
+        for (name, value) in headers.iter().filter(|(name, _)| !name.eq_ignore_ascii_case("host")) {
+            head.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        head.extend_from_slice(HTTP_LINE_END);
+        self.headers = headers;
+
+        src.advance(head_end + HTTP_HEAD_END.len());
+        Ok(Some(HttpRequest::Forward { target, head }))
+    }
+
+    // Request version (token) against the strict one (if any), otherwise against the versions accepted by default
+    // Note: a well formed but unknown version (e.g. "HTTP/2.0") is unsupported whatever strict_version
+    fn check_version(&self, token: &[u8], default: &[HttpVersion]) -> Result<(), DecodeError> {
+        let version = match HttpVersion::from_token(token) {
+            Some(version) => version,
+            None if is_version_token(token) => {
+                return Err(DecodeError::UnsupportedVersion(String::from_utf8_lossy(token).into_owned()));
+            },
+            None => return Err(DecodeError::InvalidRequest),
+        };
+        match self.strict_version {
+            Some(strict) if version != strict => Err(DecodeError::VersionNotAllowed(version)),
+            None if !default.contains(&version) => Err(DecodeError::InvalidRequest),
+            _ => Ok(()),
+        }
+    }
+
+    // "CONNECT URL:PORT HTTP/1.1" -> "URL:PORT"
+    fn connect_target(&self, request_line: &[u8]) -> Result<String, DecodeError> {
+        // Note: the version is the last token, the target may not be empty (e.g. "CONNECT HTTP/1.1")
+        let version_start = request_line.iter().rposition(|b| *b == b' ');
+        let version_start = match version_start {
+            Some(index) if request_line.starts_with(HTTP_CONNECT_START) && index >= HTTP_CONNECT_SLICE_START => index,
+            _ => return Err(DecodeError::InvalidRequest),
+        };
+        self.check_version(&request_line[version_start + 1..], &[HttpVersion::Http11])?;
+
+        let url_ : &[u8] = &request_line[HTTP_CONNECT_SLICE_START..version_start];
+        let url: String = String::from_utf8(url_.to_vec())?;
+        let target = match url.trim().rsplit_once('@') {
+            Some(_) if self.reject_userinfo => return Err(DecodeError::UserInfo),
+            Some((_userinfo, target)) => target,
+            None => url.trim(),
+        };
+        // e.g. "CONNECT  HTTP/1.1" (would fail later, confusingly, at dns resolution)
+        if target.is_empty() {
+            return Err(DecodeError::InvalidTarget(target.to_string()));
+        }
+        let target = match self.default_port {
+            Some(port) if !has_port(target) => format!("{}:{}", target, port),
+            _ => target.to_string(),
+        };
+        // e.g. "example.com:https" or "example.com:99999" (would fail later, at dns resolution)
+        if has_port(&target) && ConnectTarget::parse(&target).is_none() {
+            return Err(DecodeError::InvalidTarget(target));
+        }
+        Ok(target)
+    }
+}
+
 impl Decoder for HttpCodec {
 
     // Decoder will take some bytes, parse them and extract the destination url
-    // e.g. "CONNECT URL:PORT HTTP/1.1\r\n" -> "URL:PORT"
+    // e.g. "CONNECT URL:PORT HTTP/1.1\r\n" -> Connect("URL:PORT")
 
-    type Item = String;
+    type Item = HttpRequest;
     type Error = DecodeError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
 
-        if !src.ends_with(b"\r\n") {
-            return Ok(None); // not enough data
+        // Only CONNECT (& OPTIONS *) is supported: reject other methods (e.g. "POST ") from the first bytes, without
+        // waiting for (buffering) the whole request line
+        let is_method = |method: &[u8]| {
+            let prefix_len = src.len().min(method.len());
+            src[..prefix_len] == method[..prefix_len]
+        };
+        if !self.forward_http && !is_method(HTTP_CONNECT_START) && !is_method(HTTP_OPTIONS_START) {
+            let method = src[..].split(|b| *b == b' ').next().unwrap_or_default();
+            let method = &method[..method.len().min(HTTP_CONNECT_START.len())];
+            return Err(DecodeError::MethodNotAllowed(String::from_utf8_lossy(method).to_string()));
         }
 
-        if src.len() >= MAX_HTTP_CONNECT_SIZE {
-            return Err(DecodeError::IO(std::io::Error::new(std::io::ErrorKind::InvalidData,
-                                           format!("HTTP frame too large: {}", src.len()))));
-        }
+        let request_line_end = match find_subsequence(src, HTTP_LINE_END) {
+            Some(index) => index,
+            // Note: no CRLF (yet) in more than the max size, the request line is too large whatever comes next,
+            // reject it now instead of buffering until a CRLF arrives
+            None if src.len() > MAX_HTTP_CONNECT_SIZE => return Err(DecodeError::TooLarge(src.len())),
+            None => return Ok(None), // not enough data
+        };
 
-        if !src.starts_with(HTTP_CONNECT_START) {
-            return Err(DecodeError::IO(std::io::Error::new(std::io::ErrorKind::InvalidData,
-                                           format!("Invalid HTTP request"))));
+        // Note: only the request line is parsed, headers following it (if any) can be larger
+        if request_line_end >= MAX_HTTP_CONNECT_SIZE {
+            return Err(DecodeError::TooLarge(request_line_end));
         }
 
-        let http_connect_end_index = find_subsequence(src, b" HTTP/1.1\r\n");
+        let request_line = &src[..request_line_end];
+        let request = if request_line.starts_with(HTTP_OPTIONS_START) {
+            // Note: only the proxy itself (*), no OPTIONS request is forwarded
+            if request_line != HTTP_OPTIONS_ASTERISK {
+                return Err(DecodeError::MethodNotAllowed("OPTIONS".to_string()));
+            }
+            HttpRequest::Options
+        } else if request_line.starts_with(HTTP_CONNECT_START) || !self.forward_http {
+            HttpRequest::Connect(self.connect_target(request_line)?)
+        } else {
+            return self.decode_forward(src, request_line_end);
+        };
+
+        if !self.parse_headers {
+            // consume the request line so a reused codec does not parse it again
+            src.advance(request_line_end + HTTP_LINE_END.len());
+            return Ok(Some(request));
+        }
 
-        if http_connect_end_index.is_none() {
-            return Err(DecodeError::IO(std::io::Error::new(std::io::ErrorKind::InvalidData,
-                                           format!("Invalid HTTP request"))));
+        let (head_end, headers) = match parse_head(src, request_line_end)? {
+            Some(head) => head,
+            None => return Ok(None), // not enough data
+        };
+        let host = headers.iter().find(|(name, _)| name.eq_ignore_ascii_case("host")).map(|(_, value)| value);
+        if let (true, Some(host), HttpRequest::Connect(target)) = (self.require_host_match, host, &request) {
+            if !host_matches(host, target) {
+                return Err(DecodeError::HostMismatch(host.to_string(), target.clone()));
+            }
         }
+        self.headers = headers;
 
-        // unwrap is safe here
-        let url_ : &[u8] = &src[HTTP_CONNECT_SLICE_START..http_connect_end_index.unwrap()];
-        let url: String = String::from_utf8(url_.to_vec())?;
-        Ok(Some(url))
+        src.advance(head_end + HTTP_HEAD_END.len());
+        Ok(Some(request))
     }
 
 }
 
 #[repr(u32)]
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TunnelResult {
     Ok, // 200
     BadRequest, // 400
     Forbidden, // 403
     Timeout, // 408
     ServerError, // 500
+    BadGateway, // 502
+    ServiceUnavailable, // 503
+    MethodNotAllowed, // 405
+    UnavailableForLegalReasons, // 451
+    GatewayTimeout, // 504
+    HttpVersionNotSupported, // 505
+}
+
+impl TunnelResult {
+    // http status code & reason
+    pub fn status(&self) -> (u32, &'static str) {
+        match self {
+            TunnelResult::Ok => (200, "OK"),
+            TunnelResult::BadRequest => (400, "BAD_REQUEST"),
+            TunnelResult::Forbidden => (403, "FORBIDDEN"),
+            TunnelResult::MethodNotAllowed => (405, "METHOD_NOT_ALLOWED"),
+            TunnelResult::UnavailableForLegalReasons => (451, "UNAVAILABLE_FOR_LEGAL_REASONS"),
+            TunnelResult::ServerError => (500, "SERVER_ERROR"),
+            TunnelResult::BadGateway => (502, "BAD_GATEWAY"),
+            TunnelResult::Timeout => (408, "REQUEST_TIMEOUT"),
+            TunnelResult::ServiceUnavailable => (503, "SERVICE_UNAVAILABLE"),
+            TunnelResult::GatewayTimeout => (504, "GATEWAY_TIMEOUT"),
+            TunnelResult::HttpVersionNotSupported => (505, "HTTP_VERSION_NOT_SUPPORTED"),
+        }
+    }
 }
 
 impl Encoder<TunnelResult> for HttpCodec {
@@ -86,24 +511,63 @@ impl Encoder<TunnelResult> for HttpCodec {
 
     fn encode(&mut self, tunnel_result: TunnelResult, dst: &mut BytesMut) -> Result<(), Self::Error> {
 
-        let (code, message): (u32, &str) = match tunnel_result {
-            TunnelResult::Ok => (200, "OK"),
-            TunnelResult::BadRequest => (400, "BAD_REQUEST"),
-            TunnelResult::Forbidden => (408, "Timeout"),
-            TunnelResult::ServerError => (500, "SERVER_ERROR"),
-            _ => (400, "BAD_REQUEST"),
-        };
+        dst.clear();
+        let (code, message) = tunnel_result.status();
+
+        let to_io_error = |_| std::io::Error::from(std::io::ErrorKind::Other);
+
+        dst.write_fmt(format_args!("HTTP/1.1 {} {}\r\n", code, message)).map_err(to_io_error)?;
+        if let (200, Some(proxy_agent)) = (code, &self.proxy_agent) {
+            dst.write_fmt(format_args!("Proxy-Agent: {}\r\n", proxy_agent)).map_err(to_io_error)?;
+        }
+        dst.write_str("\r\n").map_err(to_io_error)
+    }
+}
+
+// 200 response to "OPTIONS *": the supported methods
+pub struct OptionsResponse;
+
+impl Encoder<OptionsResponse> for HttpCodec {
+
+    type Error = std::io::Error;
+
+    fn encode(&mut self, _: OptionsResponse, dst: &mut BytesMut) -> Result<(), Self::Error> {
+
+        dst.clear();
+        let to_io_error = |_| std::io::Error::from(std::io::ErrorKind::Other);
 
-        dst.write_fmt(format_args!("HTTP/1.1 {} {}\r\n\r\n", code, message)).map_err(
-            |_| std::io::Error::from(std::io::ErrorKind::Other)
-        )
+        dst.write_fmt(format_args!("HTTP/1.1 200 OK\r\nAllow: {}\r\n", ALLOWED_METHODS)).map_err(to_io_error)?;
+        if let Some(proxy_agent) = &self.proxy_agent {
+            dst.write_fmt(format_args!("Proxy-Agent: {}\r\n", proxy_agent)).map_err(to_io_error)?;
+        }
+        dst.write_str("Content-Length: 0\r\n\r\n").map_err(to_io_error)
+    }
+}
+
+// Response with a short text body (e.g. why the request was rejected)
+// Note: not for 200 responses, the tunnel data follows them
+impl Encoder<(TunnelResult, &str)> for HttpCodec {
+
+    type Error = std::io::Error;
+
+    fn encode(&mut self, (tunnel_result, body): (TunnelResult, &str), dst: &mut BytesMut) -> Result<(), Self::Error> {
+
+        dst.clear();
+        let (code, message) = tunnel_result.status();
+
+        let to_io_error = |_| std::io::Error::from(std::io::ErrorKind::Other);
+
+        dst.write_fmt(format_args!("HTTP/1.1 {} {}\r\n", code, message)).map_err(to_io_error)?;
+        dst.write_fmt(format_args!("Content-Type: text/plain\r\nContent-Length: {}\r\n\r\n", body.len()))
+            .map_err(to_io_error)?;
+        dst.write_str(body).map_err(to_io_error)
     }
 }
 
 #[cfg(test)]
 mod tests {
 
-    use super::{HttpCodec, DecodeError};
+    use super::{ConnectTarget, HttpCodec, HttpRequest, HttpVersion, DecodeError, OptionsResponse, MAX_HTTP_CONNECT_SIZE};
 
     // traits
     use tokio_util::codec::{Encoder, Decoder}; // for encode() / decode()
@@ -113,22 +577,22 @@ mod tests {
     #[test]
     fn test_decode_valid_request() -> Result<(), DecodeError> {
         let http_req = b"CONNECT google.com:80 HTTP/1.1\r\n";
-        let mut codec = HttpCodec {};
+        let mut codec = HttpCodec::default();
         let mut buffer = bytes::BytesMut::with_capacity(http_req.len());
         buffer.put(&http_req[..]);
 
-        assert_eq!(codec.decode(&mut buffer)?.unwrap(), "google.com:80");
+        assert_eq!(codec.decode(&mut buffer)?.unwrap().target(), Some("google.com:80"));
         Ok(())
     }
 
     #[test]
     fn test_decode_valid_request_2() -> Result<(), DecodeError> {
         let http_req = b"CONNECT google.com:80 HTTP/1.1\r\nHost: google.com:80\r\nUser-Agent: curl/7.64.0\r\nProxy-Connection: Keep-Alive\r\n\r\n";
-        let mut codec = HttpCodec {};
+        let mut codec = HttpCodec::default();
         let mut buffer = bytes::BytesMut::with_capacity(http_req.len());
         buffer.put(&http_req[..]);
 
-        assert_eq!(codec.decode(&mut buffer)?.unwrap(), "google.com:80");
+        assert_eq!(codec.decode(&mut buffer)?.unwrap().target(), Some("google.com:80"));
         Ok(())
     }
 
@@ -136,38 +600,527 @@ mod tests {
     fn test_decode_invalid_request() {
 
         let http_req = b"CONNEC google.com:80 HTTP/1.1\r\n"; // CONNEC vs CONNECT
-        let mut codec = HttpCodec {};
+        let mut codec = HttpCodec::default();
         let mut buffer = bytes::BytesMut::with_capacity(http_req.len());
         buffer.put(&http_req[..]);
 
-        if let Err(DecodeError::IO(e)) = codec.decode(&mut buffer) {
-            assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        if let Err(DecodeError::MethodNotAllowed(method)) = codec.decode(&mut buffer) {
+            assert_eq!(method, "CONNEC");
         }
         else {
             panic!("Shoud not happen")
         }
+
+        let http_req = b"CONNECT google.com:80 HTTP/1.0\r\n";
+        let mut buffer = bytes::BytesMut::from(&http_req[..]);
+        assert!(matches!(codec.decode(&mut buffer), Err(DecodeError::InvalidRequest)));
+    }
+
+    #[test]
+    fn test_decode_unsupported_version() -> Result<(), std::io::Error> {
+        let mut codec = HttpCodec::default();
+        let mut buffer = bytes::BytesMut::from(&b"CONNECT google.com:443 HTTP/2.0\r\n\r\n"[..]);
+        let err = codec.decode(&mut buffer).unwrap_err();
+        assert!(matches!(err, DecodeError::UnsupportedVersion(ref v) if v == "HTTP/2.0"));
+        assert_eq!(err.response(), TunnelResult::HttpVersionNotSupported);
+
+        let mut buffer = bytes::BytesMut::new();
+        codec.encode(err.response(), &mut buffer)?;
+        assert_eq!(&buffer[..], b"HTTP/1.1 505 HTTP_VERSION_NOT_SUPPORTED\r\n\r\n");
+
+        // not a version
+        for http_req in [&b"CONNECT google.com:443 HTTP/\r\n\r\n"[..], &b"CONNECT google.com:443 SPDY/3\r\n\r\n"[..]] {
+            let mut buffer = bytes::BytesMut::from(http_req);
+            assert!(matches!(codec.decode(&mut buffer), Err(DecodeError::InvalidRequest)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_strict_version() -> Result<(), DecodeError> {
+        let mut codec = HttpCodec { strict_version: Some(HttpVersion::Http11), ..Default::default() };
+        let mut buffer = bytes::BytesMut::from(&b"CONNECT google.com:80 HTTP/1.1\r\n"[..]);
+        assert_eq!(codec.decode(&mut buffer)?, Some(HttpRequest::Connect("google.com:80".to_string())));
+
+        // downgrade attempt
+        let mut buffer = bytes::BytesMut::from(&b"CONNECT google.com:80 HTTP/1.0\r\n"[..]);
+        let err = codec.decode(&mut buffer).unwrap_err();
+        assert!(matches!(err, DecodeError::VersionNotAllowed(HttpVersion::Http10)));
+        assert_eq!(err.response(), TunnelResult::BadRequest);
+        // unknown version
+        let mut buffer = bytes::BytesMut::from(&b"CONNECT google.com:80 HTTP/1.2\r\n"[..]);
+        assert!(matches!(codec.decode(&mut buffer), Err(DecodeError::UnsupportedVersion(_))));
+
+        // the other way around
+        let mut codec = HttpCodec { strict_version: Some(HttpVersion::Http10), ..Default::default() };
+        let mut buffer = bytes::BytesMut::from(&b"CONNECT google.com:80 HTTP/1.0\r\n"[..]);
+        assert_eq!(codec.decode(&mut buffer)?, Some(HttpRequest::Connect("google.com:80".to_string())));
+        let mut buffer = bytes::BytesMut::from(&b"CONNECT google.com:80 HTTP/1.1\r\n"[..]);
+        assert!(matches!(codec.decode(&mut buffer), Err(DecodeError::VersionNotAllowed(HttpVersion::Http11))));
+
+        assert_eq!("1.1".parse::<HttpVersion>(), Ok(HttpVersion::Http11));
+        assert!("2".parse::<HttpVersion>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_method_not_allowed() -> Result<(), DecodeError> {
+        let mut codec = HttpCodec::default();
+        // rejected as soon as the method is known, without a full request line
+        let mut buffer = bytes::BytesMut::from(&b"POST /"[..]);
+        assert!(matches!(codec.decode(&mut buffer), Err(DecodeError::MethodNotAllowed(m)) if m == "POST"));
+        let mut buffer = bytes::BytesMut::from(&b"G"[..]);
+        assert!(matches!(codec.decode(&mut buffer), Err(DecodeError::MethodNotAllowed(m)) if m == "G"));
+
+        // partial CONNECT: wait for more data
+        let mut buffer = bytes::BytesMut::from(&b"CONN"[..]);
+        assert!(codec.decode(&mut buffer)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_options() -> Result<(), DecodeError> {
+        let mut codec = HttpCodec { parse_headers: true, ..Default::default() };
+        let mut buffer = bytes::BytesMut::from(&b"OPTIONS * HTTP/1.1\r\nHost: proxy\r\n\r\n"[..]);
+        assert_eq!(codec.decode(&mut buffer)?, Some(HttpRequest::Options));
+        assert!(buffer.is_empty());
+
+        let mut buffer = bytes::BytesMut::from(&b"OPTI"[..]);
+        assert!(codec.decode(&mut buffer)?.is_none());
+        let mut buffer = bytes::BytesMut::from(&b"OPTIONS /index.html HTTP/1.1\r\n\r\n"[..]);
+        assert!(matches!(codec.decode(&mut buffer), Err(DecodeError::MethodNotAllowed(m)) if m == "OPTIONS"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_edge_cases() {
+        // edge cases for the fuzz target (fuzz/fuzz_targets/decode.rs): never panic, always an error
+        for http_req in [&b"CONNECT HTTP/1.1\r\n"[..], &b"CONNECT \r\n"[..], &b"\r\n"[..], &b"CONNECT \xff\xfe HTTP/1.1\r\n"[..],
+                         &b"CONNECT \r\n HTTP/1.1\r\n"[..]] {
+            let mut codec = HttpCodec { default_port: Some(443), ..Default::default() };
+            let mut buffer = bytes::BytesMut::from(http_req);
+            assert!(codec.decode(&mut buffer).is_err(), "{:?}", http_req);
+        }
+    }
+
+    #[test]
+    fn test_decode_empty_target() {
+        for http_req in [&b"CONNECT  HTTP/1.1\r\n"[..], &b"CONNECT     HTTP/1.1\r\n"[..], &b"CONNECT \t HTTP/1.1\r\n"[..]] {
+            let mut codec = HttpCodec::default();
+            let mut buffer = bytes::BytesMut::from(http_req);
+            assert!(matches!(codec.decode(&mut buffer), Err(DecodeError::InvalidTarget(_))));
+        }
+    }
+
+    #[test]
+    fn test_decode_userinfo() -> Result<(), DecodeError> {
+        let mut codec = HttpCodec::default();
+        let mut buffer = bytes::BytesMut::from(&b"CONNECT user:secret@example.com:443 HTTP/1.1\r\n"[..]);
+        assert_eq!(codec.decode(&mut buffer)?.unwrap().target(), Some("example.com:443"));
+        let mut buffer = bytes::BytesMut::from(&b"CONNECT user@[::1]:443 HTTP/1.1\r\n"[..]);
+        assert_eq!(codec.decode(&mut buffer)?.unwrap().target(), Some("[::1]:443"));
+        let mut buffer = bytes::BytesMut::from(&b"CONNECT user@ HTTP/1.1\r\n"[..]);
+        assert!(matches!(codec.decode(&mut buffer), Err(DecodeError::InvalidTarget(_))));
+
+        // strict
+        let mut codec = HttpCodec { reject_userinfo: true, ..Default::default() };
+        let mut buffer = bytes::BytesMut::from(&b"CONNECT user:secret@example.com:443 HTTP/1.1\r\n"[..]);
+        let err = codec.decode(&mut buffer).unwrap_err();
+        assert!(matches!(err, DecodeError::UserInfo));
+        assert!(!err.to_string().contains("secret"));
+        let mut buffer = bytes::BytesMut::from(&b"CONNECT example.com:443 HTTP/1.1\r\n"[..]);
+        assert_eq!(codec.decode(&mut buffer)?.unwrap().target(), Some("example.com:443"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_target_trimmed() -> Result<(), DecodeError> {
+        let mut codec = HttpCodec::default();
+        let mut buffer = bytes::BytesMut::from(&b"CONNECT  google.com:80  HTTP/1.1\r\n"[..]);
+        assert_eq!(codec.decode(&mut buffer)?.unwrap().target(), Some("google.com:80"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_default_port() -> Result<(), DecodeError> {
+        let mut codec = HttpCodec { default_port: Some(443), ..Default::default() };
+        let mut buffer = bytes::BytesMut::from(&b"CONNECT google.com HTTP/1.1\r\n"[..]);
+        assert_eq!(codec.decode(&mut buffer)?.unwrap().target(), Some("google.com:443"));
+        let mut buffer = bytes::BytesMut::from(&b"CONNECT [::1] HTTP/1.1\r\n"[..]);
+        assert_eq!(codec.decode(&mut buffer)?.unwrap().target(), Some("[::1]:443"));
+
+        // explicit port
+        let mut buffer = bytes::BytesMut::from(&b"CONNECT google.com:80 HTTP/1.1\r\n"[..]);
+        assert_eq!(codec.decode(&mut buffer)?.unwrap().target(), Some("google.com:80"));
+        let mut buffer = bytes::BytesMut::from(&b"CONNECT [::1]:8080 HTTP/1.1\r\n"[..]);
+        assert_eq!(codec.decode(&mut buffer)?.unwrap().target(), Some("[::1]:8080"));
+
+        // no default port: unchanged
+        let mut codec = HttpCodec::default();
+        let mut buffer = bytes::BytesMut::from(&b"CONNECT google.com HTTP/1.1\r\n"[..]);
+        assert_eq!(codec.decode(&mut buffer)?.unwrap().target(), Some("google.com"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_connect_target() -> Result<(), DecodeError> {
+        let mut codec = HttpCodec { default_port: Some(443), ..Default::default() };
+        for (http_req, host, port) in [
+            (&b"CONNECT example.com:8443 HTTP/1.1\r\n"[..], "example.com", 8443),
+            (&b"CONNECT example.com HTTP/1.1\r\n"[..], "example.com", 443),
+            (&b"CONNECT 10.0.0.1:80 HTTP/1.1\r\n"[..], "10.0.0.1", 80),
+            (&b"CONNECT [2001:db8::1]:443 HTTP/1.1\r\n"[..], "2001:db8::1", 443),
+            (&b"CONNECT [::1] HTTP/1.1\r\n"[..], "::1", 443),
+        ] {
+            let mut buffer = bytes::BytesMut::from(http_req);
+            let target = codec.decode(&mut buffer)?.unwrap().connect_target();
+            assert_eq!(target, Some(ConnectTarget { host: host.to_string(), port }), "{:?}", http_req);
+        }
+        assert_eq!(ConnectTarget { host: "::1".to_string(), port: 443 }.to_string(), "[::1]:443");
+        assert_eq!(ConnectTarget { host: "example.com".to_string(), port: 80 }.to_string(), "example.com:80");
+
+        // the port is validated once, by the decoder
+        for http_req in [&b"CONNECT example.com:https HTTP/1.1\r\n"[..], &b"CONNECT example.com:99999 HTTP/1.1\r\n"[..],
+                         &b"CONNECT [::1]: HTTP/1.1\r\n"[..]] {
+            let mut buffer = bytes::BytesMut::from(http_req);
+            assert!(matches!(codec.decode(&mut buffer), Err(DecodeError::InvalidTarget(_))), "{:?}", http_req);
+        }
+
+        // no port
+        let mut codec = HttpCodec::default();
+        let mut buffer = bytes::BytesMut::from(&b"CONNECT example.com HTTP/1.1\r\n"[..]);
+        assert_eq!(codec.decode(&mut buffer)?.unwrap().connect_target(), None);
+        Ok(())
     }
 
     #[test]
     fn test_decode_large_request() {
 
-        let http_req = b"CONNECT {}:80 HTTP/1.1\r\n";
-        let mut codec = HttpCodec {};
+        let mut codec = HttpCodec::default();
+        let mut buffer = bytes::BytesMut::new();
+        buffer.put(&b"CONNECT "[..]);
+        for _ in 0..48 {
+            buffer.put(&b"abcdefghijklmnopqrstuvw."[..]);
+        }
+        buffer.put(&b"com:80 HTTP/1.1\r\n"[..]);
+
+        assert!(matches!(codec.decode(&mut buffer), Err(DecodeError::TooLarge(_))));
+    }
+
+    #[test]
+    fn test_decode_large_request_no_crlf() -> Result<(), DecodeError> {
+        let mut codec = HttpCodec::default();
+        let mut buffer = bytes::BytesMut::new();
+        buffer.put(&b"CONNECT "[..]);
+        let chunk = [b'a'; 256];
+
+        // 2KB without CRLF: rejected as soon as the max size is exceeded
+        let mut rejected_at = None;
+        while buffer.len() < 2048 {
+            buffer.put(&chunk[..]);
+            match codec.decode(&mut buffer) {
+                Ok(None) => assert!(buffer.len() <= MAX_HTTP_CONNECT_SIZE),
+                Err(DecodeError::TooLarge(size)) => {
+                    rejected_at = Some(size);
+                    break;
+                },
+                other => panic!("unexpected: {:?}", other),
+            }
+        }
+        assert_eq!(rejected_at, Some(8 + 4 * 256));
+
+        // the max size with a CR last: still waiting for the LF
         let mut buffer = bytes::BytesMut::new();
+        buffer.put(&b"CONNECT "[..]);
+        buffer.put(&[b'a'; MAX_HTTP_CONNECT_SIZE - 9][..]);
+        buffer.put(&b"\r"[..]);
+        assert!(codec.decode(&mut buffer)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_large_headers() -> Result<(), DecodeError> {
+        let mut codec = HttpCodec::default();
+        let mut buffer = bytes::BytesMut::new();
+        buffer.put(&b"CONNECT google.com:80 HTTP/1.1\r\nHost: google.com:80\r\n"[..]);
         for i in 0..48 {
-            buffer.put(&http_req[..]);
+            buffer.put(format!("X-Header-{}: abcdefghijklmnopqrstuvwxyz\r\n", i).as_bytes());
         }
+        buffer.put(&b"\r\n"[..]);
+        assert!(buffer.len() > 1024);
 
-        if let Err(DecodeError::IO(e)) = codec.decode(&mut buffer) {
-            assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
-        } else {
-            panic!("Should not happen")
+        assert_eq!(codec.decode(&mut buffer)?.unwrap().target(), Some("google.com:80"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_forward() -> Result<(), DecodeError> {
+        let mut codec = HttpCodec { forward_http: true, ..Default::default() };
+        let mut buffer = bytes::BytesMut::from(&b"GET http://example.com/path HTTP/1.1\r\nHost: example.com\r\n"[..]);
+        // wait for the blank line
+        assert!(codec.decode(&mut buffer)?.is_none());
+        buffer.put(&b"User-Agent: test\r\n\r\nbody"[..]);
+        let head = b"GET /path HTTP/1.1\r\nHost: example.com\r\nUser-Agent: test\r\n\r\n".to_vec();
+        assert_eq!(codec.decode(&mut buffer)?, Some(HttpRequest::Forward { target: "example.com:80".to_string(), head }));
+        assert_eq!(codec.header("user-agent"), Some("test"));
+        assert_eq!(&buffer[..], b"body");
+
+        // port, no path, Host header replaced
+        let mut buffer = bytes::BytesMut::from(&b"POST HTTP://[::1]:8080?a=b HTTP/1.0\r\nHost: other\r\n\r\n"[..]);
+        let head = b"POST /?a=b HTTP/1.0\r\nHost: [::1]:8080\r\n\r\n".to_vec();
+        assert_eq!(codec.decode(&mut buffer)?, Some(HttpRequest::Forward { target: "[::1]:8080".to_string(), head }));
+
+        // origin-form (not for a proxy), no host, other schemes
+        for http_req in [&b"GET /path HTTP/1.1\r\n\r\n"[..], &b"GET https://example.com/ HTTP/1.1\r\n\r\n"[..]] {
+            let mut buffer = bytes::BytesMut::from(http_req);
+            assert!(matches!(codec.decode(&mut buffer), Err(DecodeError::MethodNotAllowed(m)) if m == "GET"));
+        }
+        // non ascii url
+        for http_req in ["GET ééééé HTTP/1.1\r\n\r\n", "GET http:/é HTTP/1.1\r\n\r\n"] {
+            let mut buffer = bytes::BytesMut::from(http_req.as_bytes());
+            assert!(matches!(codec.decode(&mut buffer), Err(DecodeError::MethodNotAllowed(m)) if m == "GET"));
         }
+        let mut buffer = bytes::BytesMut::from(&b"GET http:///path HTTP/1.1\r\n\r\n"[..]);
+        assert!(matches!(codec.decode(&mut buffer), Err(DecodeError::InvalidTarget(_))));
+        let mut buffer = bytes::BytesMut::from(&b"GET http://example.com/ HTTP/2\r\n\r\n"[..]);
+        assert!(matches!(codec.decode(&mut buffer), Err(DecodeError::UnsupportedVersion(v)) if v == "HTTP/2"));
+        let mut buffer = bytes::BytesMut::from(&b"GET http://example.com/ HTTP/x\r\n\r\n"[..]);
+        assert!(matches!(codec.decode(&mut buffer), Err(DecodeError::InvalidRequest)));
+
+        // strict version
+        let mut codec = HttpCodec { forward_http: true, strict_version: Some(HttpVersion::Http11), ..Default::default() };
+        let mut buffer = bytes::BytesMut::from(&b"GET http://example.com/ HTTP/1.0\r\n\r\n"[..]);
+        assert!(matches!(codec.decode(&mut buffer), Err(DecodeError::VersionNotAllowed(HttpVersion::Http10))));
+
+        // disabled
+        let mut codec = HttpCodec::default();
+        let mut buffer = bytes::BytesMut::from(&b"GET http://example.com/path HTTP/1.1\r\n\r\n"[..]);
+        assert!(matches!(codec.decode(&mut buffer), Err(DecodeError::MethodNotAllowed(m)) if m == "GET"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_headers() -> Result<(), DecodeError> {
+        let mut codec = HttpCodec { parse_headers: true, ..Default::default() };
+        let mut buffer = bytes::BytesMut::new();
+        buffer.put(&b"CONNECT google.com:80 HTTP/1.1\r\nHost: google.com:80\r\n"[..]);
+        // wait for the blank line
+        assert!(codec.decode(&mut buffer)?.is_none());
+        buffer.put(&b"X-Tunnel-Deadline-Ms:  1500 \r\n\r\ndata"[..]);
+
+        assert_eq!(codec.decode(&mut buffer)?.unwrap().target(), Some("google.com:80"));
+        assert_eq!(codec.headers.len(), 2);
+        assert_eq!(codec.header("host"), Some("google.com:80"));
+        assert_eq!(codec.header("x-tunnel-deadline-ms"), Some("1500"));
+        assert_eq!(codec.header("Proxy-Authorization"), None);
+        // the whole head is consumed
+        assert_eq!(&buffer[..], b"data");
+
+        // no headers
+        buffer.clear();
+        buffer.put(&b"CONNECT example.com:443 HTTP/1.1\r\n\r\n"[..]);
+        assert_eq!(codec.decode(&mut buffer)?.unwrap().target(), Some("example.com:443"));
+        assert!(codec.headers.is_empty());
+        assert!(buffer.is_empty());
+
+        buffer.put(&b"CONNECT example.com:443 HTTP/1.1\r\nno colon\r\n\r\n"[..]);
+        assert!(matches!(codec.decode(&mut buffer), Err(DecodeError::InvalidHeader(_))));
+
+        buffer.clear();
+        buffer.put(&b"CONNECT example.com:443 HTTP/1.1\r\n"[..]);
+        buffer.put(&vec![b'a'; 8 * 1024][..]);
+        assert!(matches!(codec.decode(&mut buffer), Err(DecodeError::TooLarge(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_strict_head() -> Result<(), DecodeError> {
+        let request = &b"CONNECT google.com:443 HTTP/1.1\r\nHost: google.com:443\r\n"[..];
+
+        // lenient: the request line is enough
+        let mut codec = HttpCodec::default();
+        let mut buffer = bytes::BytesMut::from(request);
+        assert_eq!(codec.decode(&mut buffer)?.unwrap().target(), Some("google.com:443"));
+        assert_eq!(&buffer[..], b"Host: google.com:443\r\n");
+
+        // strict: wait for the blank line, data following it is left untouched
+        let mut codec = HttpCodec { parse_headers: true, ..Default::default() };
+        let mut buffer = bytes::BytesMut::from(request);
+        assert!(codec.decode(&mut buffer)?.is_none());
+        buffer.put(&b"\r"[..]);
+        assert!(codec.decode(&mut buffer)?.is_none());
+        buffer.put(&b"\n\x16\x03\x01"[..]);
+        assert_eq!(codec.decode(&mut buffer)?.unwrap().target(), Some("google.com:443"));
+        assert_eq!(&buffer[..], b"\x16\x03\x01");
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_host_headers() -> Result<(), DecodeError> {
+        let mut codec = HttpCodec { parse_headers: true, ..Default::default() };
+        for head in ["Host: example.com:443\r\n", "Host: example.com:443\r\nhost: Example.com:443\r\nX-A: b\r\n", ""] {
+            let request = format!("CONNECT example.com:443 HTTP/1.1\r\n{}\r\n", head);
+            let mut buffer = bytes::BytesMut::from(request.as_bytes());
+            assert_eq!(codec.decode(&mut buffer)?.unwrap().target(), Some("example.com:443"));
+        }
+
+        let request = "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\nHost: internal:80\r\n\r\n";
+        let mut buffer = bytes::BytesMut::from(request.as_bytes());
+        let err = codec.decode(&mut buffer).unwrap_err();
+        assert!(matches!(&err, DecodeError::ConflictingHost(hosts) if hosts == &["example.com:443", "internal:80"]));
+        assert_eq!(err.response(), TunnelResult::BadRequest);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_require_host_match() -> Result<(), DecodeError> {
+        let mut codec = HttpCodec { parse_headers: true, require_host_match: true, ..Default::default() };
+        // Host header (if any) matching the target: same host (case insensitive), port optional
+        for (target, head) in [
+            ("example.com:443", "Host: example.com:443\r\n"),
+            ("example.com:443", "Host: EXAMPLE.com\r\n"),
+            ("[::1]:443", "Host: [::1]\r\n"),
+            ("example.com:443", ""),
+        ] {
+            let request = format!("CONNECT {} HTTP/1.1\r\n{}\r\n", target, head);
+            let mut buffer = bytes::BytesMut::from(request.as_bytes());
+            assert_eq!(codec.decode(&mut buffer)?.unwrap().target(), Some(target));
+        }
+
+        for (target, host) in [("example.com:443", "internal"), ("example.com:443", "example.com:80"), ("[::1]:443", "[::2]")] {
+            let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, host);
+            let mut buffer = bytes::BytesMut::from(request.as_bytes());
+            let err = codec.decode(&mut buffer).unwrap_err();
+            assert!(matches!(&err, DecodeError::HostMismatch(h, t) if h == host && t == target), "{:?}", err);
+            assert_eq!(err.response(), TunnelResult::BadRequest);
+        }
+
+        // not required
+        let mut codec = HttpCodec { parse_headers: true, ..Default::default() };
+        let mut buffer = bytes::BytesMut::from(&b"CONNECT example.com:443 HTTP/1.1\r\nHost: internal\r\n\r\n"[..]);
+        assert_eq!(codec.decode(&mut buffer)?.unwrap().target(), Some("example.com:443"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_sequential_requests() -> Result<(), DecodeError> {
+        let http_req = b"CONNECT google.com:80 HTTP/1.1\r\nCONNECT example.com:443 HTTP/1.1\r\n";
+        let mut codec = HttpCodec::default();
+        let mut buffer = bytes::BytesMut::with_capacity(http_req.len());
+        buffer.put(&http_req[..]);
+
+        assert_eq!(codec.decode(&mut buffer)?.unwrap().target(), Some("google.com:80"));
+        assert_eq!(codec.decode(&mut buffer)?.unwrap().target(), Some("example.com:443"));
+        assert!(buffer.is_empty());
+        assert!(codec.decode(&mut buffer)?.is_none());
+        Ok(())
     }
 
     #[test]
     fn test_encode_200() -> Result<(), std::io::Error> {
-        let mut codec = HttpCodec {};
+        let mut codec = HttpCodec::default();
+        let mut buffer = bytes::BytesMut::new();
+        codec.encode(TunnelResult::Ok, &mut buffer)?;
+        assert_eq!(buffer, b"HTTP/1.1 200 OK\r\n\r\n"[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_403() -> Result<(), std::io::Error> {
+        let mut codec = HttpCodec::default();
+        let mut buffer = bytes::BytesMut::new();
+        codec.encode(TunnelResult::Forbidden, &mut buffer)?;
+        assert_eq!(buffer, b"HTTP/1.1 403 FORBIDDEN\r\n\r\n"[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_451() -> Result<(), std::io::Error> {
+        let mut codec = HttpCodec::default();
+        let mut buffer = bytes::BytesMut::new();
+        codec.encode(TunnelResult::UnavailableForLegalReasons, &mut buffer)?;
+        assert_eq!(&buffer[..], b"HTTP/1.1 451 UNAVAILABLE_FOR_LEGAL_REASONS\r\n\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_505() -> Result<(), std::io::Error> {
+        let mut codec = HttpCodec::default();
+        let mut buffer = bytes::BytesMut::new();
+        codec.encode(TunnelResult::HttpVersionNotSupported, &mut buffer)?;
+        assert_eq!(&buffer[..], b"HTTP/1.1 505 HTTP_VERSION_NOT_SUPPORTED\r\n\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_reused_buffer() -> Result<(), std::io::Error> {
+        let mut codec = HttpCodec::default();
+        let mut buffer = bytes::BytesMut::from(&b"stale bytes"[..]);
+        codec.encode((TunnelResult::Forbidden, "denied"), &mut buffer)?;
+        codec.encode(TunnelResult::Ok, &mut buffer)?;
+        assert_eq!(&buffer[..], b"HTTP/1.1 200 OK\r\n\r\n");
+        codec.encode(OptionsResponse, &mut buffer)?;
+        assert!(buffer.starts_with(b"HTTP/1.1 200 OK\r\nAllow: "));
+        codec.encode((TunnelResult::Forbidden, "denied"), &mut buffer)?;
+        assert!(buffer.starts_with(b"HTTP/1.1 403 FORBIDDEN\r\n") && buffer.ends_with(b"\r\n\r\ndenied"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_timeouts() -> Result<(), std::io::Error> {
+        let mut codec = HttpCodec::default();
+        let mut buffer = bytes::BytesMut::new();
+        codec.encode(TunnelResult::Timeout, &mut buffer)?;
+        assert_eq!(&buffer[..], b"HTTP/1.1 408 REQUEST_TIMEOUT\r\n\r\n");
+        buffer.clear();
+        codec.encode(TunnelResult::GatewayTimeout, &mut buffer)?;
+        assert_eq!(&buffer[..], b"HTTP/1.1 504 GATEWAY_TIMEOUT\r\n\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_503() -> Result<(), std::io::Error> {
+        let mut codec = HttpCodec::default();
+        let mut buffer = bytes::BytesMut::new();
+        codec.encode(TunnelResult::ServiceUnavailable, &mut buffer)?;
+        assert_eq!(&buffer[..], b"HTTP/1.1 503 SERVICE_UNAVAILABLE\r\n\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_body() -> Result<(), std::io::Error> {
+        let mut codec = HttpCodec::default();
+        let mut buffer = bytes::BytesMut::new();
+        codec.encode((TunnelResult::BadRequest, DecodeError::InvalidRequest.reason()), &mut buffer)?;
+        assert_eq!(&buffer[..], &b"HTTP/1.1 400 BAD_REQUEST\r\nContent-Type: text/plain\r\nContent-Length: 58\r\n\r\n\
+                                   invalid request line, expected: CONNECT host:port HTTP/1.1"[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_options() -> Result<(), std::io::Error> {
+        let mut codec = HttpCodec { proxy_agent: Some("rust_http_tunnel/0.1".to_string()), ..Default::default() };
+        let mut buffer = bytes::BytesMut::new();
+        codec.encode(OptionsResponse, &mut buffer)?;
+        assert_eq!(buffer, b"HTTP/1.1 200 OK\r\nAllow: CONNECT, OPTIONS\r\nProxy-Agent: rust_http_tunnel/0.1\r\n\
+                             Content-Length: 0\r\n\r\n"[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_proxy_agent() -> Result<(), std::io::Error> {
+        let mut codec = HttpCodec { proxy_agent: Some("rust_http_tunnel/0.1".to_string()), ..Default::default() };
+        let mut buffer = bytes::BytesMut::new();
+        codec.encode(TunnelResult::Ok, &mut buffer)?;
+        assert_eq!(buffer, b"HTTP/1.1 200 OK\r\nProxy-Agent: rust_http_tunnel/0.1\r\n\r\n"[..]);
+
+        // only for 200
+        let mut buffer = bytes::BytesMut::new();
+        codec.encode(TunnelResult::Forbidden, &mut buffer)?;
+        assert_eq!(buffer, b"HTTP/1.1 403 FORBIDDEN\r\n\r\n"[..]);
+
+        // not configured
+        let mut codec = HttpCodec::default();
         let mut buffer = bytes::BytesMut::new();
         codec.encode(TunnelResult::Ok, &mut buffer)?;
         assert_eq!(buffer, b"HTTP/1.1 200 OK\r\n\r\n"[..]);
@@ -0,0 +1,1141 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::codec::HttpVersion;
+
+use crate::dns::MAX_HOSTNAME_LEN;
+use crate::filter::{PeerAllowlist, UpstreamDenylist};
+use crate::mirror::{MirrorDirection, MirrorOverflow, MirrorSink};
+use crate::relay::RELAY_BUFFER_SIZE;
+use crate::rewrite::RewriteTable;
+use crate::timeouts::TargetTimeouts;
+use crate::tls::TlsVersion;
+
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_CONNECT_ADDRS: usize = 3;
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(200);
+const LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+const REQUEST_BUFFER_SIZE: usize = 8 * 1024; // FramedRead default
+const MIN_KEY_BITS: usize = 2048;
+const ERROR_RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+const MIRROR_QUEUE: usize = 64; // writes, i.e. at most 64 relay buffers per tunnel
+
+// Tunnel configuration
+// Built from the command line: ADDR [CERT KEY] [--option value]...
+// e.g. "127.0.0.1:6161 cert.pem key.pem --dns-server 10.0.0.53"
+
+const DNS_DEFAULT_PORT: u16 = 53;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsFiles {
+    pub cert: String,
+    pub key: String,
+}
+
+// tokio runtime flavor
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RuntimeFlavor {
+    CurrentThread,
+    MultiThread,
+}
+
+impl FromStr for RuntimeFlavor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "current-thread" => Ok(RuntimeFlavor::CurrentThread),
+            "multi-thread" => Ok(RuntimeFlavor::MultiThread),
+            _ => Err(format!("unsupported runtime: {} (expect current-thread or multi-thread)", s)),
+        }
+    }
+}
+
+// Behavior of a new connection once the max connections is reached
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overload {
+    // wait for a connection to close (not accepted in the meantime)
+    Queue,
+    // 503 (tcp), closed (tls: it would require a tls handshake)
+    Reject,
+    // closed
+    Drop,
+}
+
+impl FromStr for Overload {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queue" => Ok(Overload::Queue),
+            "reject" => Ok(Overload::Reject),
+            "drop" => Ok(Overload::Drop),
+            _ => Err(format!("unsupported overload behavior: {} (expect queue, reject or drop)", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub addr: String,
+    // additional listen addresses
+    pub listen_addrs: Vec<String>,
+    // per listener settings: (listen address, option name, value) applied on top of the others, see for_listener
+    pub listener_options: Vec<(String, String, String)>,
+    // dual stack behavior of ipv6 listen addresses (IPV6_V6ONLY), if None use the OS default
+    pub ipv6_only: Option<bool>,
+    pub tls: Option<TlsFiles>,
+    // refuse to start with a plaintext listener (e.g. no cert / key, or a listener with tls=false)
+    pub tls_only: bool,
+    pub tls_min_version: TlsVersion,
+    pub tls_max_version: TlsVersion,
+    // cipher suite names (e.g. TLS13_AES_128_GCM_SHA256), if empty use rustls defaults
+    pub tls_cipher_suites: Vec<String>,
+    // smallest RSA private key size (bits) accepted (0: no check)
+    pub tls_min_key_bits: usize,
+    // check that the certificate file is an ordered chain (leaf first, then each issuer)
+    pub tls_strict_chain: bool,
+    // max duration of a tls handshake (from accept)
+    pub tls_handshake_timeout: Duration,
+    // if empty, use the system resolver
+    pub dns_servers: Vec<SocketAddr>,
+    // retries on transient resolution failures (0: no retry)
+    pub dns_retries: u32,
+    // max dns queries per minute, all connections combined (0: unlimited), beyond that resolutions fail (502)
+    // Note: cache hits are not queries, retries are
+    pub dns_budget: usize,
+    // how long resolutions (and failures) are cached (0: no cache)
+    pub dns_cache_ttl: Duration,
+    pub dns_negative_cache_ttl: Duration,
+    // /etc/hosts like file, names listed there are not resolved using dns
+    pub hosts_file: Option<String>,
+    // check the hosts file for changes (and reload it) at most once per interval (0: never reload)
+    pub hosts_file_reload: Duration,
+    // pinned hosts (lowercase) can only resolve to these ips, otherwise the request is refused
+    pub dns_pins: HashMap<String, Vec<IpAddr>>,
+    // hosts under these domain suffixes (lowercase) are resolved by their own nameservers (split horizon)
+    pub internal_dns: HashMap<String, Vec<SocketAddr>>,
+    // targets with a longer hostname are not resolved (502), at most 253 (the dns limit)
+    pub max_hostname_len: usize,
+    // max resolved addresses tried (in order) when connecting to upstream
+    pub max_connect_addrs: usize,
+    // max duration of each upstream connection attempt
+    pub connect_timeout: Duration,
+    // parallel connection attempts (happy eyeballs, RFC 8305): start the next address after this delay
+    // (or once an attempt failed), the first connected wins (0: one address after the other)
+    pub happy_eyeballs_delay: Duration,
+    // max duration from the request start to the relay start (request read, resolved, connected, 200 sent)
+    // the connection is closed after a 408 / 504 on timeout (0: no limit)
+    pub establish_timeout: Duration,
+    // a target with several addresses: try first the one picked from the client ip (same client, same address)
+    pub sticky_routing: bool,
+    // local ip of the upstream connections (None: chosen by the system)
+    pub upstream_bind: Option<IpAddr>,
+    // disable Nagle algorithm on client & upstream sockets
+    pub tcp_nodelay: bool,
+    // relay buffer size for each direction (e.g. a larger upstream -> client buffer for downloads)
+    pub relay_buffer_client_to_upstream: usize,
+    pub relay_buffer_upstream_to_client: usize,
+    // adaptive relay buffers: start with this size and grow up to the direction buffer size on sustained throughput
+    // (None: fixed size buffers)
+    pub relay_buffer_min: Option<usize>,
+    // initial read buffer size for the CONNECT request (grows if the request is larger)
+    pub request_buffer_size: usize,
+    // log (hex) the first bytes relayed in each direction, for debugging (0: disabled)
+    pub tap_bytes: usize,
+    // audit mirror: a copy of one direction of every tunnel is written to this sink (None: disabled)
+    pub mirror_sink: Option<MirrorSink>,
+    pub mirror_direction: MirrorDirection,
+    // writes queued for the sink (per tunnel), once full: see mirror_overflow
+    pub mirror_queue: usize,
+    pub mirror_overflow: MirrorOverflow,
+    // resolve & connect to targets, reply but never relay
+    pub dry_run: bool,
+    // port used for CONNECT targets without one (None: such targets fail to resolve)
+    pub default_port: Option<u16>,
+    // reject CONNECT targets with userinfo ("user@host:port", 400) instead of stripping it
+    pub reject_userinfo: bool,
+    // lowercase the target host (not the port) before rewrite, policy & resolution (dns is case insensitive),
+    // e.g. "Example.com:443" & "example.com:443" share the same cache entry
+    // Note: rewrite rules are compared as is (to be written lowercase)
+    pub lowercase_host: bool,
+    // wait for the whole request head (headers & blank line) before acting on a request
+    // if not set, the request line is enough (headers are neither parsed nor waited for, e.g. X-Tunnel-Deadline-Ms)
+    pub strict_head: bool,
+    // reject (400) requests with a Host header not matching the CONNECT target (strict head only)
+    pub require_host_match: bool,
+    // reject (400) requests followed by data sent before the response (strict head only)
+    // if not set, this data (e.g. pipelined client payload) is written to upstream first
+    pub reject_early_data: bool,
+    // forwarding mode: also relay absolute-form requests (e.g. "GET http://host/path HTTP/1.1") to their host,
+    // sent in origin-form ("GET /path HTTP/1.1" & "Host: host")
+    pub forward_http: bool,
+    // only accept requests of this HTTP version, e.g. reject HTTP/1.0 downgrade attempts (None: the default versions)
+    pub strict_http_version: Option<HttpVersion>,
+    // CONNECT target -> destination, applied before resolution
+    pub rewrites: RewriteTable,
+    // connect (& establishment) timeouts of the targets (after rewrite) matching a pattern
+    pub target_timeouts: TargetTimeouts,
+    // read a PROXY protocol (v1/v2) header at the start of each connection to get the real client address
+    pub proxy_protocol: bool,
+    // peers (ip networks) allowed to connect, if empty allow all
+    pub peer_allowlist: PeerAllowlist,
+    // send a 403 before closing the connection of a peer not allowed (tcp only)
+    pub peer_reject_response: bool,
+    // resolved addresses (ip networks) never connected to, a target with only such addresses gets a 403
+    pub upstream_denylist: UpstreamDenylist,
+    // file of denied target hosts (403), reloaded on SIGHUP
+    pub deny_targets: Option<String>,
+    // on reload, close the running tunnels whose target is now denied
+    pub terminate_denied: bool,
+    // hold rejected connections open for this long before closing them (slow down scanners)
+    pub tarpit: Duration,
+    // max duration of an error response write, the connection is closed on timeout (0: no limit)
+    pub error_response_timeout: Duration,
+    // close tunnels without data in either direction for this long (0: never)
+    pub idle_timeout: Duration,
+    // tcp keepalive probes (idle time & interval) on client & upstream sockets (0: disabled)
+    // Note: kept alive tunnels are not closed by the idle timeout, the probes close dead connections instead
+    pub relay_keepalive: Duration,
+    // TCP_USER_TIMEOUT on client & upstream sockets (0: OS default), linux only (ignored elsewhere)
+    // i.e. a connection whose sent data is not acknowledged for this long is closed (dead peer)
+    pub tcp_user_timeout: Duration,
+    // SO_RCVBUF / SO_SNDBUF on client & upstream sockets (None: OS default, i.e. autotuning)
+    // e.g. larger buffers for links with a high bandwidth-delay product
+    pub socket_recv_buffer: Option<usize>,
+    pub socket_send_buffer: Option<usize>,
+    // once one direction of a tunnel reached EOF, close the tunnel if the other one is not done after this long
+    // (0: wait for both directions)
+    pub linger_after_eof: Duration,
+    // half-close passthrough (e.g. FTP): an upstream done (EOF) without sending anything does not close the tunnel,
+    // the client can keep sending (by default the tunnel is closed, see Upstream closed immediately)
+    pub half_close: bool,
+    // close tunnels open for this long (0: unlimited), also caps the client deadline (X-Tunnel-Deadline-Ms header)
+    pub max_tunnel_lifetime: Duration,
+    // max bytes relayed by a tunnel (both directions combined), torn down once reached (0: unlimited)
+    pub max_tunnel_bytes: u64,
+    // max concurrent tunnels to the same target (host:port, after rewrite), 0: unlimited
+    // over the limit, a request waits for up to max_tunnels_per_host_wait (0: no wait) then gets a 503
+    pub max_tunnels_per_host: usize,
+    pub max_tunnels_per_host_wait: Duration,
+    // max concurrent connections, all listeners combined (0: unlimited)
+    pub max_connections: usize,
+    // once reached, see Overload
+    pub on_overload: Overload,
+    // on shutdown (Ctrl-C), keep running tunnels for this long while refusing new requests (503)
+    // 0: quit immediately
+    pub shutdown_grace: Duration,
+    // log a heartbeat (uptime, open connections & running tunnels) every interval while accepting (0: never)
+    pub heartbeat_interval: Duration,
+    // Proxy-Agent header value in 200 responses (no header if None)
+    pub proxy_agent: Option<String>,
+    // re-originate tls to these target hosts, verified with the upstream CA file (pem)
+    pub upstream_tls_hosts: Vec<String>,
+    pub upstream_tls_ca: Option<String>,
+    pub runtime_flavor: RuntimeFlavor,
+    // multi-thread runtime only, if None use tokio default (env TOKIO_WORKER_THREADS or cpu count)
+    pub worker_threads: Option<usize>,
+    // write the log to this file instead of stdout, rotated once larger than log_max_size (0: never rotated)
+    pub log_file: Option<String>,
+    pub log_max_size: u64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Please provide a host:port like 127.0.0.1:7070")]
+    MissingAddr,
+    #[error("unexpected argument: {0}")]
+    UnexpectedArg(String),
+    #[error("unknown option: {0}")]
+    UnknownOption(String),
+    #[error("missing value for option: {0}")]
+    MissingValue(String),
+    #[error("invalid value for option {0}: {1}")]
+    InvalidValue(String, String),
+}
+
+impl Config {
+    pub fn new(addr: &str) -> Self {
+        Self {
+            addr: addr.to_string(),
+            listen_addrs: Vec::new(),
+            listener_options: Vec::new(),
+            ipv6_only: None,
+            tls: None,
+            tls_only: false,
+            tls_min_version: TlsVersion::Tls12,
+            tls_max_version: TlsVersion::Tls13,
+            tls_cipher_suites: Vec::new(),
+            tls_strict_chain: false,
+            tls_min_key_bits: MIN_KEY_BITS,
+            tls_handshake_timeout: TLS_HANDSHAKE_TIMEOUT,
+            dns_servers: Vec::new(),
+            dns_retries: 0,
+            dns_budget: 0,
+            dns_cache_ttl: Duration::ZERO,
+            dns_negative_cache_ttl: Duration::ZERO,
+            hosts_file: None,
+            hosts_file_reload: Duration::ZERO,
+            dns_pins: HashMap::new(),
+            internal_dns: HashMap::new(),
+            max_hostname_len: MAX_HOSTNAME_LEN,
+            max_connect_addrs: MAX_CONNECT_ADDRS,
+            happy_eyeballs_delay: Duration::ZERO,
+            sticky_routing: false,
+            connect_timeout: CONNECT_TIMEOUT,
+            establish_timeout: Duration::ZERO,
+            upstream_bind: None,
+            tcp_nodelay: true,
+            relay_buffer_client_to_upstream: RELAY_BUFFER_SIZE,
+            relay_buffer_upstream_to_client: RELAY_BUFFER_SIZE,
+            relay_buffer_min: None,
+            request_buffer_size: REQUEST_BUFFER_SIZE,
+            tap_bytes: 0,
+            mirror_sink: None,
+            mirror_direction: MirrorDirection::Up,
+            mirror_queue: MIRROR_QUEUE,
+            mirror_overflow: MirrorOverflow::Drop,
+            dry_run: false,
+            default_port: None,
+            reject_userinfo: false,
+            lowercase_host: false,
+            strict_head: false,
+            require_host_match: false,
+            reject_early_data: false,
+            forward_http: false,
+            strict_http_version: None,
+            rewrites: RewriteTable::new(),
+            target_timeouts: TargetTimeouts::new(),
+            proxy_protocol: false,
+            peer_allowlist: PeerAllowlist::default(),
+            upstream_denylist: UpstreamDenylist::default(),
+            peer_reject_response: false,
+            deny_targets: None,
+            terminate_denied: false,
+            tarpit: Duration::ZERO,
+            error_response_timeout: ERROR_RESPONSE_TIMEOUT,
+            idle_timeout: Duration::ZERO,
+            relay_keepalive: Duration::ZERO,
+            tcp_user_timeout: Duration::ZERO,
+            socket_recv_buffer: None,
+            socket_send_buffer: None,
+            linger_after_eof: Duration::ZERO,
+            half_close: false,
+            max_tunnel_lifetime: Duration::ZERO,
+            max_tunnel_bytes: 0,
+            max_tunnels_per_host: 0,
+            max_tunnels_per_host_wait: Duration::ZERO,
+            max_connections: 0,
+            on_overload: Overload::Reject,
+            shutdown_grace: Duration::ZERO,
+            heartbeat_interval: Duration::ZERO,
+            proxy_agent: None,
+            upstream_tls_hosts: Vec::new(),
+            upstream_tls_ca: None,
+            runtime_flavor: RuntimeFlavor::MultiThread,
+            worker_threads: None,
+            log_file: None,
+            log_max_size: LOG_MAX_SIZE,
+        }
+    }
+
+    // Note: args should not contain args[0] (cmd line string)
+    pub fn from_args<I>(args: I) -> Result<Self, ConfigError> where I: IntoIterator<Item = String> {
+
+        let mut positional: Vec<String> = Vec::new();
+        let mut options: Vec<(String, String)> = Vec::new();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg.starts_with("--") {
+                let value = args.next().ok_or_else(|| ConfigError::MissingValue(arg.clone()))?;
+                options.push((arg, value));
+            } else {
+                positional.push(arg);
+            }
+        }
+
+        let mut config = match positional.len() {
+            0 => return Err(ConfigError::MissingAddr),
+            1 => Config::new(&positional[0]),
+            3 => {
+                let mut config = Config::new(&positional[0]);
+                config.tls = Some(TlsFiles { cert: positional[1].clone(), key: positional[2].clone() });
+                config
+            },
+            _ => return Err(ConfigError::UnexpectedArg(positional.pop().unwrap_or_default())),
+        };
+
+        for (name, value) in options {
+            config.set_option(&name, &value)?;
+        }
+        for (addr, name, value) in &config.listener_options {
+            if *addr != config.addr && !config.listen_addrs.contains(addr) {
+                return Err(ConfigError::InvalidValue("--listener-option".to_string(), format!("{} (not a listen address)", addr)));
+            }
+            config.clone().set_option(&format!("--{}", name), value)?;
+        }
+
+        Ok(config)
+    }
+
+    // Settings of the listener on addr: the options, then its own ones (--listener-option ADDR/NAME=VALUE)
+    // e.g. a shorter connect timeout on a public listener
+    // Note: the resolver, runtime, logs & target denylist are shared by all listeners (set with the global options)
+    pub fn for_listener(&self, addr: &str) -> Result<Config, ConfigError> {
+        let mut config = self.clone();
+        for (_, name, value) in self.listener_options.iter().filter(|(listen_addr, _, _)| listen_addr == addr) {
+            config.set_option(&format!("--{}", name), value)?;
+        }
+        Ok(config)
+    }
+
+    fn set_option(&mut self, name: &str, value: &str) -> Result<(), ConfigError> {
+        let invalid = || ConfigError::InvalidValue(name.to_string(), value.to_string());
+
+        match name {
+            "--dns-server" => {
+                // accept "ip" or "ip:port"
+                let server = match value.parse::<SocketAddr>() {
+                    Ok(addr) => addr,
+                    Err(_) => SocketAddr::new(value.parse::<IpAddr>().map_err(|_| invalid())?,
+                                              DNS_DEFAULT_PORT),
+                };
+                self.dns_servers.push(server);
+            },
+            "--listen" => self.listen_addrs.push(value.to_string()),
+            "--listener-option" => {
+                // e.g. "[::]:6161/connect-timeout=0.5"
+                let (addr, option) = value.split_once('/').ok_or_else(invalid)?;
+                let (option_name, option_value) = option.split_once('=').ok_or_else(invalid)?;
+                // Note: --tls-only is global, per listener it could be turned off
+                // Note: --max-connections is global (shared by all listeners)
+                if matches!(option_name, "listen" | "listener-option" | "tls-only" | "max-connections") {
+                    return Err(invalid());
+                }
+                self.listener_options.push((addr.to_string(), option_name.to_string(), option_value.to_string()));
+            },
+            // Note: per listener only (--listener-option ADDR/tls=false), a plain tcp listener along tls ones
+            "--tls" => match value {
+                "false" => self.tls = None,
+                _ => return Err(invalid()),
+            },
+            "--tls-only" => self.tls_only = value.parse().map_err(|_| invalid())?,
+            "--ipv6-only" => self.ipv6_only = Some(value.parse().map_err(|_| invalid())?),
+            "--dns-retries" => self.dns_retries = value.parse().map_err(|_| invalid())?,
+            "--dns-budget" => self.dns_budget = value.parse().map_err(|_| invalid())?,
+            "--dns-cache-ttl" => self.dns_cache_ttl = parse_secs(value).ok_or_else(invalid)?,
+            "--dns-negative-cache-ttl" => self.dns_negative_cache_ttl = parse_secs(value).ok_or_else(invalid)?,
+            "--tls-min-version" => self.tls_min_version = value.parse().map_err(|_| invalid())?,
+            "--tls-max-version" => self.tls_max_version = value.parse().map_err(|_| invalid())?,
+            "--tls-min-key-bits" => self.tls_min_key_bits = value.parse().map_err(|_| invalid())?,
+            "--tls-strict-chain" => self.tls_strict_chain = value.parse().map_err(|_| invalid())?,
+            "--tls-cipher-suites" => {
+                self.tls_cipher_suites = value.split(',').map(|cs| cs.trim().to_string()).collect();
+            },
+            "--hosts-file" => self.hosts_file = Some(value.to_string()),
+            "--hosts-file-reload" => self.hosts_file_reload = parse_secs(value).ok_or_else(invalid)?,
+            "--dns-pin" => {
+                // "host=ip[,ip...]"
+                let (host, ips) = value.split_once('=').ok_or_else(invalid)?;
+                let ips = ips.split(',').map(|ip| ip.trim().parse::<IpAddr>()).collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| invalid())?;
+                if host.is_empty() {
+                    return Err(invalid());
+                }
+                self.dns_pins.entry(host.to_ascii_lowercase()).or_default().extend(ips);
+            },
+            "--max-hostname-len" => match value.parse() {
+                Ok(len) if (1..=MAX_HOSTNAME_LEN).contains(&len) => self.max_hostname_len = len,
+                _ => return Err(invalid()),
+            },
+            "--internal-dns" => {
+                // "suffix=ip[:port][,ip[:port]...]"
+                let (suffix, servers) = value.split_once('=').ok_or_else(invalid)?;
+                let servers = servers.split(',').map(|server| {
+                    let server = server.trim();
+                    server.parse::<SocketAddr>()
+                        .or_else(|_| server.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, DNS_DEFAULT_PORT)))
+                }).collect::<Result<Vec<_>, _>>().map_err(|_| invalid())?;
+                let suffix = suffix.trim_matches('.').to_ascii_lowercase();
+                if suffix.is_empty() {
+                    return Err(invalid());
+                }
+                self.internal_dns.entry(suffix).or_default().extend(servers);
+            },
+            "--tls-handshake-timeout" => self.tls_handshake_timeout = parse_secs(value).ok_or_else(invalid)?,
+            "--max-connect-addrs" => self.max_connect_addrs = parse_size(value).ok_or_else(invalid)?,
+            "--happy-eyeballs-delay" => self.happy_eyeballs_delay = parse_secs(value).ok_or_else(invalid)?,
+            "--sticky-routing" => self.sticky_routing = value.parse().map_err(|_| invalid())?,
+            "--connect-timeout" => {
+                self.connect_timeout = parse_secs(value).filter(|timeout| !timeout.is_zero()).ok_or_else(invalid)?;
+            },
+            "--upstream-bind" => self.upstream_bind = Some(value.parse().map_err(|_| invalid())?),
+            "--tcp-nodelay" => self.tcp_nodelay = value.parse().map_err(|_| invalid())?,
+            "--relay-buffer-up" => self.relay_buffer_client_to_upstream = parse_size(value).ok_or_else(invalid)?,
+            "--relay-buffer-down" => self.relay_buffer_upstream_to_client = parse_size(value).ok_or_else(invalid)?,
+            "--relay-buffer-min" => self.relay_buffer_min = Some(parse_size(value).ok_or_else(invalid)?),
+            "--request-buffer" => self.request_buffer_size = parse_size(value).ok_or_else(invalid)?,
+            "--tap-bytes" => self.tap_bytes = value.parse().map_err(|_| invalid())?,
+            "--mirror-sink" => self.mirror_sink = Some(value.parse().map_err(|_| invalid())?),
+            "--mirror-direction" => self.mirror_direction = value.parse().map_err(|_| invalid())?,
+            "--mirror-queue" => self.mirror_queue = parse_size(value).ok_or_else(invalid)?,
+            "--mirror-overflow" => self.mirror_overflow = value.parse().map_err(|_| invalid())?,
+            "--default-port" => self.default_port = Some(value.parse().map_err(|_| invalid())?),
+            "--reject-userinfo" => self.reject_userinfo = value.parse().map_err(|_| invalid())?,
+            "--lowercase-host" => self.lowercase_host = value.parse().map_err(|_| invalid())?,
+            "--strict-head" => self.strict_head = value.parse().map_err(|_| invalid())?,
+            "--require-host-match" => self.require_host_match = value.parse().map_err(|_| invalid())?,
+            "--reject-early-data" => self.reject_early_data = value.parse().map_err(|_| invalid())?,
+            "--forward-http" => self.forward_http = value.parse().map_err(|_| invalid())?,
+            "--strict-http-version" => self.strict_http_version = Some(value.parse().map_err(|_| invalid())?),
+            "--rewrite" => self.rewrites.add_rule_str(value).ok_or_else(invalid)?,
+            "--fallback" => self.rewrites.add_fallback_str(value).ok_or_else(invalid)?,
+            "--target-timeout" => self.target_timeouts.add_rule_str(value).ok_or_else(invalid)?,
+            "--proxy-protocol" => self.proxy_protocol = value.parse().map_err(|_| invalid())?,
+            "--allow-peer" => self.peer_allowlist.add(value.parse().map_err(|_| invalid())?),
+            "--deny-upstream" => self.upstream_denylist.add(value.parse().map_err(|_| invalid())?),
+            "--peer-reject-response" => self.peer_reject_response = value.parse().map_err(|_| invalid())?,
+            "--deny-targets" => self.deny_targets = Some(value.to_string()),
+            "--terminate-denied" => self.terminate_denied = value.parse().map_err(|_| invalid())?,
+            "--establish-timeout" => self.establish_timeout = parse_secs(value).ok_or_else(invalid)?,
+            "--tarpit" => self.tarpit = parse_secs(value).ok_or_else(invalid)?,
+            "--error-response-timeout" => self.error_response_timeout = parse_secs(value).ok_or_else(invalid)?,
+            "--idle-timeout" => self.idle_timeout = parse_secs(value).ok_or_else(invalid)?,
+            "--relay-keepalive" => self.relay_keepalive = parse_secs(value).ok_or_else(invalid)?,
+            "--tcp-user-timeout" => self.tcp_user_timeout = parse_secs(value).ok_or_else(invalid)?,
+            "--socket-recv-buffer" => self.socket_recv_buffer = Some(parse_size(value).ok_or_else(invalid)?),
+            "--socket-send-buffer" => self.socket_send_buffer = Some(parse_size(value).ok_or_else(invalid)?),
+            "--linger-after-eof" => self.linger_after_eof = parse_secs(value).ok_or_else(invalid)?,
+            "--half-close" => self.half_close = value.parse().map_err(|_| invalid())?,
+            "--max-tunnel-lifetime" => self.max_tunnel_lifetime = parse_secs(value).ok_or_else(invalid)?,
+            "--max-tunnel-bytes" => self.max_tunnel_bytes = value.parse().map_err(|_| invalid())?,
+            "--max-tunnels-per-host" => self.max_tunnels_per_host = value.parse().map_err(|_| invalid())?,
+            "--max-tunnels-per-host-wait" => {
+                self.max_tunnels_per_host_wait = parse_secs(value).ok_or_else(invalid)?;
+            },
+            "--max-connections" => self.max_connections = value.parse().map_err(|_| invalid())?,
+            "--on-overload" => self.on_overload = value.parse().map_err(|_| invalid())?,
+            "--shutdown-grace" => self.shutdown_grace = parse_secs(value).ok_or_else(invalid)?,
+            "--heartbeat" => self.heartbeat_interval = parse_secs(value).ok_or_else(invalid)?,
+            "--proxy-agent" => {
+                // Note: no CR / LF (header injection)
+                if value.is_empty() || value.contains(|c: char| c.is_control()) {
+                    return Err(invalid());
+                }
+                self.proxy_agent = Some(value.to_string());
+            },
+            "--upstream-tls" => self.upstream_tls_hosts.push(value.to_string()),
+            "--upstream-tls-ca" => self.upstream_tls_ca = Some(value.to_string()),
+            "--runtime" => self.runtime_flavor = value.parse().map_err(|_| invalid())?,
+            "--worker-threads" => self.worker_threads = Some(parse_size(value).ok_or_else(invalid)?),
+            "--log-file" => self.log_file = Some(value.to_string()),
+            "--log-max-size" => self.log_max_size = value.parse().map_err(|_| invalid())?,
+            "--dry-run" => self.dry_run = value.parse().map_err(|_| invalid())?,
+            _ => return Err(ConfigError::UnknownOption(name.to_string())),
+        }
+        Ok(())
+    }
+}
+
+// Duration in seconds, e.g. "30" or "0.5"
+fn parse_secs(value: &str) -> Option<Duration> {
+    value.parse::<f64>().ok().and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+}
+
+// Non zero size in bytes
+fn parse_size(value: &str) -> Option<usize> {
+    value.parse::<usize>().ok().filter(|size| *size > 0)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{Config, ConfigError, Overload, RuntimeFlavor, TlsFiles};
+    use crate::tls::TlsVersion;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_config_tcp_and_tls() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&["127.0.0.1:6161"]))?;
+        assert_eq!(config, Config::new("127.0.0.1:6161"));
+
+        let config = Config::from_args(args(&["127.0.0.1:6161", "cert.pem", "key.pem"]))?;
+        assert_eq!(config.tls, Some(TlsFiles { cert: "cert.pem".to_string(), key: "key.pem".to_string() }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_dns_servers() -> Result<(), ConfigError> {
+        let config = Config::from_args(
+            args(&["127.0.0.1:6161", "--dns-server", "10.0.0.53", "--dns-server", "[::1]:5353"])
+        )?;
+        assert_eq!(config.dns_servers, vec!["10.0.0.53:53".parse().unwrap(), "[::1]:5353".parse().unwrap()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_dns_retries() -> Result<(), ConfigError> {
+        assert_eq!(Config::from_args(args(&["127.0.0.1:6161"]))?.dns_retries, 0);
+        assert_eq!(Config::from_args(args(&["127.0.0.1:6161", "--dns-retries", "3"]))?.dns_retries, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_dns_budget() -> Result<(), ConfigError> {
+        assert_eq!(Config::from_args(args(&["127.0.0.1:6161"]))?.dns_budget, 0);
+        assert_eq!(Config::from_args(args(&["127.0.0.1:6161", "--dns-budget", "600"]))?.dns_budget, 600);
+        assert!(Config::from_args(args(&["a", "--dns-budget", "-1"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_dns_cache_ttl() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&[
+            "127.0.0.1:6161", "--dns-cache-ttl", "60", "--dns-negative-cache-ttl", "0.5"
+        ]))?;
+        assert_eq!(config.dns_cache_ttl, std::time::Duration::from_secs(60));
+        assert_eq!(config.dns_negative_cache_ttl, std::time::Duration::from_millis(500));
+        assert!(Config::from_args(args(&["a", "--dns-cache-ttl", "-1"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_hosts_file() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&[
+            "127.0.0.1:6161", "--hosts-file", "/etc/tunnel_hosts", "--hosts-file-reload", "5"
+        ]))?;
+        assert_eq!(config.hosts_file, Some("/etc/tunnel_hosts".to_string()));
+        assert_eq!(config.hosts_file_reload, std::time::Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_dns_pins() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&[
+            "127.0.0.1:6161", "--dns-pin", "Bank.example.com=10.0.0.1,10.0.0.2", "--dns-pin", "bank.example.com=::1"
+        ]))?;
+        let ips: Vec<std::net::IpAddr> = vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap(), "::1".parse().unwrap()];
+        assert_eq!(config.dns_pins.get("bank.example.com"), Some(&ips));
+        assert!(Config::from_args(args(&["a", "--dns-pin", "bank.example.com"])).is_err());
+        assert!(Config::from_args(args(&["a", "--dns-pin", "bank.example.com=10.0.0"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_internal_dns() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&[
+            "127.0.0.1:6161", "--internal-dns", ".Internal=10.0.0.53,10.0.0.54:5353"
+        ]))?;
+        let servers: Vec<std::net::SocketAddr> = vec!["10.0.0.53:53".parse().unwrap(), "10.0.0.54:5353".parse().unwrap()];
+        assert_eq!(config.internal_dns.get("internal"), Some(&servers));
+        assert!(Config::from_args(args(&["a", "--internal-dns", ".=10.0.0.53"])).is_err());
+        assert!(Config::from_args(args(&["a", "--internal-dns", "internal=dns.internal"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_max_hostname_len() -> Result<(), ConfigError> {
+        assert_eq!(Config::from_args(args(&["127.0.0.1:6161"]))?.max_hostname_len, 253);
+        assert_eq!(Config::from_args(args(&["127.0.0.1:6161", "--max-hostname-len", "64"]))?.max_hostname_len, 64);
+        assert!(Config::from_args(args(&["a", "--max-hostname-len", "0"])).is_err());
+        assert!(Config::from_args(args(&["a", "--max-hostname-len", "254"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_max_connect_addrs() -> Result<(), ConfigError> {
+        assert_eq!(Config::from_args(args(&["127.0.0.1:6161"]))?.max_connect_addrs, 3);
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--max-connect-addrs", "1"]))?;
+        assert_eq!(config.max_connect_addrs, 1);
+        assert!(Config::from_args(args(&["a", "--max-connect-addrs", "0"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_sticky_routing() -> Result<(), ConfigError> {
+        assert!(!Config::from_args(args(&["127.0.0.1:6161"]))?.sticky_routing);
+        assert!(Config::from_args(args(&["127.0.0.1:6161", "--sticky-routing", "true"]))?.sticky_routing);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_happy_eyeballs_delay() -> Result<(), ConfigError> {
+        assert!(Config::from_args(args(&["127.0.0.1:6161"]))?.happy_eyeballs_delay.is_zero());
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--happy-eyeballs-delay", "0.25"]))?;
+        assert_eq!(config.happy_eyeballs_delay, std::time::Duration::from_millis(250));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_connect_timeout() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&["127.0.0.1:6161"]))?;
+        assert_eq!(config.connect_timeout, std::time::Duration::from_millis(200));
+        assert_eq!(config.upstream_bind, None);
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--connect-timeout", "1.5", "--upstream-bind", "10.0.0.2"]))?;
+        assert_eq!(config.connect_timeout, std::time::Duration::from_millis(1500));
+        assert_eq!(config.upstream_bind, Some("10.0.0.2".parse().unwrap()));
+        assert!(Config::from_args(args(&["a", "--connect-timeout", "0"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_establish_timeout() -> Result<(), ConfigError> {
+        assert!(Config::from_args(args(&["127.0.0.1:6161"]))?.establish_timeout.is_zero());
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--establish-timeout", "3"]))?;
+        assert_eq!(config.establish_timeout, std::time::Duration::from_secs(3));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_tcp_nodelay() -> Result<(), ConfigError> {
+        assert!(Config::from_args(args(&["127.0.0.1:6161"]))?.tcp_nodelay);
+        assert!(!Config::from_args(args(&["127.0.0.1:6161", "--tcp-nodelay", "false"]))?.tcp_nodelay);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_dry_run() -> Result<(), ConfigError> {
+        assert!(!Config::from_args(args(&["127.0.0.1:6161"]))?.dry_run);
+        assert!(Config::from_args(args(&["127.0.0.1:6161", "--dry-run", "true"]))?.dry_run);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_tls_versions() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&[
+            "127.0.0.1:6161", "cert.pem", "key.pem",
+            "--tls-min-version", "1.3", "--tls-cipher-suites", "TLS13_AES_256_GCM_SHA384, TLS13_AES_128_GCM_SHA256"
+        ]))?;
+        assert_eq!(config.tls_min_version, TlsVersion::Tls13);
+        assert_eq!(config.tls_max_version, TlsVersion::Tls13);
+        assert_eq!(config.tls_cipher_suites, vec!["TLS13_AES_256_GCM_SHA384", "TLS13_AES_128_GCM_SHA256"]);
+        assert!(Config::from_args(args(&["a", "--tls-max-version", "1.1"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_tls_strict_chain() -> Result<(), ConfigError> {
+        assert!(!Config::from_args(args(&["127.0.0.1:6161"]))?.tls_strict_chain);
+        assert!(Config::from_args(args(&["127.0.0.1:6161", "--tls-strict-chain", "true"]))?.tls_strict_chain);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_tls_min_key_bits() -> Result<(), ConfigError> {
+        assert_eq!(Config::from_args(args(&["127.0.0.1:6161"]))?.tls_min_key_bits, 2048);
+        assert_eq!(Config::from_args(args(&["127.0.0.1:6161", "--tls-min-key-bits", "3072"]))?.tls_min_key_bits, 3072);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_tls_handshake_timeout() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--tls-handshake-timeout", "2"]))?;
+        assert_eq!(config.tls_handshake_timeout, std::time::Duration::from_secs(2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_default_port() -> Result<(), ConfigError> {
+        assert_eq!(Config::from_args(args(&["127.0.0.1:6161"]))?.default_port, None);
+        assert_eq!(Config::from_args(args(&["127.0.0.1:6161", "--default-port", "443"]))?.default_port, Some(443));
+        assert!(Config::from_args(args(&["a", "--default-port", "70000"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_reject_userinfo() -> Result<(), ConfigError> {
+        assert!(!Config::from_args(args(&["127.0.0.1:6161"]))?.reject_userinfo);
+        assert!(Config::from_args(args(&["127.0.0.1:6161", "--reject-userinfo", "true"]))?.reject_userinfo);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_strict_head() -> Result<(), ConfigError> {
+        assert!(!Config::from_args(args(&["127.0.0.1:6161"]))?.strict_head);
+        assert!(Config::from_args(args(&["127.0.0.1:6161", "--strict-head", "true"]))?.strict_head);
+        assert!(Config::from_args(args(&["a", "--strict-head", "yes"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_lowercase_host() -> Result<(), ConfigError> {
+        assert!(!Config::from_args(args(&["127.0.0.1:6161"]))?.lowercase_host);
+        assert!(Config::from_args(args(&["127.0.0.1:6161", "--lowercase-host", "true"]))?.lowercase_host);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_require_host_match() -> Result<(), ConfigError> {
+        assert!(!Config::from_args(args(&["127.0.0.1:6161"]))?.require_host_match);
+        assert!(Config::from_args(args(&["127.0.0.1:6161", "--require-host-match", "true"]))?.require_host_match);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_reject_early_data() -> Result<(), ConfigError> {
+        assert!(!Config::from_args(args(&["127.0.0.1:6161"]))?.reject_early_data);
+        assert!(Config::from_args(args(&["127.0.0.1:6161", "--reject-early-data", "true"]))?.reject_early_data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_forward_http() -> Result<(), ConfigError> {
+        assert!(!Config::from_args(args(&["127.0.0.1:6161"]))?.forward_http);
+        assert!(Config::from_args(args(&["127.0.0.1:6161", "--forward-http", "true"]))?.forward_http);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_strict_http_version() -> Result<(), ConfigError> {
+        assert_eq!(Config::from_args(args(&["127.0.0.1:6161"]))?.strict_http_version, None);
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--strict-http-version", "1.1"]))?;
+        assert_eq!(config.strict_http_version, Some(crate::codec::HttpVersion::Http11));
+        assert!(Config::from_args(args(&["127.0.0.1:6161", "--strict-http-version", "2"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_rewrites() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&[
+            "127.0.0.1:6161", "--rewrite", "api.example.com:443=10.0.0.5:8443", "--rewrite", "a:80=b:81"
+        ]))?;
+        assert_eq!(config.rewrites.rewrite("api.example.com:443"), "10.0.0.5:8443");
+        assert_eq!(config.rewrites.rewrite("a:80"), "b:81");
+        assert!(Config::from_args(args(&["a", "--rewrite", "a:80"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_fallback() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--rewrite", "a:80=b:81", "--fallback", "b:81=c:82"]))?;
+        assert_eq!(config.rewrites.fallback("b:81"), Some("c:82"));
+        assert!(Config::from_args(args(&["a", "--fallback", "b:81"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_target_timeout() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--target-timeout", "*.slow.example.com=30,60"]))?;
+        let timeout = config.target_timeouts.get("db.slow.example.com:443").unwrap();
+        let expected = (std::time::Duration::from_secs(30), Some(std::time::Duration::from_secs(60)));
+        assert_eq!((timeout.connect, timeout.establish), expected);
+        assert!(Config::from_args(args(&["a", "--target-timeout", "*.slow.example.com"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_peer_allowlist() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&["127.0.0.1:6161"]))?;
+        assert!(config.peer_allowlist.is_allowed(&"1.2.3.4".parse().unwrap()));
+
+        let config = Config::from_args(args(&[
+            "127.0.0.1:6161", "--allow-peer", "10.0.0.0/8", "--allow-peer", "::1", "--peer-reject-response", "true"
+        ]))?;
+        assert!(config.peer_allowlist.is_allowed(&"10.2.3.4".parse().unwrap()));
+        assert!(config.peer_allowlist.is_allowed(&"::1".parse().unwrap()));
+        assert!(!config.peer_allowlist.is_allowed(&"1.2.3.4".parse().unwrap()));
+        assert!(config.peer_reject_response);
+        assert!(Config::from_args(args(&["a", "--allow-peer", "10.0.0.0/40"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_proxy_protocol() -> Result<(), ConfigError> {
+        assert!(!Config::from_args(args(&["127.0.0.1:6161"]))?.proxy_protocol);
+        assert!(Config::from_args(args(&["a", "--proxy-protocol", "true"]))?.proxy_protocol);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_tarpit() -> Result<(), ConfigError> {
+        assert!(Config::from_args(args(&["127.0.0.1:6161"]))?.tarpit.is_zero());
+        let config = Config::from_args(args(&["a", "--tarpit", "2.5"]))?;
+        assert_eq!(config.tarpit, std::time::Duration::from_millis(2500));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_error_response_timeout() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&["127.0.0.1:6161"]))?;
+        assert_eq!(config.error_response_timeout, std::time::Duration::from_secs(5));
+        let config = Config::from_args(args(&["a", "--error-response-timeout", "0.5"]))?;
+        assert_eq!(config.error_response_timeout, std::time::Duration::from_millis(500));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_upstream_denylist() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&["127.0.0.1:6161"]))?;
+        assert!(!config.upstream_denylist.is_denied(&"10.0.0.1".parse().unwrap()));
+        let config = Config::from_args(args(&["a", "--deny-upstream", "10.0.0.0/8", "--deny-upstream", "fd00::/8"]))?;
+        assert!(config.upstream_denylist.is_denied(&"10.0.0.1".parse().unwrap()));
+        assert!(config.upstream_denylist.is_denied(&"fd00::1".parse().unwrap()));
+        assert!(!config.upstream_denylist.is_denied(&"1.2.3.4".parse().unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_deny_targets() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&["127.0.0.1:6161"]))?;
+        assert_eq!(config.deny_targets, None);
+        assert!(!config.terminate_denied);
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--deny-targets", "deny.txt", "--terminate-denied", "true"]))?;
+        assert_eq!(config.deny_targets, Some("deny.txt".to_string()));
+        assert!(config.terminate_denied);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_relay_buffers() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&["127.0.0.1:6161"]))?;
+        assert_eq!(config.relay_buffer_client_to_upstream, config.relay_buffer_upstream_to_client);
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--relay-buffer-up", "1024", "--relay-buffer-down", "65536"]))?;
+        assert_eq!(config.relay_buffer_client_to_upstream, 1024);
+        assert_eq!(config.relay_buffer_upstream_to_client, 65536);
+        assert!(Config::from_args(args(&["a", "--relay-buffer-up", "0"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_relay_buffer_min() -> Result<(), ConfigError> {
+        assert_eq!(Config::from_args(args(&["127.0.0.1:6161"]))?.relay_buffer_min, None);
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--relay-buffer-min", "1024"]))?;
+        assert_eq!(config.relay_buffer_min, Some(1024));
+        assert!(Config::from_args(args(&["a", "--relay-buffer-min", "0"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_request_buffer() -> Result<(), ConfigError> {
+        assert_eq!(Config::from_args(args(&["127.0.0.1:6161"]))?.request_buffer_size, 8192);
+        assert_eq!(Config::from_args(args(&["127.0.0.1:6161", "--request-buffer", "512"]))?.request_buffer_size, 512);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_tap_bytes() -> Result<(), ConfigError> {
+        assert_eq!(Config::from_args(args(&["127.0.0.1:6161"]))?.tap_bytes, 0);
+        assert_eq!(Config::from_args(args(&["127.0.0.1:6161", "--tap-bytes", "64"]))?.tap_bytes, 64);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_mirror() -> Result<(), ConfigError> {
+        assert_eq!(Config::from_args(args(&["127.0.0.1:6161"]))?.mirror_sink, None);
+        let config = Config::from_args(args(&[
+            "127.0.0.1:6161", "--mirror-sink", "tcp://127.0.0.1:9000", "--mirror-direction", "down",
+            "--mirror-queue", "8", "--mirror-overflow", "block"
+        ]))?;
+        assert_eq!(config.mirror_sink, Some(crate::mirror::MirrorSink::Tcp("127.0.0.1:9000".to_string())));
+        assert_eq!(config.mirror_direction, crate::mirror::MirrorDirection::Down);
+        assert_eq!((config.mirror_queue, config.mirror_overflow), (8, crate::mirror::MirrorOverflow::Block));
+        assert!(Config::from_args(args(&["127.0.0.1:6161", "--mirror-overflow", "wait"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_max_tunnel_bytes() -> Result<(), ConfigError> {
+        assert_eq!(Config::from_args(args(&["127.0.0.1:6161"]))?.max_tunnel_bytes, 0);
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--max-tunnel-bytes", "1048576"]))?;
+        assert_eq!(config.max_tunnel_bytes, 1048576);
+        assert!(Config::from_args(args(&["a", "--max-tunnel-bytes", "-1"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_max_tunnels_per_host() -> Result<(), ConfigError> {
+        assert_eq!(Config::from_args(args(&["127.0.0.1:6161"]))?.max_tunnels_per_host, 0);
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--max-tunnels-per-host", "4", "--max-tunnels-per-host-wait", "2"]))?;
+        assert_eq!(config.max_tunnels_per_host, 4);
+        assert_eq!(config.max_tunnels_per_host_wait, std::time::Duration::from_secs(2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_max_connections() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&["127.0.0.1:6161"]))?;
+        assert_eq!((config.max_connections, config.on_overload), (0, Overload::Reject));
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--max-connections", "100", "--on-overload", "queue"]))?;
+        assert_eq!((config.max_connections, config.on_overload), (100, Overload::Queue));
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--on-overload", "drop"]))?;
+        assert_eq!(config.on_overload, Overload::Drop);
+        assert!(Config::from_args(args(&["a", "--on-overload", "wait"])).is_err());
+        assert!(Config::from_args(args(&["127.0.0.1:6161", "--listener-option", "127.0.0.1:6161/max-connections=1"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_idle_timeout() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--idle-timeout", "300"]))?;
+        assert_eq!(config.idle_timeout, std::time::Duration::from_secs(300));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_relay_keepalive() -> Result<(), ConfigError> {
+        assert!(Config::from_args(args(&["127.0.0.1:6161"]))?.relay_keepalive.is_zero());
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--relay-keepalive", "30"]))?;
+        assert_eq!(config.relay_keepalive, std::time::Duration::from_secs(30));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_tcp_user_timeout() -> Result<(), ConfigError> {
+        assert!(Config::from_args(args(&["127.0.0.1:6161"]))?.tcp_user_timeout.is_zero());
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--tcp-user-timeout", "20"]))?;
+        assert_eq!(config.tcp_user_timeout, std::time::Duration::from_secs(20));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_socket_buffers() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&["127.0.0.1:6161"]))?;
+        assert_eq!((config.socket_recv_buffer, config.socket_send_buffer), (None, None));
+        let config = Config::from_args(args(&[
+            "127.0.0.1:6161", "--socket-recv-buffer", "4194304", "--socket-send-buffer", "1048576"
+        ]))?;
+        assert_eq!((config.socket_recv_buffer, config.socket_send_buffer), (Some(4194304), Some(1048576)));
+        assert!(Config::from_args(args(&["127.0.0.1:6161", "--socket-recv-buffer", "0"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_linger_after_eof() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--linger-after-eof", "5"]))?;
+        assert_eq!(config.linger_after_eof, std::time::Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_half_close() -> Result<(), ConfigError> {
+        assert!(!Config::from_args(args(&["127.0.0.1:6161"]))?.half_close);
+        assert!(Config::from_args(args(&["127.0.0.1:6161", "--half-close", "true"]))?.half_close);
+        assert!(Config::from_args(args(&["a", "--half-close", "yes"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_max_tunnel_lifetime() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--max-tunnel-lifetime", "3600"]))?;
+        assert_eq!(config.max_tunnel_lifetime, std::time::Duration::from_secs(3600));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_shutdown_grace() -> Result<(), ConfigError> {
+        assert!(Config::from_args(args(&["127.0.0.1:6161"]))?.shutdown_grace.is_zero());
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--shutdown-grace", "10"]))?;
+        assert_eq!(config.shutdown_grace, std::time::Duration::from_secs(10));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_heartbeat() -> Result<(), ConfigError> {
+        assert!(Config::from_args(args(&["127.0.0.1:6161"]))?.heartbeat_interval.is_zero());
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--heartbeat", "60"]))?;
+        assert_eq!(config.heartbeat_interval, std::time::Duration::from_secs(60));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_proxy_agent() -> Result<(), ConfigError> {
+        assert_eq!(Config::from_args(args(&["127.0.0.1:6161"]))?.proxy_agent, None);
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--proxy-agent", "tunnel/1.0"]))?;
+        assert_eq!(config.proxy_agent, Some("tunnel/1.0".to_string()));
+        assert!(Config::from_args(args(&["a", "--proxy-agent", "a\r\nb: c"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_listen() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&["0.0.0.0:6161"]))?;
+        assert!(config.listen_addrs.is_empty());
+        assert_eq!(config.ipv6_only, None);
+        let config = Config::from_args(args(&["0.0.0.0:6161", "--listen", "[::]:6161", "--ipv6-only", "true"]))?;
+        assert_eq!(config.listen_addrs, vec!["[::]:6161".to_string()]);
+        assert_eq!(config.ipv6_only, Some(true));
+        assert!(Config::from_args(args(&["a", "--ipv6-only", "yes"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_listener_options() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&[
+            "0.0.0.0:443", "cert.pem", "key.pem", "--listen", "10.0.0.1:8080", "--connect-timeout", "1",
+            "--listener-option", "0.0.0.0:443/connect-timeout=0.5", "--listener-option", "10.0.0.1:8080/tls=false",
+        ]))?;
+        let public = config.for_listener("0.0.0.0:443")?;
+        assert_eq!(public.connect_timeout, std::time::Duration::from_millis(500));
+        assert!(public.tls.is_some());
+        let internal = config.for_listener("10.0.0.1:8080")?;
+        assert_eq!(internal.connect_timeout, std::time::Duration::from_secs(1));
+        assert!(internal.tls.is_none());
+
+        for option in ["10.0.0.2:80/connect-timeout=1", "0.0.0.0:443/connect-timeout", "0.0.0.0:443/connect-timeout=x",
+                       "0.0.0.0:443/unknown=1", "0.0.0.0:443/listen=[::]:443", "0.0.0.0:443/tls=true"] {
+            assert!(Config::from_args(args(&["0.0.0.0:443", "--listener-option", option])).is_err(), "{}", option);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_tls_only() -> Result<(), ConfigError> {
+        assert!(!Config::from_args(args(&["0.0.0.0:443", "cert.pem", "key.pem"]))?.tls_only);
+        assert!(Config::from_args(args(&["0.0.0.0:443", "cert.pem", "key.pem", "--tls-only", "true"]))?.tls_only);
+        // global only
+        assert!(Config::from_args(args(&["0.0.0.0:443", "--listener-option", "0.0.0.0:443/tls-only=false"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_upstream_tls() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&["127.0.0.1:6161"]))?;
+        assert!(config.upstream_tls_hosts.is_empty());
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--upstream-tls", "a.com", "--upstream-tls", "b.com",
+                                              "--upstream-tls-ca", "ca.crt"]))?;
+        assert_eq!(config.upstream_tls_hosts, vec!["a.com".to_string(), "b.com".to_string()]);
+        assert_eq!(config.upstream_tls_ca, Some("ca.crt".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_runtime() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&["127.0.0.1:6161"]))?;
+        assert_eq!(config.runtime_flavor, RuntimeFlavor::MultiThread);
+        assert_eq!(config.worker_threads, None);
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--runtime", "current-thread"]))?;
+        assert_eq!(config.runtime_flavor, RuntimeFlavor::CurrentThread);
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--worker-threads", "4"]))?;
+        assert_eq!(config.worker_threads, Some(4));
+        assert!(Config::from_args(args(&["a", "--runtime", "single"])).is_err());
+        assert!(Config::from_args(args(&["a", "--worker-threads", "0"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_log_file() -> Result<(), ConfigError> {
+        let config = Config::from_args(args(&["127.0.0.1:6161"]))?;
+        assert_eq!(config.log_file, None);
+        assert_eq!(config.log_max_size, 10 * 1024 * 1024);
+        let config = Config::from_args(args(&["127.0.0.1:6161", "--log-file", "/var/log/tunnel.log", "--log-max-size", "4096"]))?;
+        assert_eq!(config.log_file.as_deref(), Some("/var/log/tunnel.log"));
+        assert_eq!(config.log_max_size, 4096);
+        assert!(Config::from_args(args(&["a", "--log-max-size", "1MB"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_errors() {
+        assert!(matches!(Config::from_args(args(&[])), Err(ConfigError::MissingAddr)));
+        assert!(matches!(Config::from_args(args(&["a", "b"])), Err(ConfigError::UnexpectedArg(_))));
+        assert!(matches!(Config::from_args(args(&["a", "--foo", "1"])), Err(ConfigError::UnknownOption(_))));
+        assert!(matches!(Config::from_args(args(&["a", "--dns-server"])), Err(ConfigError::MissingValue(_))));
+        assert!(matches!(Config::from_args(args(&["a", "--dns-server", "nope"])), Err(ConfigError::InvalidValue(_, _))));
+    }
+}
@@ -0,0 +1,38 @@
+use std::io;
+use std::net::SocketAddr;
+
+use async_trait::async_trait;
+use tokio::net::{TcpSocket, TcpStream};
+
+// Upstream connector
+// Open the tcp connection to the (resolved) target address
+
+#[async_trait]
+pub trait Connector {
+    async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream>;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TcpConnector {
+    // local address of the upstream sockets (port 0: any port), only for targets of the same family
+    bind: Option<SocketAddr>,
+}
+
+impl TcpConnector {
+    pub fn bound(bind: Option<SocketAddr>) -> Self {
+        Self { bind }
+    }
+}
+
+#[async_trait]
+impl Connector for TcpConnector {
+    // Note: the socket is owned by the connect future, cancelling it (e.g. connect timeout) closes the socket
+    // no half-open connection is left behind
+    async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+        if let Some(bind) = self.bind.filter(|bind| bind.is_ipv4() == addr.is_ipv4()) {
+            socket.bind(bind)?;
+        }
+        socket.connect(addr).await
+    }
+}
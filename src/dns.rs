@@ -1,11 +1,18 @@
 // std
+use std::collections::{HashMap, VecDeque};
 use std::io::{Error, ErrorKind};
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
 
 // third parties
 use tokio::io;
+use tokio::net::UdpSocket;
+use tokio::time::{sleep, timeout, Duration};
 
 use async_trait::async_trait;
+use log::{info, warn};
 
 // internal
 
@@ -16,6 +23,65 @@ use async_trait::async_trait;
 #[async_trait]
 pub trait DnsResolver {
     async fn resolve(&mut self, target: &str) -> io::Result<SocketAddr>;
+
+    // All the addresses of target (e.g. several A records), default to the single resolved one
+    async fn resolve_all(&mut self, target: &str) -> io::Result<Vec<SocketAddr>> where Self: Send {
+        Ok(vec![self.resolve(target).await?])
+    }
+}
+
+// Resolution failures, whatever the resolver & the platform (e.g. the system resolver reports unknown hosts
+// with an "Uncategorized" io::ErrorKind that cannot be matched), see ResolveError::classify
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ResolveError {
+    #[error("unknown host")]
+    NotFound,
+    #[error("resolution timeout")]
+    Timeout,
+    #[error("dns server failure (SERVFAIL)")]
+    ServFail,
+    // rejected without query, see BudgetedResolver
+    #[error("dns query budget exceeded")]
+    OverBudget,
+    #[error("resolution failure")]
+    Other,
+}
+
+impl ResolveError {
+    pub fn classify(e: &Error) -> Self {
+        if let Some(resolve_error) = e.get_ref().and_then(|e| e.downcast_ref::<ResolveError>()) {
+            return *resolve_error;
+        }
+        match e.kind() {
+            ErrorKind::NotFound | ErrorKind::AddrNotAvailable => Self::NotFound,
+            ErrorKind::TimedOut => Self::Timeout,
+            _ => Self::Other,
+        }
+    }
+}
+
+// getaddrinfo failures only have a message (gai_strerror, glibc / musl / macOS wordings): map the common ones
+// to a matchable error kind (or a ResolveError), other errors are returned as is
+fn system_lookup_error(e: Error) -> Error {
+    const NOT_FOUND: [&str; 3] = [
+        "Name or service not known", "nodename nor servname provided", "No address associated with hostname",
+    ];
+    const TEMPORARY: &str = "Temporary failure in name resolution";
+    const NON_RECOVERABLE: &str = "Non-recoverable failure in name resolution";
+
+    if e.kind() == ErrorKind::InvalidInput {
+        return e;
+    }
+    let message = e.to_string();
+    if NOT_FOUND.iter().any(|not_found| message.contains(not_found)) {
+        Error::new(ErrorKind::NotFound, message)
+    } else if message.contains(TEMPORARY) {
+        Error::new(ErrorKind::TimedOut, message)
+    } else if message.contains(NON_RECOVERABLE) {
+        Error::other(ResolveError::ServFail)
+    } else {
+        e
+    }
 }
 
 #[derive(Clone)]
@@ -27,12 +93,16 @@ impl DnsResolver for SimpleDnsResolver {
     async fn resolve(&mut self, target: &str) -> io::Result<SocketAddr> {
         let resolved: Vec<SocketAddr> = SimpleDnsResolver::resolve(target).await?;
         // Note: not sure if resolved can be an empty vec
-        match resolved.get(0) {
+        match resolved.first() {
             Some(r) => Ok(*r),
             _ => Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData, "Empty resolve".to_string())),
         }
     }
+
+    async fn resolve_all(&mut self, target: &str) -> io::Result<Vec<SocketAddr>> {
+        SimpleDnsResolver::resolve(target).await
+    }
 }
 
 impl SimpleDnsResolver {
@@ -42,7 +112,7 @@ impl SimpleDnsResolver {
 
     async fn resolve(target: &str) -> io::Result<Vec<SocketAddr>> {
 
-        let resolved: Vec<_> = tokio::net::lookup_host(target).await?.collect();
+        let resolved: Vec<_> = tokio::net::lookup_host(target).await.map_err(system_lookup_error)?.collect();
         if resolved.is_empty() {
             return Err(Error::from(ErrorKind::AddrNotAvailable));
         }
@@ -52,19 +122,838 @@ impl SimpleDnsResolver {
 
 // End Dns Resolver
 
+// Configurable Dns Resolver
+// Send queries (A then AAAA) to explicit nameservers (e.g. an internal resolver) over UDP
+// instead of relying on the system resolver
+
+const DNS_QUERY_TIMEOUT: Duration = Duration::from_millis(2000);
+const DNS_MAX_PACKET_SIZE: usize = 512;
+const DNS_TYPE_A: u16 = 1;
+const DNS_TYPE_AAAA: u16 = 28;
+const DNS_CLASS_IN: u16 = 1;
+const DNS_RCODE_SERVFAIL: u8 = 2;
+const DNS_RCODE_NXDOMAIN: u8 = 3;
+
+#[derive(Clone)]
+pub struct ConfigurableResolver {
+    nameservers: Arc<Vec<SocketAddr>>,
+    timeout: Duration,
+}
+
+#[async_trait]
+impl DnsResolver for ConfigurableResolver {
+    async fn resolve(&mut self, target: &str) -> io::Result<SocketAddr> {
+        Ok(self.lookup(target, false).await?[0])
+    }
+
+    async fn resolve_all(&mut self, target: &str) -> io::Result<Vec<SocketAddr>> {
+        self.lookup(target, true).await
+    }
+}
+
+impl ConfigurableResolver {
+    pub fn new(nameservers: Vec<SocketAddr>) -> Self {
+        Self {
+            nameservers: Arc::new(nameservers),
+            timeout: DNS_QUERY_TIMEOUT,
+        }
+    }
+
+    // Query A then AAAA records (AAAA only if there is no A record unless all is set)
+    // Return a non empty vec
+    async fn lookup(&self, target: &str, all: bool) -> io::Result<Vec<SocketAddr>> {
+        let (host, port) = split_host_port(target)?;
+
+        // ip literal: nothing to resolve
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+
+        let mut last_error = Error::from(ErrorKind::AddrNotAvailable);
+        for nameserver in self.nameservers.iter() {
+            let mut addrs = Vec::new();
+            for qtype in [DNS_TYPE_A, DNS_TYPE_AAAA] {
+                match self.query(*nameserver, host, qtype).await {
+                    Ok(ips) => addrs.extend(ips.into_iter().map(|ip| SocketAddr::new(ip, port))),
+                    // Definitive answer from the nameserver, no need to ask another one
+                    Err(e) if e.kind() == ErrorKind::NotFound => return Err(e),
+                    Err(e) => last_error = e,
+                }
+                if !all && !addrs.is_empty() {
+                    break;
+                }
+            }
+            if !addrs.is_empty() {
+                return Ok(addrs);
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn query(&self, nameserver: SocketAddr, host: &str, qtype: u16) -> io::Result<Vec<IpAddr>> {
+
+        let bind_addr: SocketAddr = match nameserver {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.connect(nameserver).await?;
+
+        let id: u16 = rand::random();
+        socket.send(&build_query(id, host, qtype)?).await?;
+
+        let mut buffer = [0u8; DNS_MAX_PACKET_SIZE];
+        let n = timeout(self.timeout, socket.recv(&mut buffer)).await
+            .map_err(|_| Error::new(ErrorKind::TimedOut,
+                                    format!("No answer from nameserver {}", nameserver)))??;
+        parse_response(id, &buffer[..n])
+    }
+}
+
+// "host:port" or "[ipv6]:port" -> (host, port)
+fn split_host_port(target: &str) -> io::Result<(&str, u16)> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid target: {}", target));
+
+    let (host, port) = target.rsplit_once(':').ok_or_else(invalid)?;
+    let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+    let port = port.parse::<u16>().map_err(|_| invalid())?;
+    Ok((host, port))
+}
+
+fn build_query(id: u16, host: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(DNS_MAX_PACKET_SIZE);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00]); // flags: recursion desired
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); // 1 question, 0 answer/authority/additional
+
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("invalid hostname: {}", host)));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+fn parse_response(id: u16, packet: &[u8]) -> io::Result<Vec<IpAddr>> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "Invalid dns response");
+
+    if packet.len() < 12 || packet[0..2] != id.to_be_bytes() || packet[2] & 0x80 == 0 {
+        return Err(invalid());
+    }
+
+    match packet[3] & 0x0F {
+        0 => {},
+        DNS_RCODE_SERVFAIL => return Err(Error::other(ResolveError::ServFail)),
+        DNS_RCODE_NXDOMAIN => return Err(Error::new(ErrorKind::NotFound, "Unknown host (NXDOMAIN)")),
+        rcode => return Err(Error::other(format!("Dns server error (rcode: {})", rcode))),
+    }
+
+    let qd_count = u16::from_be_bytes([packet[4], packet[5]]);
+    let an_count = u16::from_be_bytes([packet[6], packet[7]]);
+
+    let mut pos = 12;
+    for _ in 0..qd_count {
+        pos = skip_name(packet, pos).ok_or_else(invalid)? + 4; // qtype + qclass
+    }
+
+    let mut ips = Vec::new();
+    for _ in 0..an_count {
+        pos = skip_name(packet, pos).ok_or_else(invalid)?;
+        let header = packet.get(pos..pos + 10).ok_or_else(invalid)?;
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let rdlength = u16::from_be_bytes([header[8], header[9]]) as usize;
+        pos += 10;
+        let rdata = packet.get(pos..pos + rdlength).ok_or_else(invalid)?;
+        pos += rdlength;
+
+        // Note: CNAME (& other) records are skipped, recursive resolvers also send the final records
+        match (rtype, rdlength) {
+            (DNS_TYPE_A, 4) => ips.push(IpAddr::from(<[u8; 4]>::try_from(rdata).unwrap())),
+            (DNS_TYPE_AAAA, 16) => ips.push(IpAddr::from(<[u8; 16]>::try_from(rdata).unwrap())),
+            _ => {},
+        }
+    }
+    Ok(ips)
+}
+
+// Return the position just after the (possibly compressed) name starting at pos
+fn skip_name(packet: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *packet.get(pos)? as usize;
+        match len {
+            0 => return Some(pos + 1),
+            l if l & 0xC0 == 0xC0 => return Some(pos + 2), // compression pointer
+            l => pos += l + 1,
+        }
+    }
+}
+
+// End Configurable Dns Resolver
+
+// Retrying Dns Resolver
+// Retry transient failures (e.g. timeout, SERVFAIL) with an exponential backoff
+
+#[derive(Clone)]
+pub struct RetryingResolver<D> {
+    inner: D,
+    max_retries: u32,
+    backoff: Duration,
+}
+
+impl<D> RetryingResolver<D> {
+    pub fn new(inner: D, max_retries: u32, backoff: Duration) -> Self {
+        Self { inner, max_retries, backoff }
+    }
+}
+
+// Definitive failures (e.g. unknown host / NXDOMAIN, invalid target) are not worth retrying
+fn is_retryable(e: &Error) -> bool {
+    e.kind() != ErrorKind::InvalidInput
+        && !matches!(ResolveError::classify(e), ResolveError::NotFound | ResolveError::OverBudget)
+}
+
+#[async_trait]
+impl<D> DnsResolver for RetryingResolver<D> where D: DnsResolver + Send {
+    async fn resolve(&mut self, target: &str) -> io::Result<SocketAddr> {
+        let mut delay = self.backoff;
+        let mut retries = 0;
+        loop {
+            match self.inner.resolve(target).await {
+                Err(e) if retries < self.max_retries && is_retryable(&e) => {
+                    sleep(delay).await;
+                    delay *= 2;
+                    retries += 1;
+                },
+                result => return result,
+            }
+        }
+    }
+
+    async fn resolve_all(&mut self, target: &str) -> io::Result<Vec<SocketAddr>> {
+        let mut delay = self.backoff;
+        let mut retries = 0;
+        loop {
+            match self.inner.resolve_all(target).await {
+                Err(e) if retries < self.max_retries && is_retryable(&e) => {
+                    sleep(delay).await;
+                    delay *= 2;
+                    retries += 1;
+                },
+                result => return result,
+            }
+        }
+    }
+}
+
+// End Retrying Dns Resolver
+
+// Caching Dns Resolver
+// Cache resolutions (and failures: negative caching) for a given time
+// Note: the cache is shared by all clones (one clone per connection)
+
+const DNS_CACHE_MAX_ENTRIES: usize = 10_000;
+
+struct CacheEntry {
+    result: Result<Vec<SocketAddr>, ErrorKind>,
+    expires: Instant,
+}
+
+#[derive(Clone)]
+pub struct CachingResolver<D> {
+    inner: D,
+    ttl: Duration,
+    negative_ttl: Duration,
+    cache: Arc<Mutex<HashMap<String, CacheEntry>>>,
+}
+
+impl<D> CachingResolver<D> {
+    // A zero ttl (or negative_ttl) disables caching of resolutions (or failures)
+    pub fn new(inner: D, ttl: Duration, negative_ttl: Duration) -> Self {
+        Self { inner, ttl, negative_ttl, cache: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    fn get(&self, target: &str) -> Option<io::Result<Vec<SocketAddr>>> {
+        let cache = self.cache.lock().unwrap();
+        cache
+            .get(target)
+            .filter(|entry| entry.expires > Instant::now())
+            .map(|entry| entry.result.clone().map_err(|kind| Error::new(kind, "Resolution failure (cached)")))
+    }
+
+    fn insert(&self, target: &str, result: &io::Result<Vec<SocketAddr>>) {
+        let (result, ttl) = match result {
+            Ok(addrs) => (Ok(addrs.clone()), self.ttl),
+            // Note: not a resolution failure (the target was not queried)
+            Err(e) if ResolveError::classify(e) == ResolveError::OverBudget => return,
+            Err(e) => (Err(e.kind()), self.negative_ttl),
+        };
+        if ttl.is_zero() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= DNS_CACHE_MAX_ENTRIES {
+            cache.retain(|_, entry| entry.expires > now);
+        }
+        if cache.len() < DNS_CACHE_MAX_ENTRIES {
+            cache.insert(target.to_string(), CacheEntry { result, expires: now + ttl });
+        }
+    }
+}
+
+#[async_trait]
+impl<D> DnsResolver for CachingResolver<D> where D: DnsResolver + Send {
+    async fn resolve(&mut self, target: &str) -> io::Result<SocketAddr> {
+        self.resolve_all(target).await?
+            .first()
+            .copied()
+            .ok_or_else(|| Error::from(ErrorKind::AddrNotAvailable))
+    }
+
+    // Note: all the addresses are cached (resolve returns the first one)
+    async fn resolve_all(&mut self, target: &str) -> io::Result<Vec<SocketAddr>> {
+        if let Some(result) = self.get(target) {
+            return result;
+        }
+        let result = self.inner.resolve_all(target).await;
+        self.insert(target, &result);
+        result
+    }
+}
+
+// End Caching Dns Resolver
+
+// Hosts file Dns Resolver
+// Resolve names listed in a /etc/hosts like file ("IP NAME [ALIASES...]", '#' comments), other names are
+// resolved by the inner resolver
+// If a reload interval is given, the file is checked for changes (at most once per interval, when
+// resolving) and reloaded. Note: the mappings are shared by all clones
+
+struct HostsFile {
+    entries: HashMap<String, IpAddr>,
+    // (modification time, len) of the loaded file, to detect changes
+    version: (Option<SystemTime>, u64),
+    checked: Instant,
+}
+
+#[derive(Clone)]
+pub struct HostsFileResolver<D> {
+    inner: D,
+    path: Arc<String>,
+    // zero: never reload
+    reload_interval: Duration,
+    hosts: Arc<Mutex<HostsFile>>,
+}
+
+fn parse_hosts(content: &str) -> HashMap<String, IpAddr> {
+    let mut entries = HashMap::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(Ok(ip)) = fields.next().map(|ip| ip.parse::<IpAddr>()) else { continue };
+        for name in fields {
+            // Note: first entry wins (like the system resolver)
+            entries.entry(name.to_ascii_lowercase()).or_insert(ip);
+        }
+    }
+    entries
+}
+
+async fn read_hosts(path: &str) -> io::Result<((Option<SystemTime>, u64), HashMap<String, IpAddr>)> {
+    let metadata = tokio::fs::metadata(path).await?;
+    let content = tokio::fs::read_to_string(path).await?;
+    Ok(((metadata.modified().ok(), metadata.len()), parse_hosts(&content)))
+}
+
+// Note: not a method, the inner resolver may not be Sync
+async fn reload_if_changed(hosts_file: &Mutex<HostsFile>, path: &str, reload_interval: Duration) {
+    {
+        let mut hosts = hosts_file.lock().unwrap();
+        if reload_interval.is_zero() || hosts.checked.elapsed() < reload_interval {
+            return;
+        }
+        hosts.checked = Instant::now();
+    }
+
+    let version = match tokio::fs::metadata(path).await {
+        Ok(metadata) => (metadata.modified().ok(), metadata.len()),
+        Err(e) => {
+            warn!("Unable to check hosts file {}: {}", path, e);
+            return;
+        }
+    };
+    if version == hosts_file.lock().unwrap().version {
+        return;
+    }
+    match read_hosts(path).await {
+        Ok((version, entries)) => {
+            info!("Reloaded {} entries from hosts file {}", entries.len(), path);
+            let mut hosts = hosts_file.lock().unwrap();
+            hosts.entries = entries;
+            hosts.version = version;
+        },
+        // Note: keep the previous entries
+        Err(e) => warn!("Unable to reload hosts file {}: {}", path, e),
+    }
+}
+
+impl<D> HostsFileResolver<D> {
+    pub async fn load(inner: D, path: &str, reload_interval: Duration) -> io::Result<Self> {
+        let (version, entries) = read_hosts(path).await
+            .map_err(|e| Error::new(e.kind(), format!("Unable to load hosts file {}: {}", path, e)))?;
+        info!("Loaded {} entries from hosts file {}", entries.len(), path);
+        Ok(Self {
+            inner,
+            path: Arc::new(path.to_string()),
+            reload_interval,
+            hosts: Arc::new(Mutex::new(HostsFile { entries, version, checked: Instant::now() })),
+        })
+    }
+
+    fn get(&self, target: &str) -> Option<SocketAddr> {
+        let (host, port) = split_host_port(target).ok()?;
+        let hosts = self.hosts.lock().unwrap();
+        hosts.entries.get(&host.to_ascii_lowercase()).map(|ip| SocketAddr::new(*ip, port))
+    }
+}
+
+#[async_trait]
+impl<D> DnsResolver for HostsFileResolver<D> where D: DnsResolver + Send {
+    async fn resolve(&mut self, target: &str) -> io::Result<SocketAddr> {
+        reload_if_changed(&self.hosts, &self.path, self.reload_interval).await;
+        match self.get(target) {
+            Some(addr) => Ok(addr),
+            None => self.inner.resolve(target).await,
+        }
+    }
+
+    async fn resolve_all(&mut self, target: &str) -> io::Result<Vec<SocketAddr>> {
+        reload_if_changed(&self.hosts, &self.path, self.reload_interval).await;
+        match self.get(target) {
+            Some(addr) => Ok(vec![addr]),
+            None => self.inner.resolve_all(target).await,
+        }
+    }
+}
+
+// End Hosts file Dns Resolver
+
+// Pinning Dns Resolver
+// Pinned hosts may only resolve to their expected ips (protect against dns spoofing / rebinding): a resolution
+// with any other ip is rejected (PermissionDenied, PinMismatch error), other hosts are resolved as usual
+
+#[derive(Debug, thiserror::Error)]
+#[error("{host} resolved to {ip}, not one of its pinned ips")]
+pub struct PinMismatch {
+    pub host: String,
+    pub ip: IpAddr,
+}
+
+#[derive(Clone)]
+pub struct PinningResolver<D> {
+    inner: D,
+    // lowercase host -> allowed ips
+    pins: Arc<HashMap<String, Vec<IpAddr>>>,
+}
+
+impl<D> PinningResolver<D> {
+    pub fn new(inner: D, pins: HashMap<String, Vec<IpAddr>>) -> Self {
+        let pins = pins.into_iter().map(|(host, ips)| (host.to_ascii_lowercase(), ips)).collect();
+        Self { inner, pins: Arc::new(pins) }
+    }
+
+    fn check(&self, target: &str, addrs: &[SocketAddr]) -> io::Result<()> {
+        let Ok((host, _port)) = split_host_port(target) else { return Ok(()) };
+        let Some(allowed) = self.pins.get(&host.to_ascii_lowercase()) else { return Ok(()) };
+        match addrs.iter().find(|addr| !allowed.contains(&addr.ip())) {
+            Some(addr) => Err(Error::new(ErrorKind::PermissionDenied, PinMismatch { host: host.to_string(), ip: addr.ip() })),
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl<D> DnsResolver for PinningResolver<D> where D: DnsResolver + Send {
+    async fn resolve(&mut self, target: &str) -> io::Result<SocketAddr> {
+        let addr = self.inner.resolve(target).await?;
+        self.check(target, &[addr])?;
+        Ok(addr)
+    }
+
+    async fn resolve_all(&mut self, target: &str) -> io::Result<Vec<SocketAddr>> {
+        let addrs = self.inner.resolve_all(target).await?;
+        self.check(target, &addrs)?;
+        Ok(addrs)
+    }
+}
+
+// End Pinning Dns Resolver
+
+// Validating Dns Resolver
+// Reject malformed hostnames (InvalidInput) before any lookup: clearer errors & no wasted round trip
+// IP literals are passed through
+
+// Note: 255 octets in the dns wire format, i.e. 253 characters (without the trailing dot)
+pub const MAX_HOSTNAME_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+
+// Letters, digits & hyphens (not at a label start or end), underscores are tolerated (e.g. "_acme.example.com")
+pub fn validate_hostname(host: &str) -> io::Result<()> {
+    let invalid = |reason: &str| Error::new(ErrorKind::InvalidInput, format!("invalid hostname {:?}: {}", host, reason));
+
+    if host.parse::<IpAddr>().is_ok() {
+        return Ok(());
+    }
+    let name = host.strip_suffix('.').unwrap_or(host);
+    if name.is_empty() {
+        return Err(invalid("empty"));
+    }
+    if name.len() > MAX_HOSTNAME_LEN {
+        return Err(invalid("too long"));
+    }
+    for label in name.split('.') {
+        if label.is_empty() {
+            return Err(invalid("empty label"));
+        }
+        if label.len() > MAX_LABEL_LEN {
+            return Err(invalid("label too long"));
+        }
+        if !label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+            return Err(invalid("invalid character"));
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err(invalid("label starts or ends with a hyphen"));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone)]
+pub struct ValidatingResolver<D> {
+    inner: D,
+    // hostnames longer than this are rejected (abuse guard), at most MAX_HOSTNAME_LEN
+    max_len: usize,
+}
+
+impl<D> ValidatingResolver<D> {
+    pub fn new(inner: D) -> Self {
+        Self { inner, max_len: MAX_HOSTNAME_LEN }
+    }
+
+    pub fn with_max_len(self, max_len: usize) -> Self {
+        Self { max_len: max_len.min(MAX_HOSTNAME_LEN), ..self }
+    }
+
+    fn check(&self, target: &str) -> io::Result<()> {
+        let (host, _port) = split_host_port(target)?;
+        let name = host.strip_suffix('.').unwrap_or(host);
+        if name.len() > self.max_len && host.parse::<IpAddr>().is_err() {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  format!("invalid hostname: too long ({} > {} characters)", name.len(), self.max_len)));
+        }
+        validate_hostname(host)
+    }
+}
+
+#[async_trait]
+impl<D> DnsResolver for ValidatingResolver<D> where D: DnsResolver + Send {
+    async fn resolve(&mut self, target: &str) -> io::Result<SocketAddr> {
+        self.check(target)?;
+        self.inner.resolve(target).await
+    }
+
+    async fn resolve_all(&mut self, target: &str) -> io::Result<Vec<SocketAddr>> {
+        self.check(target)?;
+        self.inner.resolve_all(target).await
+    }
+}
+
+// End Validating Dns Resolver
+
+// Timed Dns Resolver
+// Count successes & failures and record the resolution latency in a histogram
+// Note: the stats are shared by all clones (one clone per connection)
+
+// Histogram bucket upper bounds (ms), the last bucket counts the slower resolutions
+const LATENCY_BUCKETS_MS: [u64; 7] = [1, 5, 10, 50, 100, 500, 1000];
+
+#[derive(Default)]
+pub struct ResolutionStats {
+    successes: AtomicU64,
+    failures: AtomicU64,
+    // total latency (us)
+    total_us: AtomicU64,
+    buckets: [AtomicU64; LATENCY_BUCKETS_MS.len() + 1],
+}
+
+impl ResolutionStats {
+    fn record(&self, elapsed: Duration, success: bool) {
+        let counter = if success { &self.successes } else { &self.failures };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.total_us.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        let bucket = LATENCY_BUCKETS_MS.iter()
+            .position(|ms| elapsed <= Duration::from_millis(*ms))
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn successes(&self) -> u64 {
+        self.successes.load(Ordering::Relaxed)
+    }
+
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    pub fn total_latency(&self) -> Duration {
+        Duration::from_micros(self.total_us.load(Ordering::Relaxed))
+    }
+
+    // (bucket upper bound (None: above the last one), resolution count)
+    pub fn histogram(&self) -> Vec<(Option<Duration>, u64)> {
+        LATENCY_BUCKETS_MS.iter()
+            .map(|ms| Some(Duration::from_millis(*ms)))
+            .chain(std::iter::once(None))
+            .zip(self.buckets.iter().map(|b| b.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+impl std::fmt::Display for ResolutionStats {
+    // e.g. "3 ok, 1 failed, latency total: 12ms, <=1ms: 2, <=5ms: 0, ..., >1000ms: 0"
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ok, {} failed, latency total: {:?}", self.successes(), self.failures(), self.total_latency())?;
+        for (bound, count) in self.histogram() {
+            match bound {
+                Some(bound) => write!(f, ", <={:?}: {}", bound, count)?,
+                None => write!(f, ", >{}ms: {}", LATENCY_BUCKETS_MS[LATENCY_BUCKETS_MS.len() - 1], count)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct TimedResolver<D> {
+    inner: D,
+    stats: Arc<ResolutionStats>,
+}
+
+impl<D> TimedResolver<D> {
+    pub fn new(inner: D, stats: Arc<ResolutionStats>) -> Self {
+        Self { inner, stats }
+    }
+}
+
+#[async_trait]
+impl<D> DnsResolver for TimedResolver<D> where D: DnsResolver + Send {
+    async fn resolve(&mut self, target: &str) -> io::Result<SocketAddr> {
+        let start = Instant::now();
+        let result = self.inner.resolve(target).await;
+        self.stats.record(start.elapsed(), result.is_ok());
+        result
+    }
+
+    async fn resolve_all(&mut self, target: &str) -> io::Result<Vec<SocketAddr>> {
+        let start = Instant::now();
+        let result = self.inner.resolve_all(target).await;
+        self.stats.record(start.elapsed(), result.is_ok());
+        result
+    }
+}
+
+// End Timed Dns Resolver
+
+// Budgeted Dns Resolver
+// Server wide cap on the dns queries: at most budget queries per window (sliding, e.g. a minute), beyond that
+// resolutions fail fast (ResolveError::OverBudget) until the oldest queries leave the window
+// Note: shared by all clones, a rejected resolution does not count
+
+#[derive(Clone)]
+pub struct BudgetedResolver<D> {
+    inner: D,
+    budget: usize,
+    window: Duration,
+    // queries of the window, oldest first
+    queries: Arc<Mutex<VecDeque<Instant>>>,
+}
+
+impl<D> BudgetedResolver<D> {
+    // A zero budget disables the cap
+    pub fn new(inner: D, budget: usize, window: Duration) -> Self {
+        Self { inner, budget, window, queries: Arc::new(Mutex::new(VecDeque::new())) }
+    }
+
+    fn acquire(&self) -> io::Result<()> {
+        if self.budget == 0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut queries = self.queries.lock().unwrap();
+        while queries.front().is_some_and(|query| now.duration_since(*query) >= self.window) {
+            queries.pop_front();
+        }
+        if queries.len() >= self.budget {
+            return Err(Error::other(ResolveError::OverBudget));
+        }
+        queries.push_back(now);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<D> DnsResolver for BudgetedResolver<D> where D: DnsResolver + Send {
+    async fn resolve(&mut self, target: &str) -> io::Result<SocketAddr> {
+        self.acquire()?;
+        self.inner.resolve(target).await
+    }
+
+    async fn resolve_all(&mut self, target: &str) -> io::Result<Vec<SocketAddr>> {
+        self.acquire()?;
+        self.inner.resolve_all(target).await
+    }
+}
+
+// End Budgeted Dns Resolver
+
+// Suffix routing Dns Resolver
+// Split horizon: hosts under a configured domain suffix (e.g. "internal": "svc.internal", not "notinternal")
+// are resolved by the resolver of that suffix (the longest matching one), other hosts by the default resolver
+
+#[derive(Clone)]
+pub struct SuffixResolver<D, I> {
+    default: D,
+    // lowercase suffix (no leading / trailing dot) -> resolver
+    routes: Vec<(String, I)>,
+}
+
+impl<D, I> SuffixResolver<D, I> {
+    pub fn new(default: D, routes: Vec<(String, I)>) -> Self {
+        let mut routes: Vec<_> = routes.into_iter()
+            .map(|(suffix, resolver)| (suffix.trim_matches('.').to_ascii_lowercase(), resolver))
+            .collect();
+        // longest suffix first
+        routes.sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.len()));
+        Self { default, routes }
+    }
+
+    fn route(&mut self, target: &str) -> Option<&mut I> {
+        let (host, _port) = split_host_port(target).ok()?;
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.routes.iter_mut()
+            .find(|(suffix, _)| {
+                host.strip_suffix(suffix.as_str()).is_some_and(|prefix| prefix.is_empty() || prefix.ends_with('.'))
+            })
+            .map(|(_, resolver)| resolver)
+    }
+}
+
+#[async_trait]
+impl<D, I> DnsResolver for SuffixResolver<D, I> where D: DnsResolver + Send, I: DnsResolver + Send {
+    async fn resolve(&mut self, target: &str) -> io::Result<SocketAddr> {
+        match self.route(target) {
+            Some(resolver) => resolver.resolve(target).await,
+            None => self.default.resolve(target).await,
+        }
+    }
+
+    async fn resolve_all(&mut self, target: &str) -> io::Result<Vec<SocketAddr>> {
+        match self.route(target) {
+            Some(resolver) => resolver.resolve_all(target).await,
+            None => self.default.resolve_all(target).await,
+        }
+    }
+}
+
+// End Suffix routing Dns Resolver
+
 
 #[cfg(test)]
 mod tests {
 
     use crate::dns::SimpleDnsResolver;
     use crate::dns::DnsResolver;
+    use crate::dns::{system_lookup_error, ResolveError};
+    use crate::dns::ConfigurableResolver;
+    use crate::dns::RetryingResolver;
+    use crate::dns::CachingResolver;
+    use crate::dns::HostsFileResolver;
+    use crate::dns::{PinMismatch, PinningResolver};
+    use crate::dns::{validate_hostname, ValidatingResolver, MAX_HOSTNAME_LEN};
+    use crate::dns::{ResolutionStats, TimedResolver};
+    use crate::dns::BudgetedResolver;
+    use crate::dns::SuffixResolver;
+
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use async_trait::async_trait;
+    use tokio::net::UdpSocket;
+    use tokio::time::Duration;
+
+    // Fake resolver: fail with error kind for the first failures calls then resolve to 127.0.0.1
+    #[derive(Clone)]
+    struct FlakyResolver {
+        failures: u32,
+        kind: std::io::ErrorKind,
+        calls: Arc<AtomicU32>,
+    }
+
+    #[async_trait]
+    impl DnsResolver for FlakyResolver {
+        async fn resolve(&mut self, _target: &str) -> std::io::Result<SocketAddr> {
+            if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+                return Err(std::io::Error::from(self.kind));
+            }
+            Ok("127.0.0.1:80".parse().unwrap())
+        }
+    }
+
+    // Stub dns server: answer every query with rcode and (for A queries) the given ipv4
+    async fn spawn_stub_dns_server(rcode: u8, ip: [u8; 4]) -> std::io::Result<SocketAddr> {
+        spawn_stub_dns_server_multi(rcode, vec![ip]).await
+    }
+
+    // Same as spawn_stub_dns_server, answer A queries with all the given ipv4
+    async fn spawn_stub_dns_server_multi(rcode: u8, ips: Vec<[u8; 4]>) -> std::io::Result<SocketAddr> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = socket.local_addr()?;
+
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 512];
+            while let Ok((n, peer)) = socket.recv_from(&mut buffer).await {
+                let query = &buffer[..n];
+                let is_a_query = query[n - 4..n - 2] == [0, 1];
+                let an_count = if rcode == 0 && is_a_query { ips.len() as u8 } else { 0 };
+
+                let mut response = Vec::new();
+                response.extend_from_slice(&query[0..2]); // id
+                response.extend_from_slice(&[0x81, 0x80 | rcode]);
+                response.extend_from_slice(&[0, 1, 0, an_count, 0, 0, 0, 0]);
+                response.extend_from_slice(&query[12..]); // question
+                for ip in ips.iter().take(an_count as usize) {
+                    response.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+                    response.extend_from_slice(ip);
+                }
+                let _ = socket.send_to(&response, peer).await;
+            }
+        });
+        Ok(addr)
+    }
 
     #[tokio::test]
     async fn test_dns_resolve_ok() -> Result<(), std::io::Error> {
 
         let mut dns_r = SimpleDnsResolver::new();
         let res = dns_r.resolve("google.com:80").await?;
-        assert!(res.to_string().is_empty() == false);
+        assert!(!res.to_string().is_empty());
         Ok(())
     }
 
@@ -87,10 +976,354 @@ mod tests {
         match dns_r.resolve("http://fooooooooooooooooooooooooooo.com:80").await {
             Ok(_) => panic!("Unexpected!"),
             Err(e) => {
-                // Return "Uncategorized" error kind that cannot be matched...
-                // assert_eq!(e.kind(), std::io::ErrorKind::Other);
-                assert!(true);
+                // Note: an "Uncategorized" error kind from the system resolver, mapped
+                assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+                assert_eq!(ResolveError::classify(&e), ResolveError::NotFound);
             }
         }
     }
+
+    #[tokio::test]
+    async fn test_configurable_resolve_ok() -> Result<(), std::io::Error> {
+        let dns_server = spawn_stub_dns_server(0, [10, 1, 2, 3]).await?;
+        let mut dns_r = ConfigurableResolver::new(vec![dns_server]);
+
+        let res = dns_r.resolve("internal.example:443").await?;
+        assert_eq!(res, "10.1.2.3:443".parse::<SocketAddr>().unwrap());
+        // ip literal does not query the dns server
+        let res = dns_r.resolve("[::1]:8080").await?;
+        assert_eq!(res, "[::1]:8080".parse::<SocketAddr>().unwrap());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_all() -> Result<(), std::io::Error> {
+        let dns_server = spawn_stub_dns_server_multi(0, vec![[10, 0, 0, 1], [10, 0, 0, 2], [10, 0, 0, 3]]).await?;
+        let mut dns_r = ConfigurableResolver::new(vec![dns_server]);
+
+        let expected: Vec<SocketAddr> = vec!["10.0.0.1:443".parse().unwrap(), "10.0.0.2:443".parse().unwrap(), "10.0.0.3:443".parse().unwrap()];
+        assert_eq!(dns_r.resolve_all("multi.example:443").await?, expected);
+        assert_eq!(dns_r.resolve("multi.example:443").await?, expected[0]);
+
+        // decorators keep all the addresses
+        let mut dns_r = CachingResolver::new(RetryingResolver::new(dns_r, 1, Duration::from_millis(1)),
+                                             Duration::from_secs(60), Duration::ZERO);
+        assert_eq!(dns_r.resolve_all("multi.example:443").await?, expected);
+        assert_eq!(dns_r.resolve("multi.example:443").await?, expected[0]);
+
+        // system resolver
+        let mut dns_r = SimpleDnsResolver::new();
+        let addrs = dns_r.resolve_all("localhost:80").await?;
+        assert!(addrs.contains(&"127.0.0.1:80".parse().unwrap()), "{:?}", addrs);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_configurable_resolve_nxdomain() -> Result<(), std::io::Error> {
+        let dns_server = spawn_stub_dns_server(3, [0, 0, 0, 0]).await?;
+        let mut dns_r = ConfigurableResolver::new(vec![dns_server]);
+
+        match dns_r.resolve("unknown.example:443").await {
+            Ok(_) => panic!("Unexpected!"),
+            Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::NotFound),
+        }
+        match dns_r.resolve("unknown.example").await {
+            Ok(_) => panic!("Unexpected!"),
+            Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_error_classify() -> Result<(), std::io::Error> {
+        let nxdomain = ConfigurableResolver::new(vec![spawn_stub_dns_server(3, [0, 0, 0, 0]).await?]);
+        let e = nxdomain.clone().resolve("unknown.example:443").await.unwrap_err();
+        assert_eq!(ResolveError::classify(&e), ResolveError::NotFound);
+        let servfail = ConfigurableResolver::new(vec![spawn_stub_dns_server(2, [0, 0, 0, 0]).await?]);
+        let e = servfail.clone().resolve("broken.example:443").await.unwrap_err();
+        assert_eq!(ResolveError::classify(&e), ResolveError::ServFail);
+
+        // simulated timeout
+        let calls = Arc::new(AtomicU32::new(0));
+        let mut timing_out = FlakyResolver { failures: 1, kind: std::io::ErrorKind::TimedOut, calls };
+        let e = timing_out.resolve("example.com:80").await.unwrap_err();
+        assert_eq!(ResolveError::classify(&e), ResolveError::Timeout);
+
+        // system resolver (getaddrinfo) messages
+        let lookup_error = |message: &str| {
+            system_lookup_error(std::io::Error::other(format!("failed to lookup address information: {}", message)))
+        };
+        let cases = [
+            ("Name or service not known", ResolveError::NotFound),
+            ("nodename nor servname provided, or not known", ResolveError::NotFound),
+            ("Temporary failure in name resolution", ResolveError::Timeout),
+            ("Non-recoverable failure in name resolution", ResolveError::ServFail),
+            ("System error", ResolveError::Other),
+        ];
+        for (message, expected) in cases {
+            assert_eq!(ResolveError::classify(&lookup_error(message)), expected, "{}", message);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retrying_resolve_transient_failures() -> Result<(), std::io::Error> {
+        let calls = Arc::new(AtomicU32::new(0));
+        let flaky = FlakyResolver { failures: 2, kind: std::io::ErrorKind::TimedOut, calls: calls.clone() };
+        let mut dns_r = RetryingResolver::new(flaky, 3, Duration::from_millis(1));
+
+        assert_eq!(dns_r.clone().resolve("example.com:80").await?, "127.0.0.1:80".parse().unwrap());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // Not enough retries
+        calls.store(0, Ordering::SeqCst);
+        let flaky = FlakyResolver { failures: 2, kind: std::io::ErrorKind::TimedOut, calls: calls.clone() };
+        dns_r = RetryingResolver::new(flaky, 1, Duration::from_millis(1));
+        assert_eq!(dns_r.resolve("example.com:80").await.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retrying_resolve_definitive_failure() {
+        let calls = Arc::new(AtomicU32::new(0));
+        let flaky = FlakyResolver { failures: 2, kind: std::io::ErrorKind::NotFound, calls: calls.clone() };
+        let mut dns_r = RetryingResolver::new(flaky, 3, Duration::from_millis(1));
+
+        assert_eq!(dns_r.resolve("example.com:80").await.unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_caching_resolve_shared_by_clones() -> Result<(), std::io::Error> {
+        let calls = Arc::new(AtomicU32::new(0));
+        let counting = FlakyResolver { failures: 0, kind: std::io::ErrorKind::TimedOut, calls: calls.clone() };
+        let dns_r = CachingResolver::new(counting, Duration::from_secs(60), Duration::ZERO);
+
+        // e.g. 2 connections
+        let mut dns_r1 = dns_r.clone();
+        let mut dns_r2 = dns_r.clone();
+        assert_eq!(dns_r1.resolve("example.com:80").await?, "127.0.0.1:80".parse().unwrap());
+        assert_eq!(dns_r2.resolve("example.com:80").await?, "127.0.0.1:80".parse().unwrap());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        dns_r2.resolve("example.org:80").await?;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_caching_resolve_negative_and_expiry() -> Result<(), std::io::Error> {
+        let calls = Arc::new(AtomicU32::new(0));
+        let flaky = FlakyResolver { failures: 1, kind: std::io::ErrorKind::NotFound, calls: calls.clone() };
+        let mut dns_r = CachingResolver::new(flaky, Duration::from_millis(50), Duration::from_millis(50));
+
+        // failure is cached
+        assert_eq!(dns_r.resolve("example.com:80").await.unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(dns_r.clone().resolve("example.com:80").await.unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // expired
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(dns_r.resolve("example.com:80").await?, "127.0.0.1:80".parse().unwrap());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pinning_resolve() -> Result<(), std::io::Error> {
+        let calls = Arc::new(AtomicU32::new(0));
+        let counting = FlakyResolver { failures: 0, kind: std::io::ErrorKind::TimedOut, calls: calls.clone() };
+        let pins = HashMap::from([
+            ("Pinned.example.com".to_string(), vec!["10.0.0.1".parse().unwrap(), "127.0.0.1".parse().unwrap()]),
+            ("spoofed.example.com".to_string(), vec!["10.0.0.1".parse().unwrap()]),
+        ]);
+        let mut dns_r = PinningResolver::new(counting, pins);
+
+        // resolved to a pinned ip
+        assert_eq!(dns_r.resolve("pinned.example.com:443").await?, "127.0.0.1:80".parse().unwrap());
+        assert_eq!(dns_r.resolve_all("pinned.example.com:443").await?, vec!["127.0.0.1:80".parse().unwrap()]);
+        // not pinned
+        assert_eq!(dns_r.resolve("example.com:80").await?, "127.0.0.1:80".parse().unwrap());
+
+        // resolved to an ip outside of the pinned ones
+        let e = dns_r.resolve("spoofed.example.com:443").await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied);
+        let mismatch = e.get_ref().and_then(|e| e.downcast_ref::<PinMismatch>()).unwrap();
+        assert_eq!(mismatch.ip, "127.0.0.1".parse::<std::net::IpAddr>().unwrap());
+        assert!(dns_r.resolve_all("SPOOFED.example.com:443").await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_hostname() {
+        for host in ["example.com", "example.com.", "a-b.c_d.example", "localhost", "127.0.0.1", "::1", "xn--bcher-kva.example"] {
+            assert!(validate_hostname(host).is_ok(), "{}", host);
+        }
+        let long_label = format!("{}.example.com", "a".repeat(64));
+        let long_name = ["a".repeat(60).as_str(); 5].join(".");
+        for host in [long_label.as_str(), long_name.as_str(), "exa mple.com", "example.com/path", "ex%41mple.com",
+                     "", ".", "a..b", "-example.com", "example-.com"] {
+            let e = validate_hostname(host).unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput, "{}", host);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validating_resolve() -> Result<(), std::io::Error> {
+        let calls = Arc::new(AtomicU32::new(0));
+        let counting = FlakyResolver { failures: 0, kind: std::io::ErrorKind::TimedOut, calls: calls.clone() };
+        let mut dns_r = ValidatingResolver::new(counting);
+
+        assert_eq!(dns_r.resolve("example.com:443").await?, "127.0.0.1:80".parse().unwrap());
+        assert_eq!(dns_r.resolve_all("[::1]:443").await?, vec!["127.0.0.1:80".parse().unwrap()]);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // rejected without a lookup
+        let label = format!("{}.example.com:443", "a".repeat(64));
+        assert_eq!(dns_r.resolve(&label).await.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(dns_r.resolve_all("exa$mple.com:443").await.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // max length: 253 (default) then configured
+        let at_limit = format!("{}.{}", ["a".repeat(63).as_str(); 3].join("."), "a".repeat(61));
+        assert_eq!(at_limit.len(), MAX_HOSTNAME_LEN);
+        dns_r.resolve(&format!("{}:443", at_limit)).await?;
+        dns_r.resolve(&format!("{}.:443", at_limit)).await?;
+        let e = dns_r.resolve(&format!("a{}:443", at_limit)).await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+        let mut dns_r = dns_r.with_max_len(20);
+        dns_r.resolve(&format!("{}.example.com:443", "a".repeat(8))).await?;
+        let e = dns_r.resolve_all(&format!("{}.example.com:443", "a".repeat(9))).await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(e.to_string(), "invalid hostname: too long (21 > 20 characters)");
+        dns_r.resolve("[2001:db8:1234:5678:9abc:def0:1234:5678]:443").await?;
+        assert_eq!(calls.load(Ordering::SeqCst), 6);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hosts_file_resolve_and_reload() -> Result<(), std::io::Error> {
+        let path = std::env::temp_dir().join(format!("rust_http_tunnel_hosts_{}", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        std::fs::write(&path, "# comment\n10.0.0.1 internal.example.com internal # alias\n::1 v6.example.com\n")?;
+
+        let calls = Arc::new(AtomicU32::new(0));
+        let counting = FlakyResolver { failures: 0, kind: std::io::ErrorKind::TimedOut, calls: calls.clone() };
+        let mut dns_r = HostsFileResolver::load(counting, &path, Duration::from_millis(20)).await?;
+
+        assert_eq!(dns_r.resolve("internal.example.com:443").await?, "10.0.0.1:443".parse().unwrap());
+        assert_eq!(dns_r.clone().resolve("INTERNAL:80").await?, "10.0.0.1:80".parse().unwrap());
+        assert_eq!(dns_r.resolve("v6.example.com:80").await?, "[::1]:80".parse().unwrap());
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        // not in hosts file
+        assert_eq!(dns_r.resolve("example.com:80").await?, "127.0.0.1:80".parse().unwrap());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // edit the file, seen by all clones after the reload interval
+        std::fs::write(&path, "10.0.0.2 internal.example.com\n")?;
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let mut dns_r2 = dns_r.clone();
+        assert_eq!(dns_r2.resolve("internal.example.com:443").await?, "10.0.0.2:443".parse().unwrap());
+        assert_eq!(dns_r.resolve("internal.example.com:443").await?, "10.0.0.2:443".parse().unwrap());
+        assert_eq!(dns_r.resolve("v6.example.com:80").await?, "127.0.0.1:80".parse().unwrap());
+
+        std::fs::remove_file(&path)?;
+        assert!(HostsFileResolver::load(dns_r, &path, Duration::ZERO).await.is_err());
+        Ok(())
+    }
+
+    // Fake resolver: resolve to 127.0.0.1 (or fail for "fail" targets) after a delay
+    #[derive(Clone)]
+    struct SlowResolver {
+        delay: Duration,
+    }
+
+    #[async_trait]
+    impl DnsResolver for SlowResolver {
+        async fn resolve(&mut self, target: &str) -> std::io::Result<SocketAddr> {
+            tokio::time::sleep(self.delay).await;
+            if target.starts_with("fail") {
+                return Err(std::io::Error::from(std::io::ErrorKind::NotFound));
+            }
+            Ok(SocketAddr::new("127.0.0.1".parse().unwrap(), 80))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timed_resolve() -> Result<(), std::io::Error> {
+        let delay = Duration::from_millis(20);
+        let stats = Arc::new(ResolutionStats::default());
+        let mut dns_r = TimedResolver::new(SlowResolver { delay }, stats.clone());
+
+        dns_r.resolve("example.com:80").await?;
+        dns_r.clone().resolve_all("example.com:80").await?;
+        assert!(dns_r.resolve("fail.example.com:80").await.is_err());
+
+        assert_eq!(stats.successes(), 2);
+        assert_eq!(stats.failures(), 1);
+        assert!(stats.total_latency() >= delay * 3, "{:?}", stats.total_latency());
+        // 20ms: in the <=50ms bucket at least (not faster)
+        let histogram = stats.histogram();
+        assert_eq!(histogram.iter().map(|(_, count)| count).sum::<u64>(), 3);
+        assert!(histogram.iter().filter(|(bound, _)| bound.is_some_and(|b| b < delay)).all(|(_, count)| *count == 0));
+        assert!(stats.to_string().starts_with("2 ok, 1 failed, latency total: "), "{}", stats);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_budgeted_resolve() -> Result<(), std::io::Error> {
+        let calls = Arc::new(AtomicU32::new(0));
+        let counting = FlakyResolver { failures: 0, kind: std::io::ErrorKind::TimedOut, calls: calls.clone() };
+        let window = Duration::from_millis(200);
+        let dns_r = BudgetedResolver::new(counting, 2, window);
+
+        // shared by clones
+        dns_r.clone().resolve("example.com:80").await?;
+        dns_r.clone().resolve_all("example.org:80").await?;
+        let e = dns_r.clone().resolve("example.net:80").await.unwrap_err();
+        assert_eq!(ResolveError::classify(&e), ResolveError::OverBudget);
+        assert!(dns_r.clone().resolve_all("example.net:80").await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // not retried, not cached
+        let mut retrying = CachingResolver::new(RetryingResolver::new(dns_r.clone(), 3, Duration::from_millis(1)),
+                                                Duration::ZERO, Duration::from_secs(60));
+        assert!(retrying.resolve("example.net:80").await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        tokio::time::sleep(window).await;
+        assert_eq!(retrying.resolve("example.net:80").await?, "127.0.0.1:80".parse().unwrap());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // no cap
+        let mut dns_r = BudgetedResolver::new(FlakyResolver { failures: 0, kind: std::io::ErrorKind::TimedOut,
+                                                              calls: calls.clone() }, 0, window);
+        for _ in 0..5 {
+            dns_r.resolve("example.com:80").await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_suffix_resolve() -> Result<(), std::io::Error> {
+        let internal_server = spawn_stub_dns_server(0, [10, 0, 0, 7]).await?;
+        let calls = Arc::new(AtomicU32::new(0));
+        let default = FlakyResolver { failures: 0, kind: std::io::ErrorKind::TimedOut, calls: calls.clone() };
+        let internal = ConfigurableResolver::new(vec![internal_server]);
+        let mut dns_r = SuffixResolver::new(default, vec![(".Internal".to_string(), internal)]);
+
+        // internal resolver
+        assert_eq!(dns_r.resolve("svc.internal:80").await?, "10.0.0.7:80".parse().unwrap());
+        assert_eq!(dns_r.resolve_all("db.SVC.internal.:5432").await?, vec!["10.0.0.7:5432".parse().unwrap()]);
+        assert_eq!(dns_r.resolve("internal:80").await?, "10.0.0.7:80".parse().unwrap());
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        // default resolver
+        assert_eq!(dns_r.resolve("example.com:80").await?, "127.0.0.1:80".parse().unwrap());
+        assert_eq!(dns_r.resolve("notinternal:80").await?, "127.0.0.1:80".parse().unwrap());
+        assert_eq!(dns_r.resolve("internal.example.com:80").await?, "127.0.0.1:80".parse().unwrap());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
 }
@@ -0,0 +1,45 @@
+use std::net::SocketAddr;
+
+use crate::codec::TunnelResult;
+
+use crate::relay::RelayStats;
+
+// Tunnel lifecycle events, for embedding applications (see TunnelServer::with_events)
+// Note: sent without waiting (try_send), never blocking a tunnel; dropped if the channel is full or nobody listens
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TunnelEvent {
+    // target resolved, connecting then relaying
+    Started { peer: SocketAddr, target: String },
+    // tunnel closed (including when upstream could not be connected to, see stats)
+    Completed { peer: SocketAddr, target: String, stats: RelayStats },
+    // refused before connecting to upstream
+    Rejected { peer: SocketAddr, reason: RejectReason },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RejectReason {
+    // not in the peer allowlist
+    PeerNotAllowed,
+    // malformed request, method not allowed...
+    InvalidRequest,
+    ShuttingDown,
+    // new tunnels paused (see TunnelRegistry::set_paused)
+    Paused,
+    // refused by the tunnel policy (with this response)
+    Policy(TunnelResult),
+    // over the max tunnels per host
+    TooManyTunnels,
+    // target could not be resolved
+    Unresolved,
+    // target resolved to the proxy itself
+    ProxyItself,
+    // target resolved to denied upstream addresses only
+    AddressesBlocked,
+    // establishment deadline reached (before connecting)
+    Timeout,
+    // vetoed by the connect hook (with this response)
+    Hook(TunnelResult),
+    // max connections reached (see Overload)
+    Overloaded,
+}
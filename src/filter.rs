@@ -0,0 +1,205 @@
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+
+// Ip filtering
+// e.g. only allow internal subnets to use the proxy
+
+// An ip network like 10.0.0.0/8 or fd00::/8 (a single ip is a /32 or /128 network)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpCidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        // Note: handle ipv4 mapped ipv6 addresses (e.g. ::ffff:127.0.0.1) from dual stack sockets
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_match(&net.octets(), &ip.octets(), self.prefix_len)
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_match(&net.octets(), &ip.octets(), self.prefix_len)
+            },
+            _ => false,
+        }
+    }
+}
+
+fn prefix_match(net: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = (prefix_len / 8) as usize;
+    let remaining_bits = prefix_len % 8;
+
+    if net[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    if remaining_bits == 0 {
+        return true;
+    }
+    let mask = 0xFFu8 << (8 - remaining_bits);
+    net[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+impl FromStr for IpCidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid ip network: {}", s);
+
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => {
+                (addr.parse::<IpAddr>().map_err(|_| invalid())?, Some(prefix_len.parse::<u8>().map_err(|_| invalid())?))
+            },
+            None => (s.parse::<IpAddr>().map_err(|_| invalid())?, None),
+        };
+
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(max_prefix_len);
+        if prefix_len > max_prefix_len {
+            return Err(invalid());
+        }
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+// Peers allowed to connect (empty: allow everyone)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PeerAllowlist {
+    networks: Vec<IpCidr>,
+}
+
+impl PeerAllowlist {
+    pub fn add(&mut self, network: IpCidr) {
+        self.networks.push(network);
+    }
+
+    pub fn is_allowed(&self, ip: &IpAddr) -> bool {
+        self.networks.is_empty() || self.networks.iter().any(|n| n.contains(ip))
+    }
+}
+
+// Upstream addresses refused (e.g. private networks), whatever the target name
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UpstreamDenylist {
+    networks: Vec<IpCidr>,
+}
+
+impl UpstreamDenylist {
+    pub fn add(&mut self, network: IpCidr) {
+        self.networks.push(network);
+    }
+
+    pub fn is_denied(&self, ip: &IpAddr) -> bool {
+        self.networks.iter().any(|n| n.contains(ip))
+    }
+}
+
+// Is addr one of the proxy listen addresses? (e.g. a client trying to CONNECT to the proxy itself)
+// With a wildcard listen address (0.0.0.0 or ::), any local ip with the same port is the proxy
+pub fn is_listen_addr(addr: &SocketAddr, listen_addr: &SocketAddr) -> bool {
+    if addr.port() != listen_addr.port() {
+        return false;
+    }
+
+    let ip = addr.ip().to_canonical();
+    if listen_addr.ip().is_unspecified() {
+        // Note: connecting to 0.0.0.0 reaches the local host too
+        ip.is_unspecified() || ip.is_loopback() || is_local_ip(&ip)
+    } else {
+        ip == listen_addr.ip().to_canonical()
+    }
+}
+
+// Binding a socket only succeeds with the ip of a local interface
+fn is_local_ip(ip: &IpAddr) -> bool {
+    UdpSocket::bind(SocketAddr::new(*ip, 0)).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::net::{IpAddr, SocketAddr};
+    use super::{is_listen_addr, IpCidr, PeerAllowlist, UpstreamDenylist};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr_contains() -> Result<(), String> {
+        let net: IpCidr = "10.0.0.0/8".parse()?;
+        assert!(net.contains(&ip("10.1.2.3")));
+        assert!(net.contains(&ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(&ip("11.0.0.1")));
+        assert!(!net.contains(&ip("fd00::1")));
+
+        let net: IpCidr = "192.168.1.128/25".parse()?;
+        assert!(net.contains(&ip("192.168.1.200")));
+        assert!(!net.contains(&ip("192.168.1.127")));
+
+        let net: IpCidr = "fd00::/8".parse()?;
+        assert!(net.contains(&ip("fd12::1")));
+        assert!(!net.contains(&ip("fe80::1")));
+
+        let net: IpCidr = "127.0.0.1".parse()?;
+        assert!(net.contains(&ip("127.0.0.1")));
+        assert!(!net.contains(&ip("127.0.0.2")));
+
+        let net: IpCidr = "0.0.0.0/0".parse()?;
+        assert!(net.contains(&ip("1.2.3.4")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_upstream_denylist() -> Result<(), String> {
+        let mut denylist = UpstreamDenylist::default();
+        assert!(!denylist.is_denied(&ip("10.0.0.1")));
+        denylist.add("10.0.0.0/8".parse()?);
+        denylist.add("fd00::/8".parse()?);
+        assert!(denylist.is_denied(&ip("10.0.0.1")));
+        assert!(denylist.is_denied(&ip("::ffff:10.0.0.1")));
+        assert!(denylist.is_denied(&ip("fd00::1")));
+        assert!(!denylist.is_denied(&ip("1.2.3.4")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cidr_parse_errors() {
+        assert!("10.0.0.0/33".parse::<IpCidr>().is_err());
+        assert!("fd00::/129".parse::<IpCidr>().is_err());
+        assert!("10.0.0/8".parse::<IpCidr>().is_err());
+        assert!("10.0.0.0/a".parse::<IpCidr>().is_err());
+    }
+
+    #[test]
+    fn test_peer_allowlist() -> Result<(), String> {
+        let mut allowlist = PeerAllowlist::default();
+        assert!(allowlist.is_allowed(&ip("1.2.3.4")));
+
+        allowlist.add("10.0.0.0/8".parse()?);
+        allowlist.add("::1".parse()?);
+        assert!(allowlist.is_allowed(&ip("10.0.0.1")));
+        assert!(allowlist.is_allowed(&ip("::1")));
+        assert!(!allowlist.is_allowed(&ip("127.0.0.1")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_listen_addr() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+
+        let listen = addr("127.0.0.1:6161");
+        assert!(is_listen_addr(&addr("127.0.0.1:6161"), &listen));
+        assert!(is_listen_addr(&addr("[::ffff:127.0.0.1]:6161"), &listen));
+        assert!(!is_listen_addr(&addr("127.0.0.1:6162"), &listen));
+        assert!(!is_listen_addr(&addr("127.0.0.2:6161"), &listen));
+
+        let listen = addr("0.0.0.0:6161");
+        assert!(is_listen_addr(&addr("127.0.0.1:6161"), &listen));
+        assert!(is_listen_addr(&addr("127.0.0.5:6161"), &listen));
+        assert!(is_listen_addr(&addr("0.0.0.0:6161"), &listen));
+        assert!(!is_listen_addr(&addr("127.0.0.1:80"), &listen));
+        // documentation range: never a local ip
+        assert!(!is_listen_addr(&addr("192.0.2.1:6161"), &listen));
+    }
+}
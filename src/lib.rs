@@ -0,0 +1,23 @@
+// Library target: the tunnel server (see TunnelServer), e.g. to embed it in an application,
+// and the http request codec, e.g. for the fuzz targets (see fuzz/)
+pub mod codec;
+pub mod config;
+mod connector;
+mod dns;
+pub mod events;
+mod filter;
+mod listener;
+pub mod logger;
+mod mirror;
+pub mod policy;
+mod proxy_protocol;
+mod registry;
+mod relay;
+mod rewrite;
+mod server;
+mod tap;
+mod telemetry;
+mod timeouts;
+mod tls;
+
+pub use crate::server::{app_main, build_runtime, TunnelServer};
@@ -0,0 +1,169 @@
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::TcpListener;
+
+// Listening sockets
+// Bind with socket2 to control the dual stack behavior of ipv6 listeners (IPV6_V6ONLY): the OS default
+// differs (e.g. Linux accepts ipv4 clients on "[::]:port" as ipv4-mapped addresses, Windows does not)
+
+const LISTEN_BACKLOG: i32 = 1024;
+
+// Bind a listener on addr (first resolved address that can be bound, like TcpListener::bind)
+// ipv6_only: for ipv6 addresses, Some(true): ipv6 clients only, Some(false): dual stack, None: OS default
+pub async fn bind(addr: &str, ipv6_only: Option<bool>) -> std::io::Result<TcpListener> {
+    let mut last_error = None;
+    for addr in tokio::net::lookup_host(addr).await? {
+        match bind_addr(addr, ipv6_only) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        Error::new(ErrorKind::InvalidInput, format!("could not resolve to any address: {}", addr))
+    }))
+}
+
+fn bind_addr(addr: SocketAddr, ipv6_only: Option<bool>) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // Note: like TcpListener::bind (restart without waiting for TIME_WAIT sockets)
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    if let (SocketAddr::V6(_), Some(ipv6_only)) = (addr, ipv6_only) {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    socket.bind(&addr.into()).map_err(|e| bind_error(addr, e))?;
+    socket.listen(LISTEN_BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+// A clearer error for the common bind failures (same kind)
+// e.g. a privileged port (< 1024 on linux) without the privileges
+fn bind_error(addr: SocketAddr, e: Error) -> Error {
+    match e.kind() {
+        ErrorKind::PermissionDenied => Error::new(e.kind(), format!(
+            "Unable to bind {}: permission denied ({}), ports below 1024 usually require privileges: \
+             run with the CAP_NET_BIND_SERVICE capability (e.g. setcap 'cap_net_bind_service=+ep' on the binary) \
+             or listen on a higher port", addr, e)),
+        _ => e,
+    }
+}
+
+// systemd socket activation: listening sockets passed by systemd (LISTEN_PID & LISTEN_FDS env vars)
+// instead of binding (see sd_listen_fds(3))
+
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: std::os::unix::io::RawFd = 3;
+
+// None if not socket activated (no LISTEN_FDS or LISTEN_PID for another process)
+#[cfg(unix)]
+pub fn systemd_listeners() -> std::io::Result<Option<Vec<TcpListener>>> {
+    let (Ok(listen_pid), Ok(listen_fds)) = (std::env::var("LISTEN_PID"), std::env::var("LISTEN_FDS")) else {
+        return Ok(None);
+    };
+    inherited_listeners(&listen_pid, &listen_fds, SD_LISTEN_FDS_START)
+}
+
+#[cfg(unix)]
+fn inherited_listeners(listen_pid: &str, listen_fds: &str, first_fd: std::os::unix::io::RawFd)
+    -> std::io::Result<Option<Vec<TcpListener>>>
+{
+    use std::os::unix::io::FromRawFd;
+
+    let invalid = |name: &str, value: &str| Error::new(ErrorKind::InvalidInput, format!("invalid {}: {:?}", name, value));
+    let pid: u32 = listen_pid.parse().map_err(|_| invalid("LISTEN_PID", listen_pid))?;
+    if pid != std::process::id() {
+        return Ok(None);
+    }
+    let count: std::os::unix::io::RawFd = listen_fds.parse().map_err(|_| invalid("LISTEN_FDS", listen_fds))?;
+    (first_fd..first_fd + count)
+        .map(|fd| {
+            // Safety: fds passed by systemd, owned by this process from now on
+            let socket = unsafe { Socket::from_raw_fd(fd) };
+            if socket.r#type()? != Type::STREAM || !socket.is_listener()? {
+                return Err(Error::new(ErrorKind::InvalidInput, format!("inherited fd {} is not a listening stream socket", fd)));
+            }
+            socket.set_nonblocking(true)?;
+            TcpListener::from_std(socket.into())
+        })
+        .collect::<std::io::Result<Vec<_>>>()
+        .map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::net::SocketAddr;
+
+    use tokio::net::TcpStream;
+
+    use super::{bind, bind_error};
+    #[cfg(unix)]
+    use super::inherited_listeners;
+
+    #[test]
+    fn test_bind_error() {
+        let addr: SocketAddr = "0.0.0.0:443".parse().unwrap();
+        let e = bind_error(addr, std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(e.to_string().starts_with("Unable to bind 0.0.0.0:443: permission denied"), "{}", e);
+        assert!(e.to_string().contains("CAP_NET_BIND_SERVICE"), "{}", e);
+        assert!(e.to_string().ends_with("or listen on a higher port"), "{}", e);
+
+        // as is
+        let e = bind_error(addr, std::io::Error::new(std::io::ErrorKind::AddrInUse, "in use"));
+        assert_eq!((e.kind(), e.to_string()), (std::io::ErrorKind::AddrInUse, "in use".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_bind_ipv6_only() -> std::io::Result<()> {
+        let listener = bind("[::]:0", Some(true)).await?;
+        let port = listener.local_addr()?.port();
+
+        assert!(TcpStream::connect(SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], port))).await.is_ok());
+        assert!(TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], port))).await.is_err());
+
+        // both families bound separately, on the same port
+        let _listener_v4 = bind(&format!("0.0.0.0:{}", port), None).await?;
+        assert!(TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], port))).await.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bind_dual_stack() -> std::io::Result<()> {
+        let listener = bind("[::]:0", Some(false)).await?;
+        let port = listener.local_addr()?.port();
+
+        let _client = TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], port))).await?;
+        let (_, peer) = listener.accept().await?;
+        // ipv4 client seen as an ipv4-mapped address
+        assert!(matches!(peer, SocketAddr::V6(addr) if addr.ip().to_ipv4_mapped().is_some()), "{}", peer);
+        assert!(TcpStream::connect(SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], port))).await.is_ok());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_inherited_listeners() -> std::io::Result<()> {
+        use std::os::unix::io::IntoRawFd;
+
+        // pre-bound listener, passed as a raw fd (like systemd does)
+        let bound = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = bound.local_addr()?;
+        let fd = bound.into_raw_fd();
+        let pid = std::process::id().to_string();
+
+        // for another process
+        assert!(inherited_listeners("1", "1", fd)?.is_none());
+        assert!(inherited_listeners(&pid, "one", fd).is_err());
+
+        let listeners = inherited_listeners(&pid, "1", fd)?.unwrap();
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].local_addr()?, addr);
+        let _client = TcpStream::connect(addr).await?;
+        listeners[0].accept().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+// Minimal logger for the log crate macros (info!, warn!...)
+// Only print records from this crate, one message per line (like println!)
+// or write them to a (rotated) file, once set
+
+struct Logger {
+    file: OnceLock<Mutex<RotatingFile>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level() && metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            match self.file.get() {
+                // Note: nowhere to report a failed write
+                Some(file) => { let _ = file.lock().unwrap().write_line(&record.args().to_string()); },
+                None => println!("{}", record.args()),
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger { file: OnceLock::new() };
+
+pub fn init(level: LevelFilter) -> Result<(), log::SetLoggerError> {
+    log::set_logger(&LOGGER)?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+// Write the log to file (instead of stdout) from now on
+// Note: the logger starts with stdout so config errors are printed
+pub fn set_file(file: RotatingFile) {
+    let _ = LOGGER.file.set(Mutex::new(file));
+}
+
+// Rotated files: FILE.1 (most recent) to FILE.3
+const LOG_FILES_KEPT: usize = 3;
+
+// Append only log file, rotated once larger than max size (0: never rotated)
+// e.g. tunnel.log -> tunnel.log.1 -> tunnel.log.2 ...
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn open<P: AsRef<Path>>(path: P, max_size: u64) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, max_size, file, size })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        // Note: the oldest file is overwritten by the rename
+        for index in (1..LOG_FILES_KEPT).rev() {
+            match std::fs::rename(self.rotated_path(index), self.rotated_path(index + 1)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => {},
+            }
+        }
+        std::fs::rename(&self.path, self.rotated_path(1))?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    // Note: a line is never split, a line larger than max size gets a file of its own
+    pub fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.max_size > 0 && self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+}
+
+// Test logger: keep every message in memory so tests can assert on them
+// Note: tests run in parallel so assert on messages unique to the test (e.g. ports)
+#[cfg(test)]
+pub mod capture {
+
+    use std::sync::{Mutex, Once};
+    use log::{LevelFilter, Log, Metadata, Record};
+
+    struct CaptureLogger {
+        messages: Mutex<Vec<String>>,
+    }
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+        }
+
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                self.messages.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CaptureLogger = CaptureLogger { messages: Mutex::new(Vec::new()) };
+    static INIT: Once = Once::new();
+
+    pub fn init() {
+        INIT.call_once(|| {
+            log::set_logger(&LOGGER).unwrap();
+            log::set_max_level(LevelFilter::Trace);
+        });
+    }
+
+    // Captured messages containing pattern
+    pub fn find(pattern: &str) -> Vec<String> {
+        LOGGER.messages.lock().unwrap().iter().filter(|m| m.contains(pattern)).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::RotatingFile;
+
+    #[test]
+    fn test_rotating_file() -> Result<(), std::io::Error> {
+        let dir = std::env::temp_dir().join(format!("rust_http_tunnel_log_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir)?;
+        let path = dir.join("tunnel.log");
+        let rotated = |index: usize| dir.join(format!("tunnel.log.{}", index));
+
+        // 10 bytes per line (with the line feed), at most 3 lines per file
+        let mut file = RotatingFile::open(&path, 30)?;
+        for i in 0..3 {
+            file.write_line(&format!("record {:02}", i))?;
+        }
+        assert!(!rotated(1).exists());
+        assert_eq!(std::fs::metadata(&path)?.len(), 30);
+
+        for i in 3..12 {
+            file.write_line(&format!("record {:02}", i))?;
+        }
+        assert_eq!(std::fs::read_to_string(&path)?, "record 09\nrecord 10\nrecord 11\n");
+        assert_eq!(std::fs::read_to_string(rotated(1))?, "record 06\nrecord 07\nrecord 08\n");
+        assert_eq!(std::fs::read_to_string(rotated(2))?, "record 03\nrecord 04\nrecord 05\n");
+        assert_eq!(std::fs::read_to_string(rotated(3))?, "record 00\nrecord 01\nrecord 02\n");
+
+        // oldest file dropped
+        file.write_line("record 12")?;
+        assert_eq!(std::fs::read_to_string(rotated(3))?, "record 03\nrecord 04\nrecord 05\n");
+        assert!(!rotated(4).exists());
+
+        // reopened: appended, size accounted for
+        drop(file);
+        let mut file = RotatingFile::open(&path, 30)?;
+        file.write_line("record 13")?;
+        file.write_line("record 14")?;
+        assert_eq!(std::fs::read_to_string(&path)?, "record 12\nrecord 13\nrecord 14\n");
+        file.write_line("record 15")?;
+        assert_eq!(std::fs::read_to_string(&path)?, "record 15\n");
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,237 @@
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Context, Poll};
+
+use bytes::Bytes;
+use log::warn;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_util::sync::PollSender;
+
+// Audit mirror: tee a copy of the bytes written to a stream (one tunnel direction) to a sink, e.g. for security auditing
+// The copies go through a bounded queue (of writes) to a task writing them to the sink, the relay does not wait
+// for the sink, unless the overflow policy is Block (backpressure)
+// Note: the data written to the stream is not modified, a sink error only stops the mirroring
+
+// Where the copies are written, one file (or connection) per tunnel direction
+#[derive(Debug, Clone, PartialEq)]
+pub enum MirrorSink {
+    // directory, e.g. "/var/log/tunnel" -> "/var/log/tunnel/<request id>.up"
+    Dir(PathBuf),
+    // e.g. "tcp://127.0.0.1:9000", one connection per tunnel direction
+    Tcp(String),
+}
+
+impl FromStr for MirrorSink {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("tcp://") {
+            Some(addr) if !addr.is_empty() => Ok(MirrorSink::Tcp(addr.to_string())),
+            Some(_) => Err(format!("invalid mirror sink: {} (expect tcp://host:port)", s)),
+            None if !s.is_empty() => Ok(MirrorSink::Dir(PathBuf::from(s))),
+            None => Err("empty mirror sink".to_string()),
+        }
+    }
+}
+
+// Mirrored tunnel direction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MirrorDirection {
+    // client -> upstream, i.e. what upstream receives
+    Up,
+    // upstream -> client
+    Down,
+}
+
+impl FromStr for MirrorDirection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "up" => Ok(MirrorDirection::Up),
+            "down" => Ok(MirrorDirection::Down),
+            _ => Err(format!("unsupported mirror direction: {} (expect up or down)", s)),
+        }
+    }
+}
+
+// What to do with a write while the queue is full (sink too slow)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MirrorOverflow {
+    // the copy is lost (counted), the relay goes on
+    Drop,
+    // the relay waits for the sink, i.e. the tunnel is as slow as the sink
+    Block,
+}
+
+impl FromStr for MirrorOverflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(MirrorOverflow::Drop),
+            "block" => Ok(MirrorOverflow::Block),
+            _ => Err(format!("unsupported mirror overflow: {} (expect drop or block)", s)),
+        }
+    }
+}
+
+// Open the sink (name: the file name in a Dir sink), then spawn the task writing the queued copies to it
+// The task ends once the returned sender is dropped (the sink is then shut down) or on a sink error
+pub async fn spawn_sink(sink: &MirrorSink, name: &str, queue: usize) -> std::io::Result<mpsc::Sender<Bytes>> {
+    let (mut writer, label): (Box<dyn AsyncWrite + Send + Unpin>, String) = match sink {
+        MirrorSink::Dir(dir) => {
+            // Note: the name may come from the client (request id), keep it a plain file name
+            let name: String = name.chars()
+                .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' })
+                .collect();
+            let path = dir.join(name.trim_start_matches('.'));
+            let file = tokio::fs::File::create(&path).await?;
+            (Box::new(file), path.display().to_string())
+        },
+        MirrorSink::Tcp(addr) => (Box::new(TcpStream::connect(addr).await?), addr.clone()),
+    };
+    let (sender, mut receiver) = mpsc::channel::<Bytes>(queue);
+    tokio::spawn(async move {
+        while let Some(data) = receiver.recv().await {
+            if let Err(e) = writer.write_all(&data).await {
+                warn!("[Mirror] Sink {} error, mirroring stopped: {}", label, e);
+                return;
+            }
+        }
+        let _ = writer.shutdown().await;
+    });
+    Ok(sender)
+}
+
+enum Queue {
+    Drop(mpsc::Sender<Bytes>),
+    Block(PollSender<Bytes>),
+}
+
+pub struct Mirror<W> {
+    inner: W,
+    // None: not mirrored (or the sink is gone)
+    queue: Option<Queue>,
+    label: String,
+    // bytes written but not mirrored (queue full)
+    dropped: u64,
+}
+
+impl<W> Mirror<W> {
+    // sender None: a plain writer
+    pub fn new(inner: W, sender: Option<mpsc::Sender<Bytes>>, overflow: MirrorOverflow, label: String) -> Self {
+        let queue = sender.map(|sender| match overflow {
+            MirrorOverflow::Drop => Queue::Drop(sender),
+            MirrorOverflow::Block => Queue::Block(PollSender::new(sender)),
+        });
+        Self { inner, queue, label, dropped: 0 }
+    }
+}
+
+impl<W> Drop for Mirror<W> {
+    fn drop(&mut self) {
+        if self.dropped > 0 {
+            warn!("[Mirror] {}: {} bytes not mirrored (sink too slow)", self.label, self.dropped);
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Mirror<W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        // Note: a queue slot is reserved first (backpressure), it is kept if the inner write is pending
+        if let Some(Queue::Block(sender)) = &mut self.queue {
+            if ready!(sender.poll_reserve(cx)).is_err() {
+                self.queue = None; // sink is gone
+            }
+        }
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        let data = Bytes::copy_from_slice(&buf[..written]);
+        let closed = match &mut self.queue {
+            Some(Queue::Drop(sender)) => match sender.try_send(data) {
+                Ok(()) => false,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    self.dropped += written as u64;
+                    false
+                },
+                Err(mpsc::error::TrySendError::Closed(_)) => true,
+            },
+            Some(Queue::Block(sender)) => sender.send_item(data).is_err(),
+            None => false,
+        };
+        if closed {
+            self.queue = None; // sink is gone
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::time::Duration;
+
+    use super::{spawn_sink, Mirror, MirrorOverflow, MirrorSink};
+
+    // traits
+    use tokio::io::AsyncWriteExt; // for write_all()
+
+    #[tokio::test]
+    async fn test_mirror() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // drop: the relay does not wait for a full queue
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+        let mut mirror = Mirror::new(Vec::new(), Some(sender), MirrorOverflow::Drop, "test_mirror".to_string());
+        mirror.write_all(b"hello").await?;
+        mirror.write_all(b" world").await?;
+        assert_eq!(mirror.inner, b"hello world");
+        assert_eq!(mirror.dropped, 6);
+        assert_eq!(&receiver.recv().await.unwrap()[..], b"hello");
+
+        // block: the relay waits for the sink
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+        let mut mirror = Mirror::new(Vec::new(), Some(sender), MirrorOverflow::Block, "test_mirror".to_string());
+        mirror.write_all(b"hello").await?;
+        assert!(tokio::time::timeout(Duration::from_millis(50), mirror.write_all(b" world")).await.is_err());
+        assert_eq!(&receiver.recv().await.unwrap()[..], b"hello");
+        mirror.write_all(b" world").await?;
+        assert_eq!(mirror.inner, b"hello world");
+        assert_eq!(&receiver.recv().await.unwrap()[..], b" world");
+        // sink gone: still a plain writer
+        drop(receiver);
+        mirror.write_all(b"!").await?;
+        assert_eq!(mirror.inner, b"hello world!");
+
+        // dir sink, the file name is sanitized
+        let dir = std::env::temp_dir().join(format!("test_mirror_{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let sender = spawn_sink(&MirrorSink::Dir(dir.clone()), "../id.up", 4).await?;
+        let mut mirror = Mirror::new(Vec::new(), Some(sender), MirrorOverflow::Block, "test_mirror".to_string());
+        mirror.write_all(b"audited").await?;
+        drop(mirror);
+        let mut mirrored = Vec::new();
+        for _ in 0..50 {
+            mirrored = std::fs::read(dir.join("_id.up"))?;
+            if !mirrored.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(mirrored, b"audited");
+        std::fs::remove_dir_all(&dir)?;
+
+        assert_eq!("tcp://127.0.0.1:9000".parse::<MirrorSink>(), Ok(MirrorSink::Tcp("127.0.0.1:9000".to_string())));
+        assert!("tcp://".parse::<MirrorSink>().is_err());
+        Ok(())
+    }
+}
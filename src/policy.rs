@@ -0,0 +1,164 @@
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::ControlFlow;
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use crate::codec::TunnelResult;
+
+// Tunnel policy
+// Decide if a CONNECT request is refused (and with which response) given its target & peer,
+// checked after rewrites, before resolving & connecting
+
+pub trait TunnelPolicy {
+    // None: allowed, otherwise reply with this (error) result and close, e.g. 451 for legally blocked hosts
+    fn check(&self, target: &str, peer: SocketAddr) -> Option<TunnelResult>;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AllowAll {}
+
+impl TunnelPolicy for AllowAll {
+    fn check(&self, _target: &str, _peer: SocketAddr) -> Option<TunnelResult> {
+        None
+    }
+}
+
+// Two policies checked in turn, the first refusal wins, e.g. the target denylist then an embedder policy
+pub struct PolicyChain {
+    first: Arc<dyn TunnelPolicy + Send + Sync>,
+    then: Arc<dyn TunnelPolicy + Send + Sync>,
+}
+
+impl PolicyChain {
+    pub fn new(first: Arc<dyn TunnelPolicy + Send + Sync>, then: Arc<dyn TunnelPolicy + Send + Sync>) -> Self {
+        Self { first, then }
+    }
+}
+
+impl TunnelPolicy for PolicyChain {
+    fn check(&self, target: &str, peer: SocketAddr) -> Option<TunnelResult> {
+        self.first.check(target, peer).or_else(|| self.then.check(target, peer))
+    }
+}
+
+// Connect hook
+// Embedder logic run once the target is resolved (addresses filtered), before connecting & relaying,
+// e.g. checking the resolved addresses against an external service
+
+#[async_trait]
+pub trait ConnectHook {
+    // Continue: connect to the resolved addresses, Break(result): reply with this (error) result and close
+    async fn on_connect(&self, target: &str, resolved: &[SocketAddr], peer: SocketAddr) -> ControlFlow<TunnelResult>;
+}
+
+// Target denylist
+// Hosts (one per line, '#' comments) denied with a 403, a host also denies its subdomains
+// e.g. "example.com" denies "example.com:443" & "www.example.com:443"
+// The file can be reloaded at runtime (see reload)
+
+#[derive(Debug)]
+pub struct TargetDenylist {
+    path: String,
+    hosts: RwLock<Vec<String>>,
+}
+
+fn parse_hosts(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|host| !host.is_empty())
+        .map(|host| host.to_ascii_lowercase())
+        .collect()
+}
+
+impl TargetDenylist {
+    pub fn load(path: &str) -> io::Result<Self> {
+        let hosts = parse_hosts(&fs::read_to_string(path)?);
+        Ok(Self { path: path.to_string(), hosts: RwLock::new(hosts) })
+    }
+
+    // Read the file again, return the new entry count (on error, the current entries are kept)
+    pub fn reload(&self) -> io::Result<usize> {
+        let hosts = parse_hosts(&fs::read_to_string(&self.path)?);
+        let count = hosts.len();
+        *self.hosts.write().unwrap() = hosts;
+        Ok(count)
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    // target: host:port
+    pub fn is_denied(&self, target: &str) -> bool {
+        let host = target.rsplit_once(':').map_or(target, |(host, _port)| host);
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+        self.hosts.read().unwrap().iter().any(|denied| {
+            host == *denied || host.strip_suffix(denied.as_str()).is_some_and(|sub| sub.ends_with('.'))
+        })
+    }
+}
+
+impl TunnelPolicy for TargetDenylist {
+    fn check(&self, target: &str, _peer: SocketAddr) -> Option<TunnelResult> {
+        self.is_denied(target).then_some(TunnelResult::Forbidden)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use super::{AllowAll, PolicyChain, TargetDenylist, TunnelPolicy};
+    use crate::codec::TunnelResult;
+
+    #[test]
+    fn test_target_denylist() -> Result<(), std::io::Error> {
+        let path = std::env::temp_dir().join(format!("rust_http_tunnel_denylist_{}", std::process::id()));
+        std::fs::write(&path, "# legal\nExample.com\n\n10.0.0.1 # internal\n")?;
+        let denylist = TargetDenylist::load(path.to_str().unwrap())?;
+
+        assert!(denylist.is_denied("example.com:443"));
+        assert!(denylist.is_denied("www.EXAMPLE.com:443"));
+        assert!(denylist.is_denied("10.0.0.1:22"));
+        assert!(!denylist.is_denied("notexample.com:443"));
+        assert!(!denylist.is_denied("example.org:443"));
+
+        std::fs::write(&path, "example.org\n")?;
+        assert_eq!(denylist.reload()?, 1);
+        assert!(!denylist.is_denied("example.com:443"));
+        assert!(denylist.is_denied("example.org:443"));
+
+        // the current entries are kept
+        std::fs::remove_file(&path)?;
+        assert!(denylist.reload().is_err());
+        assert!(denylist.is_denied("example.org:443"));
+        Ok(())
+    }
+
+    // Refuse every target with this result
+    struct DenyAll(TunnelResult);
+
+    impl TunnelPolicy for DenyAll {
+        fn check(&self, _target: &str, _peer: SocketAddr) -> Option<TunnelResult> {
+            Some(self.0)
+        }
+    }
+
+    #[test]
+    fn test_policy_chain() {
+        let peer: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let chain = PolicyChain::new(Arc::new(AllowAll::default()), Arc::new(DenyAll(TunnelResult::Forbidden)));
+        assert_eq!(chain.check("example.com:443", peer), Some(TunnelResult::Forbidden));
+        // the first refusal wins
+        let chain = PolicyChain::new(Arc::new(DenyAll(TunnelResult::UnavailableForLegalReasons)),
+                                     Arc::new(DenyAll(TunnelResult::Forbidden)));
+        assert_eq!(chain.check("example.com:443", peer), Some(TunnelResult::UnavailableForLegalReasons));
+        let chain = PolicyChain::new(Arc::new(AllowAll::default()), Arc::new(AllowAll::default()));
+        assert_eq!(chain.check("example.com:443", peer), None);
+    }
+}
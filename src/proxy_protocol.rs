@@ -0,0 +1,160 @@
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt}; // for read_exact()
+
+// PROXY protocol (v1 & v2) - inbound side
+// When running behind a L4 load balancer, the accepted peer address is the load balancer one, the real
+// client address is sent by the load balancer in a header at the start of the connection
+// Spec: https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107; // including CRLF
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+const V2_CMD_LOCAL: u8 = 0x0;
+const V2_CMD_PROXY: u8 = 0x1;
+const V2_FAMILY_TCP4: u8 = 0x11;
+const V2_FAMILY_TCP6: u8 = 0x21;
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Invalid PROXY protocol header: {}", message))
+}
+
+// Read the PROXY protocol header, return the client (source) address
+// None if the header does not carry an address (v1 UNKNOWN, v2 LOCAL or non tcp family)
+// Note: read exactly the header bytes, data following the header is left in the reader
+pub async fn read_header<R>(reader: &mut R) -> std::io::Result<Option<SocketAddr>>
+    where R: AsyncRead + Unpin
+{
+    // Note: the shortest header (v1 "PROXY UNKNOWN\r\n") is longer than the v2 signature
+    let mut start = [0u8; 12];
+    reader.read_exact(&mut start).await?;
+
+    if start.starts_with(V1_PREFIX) {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(invalid("v1 header too long"));
+            }
+            line.push(reader.read_u8().await?);
+        }
+        parse_v1(&line[..line.len() - 2])
+    } else if &start == V2_SIGNATURE {
+        let mut header = [0u8; V2_HEADER_LEN - 12];
+        reader.read_exact(&mut header).await?;
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let mut payload = vec![0u8; len];
+        reader.read_exact(&mut payload).await?;
+        parse_v2(header[0], header[1], &payload)
+    } else {
+        Err(invalid("missing header"))
+    }
+}
+
+// e.g. "PROXY TCP4 192.168.0.1 192.168.0.11 56324 443"
+fn parse_v1(line: &[u8]) -> std::io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("v1 header is not ascii"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), src, _dst, src_port, _dst_port] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("v1 source address"))?;
+            if ip.is_ipv4() != (protocol == "TCP4") {
+                return Err(invalid("v1 address family mismatch"));
+            }
+            let port: u16 = src_port.parse().map_err(|_| invalid("v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        },
+        _ => Err(invalid("v1 header format")),
+    }
+}
+
+fn parse_v2(version_command: u8, family: u8, payload: &[u8]) -> std::io::Result<Option<SocketAddr>> {
+    if version_command >> 4 != 0x2 {
+        return Err(invalid("v2 version"));
+    }
+
+    match version_command & 0x0F {
+        V2_CMD_LOCAL => return Ok(None), // e.g. load balancer health check
+        V2_CMD_PROXY => {},
+        _ => return Err(invalid("v2 command")),
+    }
+
+    let addr = match family {
+        V2_FAMILY_TCP4 if payload.len() >= 12 => {
+            let ip = <[u8; 4]>::try_from(&payload[0..4]).unwrap();
+            Some(SocketAddr::new(IpAddr::from(ip), u16::from_be_bytes([payload[8], payload[9]])))
+        },
+        V2_FAMILY_TCP6 if payload.len() >= 36 => {
+            let ip = <[u8; 16]>::try_from(&payload[0..16]).unwrap();
+            Some(SocketAddr::new(IpAddr::from(ip), u16::from_be_bytes([payload[32], payload[33]])))
+        },
+        V2_FAMILY_TCP4 | V2_FAMILY_TCP6 => return Err(invalid("v2 address block too short")),
+        _ => None, // unspec, udp or unix socket: no usable address
+    };
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::net::SocketAddr;
+    use super::{read_header, V2_SIGNATURE};
+
+    // traits
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_read_v1_header() -> Result<(), std::io::Error> {
+        let mut input: &[u8] = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\nCONNECT a:80 HTTP/1.1\r\n";
+        let addr = read_header(&mut input).await?;
+        assert_eq!(addr, Some("192.168.0.1:56324".parse::<SocketAddr>().unwrap()));
+        // following data is untouched
+        assert!(input.starts_with(b"CONNECT "));
+
+        let mut input: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n";
+        assert_eq!(read_header(&mut input).await?, Some("[2001:db8::1]:4000".parse().unwrap()));
+
+        let mut input: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut input).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_v2_header() -> Result<(), std::io::Error> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0, 12]); // v2 PROXY, TCP4, 12 bytes
+        header.extend_from_slice(&[10, 1, 2, 3, 10, 0, 0, 1]);
+        header.extend_from_slice(&40000u16.to_be_bytes());
+        header.extend_from_slice(&443u16.to_be_bytes());
+        header.extend_from_slice(b"CONNECT");
+
+        let mut input: &[u8] = &header;
+        assert_eq!(read_header(&mut input).await?, Some("10.1.2.3:40000".parse().unwrap()));
+        let mut rest = String::new();
+        input.read_to_string(&mut rest).await?;
+        assert_eq!(rest, "CONNECT");
+
+        // LOCAL command (health check)
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        let mut input: &[u8] = &header;
+        assert_eq!(read_header(&mut input).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_invalid_header() {
+        let mut input: &[u8] = b"CONNECT a:80 HTTP/1.1\r\n";
+        assert!(read_header(&mut input).await.is_err());
+
+        let mut input: &[u8] = b"PROXY TCP4 2001:db8::1 10.0.0.1 4000 443\r\n";
+        assert!(read_header(&mut input).await.is_err());
+
+        let long = format!("PROXY TCP4 {}\r\n", "1".repeat(200));
+        let mut input: &[u8] = long.as_bytes();
+        assert!(read_header(&mut input).await.is_err());
+    }
+}
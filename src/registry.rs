@@ -0,0 +1,374 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+// Active tunnels registry
+// Each running tunnel holds a guard, dropping it (tunnel closed) removes the tunnel from the registry
+// Connections (accepted, including the ones rejected or not yet tunneling) are only counted
+// Max connections: a semaphore shared by all the listeners, a connection holds a slot until closed
+// Per target limit: a semaphore per target, dropped once unused
+// A running tunnel can be revoked (e.g. its target is no longer allowed), the tunnel then closes itself
+// New tunnels can be paused (e.g. maintenance), the running ones are not affected
+// Activity since start (tunnels, bytes relayed, results) is summed up for the shutdown summary
+// Note: shared by all clones (all the listeners)
+
+#[derive(Debug, Clone)]
+pub struct TunnelInfo {
+    pub peer: SocketAddr,
+    pub target: String,
+    pub start: Instant,
+    // cancelled once revoked
+    pub revoked: CancellationToken,
+}
+
+#[derive(Default)]
+struct Tunnels {
+    next_id: u64,
+    active: HashMap<u64, TunnelInfo>,
+}
+
+#[derive(Default)]
+struct Connections {
+    active: AtomicUsize,
+    total: AtomicU64,
+    // new tunnels refused while set
+    paused: AtomicBool,
+}
+
+#[derive(Default)]
+struct Activity {
+    tunnels: u64,
+    bytes: u64,
+    relay_duration: Duration,
+    // result -> count, e.g. "relayed", "rejected (Timeout)"
+    results: BTreeMap<String, u64>,
+}
+
+#[derive(Clone, Default)]
+pub struct TunnelRegistry {
+    tunnels: Arc<Mutex<Tunnels>>,
+    changed: Arc<Notify>,
+    connections: Arc<Connections>,
+    hosts: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+    // None: unlimited
+    slots: Option<Arc<Semaphore>>,
+    activity: Arc<Mutex<Activity>>,
+}
+
+pub struct HostPermit {
+    registry: TunnelRegistry,
+    target: String,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for HostPermit {
+    fn drop(&mut self) {
+        self.permit.take();
+        self.registry.release_host(&self.target);
+    }
+}
+
+pub struct ConnectionGuard {
+    connections: Arc<Connections>,
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct TunnelGuard {
+    registry: TunnelRegistry,
+    id: u64,
+    revoked: CancellationToken,
+}
+
+impl TunnelGuard {
+    pub fn revoked(&self) -> CancellationToken {
+        self.revoked.clone()
+    }
+}
+
+impl Drop for TunnelGuard {
+    fn drop(&mut self) {
+        self.registry.tunnels.lock().unwrap().active.remove(&self.id);
+        self.registry.changed.notify_one();
+    }
+}
+
+impl TunnelRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The tunnel is active until the guard is dropped
+    pub fn register(&self, peer: SocketAddr, target: &str) -> TunnelGuard {
+        let mut tunnels = self.tunnels.lock().unwrap();
+        let id = tunnels.next_id;
+        tunnels.next_id += 1;
+        let revoked = CancellationToken::new();
+        let info = TunnelInfo { peer, target: target.to_string(), start: Instant::now(), revoked: revoked.clone() };
+        tunnels.active.insert(id, info);
+        drop(tunnels);
+        self.changed.notify_one();
+        TunnelGuard { registry: self.clone(), id, revoked }
+    }
+
+    // At most max connections (0: unlimited)
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.slots = (max > 0).then(|| Arc::new(Semaphore::new(max)));
+        self
+    }
+
+    // The connection is active until the guard is dropped
+    // Note: counted only, whatever the max connections (e.g. a connection being rejected)
+    pub fn connection(&self) -> ConnectionGuard {
+        self.guard(None)
+    }
+
+    // Same as connection, None if the max connections is reached
+    pub fn try_connection(&self) -> Option<ConnectionGuard> {
+        let slot = match &self.slots {
+            Some(slots) => Some(slots.clone().try_acquire_owned().ok()?),
+            None => None,
+        };
+        Some(self.guard(slot))
+    }
+
+    // Same as connection, waiting for a slot if the max connections is reached
+    pub async fn queued_connection(&self) -> ConnectionGuard {
+        let slot = match &self.slots {
+            // Note: never closed
+            Some(slots) => slots.clone().acquire_owned().await.ok(),
+            None => None,
+        };
+        self.guard(slot)
+    }
+
+    fn guard(&self, slot: Option<OwnedSemaphorePermit>) -> ConnectionGuard {
+        self.connections.active.fetch_add(1, Ordering::Relaxed);
+        self.connections.total.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard { connections: self.connections.clone(), _slot: slot }
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.connections.active.load(Ordering::Relaxed)
+    }
+
+    // Since start
+    pub fn total_connections(&self) -> u64 {
+        self.connections.total.load(Ordering::Relaxed)
+    }
+
+    // Return the previous state
+    pub fn set_paused(&self, paused: bool) -> bool {
+        self.connections.paused.swap(paused, Ordering::Relaxed)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.connections.paused.load(Ordering::Relaxed)
+    }
+
+    // One of the max tunnels to target, waiting for up to wait (no wait if zero)
+    // None if over the limit
+    pub async fn acquire_host(&self, target: &str, max: usize, wait: Duration) -> Option<HostPermit> {
+        let semaphore = self.hosts.lock().unwrap()
+            .entry(target.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(max)))
+            .clone();
+        let permit = if wait.is_zero() {
+            semaphore.try_acquire_owned().ok()
+        } else {
+            tokio::time::timeout(wait, semaphore.acquire_owned()).await.ok().and_then(|permit| permit.ok())
+        };
+        match permit {
+            Some(permit) => Some(HostPermit { registry: self.clone(), target: target.to_string(), permit: Some(permit) }),
+            None => {
+                self.release_host(target);
+                None
+            },
+        }
+    }
+
+    // Note: unused if only held by the map (no permit, no waiter)
+    fn release_host(&self, target: &str) {
+        let mut hosts = self.hosts.lock().unwrap();
+        if hosts.get(target).is_some_and(|semaphore| Arc::strong_count(semaphore) == 1) {
+            hosts.remove(target);
+        }
+    }
+
+    // Active tunnels, oldest first
+    pub fn active(&self) -> Vec<TunnelInfo> {
+        let mut active: Vec<TunnelInfo> = self.tunnels.lock().unwrap().active.values().cloned().collect();
+        active.sort_by_key(|t| t.start);
+        active
+    }
+
+    // Revoke the active tunnels matching f, return how many
+    pub fn revoke_where<F>(&self, f: F) -> usize where F: Fn(&TunnelInfo) -> bool {
+        let tunnels = self.tunnels.lock().unwrap();
+        let revoked: Vec<&TunnelInfo> = tunnels.active.values().filter(|t| !t.revoked.is_cancelled() && f(t)).collect();
+        for tunnel in &revoked {
+            tunnel.revoked.cancel();
+        }
+        revoked.len()
+    }
+
+    // A relayed tunnel (both directions bytes), counted as a "relayed" result
+    pub fn record_tunnel(&self, bytes: u64, duration: Duration) {
+        let mut activity = self.activity.lock().unwrap();
+        activity.tunnels += 1;
+        activity.bytes += bytes;
+        activity.relay_duration += duration;
+        *activity.results.entry("relayed".to_string()).or_default() += 1;
+    }
+
+    // Any other outcome of a request (e.g. rejected)
+    pub fn record_result(&self, result: &str) {
+        *self.activity.lock().unwrap().results.entry(result.to_string()).or_default() += 1;
+    }
+
+    // e.g. "12 connection(s), 2 tunnel(s), 1024 bytes relayed, average relay duration: 1.5s, results: relayed: 2, ..."
+    pub fn summary(&self) -> String {
+        let activity = self.activity.lock().unwrap();
+        let average = activity.relay_duration.checked_div(activity.tunnels as u32).unwrap_or_default();
+        let results: Vec<String> = activity.results.iter().map(|(result, count)| format!("{}: {}", result, count)).collect();
+        format!("{} connection(s), {} tunnel(s), {} bytes relayed, average relay duration: {:?}, results: {}",
+                self.total_connections(), activity.tunnels, activity.bytes, average,
+                if results.is_empty() { "none".to_string() } else { results.join(", ") })
+    }
+
+    // Wait for a tunnel to be registered or closed (or for timeout)
+    pub async fn changed(&self, timeout: Duration) {
+        let _ = tokio::time::timeout(timeout, self.changed.notified()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::TunnelRegistry;
+    use tokio::time::Duration;
+
+    #[test]
+    fn test_registry() {
+        let registry = TunnelRegistry::new();
+        let peer = "127.0.0.1:4000".parse().unwrap();
+        let first = registry.register(peer, "a.com:443");
+        let second = registry.clone().register(peer, "b.com:443");
+        assert_eq!(registry.active().len(), 2);
+        assert_eq!(registry.active()[0].target, "a.com:443");
+
+        drop(first);
+        assert_eq!(registry.active().len(), 1);
+        assert_eq!(registry.active()[0].target, "b.com:443");
+        drop(second);
+        assert!(registry.active().is_empty());
+    }
+
+    #[test]
+    fn test_revoke_where() {
+        let registry = TunnelRegistry::new();
+        let peer = "127.0.0.1:4000".parse().unwrap();
+        let first = registry.register(peer, "a.com:443");
+        let second = registry.register(peer, "b.com:443");
+
+        assert_eq!(registry.revoke_where(|t| t.target.starts_with("a.com:")), 1);
+        assert!(first.revoked().is_cancelled());
+        assert!(!second.revoked().is_cancelled());
+        // already revoked
+        assert_eq!(registry.revoke_where(|_| true), 1);
+        assert!(second.revoked().is_cancelled());
+        assert_eq!(registry.active().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_acquire_host() {
+        let registry = TunnelRegistry::new();
+        let first = registry.acquire_host("a.com:443", 1, Duration::ZERO).await;
+        assert!(first.is_some());
+        assert!(registry.acquire_host("a.com:443", 1, Duration::ZERO).await.is_none());
+        assert!(registry.acquire_host("a.com:443", 1, Duration::from_millis(20)).await.is_none());
+        let other = registry.acquire_host("b.com:443", 1, Duration::ZERO).await;
+        assert!(other.is_some());
+
+        // waiting for the first one to be released
+        let registry_ = registry.clone();
+        let waiting = tokio::spawn(async move {
+            registry_.acquire_host("a.com:443", 1, Duration::from_secs(5)).await.is_some()
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(first);
+        assert!(waiting.await.unwrap());
+
+        drop(other);
+        assert!(registry.hosts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_connection_counters() {
+        let registry = TunnelRegistry::new();
+        let first = registry.connection();
+        let second = registry.clone().connection();
+        assert_eq!(registry.active_connections(), 2);
+        assert_eq!(registry.total_connections(), 2);
+
+        drop(first);
+        assert_eq!(registry.active_connections(), 1);
+        drop(registry.connection());
+        drop(second);
+        assert_eq!(registry.active_connections(), 0);
+        assert_eq!(registry.total_connections(), 3);
+    }
+
+    #[test]
+    fn test_summary() {
+        let registry = TunnelRegistry::new();
+        assert_eq!(registry.summary(), "0 connection(s), 0 tunnel(s), 0 bytes relayed, average relay duration: 0ns, \
+                                        results: none");
+        drop(registry.connection());
+        drop(registry.connection());
+        registry.record_tunnel(100, Duration::from_secs(1));
+        registry.clone().record_tunnel(50, Duration::from_secs(2));
+        registry.record_result("rejected (Paused)");
+        assert_eq!(registry.summary(), "2 connection(s), 2 tunnel(s), 150 bytes relayed, average relay duration: 1.5s, \
+                                        results: rejected (Paused): 1, relayed: 2");
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let registry = TunnelRegistry::new().with_max_connections(1);
+        let first = registry.try_connection();
+        assert!(first.is_some());
+        assert!(registry.clone().try_connection().is_none());
+        // counted only
+        let rejected = registry.connection();
+        assert_eq!(registry.active_connections(), 2);
+        drop(rejected);
+
+        // waiting for the first one to be closed
+        let registry_ = registry.clone();
+        let waiting = tokio::spawn(async move { registry_.queued_connection().await });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+        drop(first);
+        let queued = waiting.await.unwrap();
+        assert!(registry.try_connection().is_none());
+        drop(queued);
+        assert!(registry.try_connection().is_some());
+        assert_eq!(registry.total_connections(), 4);
+
+        // unlimited
+        let registry = TunnelRegistry::new().with_max_connections(0);
+        let guards: Vec<_> = (0..3).map(|_| registry.try_connection()).collect();
+        assert!(guards.iter().all(|guard| guard.is_some()));
+    }
+}
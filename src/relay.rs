@@ -0,0 +1,515 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{Duration, Instant};
+use tokio_rustls::rustls;
+use tokio_util::sync::CancellationToken;
+// traits
+use tokio::io::{AsyncReadExt, AsyncWriteExt}; // for read() / write_all()
+
+// Relay data between proxy client and upstream (one direction per copy)
+// Like tokio::io::copy but with stats
+
+pub const RELAY_BUFFER_SIZE: usize = 8 * 1024;
+// adaptive buffer: consecutive reads filling the whole buffer before it doubles
+const BUFFER_GROW_READS: u32 = 2;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DirectionStats {
+    // bytes relayed
+    pub bytes: u64,
+    // largest chunk read then written at once (at most the relay buffer size)
+    pub peak_chunk: usize,
+    // why the copy failed (None: EOF)
+    pub error: Option<RelayErrorKind>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RelayStats {
+    pub client_to_upstream: DirectionStats,
+    pub upstream_to_client: DirectionStats,
+    // time spent resolving the target, then connecting to upstream
+    pub resolve_duration: Duration,
+    pub connect_duration: Duration,
+    // upstream connection local & peer addresses, to correlate with upstream side logs (None: not connected)
+    pub upstream_local: Option<SocketAddr>,
+    pub upstream_peer: Option<SocketAddr>,
+    // correlation id, from the client (X-Request-ID) or generated
+    pub request_id: String,
+}
+
+// Why a relay was stopped before EOF
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    // byte quota reached
+    Quota,
+    // no data in either direction for the idle timeout
+    Idle,
+    // upstream closed without sending anything, the client side is not worth waiting for
+    Closed,
+    // one direction reached EOF, the other one did not finish within the linger window
+    Linger,
+    // the tunnel max lifetime elapsed
+    Lifetime,
+    // the tunnel was revoked (e.g. its target is no longer allowed)
+    Revoked,
+}
+
+// Limits shared by the copy of each direction: max bytes (both directions combined), idle timeout, linger & lifetime
+// Once a limit is reached (or stop is called), both copies stop, even if blocked on a read
+#[derive(Debug)]
+pub struct RelayLimits {
+    // 0: unlimited
+    max_bytes: u64,
+    // bytes relayed so far (both directions)
+    used: AtomicU64,
+    // zero: no idle timeout
+    idle_timeout: Duration,
+    // tcp keepalive probes on both sockets: an idle relay is kept (a dead peer fails the relay instead)
+    kept_alive: bool,
+    start: Instant,
+    // since start
+    last_activity_ms: AtomicU64,
+    // zero: no limit, once a direction reached EOF the other one can take forever
+    linger: Duration,
+    // cancelled on the first EOF (either direction)
+    first_eof: CancellationToken,
+    // zero: no limit, since start
+    lifetime: Duration,
+    stopped: CancellationToken,
+    reason: OnceLock<StopReason>,
+}
+
+impl Default for RelayLimits {
+    fn default() -> Self {
+        Self::new(0, Duration::ZERO, Duration::ZERO)
+    }
+}
+
+impl RelayLimits {
+    pub fn new(max_bytes: u64, idle_timeout: Duration, linger: Duration) -> Self {
+        Self {
+            max_bytes,
+            used: AtomicU64::new(0),
+            idle_timeout,
+            kept_alive: false,
+            start: Instant::now(),
+            last_activity_ms: AtomicU64::new(0),
+            linger,
+            first_eof: CancellationToken::new(),
+            lifetime: Duration::ZERO,
+            stopped: CancellationToken::new(),
+            reason: OnceLock::new(),
+        }
+    }
+
+    pub fn with_lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    pub fn with_keepalive(mut self, kept_alive: bool) -> Self {
+        self.kept_alive = kept_alive;
+        self
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    pub fn linger(&self) -> Duration {
+        self.linger
+    }
+
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    // Bytes relayed so far, both directions combined
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn stop(&self, reason: StopReason) {
+        // Note: first reason wins
+        let _ = self.reason.set(reason);
+        self.stopped.cancel();
+    }
+
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        self.reason.get().copied()
+    }
+
+    // Account for n bytes, return how many of them can be relayed
+    fn consume(&self, n: usize) -> usize {
+        self.last_activity_ms.store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+        let before = self.used.fetch_add(n as u64, Ordering::Relaxed);
+        if self.max_bytes == 0 {
+            return n;
+        }
+        if before + n as u64 >= self.max_bytes {
+            self.stop(StopReason::Quota);
+        }
+        self.max_bytes.saturating_sub(before).min(n as u64) as usize
+    }
+
+    // Stop the relay once idle for the idle timeout (return when stopped, never if there is no idle timeout
+    // or the relay is kept alive)
+    pub async fn watch_idle(&self) {
+        if self.idle_timeout.is_zero() || self.kept_alive {
+            return std::future::pending().await;
+        }
+        loop {
+            let last_activity = self.start + Duration::from_millis(self.last_activity_ms.load(Ordering::Relaxed));
+            let deadline = last_activity + self.idle_timeout;
+            if Instant::now() >= deadline {
+                self.stop(StopReason::Idle);
+                return;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep_until(deadline) => {},
+                _ = self.stopped.cancelled() => return,
+            }
+        }
+    }
+
+    // Stop the relay once a direction reached EOF and the linger window elapsed
+    // (return when stopped, never if there is no linger limit)
+    pub async fn watch_linger(&self) {
+        if self.linger.is_zero() {
+            return std::future::pending().await;
+        }
+        tokio::select! {
+            _ = self.first_eof.cancelled() => {},
+            _ = self.stopped.cancelled() => return,
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(self.linger) => self.stop(StopReason::Linger),
+            _ = self.stopped.cancelled() => {},
+        }
+    }
+
+    // Stop the relay once its lifetime elapsed (return when stopped, never if there is no lifetime limit)
+    pub async fn watch_lifetime(&self) {
+        if self.lifetime.is_zero() {
+            return std::future::pending().await;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep_until(self.start + self.lifetime) => self.stop(StopReason::Lifetime),
+            _ = self.stopped.cancelled() => {},
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("relay stopped: {0:?}")]
+struct Stopped(StopReason);
+
+fn stopped(limits: &RelayLimits) -> std::io::Error {
+    std::io::Error::other(Stopped(limits.stop_reason().unwrap_or(StopReason::Closed)))
+}
+
+// Why a relay copy failed (for logging)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelayErrorKind {
+    // e.g. malformed record, renegotiation attempt (the tls session is unusable)
+    Tls,
+    // e.g. connection reset, broken pipe
+    Transport,
+    // stopped (see StopReason)
+    Quota,
+    Idle,
+    Closed,
+    Linger,
+    Lifetime,
+    Revoked,
+}
+
+impl std::fmt::Display for RelayErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RelayErrorKind::Tls => "TLS protocol error",
+            RelayErrorKind::Transport => "transport error",
+            RelayErrorKind::Quota => "quota exceeded",
+            RelayErrorKind::Idle => "idle timeout",
+            RelayErrorKind::Closed => "closed",
+            RelayErrorKind::Linger => "linger timeout",
+            RelayErrorKind::Lifetime => "max lifetime reached",
+            RelayErrorKind::Revoked => "revoked",
+        })
+    }
+}
+
+// Note: tokio-rustls reports tls errors as io errors (InvalidData) wrapping the rustls error
+pub fn classify_error(e: &std::io::Error) -> RelayErrorKind {
+    match e.get_ref() {
+        Some(inner) if inner.is::<rustls::Error>() => RelayErrorKind::Tls,
+        Some(inner) => match inner.downcast_ref::<Stopped>() {
+            Some(Stopped(StopReason::Quota)) => RelayErrorKind::Quota,
+            Some(Stopped(StopReason::Idle)) => RelayErrorKind::Idle,
+            Some(Stopped(StopReason::Closed)) => RelayErrorKind::Closed,
+            Some(Stopped(StopReason::Linger)) => RelayErrorKind::Linger,
+            Some(Stopped(StopReason::Lifetime)) => RelayErrorKind::Lifetime,
+            Some(Stopped(StopReason::Revoked)) => RelayErrorKind::Revoked,
+            None => RelayErrorKind::Transport,
+        },
+        None => RelayErrorKind::Transport,
+    }
+}
+
+// Copy from reader to writer until EOF
+// The buffer starts at min_buffer_size and doubles (up to max_buffer_size) on sustained throughput,
+// i.e. when reads keep filling the whole buffer (same min & max: fixed size buffer)
+// Note: stats are updated as data flows so they are still meaningful on error
+pub async fn copy<R, W>(reader: &mut R, writer: &mut W, min_buffer_size: usize, max_buffer_size: usize,
+                        stats: &mut DirectionStats, limits: &RelayLimits)
+    -> std::io::Result<u64>
+    where R: AsyncRead + Unpin + ?Sized,
+          W: AsyncWrite + Unpin + ?Sized
+{
+    let mut buffer = vec![0u8; min_buffer_size.min(max_buffer_size)];
+    let mut full_reads = 0;
+    loop {
+        let n = tokio::select! {
+            n = reader.read(&mut buffer) => n?,
+            _ = limits.stopped.cancelled() => return Err(stopped(limits)),
+        };
+        if n == 0 {
+            limits.first_eof.cancel();
+            return Ok(stats.bytes);
+        }
+        let allowed = limits.consume(n);
+        writer.write_all(&buffer[..allowed]).await?;
+        // Note: required for buffered writers (e.g. tls streams)
+        writer.flush().await?;
+
+        stats.bytes += allowed as u64;
+        stats.peak_chunk = stats.peak_chunk.max(allowed);
+        if allowed < n {
+            return Err(stopped(limits));
+        }
+
+        if n < buffer.len() {
+            full_reads = 0;
+        } else if buffer.len() < max_buffer_size {
+            full_reads += 1;
+            if full_reads >= BUFFER_GROW_READS {
+                buffer.resize((buffer.len() * 2).min(max_buffer_size), 0);
+                full_reads = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{classify_error, copy, DirectionStats, RelayErrorKind, RelayLimits, StopReason, RELAY_BUFFER_SIZE};
+
+    // traits
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_copy_peak_chunk() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let (mut client, mut reader) = tokio::io::duplex(64 * 1024);
+        let (mut writer, mut upstream) = tokio::io::duplex(64 * 1024);
+
+        let relay = tokio::spawn(async move {
+            let mut stats = DirectionStats::default();
+            copy(&mut reader, &mut writer, RELAY_BUFFER_SIZE, RELAY_BUFFER_SIZE, &mut stats, &RelayLimits::default()).await.map(|_| stats)
+        });
+
+        // one chunk at a time: wait for each chunk to be relayed before sending the next one
+        let chunks = [10, 3000, 700, 4096, 1];
+        for size in chunks {
+            client.write_all(&vec![42u8; size]).await?;
+            let mut received = vec![0u8; size];
+            upstream.read_exact(&mut received).await?;
+        }
+        drop(client);
+
+        let stats = relay.await??;
+        assert_eq!(stats.bytes, chunks.iter().sum::<usize>() as u64);
+        assert_eq!(stats.peak_chunk, 4096);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_copy_peak_chunk_bounded_by_buffer() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let (mut client, mut reader) = tokio::io::duplex(64 * 1024);
+        let (mut writer, mut upstream) = tokio::io::duplex(64 * 1024);
+
+        client.write_all(&[1u8; 5000]).await?;
+        drop(client);
+
+        let mut stats = DirectionStats::default();
+        copy(&mut reader, &mut writer, 1024, 1024, &mut stats, &RelayLimits::default()).await?;
+        drop(writer);
+
+        let mut received = Vec::new();
+        upstream.read_to_end(&mut received).await?;
+        assert_eq!(received.len(), 5000);
+        assert_eq!(stats, DirectionStats { bytes: 5000, peak_chunk: 1024, error: None });
+        Ok(())
+    }
+
+    // Record the size of each write
+    #[derive(Default)]
+    struct ChunkRecorder {
+        chunks: Vec<usize>,
+    }
+
+    impl tokio::io::AsyncWrite for ChunkRecorder {
+        fn poll_write(mut self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>, buf: &[u8])
+            -> std::task::Poll<std::io::Result<usize>>
+        {
+            self.chunks.push(buf.len());
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_copy_adaptive_buffer() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // large payload, already available: every read fills the buffer
+        let payload = vec![3u8; 512 * 1024];
+        let mut reader = &payload[..];
+        let mut writer = ChunkRecorder::default();
+        let mut stats = DirectionStats::default();
+        copy(&mut reader, &mut writer, 1024, 16384, &mut stats, &RelayLimits::default()).await?;
+        assert_eq!(stats.bytes, payload.len() as u64);
+        assert_eq!(stats.peak_chunk, 16384);
+
+        // the buffer grew from the min to the max then stayed there (the last chunk is the payload tail)
+        let chunks = &writer.chunks[..writer.chunks.len() - 1];
+        assert_eq!(&chunks[..2], &[1024, 1024]);
+        let mut sizes = chunks.to_vec();
+        sizes.dedup();
+        assert_eq!(sizes, vec![1024, 2048, 4096, 8192, 16384]);
+
+        // small chunks (never filling the buffer): no growth
+        let (mut client, mut reader) = tokio::io::duplex(64 * 1024);
+        let relay = tokio::spawn(async move {
+            let mut writer = ChunkRecorder::default();
+            let mut stats = DirectionStats::default();
+            copy(&mut reader, &mut writer, 1024, 16384, &mut stats, &RelayLimits::default()).await
+                .map(|_| writer.chunks)
+        });
+        for _ in 0..10 {
+            client.write_all(&[4u8; 1000]).await?;
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        drop(client);
+        let chunks = relay.await??;
+        assert!(chunks.iter().all(|c| *c <= 1024), "{:?}", chunks);
+        assert_eq!(chunks.iter().sum::<usize>(), 10_000);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_copy_quota() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let (mut client, mut reader) = tokio::io::duplex(64 * 1024);
+        let (mut writer, mut upstream) = tokio::io::duplex(64 * 1024);
+
+        let payload = vec![7u8; 20_000];
+        client.write_all(&payload).await?;
+
+        let limits = RelayLimits::new(3000, Duration::ZERO, Duration::ZERO);
+        let mut stats = DirectionStats::default();
+        let copied = copy(&mut reader, &mut writer, 1024, 1024, &mut stats, &limits).await;
+        assert_eq!(classify_error(&copied.unwrap_err()), RelayErrorKind::Quota);
+        assert_eq!(limits.stop_reason(), Some(StopReason::Quota));
+        drop(writer);
+
+        let mut received = Vec::new();
+        upstream.read_to_end(&mut received).await?;
+        assert_eq!(received.len(), 3000);
+        assert_eq!(stats.bytes, 3000);
+
+        // the other direction stops too (even while waiting for data)
+        let (_client2, mut reader2) = tokio::io::duplex(1024);
+        let mut stats = DirectionStats::default();
+        assert!(copy(&mut reader2, &mut tokio::io::sink(), 1024, 1024, &mut stats, &limits).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watch_idle() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let (mut client, mut reader) = tokio::io::duplex(1024);
+        let limits = RelayLimits::new(0, Duration::from_millis(100), Duration::ZERO);
+
+        let start = Instant::now();
+        let relay = async {
+            let mut stats = DirectionStats::default();
+            copy(&mut reader, &mut tokio::io::sink(), 1024, 1024, &mut stats, &limits).await
+        };
+        let activity = async {
+            // activity postpones the idle timeout
+            for _ in 0..3 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                client.write_all(b"ping").await.unwrap();
+            }
+        };
+        let (copied, _, _) = tokio::join!(relay, activity, limits.watch_idle());
+        assert_eq!(classify_error(&copied.unwrap_err()), RelayErrorKind::Idle);
+        assert!(start.elapsed() >= Duration::from_millis(250), "{:?}", start.elapsed());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watch_idle_kept_alive() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let limits = RelayLimits::new(0, Duration::from_millis(50), Duration::ZERO).with_keepalive(true);
+        // idle for longer than the idle timeout: not stopped
+        assert!(tokio::time::timeout(Duration::from_millis(200), limits.watch_idle()).await.is_err());
+        assert_eq!(limits.stop_reason(), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watch_linger() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let limits = RelayLimits::new(0, Duration::ZERO, Duration::from_millis(100));
+        // one side is done (EOF), the other one stalls
+        let (client, mut client_reader) = tokio::io::duplex(1024);
+        drop(client);
+        let (_upstream, mut upstream_reader) = tokio::io::duplex(1024);
+
+        let start = Instant::now();
+        let done = async {
+            let mut stats = DirectionStats::default();
+            copy(&mut client_reader, &mut tokio::io::sink(), 1024, 1024, &mut stats, &limits).await
+        };
+        let stalled = async {
+            let mut stats = DirectionStats::default();
+            copy(&mut upstream_reader, &mut tokio::io::sink(), 1024, 1024, &mut stats, &limits).await
+        };
+        let (done, stalled, _) = tokio::join!(done, stalled, limits.watch_linger());
+        assert_eq!(done?, 0);
+        assert_eq!(classify_error(&stalled.unwrap_err()), RelayErrorKind::Linger);
+        assert_eq!(limits.stop_reason(), Some(StopReason::Linger));
+        assert!(start.elapsed() >= Duration::from_millis(100), "{:?}", start.elapsed());
+        Ok(())
+    }
+
+    #[test]
+    fn test_classify_error() {
+        let tls_error = std::io::Error::new(std::io::ErrorKind::InvalidData, tokio_rustls::rustls::Error::DecryptError);
+        assert_eq!(classify_error(&tls_error), RelayErrorKind::Tls);
+        assert_eq!(classify_error(&std::io::Error::from(std::io::ErrorKind::ConnectionReset)), RelayErrorKind::Transport);
+        let other = std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid data");
+        assert_eq!(classify_error(&other), RelayErrorKind::Transport);
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+
+use log::info;
+
+// Target rewrite table (DNAT like)
+// Transparently redirect a CONNECT target (host:port) to another destination (host:port)
+// e.g. "api.example.com:443" -> "10.0.0.5:8443"
+// Note: applied before dns resolution, so unlike a resolver override the port can change too
+// A destination can also have a fallback: tried if none of the destination addresses can be connected to
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RewriteTable {
+    rules: HashMap<String, String>,
+    // destination (after rewrite) -> fallback destination
+    fallbacks: HashMap<String, String>,
+}
+
+impl RewriteTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_rule(&mut self, from: &str, to: &str) {
+        self.rules.insert(from.to_string(), to.to_string());
+    }
+
+    // Parse a rule like: "from_host:port=to_host:port"
+    pub fn add_rule_str(&mut self, rule: &str) -> Option<()> {
+        let (from, to) = rule.split_once('=')?;
+        if from.is_empty() || to.is_empty() {
+            return None;
+        }
+        self.add_rule(from, to);
+        Some(())
+    }
+
+    pub fn add_fallback(&mut self, destination: &str, fallback: &str) {
+        self.fallbacks.insert(destination.to_string(), fallback.to_string());
+    }
+
+    // Parse a fallback like: "host:port=fallback_host:port"
+    pub fn add_fallback_str(&mut self, fallback: &str) -> Option<()> {
+        let (destination, fallback) = fallback.split_once('=')?;
+        if destination.is_empty() || fallback.is_empty() {
+            return None;
+        }
+        self.add_fallback(destination, fallback);
+        Some(())
+    }
+
+    // Fallback for a destination (after rewrite), if any
+    pub fn fallback(&self, destination: &str) -> Option<&str> {
+        self.fallbacks.get(destination).map(|fallback| fallback.as_str())
+    }
+
+    // Return the destination for target (target itself if no rule matches)
+    pub fn rewrite<'a>(&'a self, target: &'a str) -> &'a str {
+        match self.rules.get(target) {
+            Some(destination) => {
+                info!("Rewrite target {} -> {}", target, destination);
+                destination
+            },
+            None => target,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::RewriteTable;
+    use crate::logger::capture;
+
+    #[test]
+    fn test_rewrite_match() {
+        let mut table = RewriteTable::new();
+        assert!(table.add_rule_str("api.example.com:443=10.0.0.5:8443").is_some());
+        assert_eq!(table.rewrite("api.example.com:443"), "10.0.0.5:8443");
+    }
+
+    #[test]
+    fn test_rewrite_passthrough() {
+        let mut table = RewriteTable::new();
+        table.add_rule("api.example.com:443", "10.0.0.5:8443");
+        assert_eq!(table.rewrite("api.example.com:80"), "api.example.com:80");
+        assert_eq!(table.rewrite("www.example.com:443"), "www.example.com:443");
+        assert!(table.add_rule_str("api.example.com:443").is_none());
+        assert!(table.add_rule_str("=10.0.0.5:8443").is_none());
+    }
+
+    #[test]
+    fn test_fallback() {
+        let mut table = RewriteTable::new();
+        table.add_rule("api.example.com:443", "10.0.0.5:8443");
+        assert!(table.add_fallback_str("10.0.0.5:8443=10.0.0.6:8443").is_some());
+        // by destination, after rewrite
+        let destination = table.rewrite("api.example.com:443");
+        assert_eq!(table.fallback(destination), Some("10.0.0.6:8443"));
+        assert_eq!(table.fallback("api.example.com:443"), None);
+        assert!(table.add_fallback_str("10.0.0.5:8443=").is_none());
+    }
+
+    #[test]
+    fn test_rewrite_log() {
+        capture::init();
+        let mut table = RewriteTable::new();
+        table.add_rule("log.example.com:443", "10.0.0.6:8443");
+
+        table.rewrite("log.example.com:443");
+        assert_eq!(capture::find("log.example.com"), vec!["Rewrite target log.example.com:443 -> 10.0.0.6:8443"]);
+    }
+}
@@ -0,0 +1,3789 @@
+use core::fmt::Debug;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::future::Future;
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::{self, Runtime};
+use tokio::signal;
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Instant};
+// Tls
+use tokio_rustls::rustls::{ServerConfig, ServerName};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::codec::FramedRead;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::AbortOnDropHandle;
+
+// traits
+// use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::AsyncWriteExt; // for write_all_buf()
+use tokio_util::codec::Encoder; // for encode()
+use futures::{FutureExt, StreamExt}; // for left_future() & next()
+use log::{info, warn};
+
+use crate::codec::{ConnectTarget, DecodeError, HttpCodec, HttpRequest, OptionsResponse, TunnelResult};
+use crate::connector::{Connector, TcpConnector};
+use crate::policy::{AllowAll, ConnectHook, PolicyChain, TargetDenylist, TunnelPolicy};
+use crate::config::{Config, Overload, RuntimeFlavor};
+use crate::events::{RejectReason, TunnelEvent};
+use crate::{filter, listener, proxy_protocol};
+use crate::mirror::{self, Mirror, MirrorDirection};
+use crate::registry::{ConnectionGuard, TunnelRegistry};
+use crate::relay::{self, DirectionStats, RelayErrorKind, RelayLimits, RelayStats, StopReason};
+use crate::tap::Tap;
+use crate::telemetry::Span;
+use crate::tls::{load_server_config, UpstreamTls};
+
+use crate::dns::{CachingResolver, ConfigurableResolver, DnsResolver, HostsFileResolver, PinningResolver, RetryingResolver, SimpleDnsResolver};
+use crate::dns::ValidatingResolver;
+use crate::dns::{ResolutionStats, TimedResolver};
+use crate::dns::SuffixResolver;
+use crate::dns::BudgetedResolver;
+
+// Easy error handling with async code
+type AResult<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+// Upstream halves, either a plain tcp stream or a tls one
+type UpstreamReader = Box<dyn AsyncRead + Send + Unpin>;
+type UpstreamWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+const PROXY_INITIAL_RESPONSE_SIZE: usize = 64;
+const PROXY_PROTOCOL_HEADER_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_millis(1000);
+const DNS_RETRY_BACKOFF: tokio::time::Duration = tokio::time::Duration::from_millis(100);
+// see --dns-budget (queries per minute)
+const DNS_BUDGET_WINDOW: tokio::time::Duration = tokio::time::Duration::from_secs(60);
+const DRAIN_PROGRESS_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_secs(5);
+// client requested tunnel lifetime (milliseconds), capped by config.max_tunnel_lifetime
+const DEADLINE_HEADER: &str = "X-Tunnel-Deadline-Ms";
+// client provided correlation id (logged, in the tunnel span & stats)
+const REQUEST_ID_HEADER: &str = "X-Request-ID";
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// write a response to proxy client
+async fn write_response<W>(writer: &mut W, result: TunnelResult, config: &Config) -> AResult<()>
+    where W: AsyncWrite + Unpin
+{
+    write_status(writer, result, result, config).await
+}
+
+// write a response with a short text body (e.g. why the request was rejected) to proxy client
+async fn write_response_body<W>(writer: &mut W, result: TunnelResult, body: &str, config: &Config) -> AResult<()>
+    where W: AsyncWrite + Unpin
+{
+    write_status(writer, result, (result, body), config).await
+}
+
+// Error responses are written within the error response timeout: a client not reading them cannot hold
+// the connection (the caller closes it on error)
+async fn write_status<W, I>(writer: &mut W, result: TunnelResult, item: I, config: &Config) -> AResult<()>
+    where W: AsyncWrite + Unpin,
+          HttpCodec: Encoder<I, Error = std::io::Error>
+{
+    if result == TunnelResult::Ok || config.error_response_timeout.is_zero() {
+        return write_encoded(writer, item, config).await;
+    }
+    match timeout(config.error_response_timeout, write_encoded(writer, item, config)).await {
+        Ok(written) => written,
+        Err(_) => {
+            warn!("Timeout sending a {} response after {:?}, closing", result.status().0, config.error_response_timeout);
+            Err(format!("Timeout sending a {} response", result.status().0).into())
+        },
+    }
+}
+
+async fn write_encoded<W, I>(writer: &mut W, item: I, config: &Config) -> AResult<()>
+    where W: AsyncWrite + Unpin,
+          HttpCodec: Encoder<I, Error = std::io::Error>
+{
+    let mut codec = HttpCodec { proxy_agent: config.proxy_agent.clone(), ..Default::default() };
+    let mut response_buffer = bytes::BytesMut::with_capacity(PROXY_INITIAL_RESPONSE_SIZE);
+
+    // Note: no need to use FrameWrite here
+    codec.encode(item, &mut response_buffer)?;
+    // Note: write_buf may write only a part of the buffer
+    writer.write_all_buf(&mut response_buffer).await?;
+    // Send it now, the client waits for it before sending any data
+    writer.flush().await?;
+    Ok(())
+}
+
+// Move the address picked for client first (the others keep their order, e.g. if it is unreachable)
+// Note: picked from the sorted addresses, a resolver returning them in another order (e.g. round robin dns)
+// does not change the pick
+fn sticky_order(addrs: &mut [SocketAddr], client: IpAddr) {
+    if addrs.len() < 2 {
+        return;
+    }
+    let mut sorted = addrs.to_vec();
+    sorted.sort();
+    let mut hasher = DefaultHasher::new();
+    client.hash(&mut hasher);
+    let picked = sorted[(hasher.finish() % sorted.len() as u64) as usize];
+    if let Some(index) = addrs.iter().position(|addr| *addr == picked) {
+        addrs[..=index].rotate_right(1);
+    }
+}
+
+// Await f until the establishment deadline (None: no deadline)
+async fn before_deadline<F: Future>(deadline: Option<Instant>, f: F) -> Result<F::Output, tokio::time::error::Elapsed> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, f).await,
+        None => Ok(f.await),
+    }
+}
+
+// Establishment deadline reached while step: answer with result (e.g. 504), return the error closing the connection
+async fn establish_timeout<W>(writer: &mut W, result: TunnelResult, step: &str, config: &Config)
+    -> Box<dyn std::error::Error + Send + Sync>
+    where W: AsyncWrite + Unpin
+{
+    if let Err(e) = write_response(writer, result, config).await {
+        return e;
+    }
+    format!("Establishment timeout ({:?}) while {}", config.establish_timeout, step).into()
+}
+
+// Close the client connection after an error response (early return): shut its writer down (FIN or tls
+// close_notify) & drop its reader, instead of relying on both halves being dropped at some point
+async fn close_client<R, W>(reader: R, mut writer: W)
+    where W: AsyncWrite + Unpin
+{
+    let _ = writer.shutdown().await;
+    drop(reader);
+}
+
+// TCP keepalive probes on a client or upstream socket (if config.relay_keepalive is set)
+fn set_keepalive(socket: &TcpStream, config: &Config) -> std::io::Result<()> {
+    if config.relay_keepalive.is_zero() {
+        return Ok(());
+    }
+    let keepalive = socket2::TcpKeepalive::new().with_time(config.relay_keepalive);
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd", windows))]
+    let keepalive = keepalive.with_interval(config.relay_keepalive);
+    socket2::SockRef::from(socket).set_tcp_keepalive(&keepalive)
+}
+
+// TCP_USER_TIMEOUT on a client or upstream socket (if config.tcp_user_timeout is set), linux only
+fn set_user_timeout(socket: &TcpStream, config: &Config) -> std::io::Result<()> {
+    if config.tcp_user_timeout.is_zero() {
+        return Ok(());
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    socket2::SockRef::from(socket).set_tcp_user_timeout(Some(config.tcp_user_timeout))?;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = socket;
+    Ok(())
+}
+
+// SO_RCVBUF / SO_SNDBUF on a client or upstream socket (if configured)
+fn set_buffer_sizes(socket: &TcpStream, config: &Config) -> std::io::Result<()> {
+    let socket = socket2::SockRef::from(socket);
+    if let Some(size) = config.socket_recv_buffer {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = config.socket_send_buffer {
+        socket.set_send_buffer_size(size)?;
+    }
+    Ok(())
+}
+
+// Queue to the audit mirror sink (if configured), None if the sink cannot be opened: the tunnel is not mirrored
+async fn open_mirror(config: &Config, request_id: &str) -> Option<tokio::sync::mpsc::Sender<bytes::Bytes>> {
+    let sink = config.mirror_sink.as_ref()?;
+    let name = match config.mirror_direction {
+        MirrorDirection::Up => format!("{}.up", request_id),
+        MirrorDirection::Down => format!("{}.down", request_id),
+    };
+    match timeout(config.connect_timeout, mirror::spawn_sink(sink, &name, config.mirror_queue)).await {
+        Ok(Ok(sender)) => Some(sender),
+        Ok(Err(e)) => {
+            warn!("Cannot open mirror sink {:?}: {}", sink, e);
+            None
+        },
+        Err(_) => {
+            warn!("Mirror sink {:?} timeout ({:?})", sink, config.connect_timeout);
+            None
+        },
+    }
+}
+
+// Connect to the first reachable address (in order), at most max_addrs are tried, each within the connect timeout
+// Return the stream & its address or the response for the last failure
+async fn connect_any(connector: &(dyn Connector + Send + Sync), addrs: &[SocketAddr], max_addrs: usize,
+                     connect_timeout: tokio::time::Duration)
+    -> Result<(TcpStream, SocketAddr), TunnelResult>
+{
+    let mut connected = Err(TunnelResult::BadGateway);
+    for addr in addrs.iter().take(max_addrs) {
+        match timeout(connect_timeout, connector.connect(*addr)).await {
+            Ok(Ok(stream)) => return Ok((stream, *addr)),
+            Ok(Err(e)) => {
+                warn!("Could not connect to {}: {}", addr, e);
+                connected = Err(TunnelResult::BadGateway);
+            },
+            Err(e) => {
+                warn!("Timeout while trying to connect to {}: {}", addr, e);
+                connected = Err(TunnelResult::BadRequest);
+            },
+        }
+    }
+    if addrs.len() > max_addrs {
+        info!("Tried {} of {} addresses ({:?}...)", max_addrs, addrs.len(), addrs.first());
+    }
+    connected
+}
+
+// Alternate address families (RFC 8305 section 4), starting with the family of the first address
+// e.g. [v6a, v6b, v4a] -> [v6a, v4a, v6b]
+fn interleave_families(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let first_is_ipv6 = addrs.first().is_some_and(|addr| addr.is_ipv6());
+    let (mut first, mut second): (std::collections::VecDeque<_>, std::collections::VecDeque<_>) = addrs.iter()
+        .partition(|addr| addr.is_ipv6() == first_is_ipv6);
+    let mut interleaved = Vec::with_capacity(addrs.len());
+    while let Some(addr) = first.pop_front() {
+        interleaved.push(addr);
+        interleaved.extend(second.pop_front());
+    }
+    interleaved.extend(second);
+    interleaved
+}
+
+// Happy eyeballs: like connect_any but the next attempt starts after delay (or as soon as the last started one
+// failed) without waiting for the others, the first connected stream wins
+// Note: the other attempts are cancelled (dropped, closing their socket) once a stream is connected
+async fn connect_parallel(connector: &(dyn Connector + Send + Sync), addrs: &[SocketAddr], max_addrs: usize,
+                          connect_timeout: tokio::time::Duration, delay: tokio::time::Duration)
+    -> Result<(TcpStream, SocketAddr), TunnelResult>
+{
+    let mut pending = interleave_families(&addrs[..addrs.len().min(max_addrs)]).into_iter();
+    let attempt = |addr: SocketAddr| async move { (addr, timeout(connect_timeout, connector.connect(addr)).await) };
+    let mut attempts = futures::stream::FuturesUnordered::new();
+    let mut connected = Err(TunnelResult::BadGateway);
+    let mut next_start = Instant::now();
+    loop {
+        let has_pending = pending.len() > 0;
+        if attempts.is_empty() && !has_pending {
+            break;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep_until(next_start), if has_pending => {
+                attempts.extend(pending.next().map(attempt));
+                next_start = Instant::now() + delay;
+            },
+            Some((addr, result)) = attempts.next() => match result {
+                Ok(Ok(stream)) => return Ok((stream, addr)),
+                Ok(Err(e)) => {
+                    warn!("Could not connect to {}: {}", addr, e);
+                    connected = Err(TunnelResult::BadGateway);
+                    next_start = Instant::now();
+                },
+                Err(e) => {
+                    warn!("Timeout while trying to connect to {}: {}", addr, e);
+                    connected = Err(TunnelResult::BadRequest);
+                    next_start = Instant::now();
+                },
+            },
+        }
+    }
+    if addrs.len() > max_addrs {
+        info!("Tried {} of {} addresses ({:?}...)", max_addrs, addrs.len(), addrs.first());
+    }
+    connected
+}
+
+// Sequential or parallel (happy eyeballs) connection attempts, see Config::happy_eyeballs_delay
+async fn connect_addrs(connector: &(dyn Connector + Send + Sync), addrs: &[SocketAddr], config: &Config)
+    -> Result<(TcpStream, SocketAddr), TunnelResult>
+{
+    if config.happy_eyeballs_delay.is_zero() {
+        connect_any(connector, addrs, config.max_connect_addrs, config.connect_timeout).await
+    } else {
+        connect_parallel(connector, addrs, config.max_connect_addrs, config.connect_timeout, config.happy_eyeballs_delay)
+            .await
+    }
+}
+
+// Per tunnel options (from the request)
+#[derive(Default)]
+struct TunnelOptions {
+    // re-originate tls to upstream
+    upstream_tls: Option<(UpstreamTls, ServerName)>,
+    // relay max lifetime (zero: unlimited)
+    lifetime: tokio::time::Duration,
+    // addresses tried if no target address can be connected to
+    fallback: Vec<SocketAddr>,
+    // cancelled once the tunnel is revoked (see TunnelRegistry::revoke_where)
+    revoked: CancellationToken,
+    // client data read along with the request (after the request head), written to upstream first
+    early_data: Vec<u8>,
+    // forwarded request (the request itself is in early_data): no 200 response, upstream answers
+    forward: bool,
+    // the relay must start (200 sent) before (see Config::establish_timeout)
+    deadline: Option<Instant>,
+    // audit mirror file name (see Config::mirror_sink)
+    request_id: String,
+}
+
+// Lowercase the host of target (host:port), the port is kept as is
+fn lowercase_host(target: &str) -> String {
+    match target.rsplit_once(':') {
+        Some((host, port)) => format!("{}:{}", host.to_ascii_lowercase(), port),
+        None => target.to_ascii_lowercase(),
+    }
+}
+
+// Client deadline (if any) capped by the configured max lifetime
+fn tunnel_lifetime(deadline_ms: Option<&str>, max: tokio::time::Duration) -> Result<tokio::time::Duration, &'static str> {
+    let deadline = match deadline_ms {
+        None => return Ok(max),
+        Some(value) => match value.parse::<u64>() {
+            Ok(ms) if ms > 0 => tokio::time::Duration::from_millis(ms),
+            _ => return Err("invalid X-Tunnel-Deadline-Ms header, expected a positive number of milliseconds"),
+        },
+    };
+    Ok(if max.is_zero() { deadline } else { deadline.min(max) })
+}
+
+// Client correlation id if usable in logs (visible ascii, at most MAX_REQUEST_ID_LEN), otherwise a generated one
+// Note: headers are only parsed with a strict head, a lenient one always gets a generated id
+fn request_id(header: Option<&str>) -> String {
+    match header {
+        Some(id) if !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic()) => {
+            id.to_string()
+        },
+        _ => format!("{:016x}", rand::random::<u64>()),
+    }
+}
+
+// addrs: the resolved target addresses, tried in order
+// span: the tunnel span, ends with the relay
+async fn tunnel_relay<R, W>(reader: R, mut writer: W, addrs: Vec<SocketAddr>,
+                            connector: Arc<dyn Connector + Send + Sync>,
+                            options: TunnelOptions, config: Arc<Config>, mut span: Span)
+    -> AResult<RelayStats>
+    where R: AsyncRead + Send + Unpin + 'static,
+          W: AsyncWrite + Send + Unpin + 'static
+{
+    let mut stats = RelayStats::default();
+
+    // connect to destination then write ok response then relay data in both direction
+    let mut connect_span = span.child("connect");
+    let connect_start = Instant::now();
+    let connecting = async {
+        let connected = connect_addrs(connector.as_ref(), &addrs, &config).await;
+        if connected.is_err() && !options.fallback.is_empty() {
+            info!("Target {:?} unreachable, trying fallback {:?}", addrs, options.fallback);
+            return connect_addrs(connector.as_ref(), &options.fallback, &config).await;
+        }
+        connected
+    };
+    let connected = match before_deadline(options.deadline, connecting).await {
+        Ok(connected) => connected,
+        Err(_) => {
+            span.set_attribute("status", TunnelResult::GatewayTimeout.status().0);
+            let e = establish_timeout(&mut writer, TunnelResult::GatewayTimeout, "connecting", &config).await;
+            close_client(reader, writer).await;
+            return Err(e);
+        },
+    };
+    stats.connect_duration = connect_start.elapsed();
+    let response = match connected {
+        Ok((_, addr)) => {
+            connect_span.set_attribute("addr", addr.to_string());
+            TunnelResult::Ok
+        },
+        Err(response) => response,
+    };
+    connect_span.set_attribute("status", response.status().0);
+    drop(connect_span);
+    span.set_attribute("status", response.status().0);
+
+    match connected {
+        Ok((stream, addr)) => {
+
+            stats.upstream_local = stream.local_addr().ok();
+            stats.upstream_peer = stream.peer_addr().ok();
+            if let Some(local) = stats.upstream_local {
+                span.set_attribute("upstream_local", local.to_string());
+            }
+            stream.set_nodelay(config.tcp_nodelay)?;
+            set_keepalive(&stream, &config)?;
+            set_user_timeout(&stream, &config)?;
+            set_buffer_sizes(&stream, &config)?;
+            stream.writable().await?;
+
+            // Note: tls handshake before the response, the client gets a 502 if it fails
+            let (stream_reader, stream_writer): (UpstreamReader, UpstreamWriter) = match options.upstream_tls {
+                None => {
+                    let (stream_reader, stream_writer) = stream.into_split();
+                    (Box::new(stream_reader), Box::new(stream_writer))
+                },
+                Some((upstream_tls, server_name)) => {
+                    let handshake = timeout(config.tls_handshake_timeout, upstream_tls.connect(server_name, stream));
+                    let Ok(handshake) = before_deadline(options.deadline, handshake).await else {
+                        span.set_attribute("status", TunnelResult::GatewayTimeout.status().0);
+                        let step = "in the upstream tls handshake";
+                        let e = establish_timeout(&mut writer, TunnelResult::GatewayTimeout, step, &config).await;
+                        close_client(reader, writer).await;
+                        return Err(e);
+                    };
+                    match handshake {
+                        Ok(Ok(tls_stream)) => {
+                            let (stream_reader, stream_writer) = tokio::io::split(tls_stream);
+                            (Box::new(stream_reader), Box::new(stream_writer))
+                        },
+                        Ok(Err(e)) => {
+                            warn!("Upstream tls handshake error with {}: {}", addr, e);
+                            span.set_attribute("status", TunnelResult::BadGateway.status().0);
+                            let written = write_response(&mut writer, TunnelResult::BadGateway, &config).await;
+                            close_client(reader, writer).await;
+                            written?;
+                            return Ok(stats);
+                        },
+                        Err(_) => {
+                            warn!("Upstream tls handshake timeout with {}", addr);
+                            span.set_attribute("status", TunnelResult::BadGateway.status().0);
+                            let written = write_response(&mut writer, TunnelResult::BadGateway, &config).await;
+                            close_client(reader, writer).await;
+                            written?;
+                            return Ok(stats);
+                        },
+                    }
+                },
+            };
+
+            if !options.forward {
+                // Note: a client not reading the response
+                let sent = before_deadline(options.deadline, write_response(&mut writer, response, &config)).await
+                    .unwrap_or_else(|_| {
+                        Err(format!("Establishment timeout ({:?}) while sending the response", config.establish_timeout).into())
+                    });
+                if let Err(e) = sent {
+                    close_client(reader, writer).await;
+                    return Err(e);
+                }
+            }
+
+            if config.dry_run {
+                // target is valid & reachable: close both sides without relaying anything
+                info!("[Dry run] Target {} is reachable", addr);
+                writer.shutdown().await?;
+                return Ok(stats);
+            }
+
+            // Note: the early data is mirrored too (written to upstream below)
+            let mirror = open_mirror(&config, &options.request_id).await;
+            let (mirror_up, mirror_down) = match config.mirror_direction {
+                MirrorDirection::Up => (mirror, None),
+                MirrorDirection::Down => (None, mirror),
+            };
+            let mut stream_writer = Mirror::new(stream_writer, mirror_up, config.mirror_overflow, format!("client -> {}", addr));
+            let mut writer = Mirror::new(writer, mirror_down, config.mirror_overflow, format!("{} -> client", addr));
+
+            let mut relay_span = span.child("relay");
+            let limits = RelayLimits::new(config.max_tunnel_bytes, config.idle_timeout, config.linger_after_eof)
+                .with_lifetime(options.lifetime)
+                .with_keepalive(!config.relay_keepalive.is_zero());
+            let limits = Arc::new(limits);
+            let (limits_r1, limits_r2) = (limits.clone(), limits.clone());
+            let (buffer_r1, buffer_r2) = (config.relay_buffer_client_to_upstream, config.relay_buffer_upstream_to_client);
+            // Note: fixed size buffers if no min
+            let (min_buffer_r1, min_buffer_r2) = config.relay_buffer_min.map_or((buffer_r1, buffer_r2), |min| (min, min));
+            // Note: not checked against the relay limits (at most a request buffer)
+            if !options.early_data.is_empty() {
+                stream_writer.write_all(&options.early_data).await?;
+                stats.client_to_upstream.bytes = options.early_data.len() as u64;
+            }
+
+            let mut reader = Tap::new(reader, config.tap_bytes, format!("client -> {}", addr));
+            let mut stream_reader = Tap::new(stream_reader, config.tap_bytes, format!("{} -> client", addr));
+            // Note: every teardown path (EOF, error, limits) ends both copies, each copy then shuts its writer down
+            // so both peers see a clean close (FIN)
+            // Note: the copies are aborted if this function is (e.g. its task on shutdown), dropping the halves
+            let r1 = AbortOnDropHandle::new(tokio::spawn(async move {
+                // from proxy client to dest writer
+                let mut stats = DirectionStats::default();
+                let copied = relay::copy(&mut reader, &mut stream_writer, min_buffer_r1, buffer_r1, &mut stats, &limits_r1).await;
+                let _ = stream_writer.shutdown().await;
+                (stats, copied)
+            }));
+
+            let r2 = AbortOnDropHandle::new(tokio::spawn(async move {
+                // from dest reader to proxy writer
+                let mut stats = DirectionStats::default();
+                let copied = relay::copy(&mut stream_reader, &mut writer, min_buffer_r2, buffer_r2, &mut stats, &limits_r2).await;
+                // Note: client sees a clean close (EOF) once upstream is done
+                let _ = writer.shutdown().await;
+                (stats, copied)
+            }));
+
+            let limits_ = limits.clone();
+            let revoked = options.revoked.clone();
+            let limits_watch = AbortOnDropHandle::new(tokio::spawn(async move {
+                let watch_revoked = async {
+                    revoked.cancelled().await;
+                    limits_.stop(StopReason::Revoked);
+                };
+                tokio::join!(limits_.watch_idle(), limits_.watch_linger(), limits_.watch_lifetime(), watch_revoked);
+            }));
+
+            // Each direction outcome is recorded in its stats, errors are logged once both directions are done
+            // Note: relay stopped because of limits is logged below
+            let record_error = |direction: &str, direction_stats: &mut DirectionStats, e: std::io::Error| {
+                let kind = relay::classify_error(&e);
+                direction_stats.error = Some(kind);
+                matches!(kind, RelayErrorKind::Tls | RelayErrorKind::Transport)
+                    .then(|| format!("{} {} ({}): {}", direction, addr, kind, e))
+            };
+            let mut failure_from = None;
+            let mut failure_to = None;
+
+            match r2.await {
+                // Note: each copy shuts its writer down on EOF, i.e. a half-close is passed through as is
+                // Note: upstream done without a byte sent back, but after reading client data (e.g. an upload),
+                // is a normal end: the client direction is left to finish
+                Ok((_, Ok(0))) if !config.half_close && limits.used() == 0 && options.early_data.is_empty() => {
+                    // Nothing will ever be sent back, no need to wait for the client
+                    info!("Upstream {} closed immediately", addr);
+                    limits.stop(StopReason::Closed);
+                },
+                Ok((mut direction_stats, copied)) => {
+                    if let Err(e) = copied {
+                        failure_from = record_error("from", &mut direction_stats, e);
+                    }
+                    stats.upstream_to_client = direction_stats;
+                },
+                Err(e) => warn!("Relay task error from {}: {}", addr, e),
+            }
+            match r1.await {
+                Ok((mut direction_stats, copied)) => {
+                    if let Err(e) = copied {
+                        failure_to = record_error("to", &mut direction_stats, e);
+                    }
+                    direction_stats.bytes += stats.client_to_upstream.bytes;
+                    stats.client_to_upstream = direction_stats;
+                },
+                Err(e) => warn!("Relay task error to {}: {}", addr, e),
+            }
+            limits_watch.abort();
+            // e.g. Relay error to 1.2.3.4:443 (transport error): ..., from 1.2.3.4:443 (transport error): ...
+            let failures: Vec<String> = [failure_to, failure_from].into_iter().flatten().collect();
+            if !failures.is_empty() {
+                warn!("Relay error {}", failures.join(", "));
+            }
+
+            match limits.stop_reason() {
+                Some(StopReason::Quota) => {
+                    warn!("Tunnel to {} exceeded its byte quota ({} bytes), closed", addr, limits.max_bytes());
+                },
+                Some(StopReason::Idle) => info!("Tunnel to {} idle for {:?}, closed", addr, limits.idle_timeout()),
+                Some(StopReason::Linger) => {
+                    info!("Tunnel to {} still open {:?} after one side closed, closed", addr, limits.linger());
+                },
+                Some(StopReason::Lifetime) => info!("Tunnel to {} reached its lifetime ({:?}), closed", addr, limits.lifetime()),
+                Some(StopReason::Revoked) => info!("Tunnel to {} revoked, closed", addr),
+                Some(StopReason::Closed) | None => {},
+            }
+            if let Some(reason) = limits.stop_reason() {
+                relay_span.set_attribute("stop_reason", format!("{:?}", reason));
+            }
+            relay_span.set_attribute("bytes_client_to_upstream", stats.client_to_upstream.bytes);
+            relay_span.set_attribute("bytes_upstream_to_client", stats.upstream_to_client.bytes);
+        }
+        // connect error or timeout (logged by connect_addrs)
+        Err(_) => {
+            let written = write_response(&mut writer, response, &config).await;
+            close_client(reader, writer).await;
+            written?;
+        },
+    }
+
+    span.set_attribute("bytes", stats.client_to_upstream.bytes + stats.upstream_to_client.bytes);
+
+    Ok(stats)
+}
+
+
+// Listener state, shared by all its tunnels
+#[derive(Clone)]
+struct ListenerState {
+    // the proxy listen address (to refuse targets looping back to the proxy)
+    listen_addr: SocketAddr,
+    // re-originate tls to the upstream tls hosts
+    upstream_tls: Option<UpstreamTls>,
+    // once cancelled (shutting down), requests are refused with a 503
+    shutdown: CancellationToken,
+    // running tunnels (all listeners)
+    tunnels: TunnelRegistry,
+    connector: Arc<dyn Connector + Send + Sync>,
+    policy: Arc<dyn TunnelPolicy + Send + Sync>,
+    // called once the target is resolved, can veto the tunnel (if set)
+    connect_hook: Option<Arc<dyn ConnectHook + Send + Sync>>,
+    // lifecycle events (if set)
+    events: Option<mpsc::Sender<TunnelEvent>>,
+}
+
+impl ListenerState {
+    fn new(listener: &TcpListener, config: &Config, shutdown: CancellationToken, tunnels: TunnelRegistry,
+           policy: Arc<dyn TunnelPolicy + Send + Sync>)
+        -> std::io::Result<Self>
+    {
+        Ok(Self {
+            listen_addr: listener.local_addr()?,
+            upstream_tls: UpstreamTls::from_config(config)?,
+            shutdown,
+            tunnels,
+            connector: Arc::new(TcpConnector::bound(config.upstream_bind.map(|ip| SocketAddr::new(ip, 0)))),
+            policy,
+            connect_hook: None,
+            events: None,
+        })
+    }
+
+    fn emit(&self, event: TunnelEvent) {
+        if let Some(events) = &self.events {
+            // Note: never waits, lost if the channel is full
+            let _ = events.try_send(event);
+        }
+    }
+
+    fn reject(&self, peer: SocketAddr, reason: RejectReason) {
+        self.tunnels.record_result(&format!("rejected ({:?})", reason));
+        self.emit(TunnelEvent::Rejected { peer, reason });
+    }
+}
+
+// CONNECT request decoder, the read buffer is released once the request is read (see into_inner)
+fn request_reader<R: AsyncRead>(reader: R, config: &Config) -> FramedRead<R, HttpCodec> {
+    let codec = HttpCodec {
+        default_port: config.default_port,
+        reject_userinfo: config.reject_userinfo,
+        parse_headers: config.strict_head,
+        require_host_match: config.require_host_match,
+        forward_http: config.forward_http,
+        strict_version: config.strict_http_version,
+        ..Default::default()
+    };
+    FramedRead::with_capacity(reader, codec, config.request_buffer_size)
+}
+
+async fn tunnel_stream<R, W, D>(reader: R, mut writer: W, peer: SocketAddr, mut resolver: D, config: Arc<Config>,
+                                state: ListenerState)
+    -> AResult<()>
+    where R: AsyncRead + Send + Unpin + Debug + 'static,
+          W: AsyncWrite + Send + Unpin + 'static,
+          D: DnsResolver + Send
+{
+    let mut fr = request_reader(reader, &config);
+    // Note: from the request start, the tls handshake (or PROXY header) has its own timeout
+    let request_start = Instant::now();
+    let deadline_after = |timeout: tokio::time::Duration| (!timeout.is_zero()).then(|| request_start + timeout);
+    let deadline = deadline_after(config.establish_timeout);
+
+    let request = match before_deadline(deadline, fr.next()).await {
+        Ok(request) => request.ok_or("Cannot read frame")?,
+        Err(_) => {
+            state.reject(peer, RejectReason::Timeout);
+            return Err(establish_timeout(&mut writer, TunnelResult::Timeout, "reading the request", &config).await);
+        },
+    };
+    match &request {
+        // Note: the connection is unusable, no response
+        Err(DecodeError::IO(e)) => return Err(format!("Cannot read request: {}", e).into()),
+        Err(e) => {
+            state.reject(peer, RejectReason::InvalidRequest);
+            write_response_body(&mut writer, e.response(), e.reason(), &config).await?;
+            return Err(format!("Invalid request: {}", e).into());
+        },
+        Ok(HttpRequest::Options) => {
+            write_encoded(&mut writer, OptionsResponse, &config).await?;
+            info!("Answered OPTIONS * from {}", peer);
+            return Ok(());
+        },
+        Ok(HttpRequest::Connect(_)) | Ok(HttpRequest::Forward { .. }) => {},
+    }
+    // Note: a forwarded request head is sent upstream (rewritten in origin-form by the decoder)
+    let forward_head = match &request {
+        Ok(HttpRequest::Forward { head, .. }) => Some(head.clone()),
+        _ => None,
+    };
+    if let Ok(HttpRequest::Connect(url_) | HttpRequest::Forward { target: url_, .. }) = request {
+        if state.shutdown.is_cancelled() {
+            state.reject(peer, RejectReason::ShuttingDown);
+            write_response(&mut writer, TunnelResult::ServiceUnavailable, &config).await?;
+            info!("Refused {} from {}: shutting down", url_, peer);
+            return Ok(());
+        }
+        if state.tunnels.is_paused() {
+            state.reject(peer, RejectReason::Paused);
+            write_response_body(&mut writer, TunnelResult::ServiceUnavailable, "paused, retry later", &config).await?;
+            info!("Refused {} from {}: paused", url_, peer);
+            return Ok(());
+        }
+        // println!("{}", url_);
+        let lifetime = match tunnel_lifetime(fr.decoder().header(DEADLINE_HEADER), config.max_tunnel_lifetime) {
+            Ok(lifetime) => lifetime,
+            Err(reason) => {
+                state.reject(peer, RejectReason::InvalidRequest);
+                write_response_body(&mut writer, TunnelResult::BadRequest, reason, &config).await?;
+                return Err(format!("Invalid request for {}: {}", url_, reason).into());
+            },
+        };
+        if forward_head.is_none() && config.strict_head && config.reject_early_data && !fr.read_buffer().is_empty() {
+            state.reject(peer, RejectReason::InvalidRequest);
+            let reason = "data sent before the CONNECT response";
+            write_response_body(&mut writer, TunnelResult::BadRequest, reason, &config).await?;
+            return Err(format!("Invalid request for {}: {}", url_, reason).into());
+        }
+        // Note: the target as requested is logged above (refused while shutting down or paused)
+        let url_ = if config.lowercase_host { lowercase_host(&url_) } else { url_ };
+        let target = config.rewrites.rewrite(&url_);
+        // Note: the target settings (like the listener ones) replace the config ones for this tunnel
+        let (config, deadline) = match config.target_timeouts.get(target) {
+            Some(target_timeout) => {
+                let establish_timeout = target_timeout.establish.unwrap_or(config.establish_timeout);
+                let target_config = Config {
+                    connect_timeout: target_timeout.connect, establish_timeout, ..Config::clone(&config)
+                };
+                (Arc::new(target_config), deadline_after(establish_timeout))
+            },
+            None => (config.clone(), deadline),
+        };
+        if let Some(response) = state.policy.check(target, peer) {
+            state.reject(peer, RejectReason::Policy(response));
+            write_response(&mut writer, response, &config).await?;
+            info!("Refused {} from {}: policy ({})", target, peer, response.status().0);
+            return Ok(());
+        }
+        let _host_permit = if config.max_tunnels_per_host > 0 {
+            let acquire = state.tunnels.acquire_host(target, config.max_tunnels_per_host, config.max_tunnels_per_host_wait);
+            let Ok(permit) = before_deadline(deadline, acquire).await else {
+                state.reject(peer, RejectReason::Timeout);
+                let step = "waiting for a tunnel slot";
+                return Err(establish_timeout(&mut writer, TunnelResult::GatewayTimeout, step, &config).await);
+            };
+            if permit.is_none() {
+                state.reject(peer, RejectReason::TooManyTunnels);
+                let reason = format!("too many tunnels to {}", target);
+                write_response_body(&mut writer, TunnelResult::ServiceUnavailable, &reason, &config).await?;
+                info!("Refused {} from {}: {} tunnel(s) already running", target, peer, config.max_tunnels_per_host);
+                return Ok(());
+            }
+            permit
+        } else {
+            None
+        };
+        let registered = state.tunnels.register(peer, target);
+        let request_id = request_id(fr.decoder().header(REQUEST_ID_HEADER));
+        let mut span = Span::new("tunnel", None);
+        span.set_attribute("peer", peer.to_string());
+        span.set_attribute("target", target);
+        span.set_attribute("request_id", request_id.clone());
+
+        let mut resolve_span = span.child("resolve");
+        resolve_span.set_attribute("target", target);
+        let resolve_start = Instant::now();
+        let Ok(resolved) = before_deadline(deadline, resolver.resolve_all(target)).await else {
+            state.reject(peer, RejectReason::Timeout);
+            span.set_attribute("status", TunnelResult::GatewayTimeout.status().0);
+            return Err(establish_timeout(&mut writer, TunnelResult::GatewayTimeout, "resolving", &config).await);
+        };
+        let resolve_duration = resolve_start.elapsed();
+        resolve_span.set_attribute("status", if resolved.is_ok() { "ok" } else { "error" });
+        drop(resolve_span);
+
+        let resolved = match resolved {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                state.reject(peer, RejectReason::Unresolved);
+                span.set_attribute("status", TunnelResult::BadGateway.status().0);
+                write_response(&mut writer, TunnelResult::BadGateway, &config).await?;
+                return Err(format!("Could not resolve {}: {}", target, e).into());
+            }
+        };
+        let addrs: Vec<SocketAddr> = resolved.iter()
+            .filter(|addr| !filter::is_listen_addr(addr, &state.listen_addr))
+            .copied()
+            .collect();
+        if addrs.is_empty() {
+            state.reject(peer, RejectReason::ProxyItself);
+            span.set_attribute("status", TunnelResult::Forbidden.status().0);
+            write_response(&mut writer, TunnelResult::Forbidden, &config).await?;
+            return Err(format!("Target {} ({:?}) is the proxy itself", target, resolved).into());
+        }
+        let (blocked, mut addrs): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.into_iter()
+            .partition(|addr| config.upstream_denylist.is_denied(&addr.ip()));
+        if addrs.is_empty() {
+            state.reject(peer, RejectReason::AddressesBlocked);
+            span.set_attribute("status", TunnelResult::Forbidden.status().0);
+            write_response_body(&mut writer, TunnelResult::Forbidden, "target addresses not allowed", &config).await?;
+            info!("Refused {} from {}: all resolved addresses denied {:?}", target, peer, blocked);
+            return Ok(());
+        }
+        if !blocked.is_empty() {
+            info!("Target {}: denied address(es) skipped {:?}", target, blocked);
+        }
+        if config.sticky_routing {
+            sticky_order(&mut addrs, peer.ip());
+        }
+        if let Some(connect_hook) = &state.connect_hook {
+            let Ok(flow) = before_deadline(deadline, connect_hook.on_connect(target, &addrs, peer)).await else {
+                state.reject(peer, RejectReason::Timeout);
+                span.set_attribute("status", TunnelResult::GatewayTimeout.status().0);
+                let step = "in the connect hook";
+                return Err(establish_timeout(&mut writer, TunnelResult::GatewayTimeout, step, &config).await);
+            };
+            if let ControlFlow::Break(response) = flow {
+                state.reject(peer, RejectReason::Hook(response));
+                span.set_attribute("status", response.status().0);
+                write_response(&mut writer, response, &config).await?;
+                info!("Refused {} from {}: connect hook ({})", target, peer, response.status().0);
+                return Ok(());
+            }
+        }
+        // Note: resolved now (if configured) but only tried if the target is unreachable
+        let fallback = match config.rewrites.fallback(target) {
+            Some(fallback) => match before_deadline(deadline, resolver.resolve_all(fallback)).await {
+                Err(_) => {
+                    state.reject(peer, RejectReason::Timeout);
+                    span.set_attribute("status", TunnelResult::GatewayTimeout.status().0);
+                    let step = "resolving the fallback";
+                    return Err(establish_timeout(&mut writer, TunnelResult::GatewayTimeout, step, &config).await);
+                },
+                Ok(Ok(resolved)) => resolved.into_iter()
+                    .filter(|addr| !filter::is_listen_addr(addr, &state.listen_addr))
+                    .filter(|addr| !config.upstream_denylist.is_denied(&addr.ip()))
+                    .collect(),
+                Ok(Err(e)) => {
+                    warn!("Could not resolve fallback {} for {}: {}", fallback, target, e);
+                    Vec::new()
+                },
+            },
+            None => Vec::new(),
+        };
+        let upstream_tls = state.upstream_tls.clone()
+            .zip(ConnectTarget::parse(target))
+            .and_then(|(tls, target)| tls.server_name(&target.host).map(|name| (tls, name)));
+        // Note: with a lenient head, the buffer holds the CONNECT headers (not tunnel data): dropped
+        let forward = forward_head.is_some();
+        let early_data = match forward_head {
+            Some(mut head) => {
+                head.extend_from_slice(fr.read_buffer());
+                head
+            },
+            None if config.strict_head => fr.read_buffer().to_vec(),
+            None => Vec::new(),
+        };
+        let options = TunnelOptions {
+            upstream_tls, lifetime, fallback, revoked: registered.revoked(), early_data, forward, deadline,
+            request_id: request_id.clone(),
+        };
+        let reader = fr.into_inner(); // get back reader
+        state.emit(TunnelEvent::Started { peer, target: target.to_string() });
+        let relay_start = Instant::now();
+        let relayed = tunnel_relay(reader, writer, addrs, state.connector.clone(), options, config.clone(), span).await;
+        let mut stats = match relayed {
+            Ok(stats) => stats,
+            Err(e) => {
+                state.tunnels.record_result("failed");
+                return Err(e);
+            },
+        };
+        match stats.upstream_peer {
+            Some(_) => {
+                let bytes = stats.client_to_upstream.bytes + stats.upstream_to_client.bytes;
+                state.tunnels.record_tunnel(bytes, relay_start.elapsed());
+            },
+            None => state.tunnels.record_result("connect failed"),
+        }
+        stats.resolve_duration = resolve_duration;
+        stats.request_id = request_id;
+        info!("Tunnel {} -> {} closed (request id: {}, upstream: {:?} -> {:?}, resolve: {:?}, connect: {:?}): {:?}",
+              peer, target, stats.request_id, stats.upstream_local, stats.upstream_peer, stats.resolve_duration,
+              stats.connect_duration, stats);
+        state.emit(TunnelEvent::Completed { peer, target: target.to_string(), stats });
+    }
+    Ok(())
+}
+
+async fn tunnel(server: &TunnelServer) -> AResult<()> {
+    let config = &server.config;
+
+    info!("addr: {}", config.addr);
+    info!("Enable tls: {}", config.tls.is_some() || server.tls_config.is_some());
+
+    // Note: no internal domain, every host goes to the default resolver
+    let internal_routes: Vec<_> = config.internal_dns.iter()
+        .map(|(suffix, servers)| (suffix.clone(), ConfigurableResolver::new(servers.clone())))
+        .collect();
+    if !internal_routes.is_empty() {
+        info!("Internal dns: {:?}", config.internal_dns);
+    }
+
+    if config.dns_servers.is_empty() {
+        let resolver = SuffixResolver::new(SimpleDnsResolver::new(), internal_routes);
+        serve_with_resolver(server, resolver).await
+    } else {
+        info!("Dns servers: {:?}", config.dns_servers);
+        let resolver = SuffixResolver::new(ConfigurableResolver::new(config.dns_servers.clone()), internal_routes);
+        serve_with_resolver(server, resolver).await
+    }
+}
+
+// Add retries, cache, pins & hosts file (if any) on top of the base resolver then serve
+async fn serve_with_resolver<D>(server: &TunnelServer, resolver: D) -> AResult<()>
+    where D: DnsResolver + Clone + Send + 'static
+{
+    let config = &server.config;
+    // Note: timed around the base resolver, i.e. each lookup (retries included, cache hits excluded)
+    let dns_stats = Arc::new(ResolutionStats::default());
+    let resolver = TimedResolver::new(resolver, dns_stats.clone());
+    // Note: around the base resolver too (each query counts, once past the cache)
+    let resolver = BudgetedResolver::new(resolver, config.dns_budget, DNS_BUDGET_WINDOW);
+    // Note: a malformed hostname fails before any lookup (InvalidInput errors are not retried)
+    let resolver = ValidatingResolver::new(resolver).with_max_len(config.max_hostname_len);
+    let resolver = RetryingResolver::new(resolver, config.dns_retries, DNS_RETRY_BACKOFF);
+    let resolver = CachingResolver::new(resolver, config.dns_cache_ttl, config.dns_negative_cache_ttl);
+    // Note: after the cache, a resolution outside of the pins is rejected even if cached
+    let resolver = PinningResolver::new(resolver, config.dns_pins.clone());
+    let served = match &config.hosts_file {
+        Some(path) => {
+            let resolver = HostsFileResolver::load(resolver, path, config.hosts_file_reload).await?;
+            serve(server, resolver).await
+        },
+        None => serve(server, resolver).await,
+    };
+    info!("Dns resolutions: {}", dns_stats);
+    served
+}
+
+async fn serve<D>(server: &TunnelServer, resolver: D) -> AResult<()>
+    where D: DnsResolver + Clone + Send + 'static
+{
+    let TunnelServer { config, shutdown, tunnels, tls_config, .. } = server;
+    #[cfg(unix)]
+    let inherited = listener::systemd_listeners()?;
+    #[cfg(not(unix))]
+    let inherited = None;
+
+    // Listeners & their own settings (see Config::for_listener)
+    let listeners: Vec<(TcpListener, Arc<Config>)> = match inherited {
+        Some(listeners) => {
+            info!("Socket activation: serving {} inherited listener(s), listen addresses ignored", listeners.len());
+            listeners.into_iter().map(|l| (l, config.clone())).collect()
+        },
+        None => {
+            // e.g. "0.0.0.0:6161" & "[::]:6161" (ipv6 only) to bind both families separately
+            let mut listeners = Vec::new();
+            for addr in std::iter::once(&config.addr).chain(&config.listen_addrs) {
+                let listener_config = Arc::new(config.for_listener(addr)?);
+                listeners.push((listener::bind(addr, listener_config.ipv6_only).await?, listener_config));
+            }
+            listeners
+        },
+    };
+    if tls_config.is_some() {
+        info!("Custom tls config: serving all listeners with it, cert / key files & per listener tls settings ignored");
+    } else if config.tls_only {
+        if let Some((listener, _)) = listeners.iter().find(|(_, listener_config)| listener_config.tls.is_none()) {
+            return Err(format!("Plaintext listener {} refused (--tls-only)", listener.local_addr()?).into());
+        }
+    }
+
+    // Note: shared by all listeners
+    let denylist = match &config.deny_targets {
+        Some(path) => {
+            let denylist = Arc::new(TargetDenylist::load(path)?);
+            #[cfg(unix)]
+            tokio::spawn(reload_on_hangup(denylist.clone(), tunnels.clone(), config.terminate_denied));
+            Some(denylist)
+        },
+        None => None,
+    };
+    // Note: the target denylist first, then the embedder policy (see TunnelServer::with_policy)
+    let policy: Arc<dyn TunnelPolicy + Send + Sync> = match (denylist, server.policy.clone()) {
+        (Some(denylist), Some(policy)) => Arc::new(PolicyChain::new(denylist, policy)),
+        (Some(denylist), None) => denylist,
+        (None, Some(policy)) => policy,
+        (None, None) => Arc::new(AllowAll::default()),
+    };
+
+    #[cfg(unix)]
+    tokio::spawn(pause_on_signal(tunnels.clone()));
+
+    if !config.heartbeat_interval.is_zero() {
+        tokio::spawn(heartbeat(tunnels.clone(), config.heartbeat_interval, shutdown.clone()));
+    }
+
+    let mut serving = Vec::new();
+    for (listener, listener_config) in listeners {
+        let acceptor = match (tls_config, &listener_config.tls) {
+            (Some(tls_config), _) => Some(TlsAcceptor::from(tls_config.clone())),
+            // Note: per listener, its tls settings (versions, cipher suites...) may differ
+            (None, Some(tls_files)) => {
+                let tls_config = load_server_config(&tls_files.cert, &tls_files.key, &listener_config)?;
+                Some(TlsAcceptor::from(Arc::new(tls_config)))
+            },
+            (None, None) => None,
+        };
+        let mut state = ListenerState::new(&listener, &listener_config, shutdown.clone(), tunnels.clone(),
+                                           policy.clone())?;
+        state.events = server.events.clone();
+        state.connect_hook = server.connect_hook.clone();
+        match acceptor {
+            Some(acceptor) => {
+                serving.push(serve_tls(listener, acceptor, listener_config, resolver.clone(), state).left_future());
+            },
+            None => {
+                serving.push(serve_tcp(listener, listener_config, resolver.clone(), state).right_future());
+            },
+        }
+    }
+    futures::future::try_join_all(serving).await?;
+    Ok(())
+}
+
+// Get the client address (from the PROXY protocol header if enabled)
+// Return None (connection should be closed) if the header is invalid
+async fn client_addr(socket: &mut TcpStream, peer: SocketAddr, config: &Config) -> Option<SocketAddr> {
+    if !config.proxy_protocol {
+        return Some(peer);
+    }
+
+    match timeout(PROXY_PROTOCOL_HEADER_TIMEOUT, proxy_protocol::read_header(socket)).await {
+        Ok(Ok(Some(client))) => Some(client),
+        Ok(Ok(None)) => Some(peer),
+        Ok(Err(e)) => {
+            warn!("Closing connection from {}: {}", peer, e);
+            None
+        },
+        Err(_) => {
+            warn!("Closing connection from {}: timeout while reading PROXY protocol header", peer);
+            None
+        }
+    }
+}
+
+fn is_peer_allowed(peer: &SocketAddr, config: &Config) -> bool {
+    let allowed = config.peer_allowlist.is_allowed(&peer.ip());
+    if !allowed {
+        warn!("Peer {} not allowed, closing connection", peer);
+    }
+    allowed
+}
+
+// The connection slot (see --max-connections & --on-overload), None if refused (socket closed)
+// Note: the tarpit does not apply (it would hold a slot longer)
+async fn admit_connection(connection: Option<ConnectionGuard>, socket: &mut TcpStream, peer: SocketAddr,
+                          response: bool, config: &Config, state: &ListenerState) -> Option<ConnectionGuard> {
+    if connection.is_some() {
+        return connection;
+    }
+    if config.on_overload == Overload::Queue {
+        return Some(state.tunnels.queued_connection().await);
+    }
+    let _connection = state.tunnels.connection();
+    state.reject(peer, RejectReason::Overloaded);
+    info!("Refused connection from {}: max connections ({}) reached", peer, config.max_connections);
+    if response && config.on_overload == Overload::Reject {
+        let _ = write_response(&mut *socket, TunnelResult::ServiceUnavailable, config).await;
+    }
+    let _ = socket.shutdown().await;
+    None
+}
+
+// Close a rejected connection, after the tarpit delay (if any) and an optional response
+async fn reject_connection<S>(mut stream: S, response: Option<TunnelResult>, config: Arc<Config>)
+    where S: AsyncWrite + Unpin
+{
+    if !config.tarpit.is_zero() {
+        tokio::time::sleep(config.tarpit).await;
+    }
+    if let Some(response) = response {
+        let _ = write_response(&mut stream, response, &config).await;
+    }
+    let _ = stream.shutdown().await;
+}
+
+async fn serve_tls<D>(listener: TcpListener, acceptor: TlsAcceptor, config: Arc<Config>, resolver: D,
+                      state: ListenerState) -> AResult<()>
+    where D: DnsResolver + Clone + Send + 'static
+{
+    info!("[Tcp/Tls] Listening on {}", state.listen_addr);
+    loop {
+        let (mut socket, peer) = listener.accept().await?;
+        let connection = state.tunnels.try_connection();
+        let acceptor_ = acceptor.clone();
+        let resolver_ = resolver.clone();
+        let config_ = config.clone();
+        let state_ = state.clone();
+
+        // Note: everything per connection (PROXY header, handshake...) runs in the connection task,
+        // a slow client must not block the accept loop
+        tokio::spawn(async move {
+            // Note: refused without response (see Overload)
+            let Some(_connection) = admit_connection(connection, &mut socket, peer, false, &config_, &state_).await
+                else { return };
+            let Some(peer) = client_addr(&mut socket, peer, &config_).await else { return };
+            if !is_peer_allowed(&peer, &config_) {
+                state_.reject(peer, RejectReason::PeerNotAllowed);
+                // Note: no http response here (it would require a tls handshake)
+                reject_connection(socket, None, config_).await;
+                return;
+            }
+
+            let result = async {
+                socket.set_nodelay(config_.tcp_nodelay)?;
+                set_keepalive(&socket, &config_)?;
+                set_user_timeout(&socket, &config_)?;
+                set_buffer_sizes(&socket, &config_)?;
+                let stream = match timeout(config_.tls_handshake_timeout, acceptor_.accept(socket)).await {
+                    Ok(stream) => stream.map_err(|e| format!("Tls handshake error: {}", e))?,
+                    Err(_) => return Err("Tls handshake timeout".into()),
+                };
+                let (reader, writer) = tokio::io::split(stream);
+                tunnel_stream(reader, writer, peer, resolver_, config_, state_).await
+            };
+            if let Err(e) = result.await {
+                warn!("[Tcp/Tls] Tunnel stream error ({}): {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn serve_tcp<D>(listener: TcpListener, config: Arc<Config>, resolver: D, state: ListenerState) -> AResult<()>
+    where D: DnsResolver + Clone + Send + 'static
+{
+    info!("[Tcp] Listening on {}", state.listen_addr);
+    loop {
+        let (mut socket, peer) = listener.accept().await?;
+        let connection = state.tunnels.try_connection();
+        let resolver_ = resolver.clone();
+        let config_ = config.clone();
+        let state_ = state.clone();
+
+        tokio::spawn(async move {
+            let Some(_connection) = admit_connection(connection, &mut socket, peer, true, &config_, &state_).await
+                else { return };
+            let Some(peer) = client_addr(&mut socket, peer, &config_).await else { return };
+            if !is_peer_allowed(&peer, &config_) {
+                state_.reject(peer, RejectReason::PeerNotAllowed);
+                let response = config_.peer_reject_response.then_some(TunnelResult::Forbidden);
+                reject_connection(socket, response, config_).await;
+                return;
+            }
+
+            let result = async {
+                socket.set_nodelay(config_.tcp_nodelay)?;
+                set_keepalive(&socket, &config_)?;
+                set_user_timeout(&socket, &config_)?;
+                set_buffer_sizes(&socket, &config_)?;
+                socket.writable().await?;
+                let (reader, writer) = socket.into_split();
+                tunnel_stream(reader, writer, peer, resolver_, config_, state_).await
+            };
+            if let Err(e) = result.await {
+                warn!("[Tcp] Tunnel stream error ({}): {}", peer, e);
+            }
+        });
+    }
+}
+
+// Reload the target denylist, if terminate also revoke the running tunnels to targets now denied
+fn reload_denylist(denylist: &TargetDenylist, tunnels: &TunnelRegistry, terminate: bool) {
+    match denylist.reload() {
+        Ok(count) => {
+            let revoked = if terminate { tunnels.revoke_where(|t| denylist.is_denied(&t.target)) } else { 0 };
+            info!("Reloaded target denylist {}: {} host(s), {} running tunnel(s) revoked", denylist.path(), count, revoked);
+        },
+        Err(e) => warn!("Unable to reload target denylist {} (keeping the current one): {}", denylist.path(), e),
+    }
+}
+
+#[cfg(unix)]
+async fn reload_on_hangup(denylist: Arc<TargetDenylist>, tunnels: TunnelRegistry, terminate: bool) {
+    let mut hangup = match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("Unable to listen for SIGHUP, target denylist {} will not be reloaded: {}", denylist.path(), e);
+            return;
+        },
+    };
+    while hangup.recv().await.is_some() {
+        reload_denylist(&denylist, &tunnels, terminate);
+    }
+}
+
+// Pause new tunnels if running, resume them otherwise, return the new state
+fn toggle_pause(tunnels: &TunnelRegistry) -> bool {
+    let paused = !tunnels.is_paused();
+    tunnels.set_paused(paused);
+    if paused {
+        info!("Paused: new tunnels are refused (503), {} running tunnel(s) continue", tunnels.active().len());
+    } else {
+        info!("Resumed: new tunnels are accepted");
+    }
+    paused
+}
+
+// SIGUSR1: pause / resume new tunnels (e.g. maintenance)
+#[cfg(unix)]
+async fn pause_on_signal(tunnels: TunnelRegistry) {
+    let mut user1 = match signal::unix::signal(signal::unix::SignalKind::user_defined1()) {
+        Ok(user1) => user1,
+        Err(e) => {
+            warn!("Unable to listen for SIGUSR1, new tunnels cannot be paused: {}", e);
+            return;
+        },
+    };
+    while user1.recv().await.is_some() {
+        toggle_pause(&tunnels);
+    }
+}
+
+// Log a heartbeat every interval until shutdown (i.e. while accepting new requests)
+async fn heartbeat(tunnels: TunnelRegistry, interval: tokio::time::Duration, shutdown: CancellationToken) {
+    let start = Instant::now();
+    let mut ticks = tokio::time::interval_at(start + interval, interval);
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {},
+            _ = shutdown.cancelled() => return,
+        }
+        info!("Heartbeat: up {}s, {} connection(s) open, {} tunnel(s) running, {} accepted since start",
+              start.elapsed().as_secs(), tunnels.active_connections(), tunnels.active().len(), tunnels.total_connections());
+    }
+}
+
+// Wait for the running tunnels to finish (for at most grace), log the progress on changes & every interval
+async fn drain(tunnels: &TunnelRegistry, grace: tokio::time::Duration, interval: tokio::time::Duration) {
+    let start = Instant::now();
+    let deadline = start + grace;
+    let mut last_progress: Option<(usize, Instant)> = None;
+    loop {
+        let active = tunnels.active();
+        if active.is_empty() {
+            info!("Drained: all tunnels closed in {:?}", start.elapsed());
+            return;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            warn!("Drain timeout: {} tunnel(s) still running after {:?}, closing them", active.len(), grace);
+            return;
+        }
+        let report = match last_progress {
+            Some((count, at)) => count != active.len() || now >= at + interval,
+            None => true,
+        };
+        if report {
+            info!("Draining: {} tunnel(s) running (oldest: {} -> {} for {:?})",
+                  active.len(), active[0].peer, active[0].target, active[0].start.elapsed());
+            last_progress = Some((active.len(), now));
+        }
+        tunnels.changed(interval.min(deadline - now)).await;
+    }
+}
+
+// Tunnel server, e.g. embedded in an application:
+//   let server = TunnelServer::new(config);
+//   tokio::spawn({ let server = server.clone(); async move { server.run().await } });
+//   ... server.active_connections() ..., then server.shutdown() to refuse new requests
+#[derive(Clone)]
+pub struct TunnelServer {
+    config: Arc<Config>,
+    // once cancelled, new requests are refused (503), the running tunnels go on
+    shutdown: CancellationToken,
+    // running tunnels & connection counters (all listeners)
+    tunnels: TunnelRegistry,
+    // lifecycle events (if set)
+    events: Option<mpsc::Sender<TunnelEvent>>,
+    // called once the target is resolved, can veto the tunnel (if set)
+    connect_hook: Option<Arc<dyn ConnectHook + Send + Sync>>,
+    // custom tls server config used by all the listeners (if set)
+    tls_config: Option<Arc<ServerConfig>>,
+    // refuse targets (if set), after the target denylist (see --deny-targets)
+    policy: Option<Arc<dyn TunnelPolicy + Send + Sync>>,
+}
+
+impl TunnelServer {
+    pub fn new(config: Config) -> Self {
+        let tunnels = TunnelRegistry::new().with_max_connections(config.max_connections);
+        Self {
+            config: Arc::new(config), shutdown: CancellationToken::new(), tunnels, events: None, connect_hook: None,
+            tls_config: None, policy: None,
+        }
+    }
+
+    // Serve all the listeners with this tls server config (e.g. client auth, cert resolver, ALPN),
+    // instead of the one built from the cert & key files of each listener (see load_server_config)
+    pub fn with_tls_config(mut self, tls_config: Arc<ServerConfig>) -> Self {
+        self.tls_config = Some(tls_config);
+        self
+    }
+
+    // Same as with_tls_config, the tls server config is built from these cert & key files (and the tls settings)
+    pub fn with_tls_files<P: AsRef<Path>>(self, cert_path: P, key_path: P) -> std::io::Result<Self> {
+        let tls_config = load_server_config(cert_path, key_path, &self.config)?;
+        Ok(self.with_tls_config(Arc::new(tls_config)))
+    }
+
+    // Check the targets with this policy (see TunnelPolicy::check), before resolving & connecting
+    // Note: with --deny-targets, a target denied by the list is refused (403) before this policy is checked
+    pub fn with_policy(mut self, policy: Arc<dyn TunnelPolicy + Send + Sync>) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    // Call this hook (see ConnectHook::on_connect) once the target of a tunnel is resolved, before connecting
+    pub fn with_connect_hook(mut self, hook: Arc<dyn ConnectHook + Send + Sync>) -> Self {
+        self.connect_hook = Some(hook);
+        self
+    }
+
+    // Send the tunnel lifecycle events (see TunnelEvent) to this channel
+    // Note: a tunnel never waits for the receiver, events are lost while the channel is full
+    pub fn with_events(mut self, events: mpsc::Sender<TunnelEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    // Open client connections (accepted, not closed yet)
+    pub fn active_connections(&self) -> usize {
+        self.tunnels.active_connections()
+    }
+
+    // Client connections accepted since start
+    pub fn total_connections(&self) -> u64 {
+        self.tunnels.total_connections()
+    }
+
+    // Refuse new requests (503) from now on, the running tunnels go on
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
+    // Bind the listeners & serve, return on a startup error (e.g. address in use, see --tls-only)
+    // or if a listener fails
+    pub async fn run(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        tunnel(self).await
+    }
+}
+
+pub async fn app_main(config: Config) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    info!("Starting http tunnel...");
+
+    #[cfg(feature = "otel")]
+    {
+        // Note: the exporter task runs on the runtime
+        if let Some(exporter) = crate::telemetry::otlp::OtlpExporter::from_env() {
+            crate::telemetry::init(Box::new(exporter));
+        }
+    }
+
+    let shutdown_grace = config.shutdown_grace;
+    let server = TunnelServer::new(config);
+    let tunnels = &server.tunnels;
+    let tunnel = server.run();
+    tokio::pin!(tunnel);
+
+    tokio::select! {
+        tunnel_result = &mut tunnel => {
+            // Note: an error is returned (exit code 1)
+            if tunnel_result.is_err() {
+                warn!("Unable to start tunnel: {:?}", tunnel_result);
+            }
+            return tunnel_result;
+        },
+        _ = signal::ctrl_c() => { info!("\nReceived [Ctrl-C]..."); },
+    };
+    info!("Connections: {} open, {} accepted since start", server.active_connections(), server.total_connections());
+
+    if !shutdown_grace.is_zero() {
+        // keep running tunnels for a while, new requests are refused (503)
+        info!("Shutting down in {:?} (press [Ctrl-C] again to quit now)...", shutdown_grace);
+        server.shutdown();
+        tokio::select! {
+            _ = &mut tunnel => {},
+            _ = drain(tunnels, shutdown_grace, DRAIN_PROGRESS_INTERVAL) => {},
+            _ = signal::ctrl_c() => {},
+        };
+    }
+    info!("Summary: {}", tunnels.summary());
+    Ok(())
+}
+
+// Build the tokio async runtime from the config - default is a multi threaded runtime
+pub fn build_runtime(config: &Config) -> std::io::Result<Runtime> {
+    let mut builder = match config.runtime_flavor {
+        RuntimeFlavor::CurrentThread => runtime::Builder::new_current_thread(),
+        RuntimeFlavor::MultiThread => {
+            let mut builder = runtime::Builder::new_multi_thread();
+            if let Some(worker_threads) = config.worker_threads {
+                builder.worker_threads(worker_threads);
+            }
+            builder
+        },
+    };
+    builder.enable_all().build()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::time::{timeout, Duration, Instant};
+    use tokio_util::sync::CancellationToken;
+    // traits
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use futures::StreamExt; // for next()
+
+    use tokio::runtime;
+
+    use crate::codec::TunnelResult;
+    use crate::config::{Config, Overload, RuntimeFlavor, TlsFiles};
+    use crate::dns::{CachingResolver, SimpleDnsResolver};
+    use crate::logger::capture;
+    use crate::connector::{Connector, TcpConnector};
+    use crate::policy::{AllowAll, ConnectHook, TargetDenylist, TunnelPolicy};
+    use crate::relay::{DirectionStats, RelayErrorKind};
+    use crate::events::{RejectReason, TunnelEvent};
+    use crate::telemetry::{self, Span, Value};
+    use crate::tls::{load_server_config, testing};
+    use crate::registry::TunnelRegistry;
+    use crate::dns::DnsResolver;
+    use super::{app_main, build_runtime, TunnelServer, connect_any, drain, heartbeat, reload_denylist, serve_tcp, serve_tls, tunnel_relay, tunnel_stream, ListenerState, TunnelOptions};
+    use super::{interleave_families, lowercase_host, request_id, request_reader, set_keepalive, sticky_order, toggle_pause, write_response, write_response_body};
+
+    // Start a tunnel on a random local port and return its address
+    async fn spawn_tunnel(config: Config) -> std::io::Result<SocketAddr> {
+        Ok(spawn_tunnel_with_shutdown(config).await?.0)
+    }
+
+    // Same as spawn_tunnel, also return the token to initiate the shutdown & the running tunnels
+    async fn spawn_tunnel_with_shutdown(config: Config)
+        -> std::io::Result<(SocketAddr, CancellationToken, TunnelRegistry)>
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let shutdown = CancellationToken::new();
+        let tunnels = TunnelRegistry::new().with_max_connections(config.max_connections);
+        let state = ListenerState::new(&listener, &config, shutdown.clone(), tunnels.clone(), Arc::new(AllowAll::default()))?;
+        tokio::spawn(serve_tcp(listener, Arc::new(config), SimpleDnsResolver::new(), state));
+        Ok((addr, shutdown, tunnels))
+    }
+
+    // Connect to a server started in the background once it listens
+    async fn connect_when_listening(addr: SocketAddr) -> Result<TcpStream, tokio::time::error::Elapsed> {
+        timeout(Duration::from_millis(500), async {
+            loop {
+                match TcpStream::connect(addr).await {
+                    Ok(socket) => return socket,
+                    Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        }).await
+    }
+
+    // Start a tls tunnel (test certificate for localhost) on a random local port and return its address
+    async fn spawn_tls_tunnel(config: Config) -> Result<SocketAddr, Box<dyn std::error::Error + Send + Sync>> {
+        let tls_config = load_server_config(testing::TEST_SERVER_CERT, testing::TEST_SERVER_KEY, &config)?;
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tls_config));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let state = ListenerState::new(&listener, &config, CancellationToken::new(), TunnelRegistry::new(),
+                                       Arc::new(AllowAll::default()))?;
+        tokio::spawn(serve_tls(listener, acceptor, Arc::new(config), SimpleDnsResolver::new(), state));
+        Ok(addr)
+    }
+
+    async fn tls_connect(addr: SocketAddr) -> std::io::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(testing::client_config()));
+        let server_name = tokio_rustls::rustls::ServerName::try_from("localhost").unwrap();
+        connector.connect(server_name, TcpStream::connect(addr).await?).await
+    }
+
+    // Upstream server that accepts connections but never sends anything
+    async fn spawn_silent_upstream() -> std::io::Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+        Ok(addr)
+    }
+
+    // Upstream server that reads each connection until EOF and reports what it received
+    async fn spawn_recording_upstream() -> std::io::Result<(SocketAddr, tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let mut received = Vec::new();
+                    let _ = socket.read_to_end(&mut received).await;
+                    let _ = tx.send(received);
+                });
+            }
+        });
+        Ok((addr, rx))
+    }
+
+    // Upstream server that closes every connection right after accept
+    async fn spawn_closing_upstream() -> std::io::Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                drop(socket);
+            }
+        });
+        Ok(addr)
+    }
+
+    #[tokio::test]
+    async fn test_upstream_closes_after_upload() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        capture::init();
+        // upstream reads an upload, then closes its side without answering
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let upstream = listener.local_addr()?;
+        let upstream_task = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await?;
+            let mut upload = vec![0u8; 6];
+            socket.read_exact(&mut upload).await?;
+            socket.shutdown().await?;
+            socket.read_to_end(&mut upload).await?;
+            Ok::<_, std::io::Error>(upload)
+        });
+        let tunnel = spawn_tunnel(Config::new("127.0.0.1:0")).await?;
+
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        client.write_all(b"upload").await?;
+        let mut rest = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut rest)).await??;
+        assert!(rest.is_empty());
+        // the client direction is still relayed
+        client.write_all(b" done").await?;
+        client.shutdown().await?;
+        let received = timeout(Duration::from_millis(500), upstream_task).await???;
+        assert_eq!(received, b"upload done");
+        assert!(capture::find(&format!("Upstream {} closed immediately", upstream)).is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_half_close() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // upstream done first (without sending anything), then reading what the client still sends
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let upstream = listener.local_addr()?;
+        let upstream_task = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await?;
+            socket.shutdown().await?;
+            let mut received = Vec::new();
+            socket.read_to_end(&mut received).await?;
+            Ok::<_, std::io::Error>(received)
+        });
+        let mut config = Config::new("127.0.0.1:0");
+        config.half_close = true;
+        let tunnel = spawn_tunnel(config.clone()).await?;
+
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+        // still open upstream
+        client.write_all(b"after half close").await?;
+        client.shutdown().await?;
+        let received = timeout(Duration::from_millis(500), upstream_task).await???;
+        assert_eq!(received, b"after half close");
+
+        // client done first, upstream answers once it got everything
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let upstream = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await?;
+            let mut received = Vec::new();
+            socket.read_to_end(&mut received).await?;
+            socket.write_all(format!("got {} bytes", received.len()).as_bytes()).await?;
+            Ok::<_, std::io::Error>(())
+        });
+        let tunnel = spawn_tunnel(config).await?;
+
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        client.write_all(b"hello").await?;
+        client.shutdown().await?;
+        let mut answer = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut answer)).await??;
+        assert_eq!(answer, b"got 5 bytes");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ok_response_before_upstream_data() -> Result<(), std::io::Error> {
+        let upstream = spawn_silent_upstream().await?;
+        let tunnel = spawn_tunnel(Config::new("127.0.0.1:0")).await?;
+
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+
+        let expected = b"HTTP/1.1 200 OK\r\n\r\n";
+        let mut response = vec![0u8; expected.len()];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        assert_eq!(response, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upstream_closes_immediately() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let upstream = spawn_closing_upstream().await?;
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(server);
+
+        let relay = tokio::spawn(tunnel_relay(reader, writer, vec![upstream], Arc::new(TcpConnector::default()), TunnelOptions::default(),
+                                              Arc::new(Config::new("127.0.0.1:0")), Span::new("tunnel", None)));
+
+        // Client gets the 200 OK then a clean close, even if it keeps its side open
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+
+        let stats = timeout(Duration::from_millis(500), relay).await???;
+        // the client side copy is stopped by the tunnel
+        assert_eq!(stats.client_to_upstream, DirectionStats { error: Some(RelayErrorKind::Closed), ..Default::default() });
+        assert_eq!(stats.upstream_to_client, DirectionStats::default());
+        Ok(())
+    }
+
+    // Connect to upstream after a delay
+    struct DelayedConnector {
+        delay: Duration,
+    }
+
+    #[async_trait::async_trait]
+    impl Connector for DelayedConnector {
+        async fn connect(&self, addr: SocketAddr) -> std::io::Result<TcpStream> {
+            tokio::time::sleep(self.delay).await;
+            TcpStream::connect(addr).await
+        }
+    }
+
+    // Resolve to addr after a delay
+    #[derive(Clone)]
+    struct DelayedResolver {
+        delay: Duration,
+        addr: SocketAddr,
+    }
+
+    #[async_trait::async_trait]
+    impl DnsResolver for DelayedResolver {
+        async fn resolve(&mut self, _target: &str) -> std::io::Result<SocketAddr> {
+            tokio::time::sleep(self.delay).await;
+            Ok(self.addr)
+        }
+    }
+
+    // Writer recording whether it was shut down
+    struct ShutdownWriter<W> {
+        inner: W,
+        shut_down: Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    impl<W: tokio::io::AsyncWrite + Unpin> tokio::io::AsyncWrite for ShutdownWriter<W> {
+        fn poll_write(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &[u8])
+            -> std::task::Poll<std::io::Result<usize>>
+        {
+            std::pin::Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            self.shut_down.store(true, std::sync::atomic::Ordering::SeqCst);
+            std::pin::Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_response_closes_client() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let upstream = spawn_echo_upstream().await?;
+        let connector = Arc::new(DelayedConnector { delay: Duration::from_millis(300) });
+        let options = TunnelOptions { deadline: Some(Instant::now() + Duration::from_millis(100)), ..Default::default() };
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(server);
+        let shut_down = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let writer = ShutdownWriter { inner: writer, shut_down: shut_down.clone() };
+        let relay = tokio::spawn(tunnel_relay(reader, writer, vec![upstream], connector, options,
+                                              Arc::new(Config::new("127.0.0.1:0")), Span::new("tunnel", None)));
+
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 504 GATEWAY_TIMEOUT\r\n\r\n");
+        assert!(timeout(Duration::from_millis(500), relay).await??.is_err());
+        assert!(shut_down.load(std::sync::atomic::Ordering::SeqCst));
+
+        // connect error
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(server);
+        let shut_down = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let writer = ShutdownWriter { inner: writer, shut_down: shut_down.clone() };
+        let relay = tokio::spawn(tunnel_relay(reader, writer, vec![closed], Arc::new(TcpConnector::default()),
+                                              TunnelOptions::default(), Arc::new(Config::new("127.0.0.1:0")),
+                                              Span::new("tunnel", None)));
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 502 BAD_GATEWAY\r\n\r\n");
+        assert!(timeout(Duration::from_millis(500), relay).await??.is_ok());
+        assert!(shut_down.load(std::sync::atomic::Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_relay_cancelled_closes_client() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let upstream = spawn_echo_upstream().await?;
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(server);
+        let relay = tokio::spawn(tunnel_relay(reader, writer, vec![upstream], Arc::new(TcpConnector::default()),
+                                              TunnelOptions::default(), Arc::new(Config::new("127.0.0.1:0")),
+                                              Span::new("tunnel", None)));
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+
+        // e.g. the tunnel task aborted: the relay copies are aborted too, the client connection is closed
+        relay.abort();
+        let mut rest = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut rest)).await??;
+        assert!(rest.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_establish_timeout() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let upstream = spawn_echo_upstream().await?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut config = Config::new("127.0.0.1:0");
+        config.establish_timeout = Duration::from_millis(200);
+        config.connect_timeout = Duration::from_secs(1);
+        let mut state = ListenerState::new(&listener, &config, CancellationToken::new(), TunnelRegistry::new(),
+                                           Arc::new(AllowAll::default()))?;
+        state.connector = Arc::new(DelayedConnector { delay: Duration::from_millis(150) });
+        let config = Arc::new(config);
+        let request = format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream);
+
+        // each step is shorter than the deadline, not both: resolve (150ms) + connect (150ms)
+        let resolver = DelayedResolver { delay: Duration::from_millis(150), addr: upstream };
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(server);
+        let start = Instant::now();
+        let tunnel = tokio::spawn(tunnel_stream(reader, writer, "127.0.0.1:4000".parse()?, resolver, config.clone(),
+                                                state.clone()));
+        client.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 504 GATEWAY_TIMEOUT\r\n\r\n");
+        let e = timeout(Duration::from_millis(500), tunnel).await??.unwrap_err().to_string();
+        assert_eq!(e, "Establishment timeout (200ms) while connecting");
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(200) && elapsed < Duration::from_millis(290), "{:?}", elapsed);
+
+        // request not sent in time
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(server);
+        let tunnel = tokio::spawn(tunnel_stream(reader, writer, "127.0.0.1:4000".parse()?, SimpleDnsResolver::new(),
+                                                config.clone(), state.clone()));
+        client.write_all(b"CONNECT ").await?;
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 408 REQUEST_TIMEOUT\r\n\r\n");
+        assert!(timeout(Duration::from_millis(500), tunnel).await??.is_err());
+
+        // fast enough
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(server);
+        let tunnel = tokio::spawn(tunnel_stream(reader, writer, "127.0.0.1:4000".parse()?, SimpleDnsResolver::new(),
+                                                config, state));
+        client.write_all(request.as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+        drop(client);
+        timeout(Duration::from_millis(500), tunnel).await???;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout_closes_socket() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // blackholed: once the accept queue of a listener is full, connection attempts (SYN) are dropped
+        let socket = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None)?;
+        socket.bind(&"127.0.0.1:0".parse::<SocketAddr>()?.into())?;
+        socket.listen(0)?;
+        let blackhole = socket.local_addr()?.as_socket().unwrap();
+        let mut queued = Vec::new();
+        for _ in 0..3 {
+            if let Ok(Ok(stream)) = timeout(Duration::from_millis(100), TcpStream::connect(blackhole)).await {
+                queued.push(stream);
+            }
+        }
+
+        // a free local port for the upstream socket
+        let local = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let connector = TcpConnector::bound(Some(local));
+        let connected = connect_any(&connector, &[blackhole], 1, Duration::from_millis(100)).await;
+        assert_eq!(connected.err(), Some(TunnelResult::BadRequest));
+
+        // the socket is closed: its local port can be bound again
+        std::net::TcpListener::bind(local)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_listener_connect_timeouts() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // blackholed upstream (see test_connect_timeout_closes_socket)
+        let socket = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None)?;
+        socket.bind(&"127.0.0.1:0".parse::<SocketAddr>()?.into())?;
+        socket.listen(0)?;
+        let blackhole = socket.local_addr()?.as_socket().unwrap();
+        let mut queued = Vec::new();
+        for _ in 0..3 {
+            if let Ok(Ok(stream)) = timeout(Duration::from_millis(100), TcpStream::connect(blackhole)).await {
+                queued.push(stream);
+            }
+        }
+
+        let config = Config::from_args([
+            "127.0.0.1:0", "--listen", "127.0.0.2:0", "--connect-timeout", "0.1",
+            "--listener-option", "127.0.0.2:0/connect-timeout=0.4",
+        ].iter().map(|arg| arg.to_string()))?;
+        let shutdown = CancellationToken::new();
+        let mut addrs = Vec::new();
+        for addr in ["127.0.0.1:0", "127.0.0.2:0"] {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            addrs.push(listener.local_addr()?);
+            let listener_config = config.for_listener(addr)?;
+            let state = ListenerState::new(&listener, &listener_config, shutdown.clone(), TunnelRegistry::new(),
+                                           Arc::new(AllowAll::default()))?;
+            tokio::spawn(serve_tcp(listener, Arc::new(listener_config), SimpleDnsResolver::new(), state));
+        }
+
+        // each listener gives up on the upstream after its own connect timeout
+        let mut elapsed = Vec::new();
+        for addr in addrs {
+            let mut client = TcpStream::connect(addr).await?;
+            let start = Instant::now();
+            client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", blackhole).as_bytes()).await?;
+            let mut response = Vec::new();
+            timeout(Duration::from_secs(1), client.read_to_end(&mut response)).await??;
+            assert!(!response.starts_with(b"HTTP/1.1 200"), "{:?}", String::from_utf8_lossy(&response));
+            elapsed.push(start.elapsed());
+        }
+        assert!(elapsed[0] >= Duration::from_millis(100) && elapsed[0] < Duration::from_millis(300), "{:?}", elapsed);
+        assert!(elapsed[1] >= Duration::from_millis(400), "{:?}", elapsed);
+        shutdown.cancel();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_target_timeouts() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let upstream = spawn_echo_upstream().await?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let config = Config::from_args([
+            "127.0.0.1:0", "--connect-timeout", "0.1", "--target-timeout", "*.slow.example.com=0.5",
+            "--target-timeout", "api.example.com=0.5,0.05",
+        ].iter().map(|arg| arg.to_string()))?;
+        let mut state = ListenerState::new(&listener, &config, CancellationToken::new(), TunnelRegistry::new(),
+                                           Arc::new(AllowAll::default()))?;
+        // every connection takes 200ms
+        state.connector = Arc::new(DelayedConnector { delay: Duration::from_millis(200) });
+        let config = Arc::new(config);
+        let resolver = DelayedResolver { delay: Duration::ZERO, addr: upstream };
+
+        let cases = [
+            // matching: longer connect timeout
+            ("db.slow.example.com:443", &b"HTTP/1.1 200 OK\r\n\r\n"[..]),
+            // not matching: default connect timeout (see connect_any)
+            ("example.com:443", &b"HTTP/1.1 400 BAD_REQUEST\r\n\r\n"[..]),
+            // matching: longer connect timeout, shorter establishment timeout
+            ("api.example.com:443", &b"HTTP/1.1 504 GATEWAY_TIMEOUT\r\n\r\n"[..]),
+        ];
+        for (target, expected) in cases {
+            let (mut client, server) = tokio::io::duplex(1024);
+            let (reader, writer) = tokio::io::split(server);
+            let tunnel = tokio::spawn(tunnel_stream(reader, writer, "127.0.0.1:4000".parse()?, resolver.clone(),
+                                                    config.clone(), state.clone()));
+            client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", target).as_bytes()).await?;
+            let mut response = vec![0u8; expected.len()];
+            timeout(Duration::from_secs(1), client.read_exact(&mut response)).await??;
+            assert_eq!(response, expected, "{}", target);
+            drop(client);
+            let _ = timeout(Duration::from_secs(1), tunnel).await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_duration() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let upstream = spawn_closing_upstream().await?;
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(server);
+
+        let delay = Duration::from_millis(50);
+        let relay = tokio::spawn(tunnel_relay(reader, writer, vec![upstream], Arc::new(DelayedConnector { delay }), TunnelOptions::default(),
+                                              Arc::new(Config::new("127.0.0.1:0")), Span::new("tunnel", None)));
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+
+        let stats = timeout(Duration::from_millis(500), relay).await???;
+        assert!(stats.connect_duration >= delay, "{:?}", stats.connect_duration);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mirror() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let (upstream, mut upstream_received) = spawn_recording_upstream().await?;
+        let (sink, mut sink_received) = spawn_recording_upstream().await?;
+        let mut config = Config::new("127.0.0.1:0");
+        config.mirror_sink = Some(format!("tcp://{}", sink).parse()?);
+        config.strict_head = true; // keep the early data
+        let tunnel = spawn_tunnel(config).await?;
+
+        let mut client = TcpStream::connect(tunnel).await?;
+        // with early data
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\nhello", upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+        client.write_all(b" world").await?;
+        client.shutdown().await?;
+
+        let received = timeout(Duration::from_millis(500), upstream_received.recv()).await?;
+        assert_eq!(received.as_deref(), Some(&b"hello world"[..]));
+        let mirrored = timeout(Duration::from_millis(500), sink_received.recv()).await?;
+        assert_eq!(mirrored, received);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let (upstream, mut upstream_received) = spawn_recording_upstream().await?;
+        let mut config = Config::new("127.0.0.1:0");
+        config.dry_run = true;
+        let tunnel = spawn_tunnel(config).await?;
+
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+        // data sent after the response is never relayed
+        let _ = client.write_all(b"hello").await;
+        let received = timeout(Duration::from_millis(500), upstream_received.recv()).await?;
+        assert_eq!(received, Some(vec![]));
+
+        // unresolvable target
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(b"CONNECT unknown.invalid:80 HTTP/1.1\r\n\r\n").await?;
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(2000), client.read_to_end(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 502 BAD_GATEWAY\r\n\r\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_peer_not_allowed() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let upstream = spawn_silent_upstream().await?;
+        let mut config = Config::new("127.0.0.1:0");
+        config.peer_allowlist.add("10.0.0.0/8".parse()?);
+        let tunnel = spawn_tunnel(config.clone()).await?;
+
+        // closed right away, before reading any request
+        let mut client = TcpStream::connect(tunnel).await?;
+        let mut response = Vec::new();
+        let _ = timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await?;
+        assert!(response.is_empty());
+
+        // closed after a 403
+        config.peer_reject_response = true;
+        let tunnel = spawn_tunnel(config.clone()).await?;
+        let mut client = TcpStream::connect(tunnel).await?;
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 403 FORBIDDEN\r\n\r\n");
+
+        // allowed
+        config.peer_allowlist.add("127.0.0.0/8".parse()?);
+        let tunnel = spawn_tunnel(config).await?;
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_client_addr() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let upstream = spawn_silent_upstream().await?;
+        let mut config = Config::new("127.0.0.1:0");
+        config.proxy_protocol = true;
+        config.peer_allowlist.add("10.0.0.0/8".parse()?);
+        let tunnel = spawn_tunnel(config).await?;
+
+        // real client (from the load balancer header) is allowed
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(b"PROXY TCP4 10.1.2.3 10.0.0.1 56324 6161\r\n").await?;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+
+        // real client is not allowed
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(b"PROXY TCP4 192.168.1.1 10.0.0.1 56324 6161\r\n").await?;
+        let mut response = Vec::new();
+        let _ = timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await?;
+        assert!(response.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tarpit() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut config = Config::new("127.0.0.1:0");
+        config.peer_allowlist.add("10.0.0.0/8".parse()?);
+        config.peer_reject_response = true;
+        config.tarpit = Duration::from_millis(300);
+        let tunnel = spawn_tunnel(config).await?;
+
+        let start = Instant::now();
+        let mut client = TcpStream::connect(tunnel).await?;
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(2000), client.read_to_end(&mut response)).await??;
+        let elapsed = start.elapsed();
+
+        assert_eq!(response, b"HTTP/1.1 403 FORBIDDEN\r\n\r\n");
+        assert!(elapsed >= Duration::from_millis(300), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1000), "{:?}", elapsed);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_spans() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        telemetry::capture::init();
+        let (upstream, mut upstream_received) = spawn_recording_upstream().await?;
+        let tunnel = spawn_tunnel(Config::new("127.0.0.1:0")).await?;
+
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        client.write_all(b"hello").await?;
+        client.shutdown().await?;
+        timeout(Duration::from_millis(500), upstream_received.recv()).await?;
+
+        // spans are exported when the tunnel task is done
+        let target = Value::from(upstream.to_string());
+        let mut spans = Vec::new();
+        for _ in 0..50 {
+            spans = telemetry::capture::find_traces("target", &target);
+            if spans.iter().any(|s| s.name == "tunnel") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let names: Vec<&str> = spans.iter().map(|s| s.name).collect();
+        assert_eq!(names, vec!["resolve", "connect", "relay", "tunnel"]);
+        let root = &spans[3];
+        assert_eq!(root.attribute("status"), Some(&Value::Int(200)));
+        assert_eq!(root.attribute("bytes"), Some(&Value::Int(5)));
+        assert!(spans[..3].iter().all(|s| s.parent_span_id == Some(root.context.span_id)));
+        assert_eq!(spans[0].attribute("status"), Some(&Value::from("ok")));
+        assert_eq!(spans[2].attribute("bytes_client_to_upstream"), Some(&Value::Int(5)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_target_is_proxy() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let tunnel = spawn_tunnel(Config::new("127.0.0.1:0")).await?;
+
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", tunnel).as_bytes()).await?;
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 403 FORBIDDEN\r\n\r\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_tunnel_bytes() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let (upstream, mut upstream_received) = spawn_recording_upstream().await?;
+        let mut config = Config::new("127.0.0.1:0");
+        config.max_tunnel_bytes = 1000;
+        let tunnel = spawn_tunnel(config).await?;
+
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+
+        // Note: the tunnel may close before the whole payload is written
+        let _ = client.write_all(&[1u8; 100_000]).await;
+        let received = timeout(Duration::from_millis(1000), upstream_received.recv()).await?;
+        assert_eq!(received.map(|r| r.len()), Some(1000));
+
+        // tunnel is torn down: client gets EOF
+        let mut rest = Vec::new();
+        let _ = timeout(Duration::from_millis(1000), client.read_to_end(&mut rest)).await?;
+        assert!(rest.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_relay_buffer_per_direction() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // Upstream sends a large payload (e.g. a download) then reads until EOF
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let upstream = listener.local_addr()?;
+        tokio::spawn(async move {
+            if let Ok((mut socket, _)) = listener.accept().await {
+                let _ = socket.write_all(&[2u8; 64 * 1024]).await;
+                let mut received = Vec::new();
+                let _ = socket.read_to_end(&mut received).await;
+            }
+        });
+
+        let mut config = Config::new("127.0.0.1:0");
+        config.relay_buffer_client_to_upstream = 1024;
+        config.relay_buffer_upstream_to_client = 4096;
+        let (mut client, server) = tokio::io::duplex(256 * 1024);
+        let (reader, writer) = tokio::io::split(server);
+        let relay = tokio::spawn(tunnel_relay(reader, writer, vec![upstream], Arc::new(TcpConnector::default()), TunnelOptions::default(), Arc::new(config), Span::new("tunnel", None)));
+
+        client.write_all(&[1u8; 20_000]).await?;
+        let mut received = vec![0u8; 19 + 64 * 1024];
+        timeout(Duration::from_millis(1000), client.read_exact(&mut received)).await??;
+        client.shutdown().await?;
+
+        let stats = timeout(Duration::from_millis(1000), relay).await???;
+        assert_eq!(stats.client_to_upstream.bytes, 20_000);
+        assert_eq!(stats.client_to_upstream.peak_chunk, 1024);
+        assert_eq!(stats.upstream_to_client.bytes, 64 * 1024);
+        assert_eq!(stats.upstream_to_client.peak_chunk, 4096);
+        Ok(())
+    }
+
+    struct FailingClient {
+        // bytes accepted before the writes fail
+        accepted: usize,
+    }
+
+    impl tokio::io::AsyncRead for FailingClient {
+        fn poll_read(self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>, _buf: &mut tokio::io::ReadBuf<'_>)
+            -> std::task::Poll<std::io::Result<()>>
+        {
+            std::task::Poll::Ready(Err(std::io::ErrorKind::ConnectionReset.into()))
+        }
+    }
+
+    impl tokio::io::AsyncWrite for FailingClient {
+        fn poll_write(mut self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>, buf: &[u8])
+            -> std::task::Poll<std::io::Result<usize>>
+        {
+            if self.accepted < buf.len() {
+                return std::task::Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+            }
+            self.accepted -= buf.len();
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_errors_both_directions() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        capture::init();
+        // Upstream sends data right away, the client can neither send nor receive
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let upstream = listener.local_addr()?;
+        tokio::spawn(async move {
+            if let Ok((mut socket, _)) = listener.accept().await {
+                let _ = socket.write_all(b"hello").await;
+                let _ = socket.read_to_end(&mut Vec::new()).await;
+            }
+        });
+
+        let (reader, writer) = tokio::io::split(FailingClient { accepted: 19 });
+        let relay = tunnel_relay(reader, writer, vec![upstream], Arc::new(TcpConnector::default()), TunnelOptions::default(),
+                                 Arc::new(Config::new("127.0.0.1:0")), Span::new("tunnel", None));
+        let stats = timeout(Duration::from_millis(1000), relay).await??;
+        assert_eq!(stats.client_to_upstream.error, Some(RelayErrorKind::Transport));
+        assert_eq!(stats.upstream_to_client.error, Some(RelayErrorKind::Transport));
+
+        let logs = capture::find(&format!("Relay error to {}", upstream));
+        assert_eq!(logs.len(), 1, "{:?}", logs);
+        assert!(logs[0].contains(&format!(", from {} (transport error)", upstream)), "{:?}", logs);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_refuses_new_requests() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let upstream = spawn_silent_upstream().await?;
+        let (tunnel, shutdown, _) = spawn_tunnel_with_shutdown(Config::new("127.0.0.1:0")).await?;
+
+        // tunnel opened before the shutdown
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+
+        shutdown.cancel();
+        let mut new_client = TcpStream::connect(tunnel).await?;
+        new_client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(500), new_client.read_to_end(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 503 SERVICE_UNAVAILABLE\r\n\r\n");
+
+        // running tunnel is still open
+        client.write_all(b"hello").await?;
+        assert!(timeout(Duration::from_millis(100), client.read(&mut [0u8; 1])).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tls_handshake_timeout() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let upstream = spawn_silent_upstream().await?;
+        let mut config = Config::new("127.0.0.1:0");
+        config.tls_handshake_timeout = Duration::from_millis(200);
+        let tunnel = spawn_tls_tunnel(config).await?;
+
+        // connected but never sends a ClientHello
+        let mut stalled = TcpStream::connect(tunnel).await?;
+
+        // server keeps accepting (and handshaking) other clients
+        let mut client = timeout(Duration::from_millis(500), tls_connect(tunnel)).await??;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+
+        // stalled connection is closed once the handshake timed out
+        let start = Instant::now();
+        let n = timeout(Duration::from_millis(1000), stalled.read(&mut [0u8; 16])).await??;
+        assert_eq!(n, 0);
+        assert!(start.elapsed() < Duration::from_millis(500));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tls_stalled_clients_do_not_block_accept() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let upstream = spawn_silent_upstream().await?;
+        let mut config = Config::new("127.0.0.1:0");
+        config.proxy_protocol = true;
+        let tunnel = spawn_tls_tunnel(config).await?;
+
+        // never sends the PROXY header
+        let _stalled_proxy = TcpStream::connect(tunnel).await?;
+        // sends the PROXY header then a truncated ClientHello (tls record header only)
+        let mut stalled_tls = TcpStream::connect(tunnel).await?;
+        stalled_tls.write_all(b"PROXY TCP4 10.0.0.1 10.0.0.2 4000 443\r\n").await?;
+        stalled_tls.write_all(&[0x16, 0x03, 0x01, 0x02, 0x00]).await?;
+
+        for _ in 0..3 {
+            let mut tcp = TcpStream::connect(tunnel).await?;
+            tcp.write_all(b"PROXY TCP4 10.0.0.3 10.0.0.2 4001 443\r\n").await?;
+            let connector = tokio_rustls::TlsConnector::from(Arc::new(testing::client_config()));
+            let server_name = tokio_rustls::rustls::ServerName::try_from("localhost").unwrap();
+            let mut client = timeout(Duration::from_millis(500), connector.connect(server_name, tcp)).await??;
+
+            client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+            let mut response = vec![0u8; 19];
+            timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+            assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tls_error_during_relay() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        capture::init();
+        let (upstream, _upstream_received) = spawn_recording_upstream().await?;
+        let tunnel = spawn_tls_tunnel(Config::new("127.0.0.1:0")).await?;
+
+        let mut client = tls_connect(tunnel).await?;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+
+        // malformed record mid-session (application data that cannot be decrypted)
+        let (tcp, _) = client.get_mut();
+        tcp.write_all(&[0x17, 0x03, 0x03, 0x00, 0x20]).await?;
+        tcp.write_all(&[0u8; 32]).await?;
+        let _ = timeout(Duration::from_millis(500), tcp.read_to_end(&mut Vec::new())).await;
+
+        // Note: logged once both directions are done
+        let mut logs = Vec::new();
+        for _ in 0..50 {
+            logs = capture::find(&format!("Relay error to {}", upstream));
+            if !logs.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(logs.len(), 1, "{:?}", logs);
+        assert!(logs[0].contains("(TLS protocol error)"), "{:?}", logs);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_method_not_allowed() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let tunnel = spawn_tunnel(Config::new("127.0.0.1:0")).await?;
+
+        // Note: no line end, rejected from the method alone
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(b"POST /upload").await?;
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 405 METHOD_NOT_ALLOWED\r\nContent-Type: text/plain\r\nContent-Length: 45\r\n\r\n\
+                               method not allowed, only CONNECT is supported");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_options_asterisk() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let tunnel = spawn_tunnel(Config::new("127.0.0.1:0")).await?;
+
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(b"OPTIONS * HTTP/1.1\r\nHost: proxy\r\n\r\n").await?;
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\nAllow: CONNECT, OPTIONS\r\nContent-Length: 0\r\n\r\n");
+
+        // not a proxy probe
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(b"OPTIONS http://example.com/ HTTP/1.1\r\n\r\n").await?;
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await??;
+        assert!(response.starts_with(b"HTTP/1.1 405 METHOD_NOT_ALLOWED\r\n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_malformed_request_body() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let tunnel = spawn_tunnel(Config::new("127.0.0.1:0")).await?;
+
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(b"CONNECT example.com:443 HTTP/1.0\r\n\r\n").await?;
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await??;
+        let response = String::from_utf8(response)?;
+        assert!(response.starts_with("HTTP/1.1 400 BAD_REQUEST\r\n"), "{}", response);
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        assert_eq!(body, "invalid request line, expected: CONNECT host:port HTTP/1.1");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_clean_close() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // Upstream keeps its side open, report whether it sees a clean EOF
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let upstream = listener.local_addr()?;
+        let (tx, mut upstream_eof) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            if let Ok((mut socket, _)) = listener.accept().await {
+                let mut received = Vec::new();
+                let _ = tx.send(socket.read_to_end(&mut received).await.map(|_| received));
+                // still open
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        });
+
+        let mut config = Config::new("127.0.0.1:0");
+        config.idle_timeout = Duration::from_millis(200);
+        let tunnel = spawn_tunnel(config).await?;
+
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        client.write_all(b"hello").await?;
+
+        // both peers see a clean close (EOF, not a reset) once idle
+        let start = Instant::now();
+        let mut rest = Vec::new();
+        timeout(Duration::from_millis(2000), client.read_to_end(&mut rest)).await??;
+        assert!(rest.is_empty());
+        assert!(start.elapsed() >= Duration::from_millis(150), "{:?}", start.elapsed());
+        let received = timeout(Duration::from_millis(500), upstream_eof.recv()).await?.unwrap()?;
+        assert_eq!(received, b"hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_relay_keepalive() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let socket = TcpStream::connect(listener.local_addr()?).await?;
+        let mut config = Config::new("127.0.0.1:0");
+        set_keepalive(&socket, &config)?;
+        assert!(!socket2::SockRef::from(&socket).keepalive()?);
+        config.relay_keepalive = Duration::from_secs(30);
+        set_keepalive(&socket, &config)?;
+        assert!(socket2::SockRef::from(&socket).keepalive()?);
+
+        // idle for longer than the idle timeout: kept alive, still relaying
+        let upstream = spawn_echo_upstream().await?;
+        config.idle_timeout = Duration::from_millis(100);
+        let tunnel = spawn_tunnel(config).await?;
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        client.write_all(b"hello").await?;
+        let mut echoed = vec![0u8; 5];
+        timeout(Duration::from_millis(500), client.read_exact(&mut echoed)).await??;
+        assert_eq!(echoed, b"hello");
+        Ok(())
+    }
+
+    // Connect to upstream, keeping a handle on each connected socket (same socket, to check its options)
+    #[cfg(target_os = "linux")]
+    #[derive(Default)]
+    struct HandleConnector {
+        sockets: std::sync::Mutex<Vec<std::net::TcpStream>>,
+    }
+
+    #[cfg(target_os = "linux")]
+    #[async_trait::async_trait]
+    impl Connector for HandleConnector {
+        async fn connect(&self, addr: SocketAddr) -> std::io::Result<TcpStream> {
+            let stream = TcpStream::connect(addr).await?.into_std()?;
+            self.sockets.lock().unwrap().push(stream.try_clone()?);
+            TcpStream::from_std(stream)
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_tcp_user_timeout() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let upstream = spawn_echo_upstream().await?;
+        let mut config = Config::new("127.0.0.1:0");
+        config.tcp_user_timeout = Duration::from_secs(20);
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut state = ListenerState::new(&listener, &config, CancellationToken::new(), TunnelRegistry::new(),
+                                           Arc::new(AllowAll::default()))?;
+        let connector = Arc::new(HandleConnector::default());
+        state.connector = connector.clone();
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(server);
+        let peer = "127.0.0.1:4000".parse()?;
+        let tunnel = tokio::spawn(tunnel_stream(reader, writer, peer, SimpleDnsResolver::new(), Arc::new(config), state));
+
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+        let sockets = std::mem::take(&mut *connector.sockets.lock().unwrap());
+        assert_eq!(sockets.len(), 1);
+        assert_eq!(socket2::SockRef::from(&sockets[0]).tcp_user_timeout()?, Some(Duration::from_secs(20)));
+        drop(client);
+        let _ = timeout(Duration::from_millis(500), tunnel).await?;
+
+        // not set by default
+        let socket = TcpStream::connect(listener.local_addr()?).await?;
+        super::set_user_timeout(&socket, &Config::new("127.0.0.1:0"))?;
+        assert_eq!(socket2::SockRef::from(&socket).tcp_user_timeout()?, None);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_socket_buffer_sizes() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let upstream = spawn_echo_upstream().await?;
+        let mut config = Config::new("127.0.0.1:0");
+        // Note: below the usual rmem_max (212992) so it is not capped
+        config.socket_recv_buffer = Some(64 * 1024);
+        config.socket_send_buffer = Some(32 * 1024);
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut state = ListenerState::new(&listener, &config, CancellationToken::new(), TunnelRegistry::new(),
+                                           Arc::new(AllowAll::default()))?;
+        let connector = Arc::new(HandleConnector::default());
+        state.connector = connector.clone();
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(server);
+        let peer = "127.0.0.1:4000".parse()?;
+        let tunnel = tokio::spawn(tunnel_stream(reader, writer, peer, SimpleDnsResolver::new(), Arc::new(config), state));
+
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+        let sockets = std::mem::take(&mut *connector.sockets.lock().unwrap());
+        assert_eq!(sockets.len(), 1);
+        // Note: linux reports twice the configured size (bookkeeping overhead)
+        let recv_buffer = socket2::SockRef::from(&sockets[0]).recv_buffer_size()?;
+        assert!((64 * 1024..=2 * 64 * 1024).contains(&recv_buffer), "{}", recv_buffer);
+        let send_buffer = socket2::SockRef::from(&sockets[0]).send_buffer_size()?;
+        assert!((32 * 1024..=2 * 32 * 1024).contains(&send_buffer), "{}", send_buffer);
+        drop(client);
+        let _ = timeout(Duration::from_millis(500), tunnel).await?;
+        Ok(())
+    }
+
+    // Accept at most a few bytes per write call
+    #[derive(Default)]
+    struct TrickleWriter {
+        written: Vec<u8>,
+    }
+
+    impl tokio::io::AsyncWrite for TrickleWriter {
+        fn poll_write(mut self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>, buf: &[u8])
+            -> std::task::Poll<std::io::Result<usize>>
+        {
+            let n = buf.len().min(3);
+            self.written.extend_from_slice(&buf[..n]);
+            std::task::Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_response_partial_writes() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut config = Config::new("127.0.0.1:0");
+        config.proxy_agent = Some("rust_http_tunnel".to_string());
+        let mut writer = TrickleWriter::default();
+        write_response(&mut writer, TunnelResult::Ok, &config).await?;
+        assert_eq!(writer.written, b"HTTP/1.1 200 OK\r\nProxy-Agent: rust_http_tunnel\r\n\r\n");
+
+        let body = "request line too large, ".repeat(10);
+        let mut writer = TrickleWriter::default();
+        write_response_body(&mut writer, TunnelResult::BadRequest, &body, &config).await?;
+        let written = String::from_utf8(writer.written)?;
+        assert!(written.starts_with("HTTP/1.1 400 BAD_REQUEST\r\n"), "{}", written);
+        assert!(written.ends_with(&format!("\r\n\r\n{}", body)), "{}", written);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_request_buffer_size() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut config = Config::new("127.0.0.1:0");
+        config.request_buffer_size = 16;
+        let (mut client, reader) = tokio::io::duplex(1024);
+        let mut fr = request_reader(reader, &config);
+        assert_eq!(fr.read_buffer().capacity(), 16);
+
+        // larger than the buffer: several reads
+        let request = "CONNECT a-rather-long-hostname.example.com:443 HTTP/1.1\r\nHost: a-rather-long-hostname.example.com\r\n\r\n";
+        for chunk in request.as_bytes().chunks(10) {
+            client.write_all(chunk).await?;
+        }
+        let target = timeout(Duration::from_millis(500), fr.next()).await?.unwrap()?;
+        assert_eq!(target.target(), Some("a-rather-long-hostname.example.com:443"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_tunnels_per_host() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let (hot, other) = (spawn_echo_upstream().await?, spawn_echo_upstream().await?);
+        let mut config = Config::new("127.0.0.1:0");
+        config.max_tunnels_per_host = 1;
+        let tunnel = spawn_tunnel(config).await?;
+
+        let connect = |upstream: SocketAddr| async move {
+            let mut client = TcpStream::connect(tunnel).await?;
+            client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+            let mut response = vec![0u8; 19];
+            timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>((client, String::from_utf8(response)?))
+        };
+
+        let (first, response) = connect(hot).await?;
+        assert_eq!(response, "HTTP/1.1 200 OK\r\n\r\n");
+        let (_, response) = connect(hot).await?;
+        assert!(response.starts_with("HTTP/1.1 503 "), "{}", response);
+        // another host is not throttled
+        let (_other, response) = connect(other).await?;
+        assert_eq!(response, "HTTP/1.1 200 OK\r\n\r\n");
+
+        // available again once the first tunnel is closed
+        drop(first);
+        let mut response = String::new();
+        for _ in 0..50 {
+            response = connect(hot).await?.1;
+            if response.starts_with("HTTP/1.1 200 ") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(response, "HTTP/1.1 200 OK\r\n\r\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fallback() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // nothing listening on primary (connection refused)
+        let primary = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let fallback = spawn_echo_upstream().await?;
+        let mut config = Config::new("127.0.0.1:0");
+        config.rewrites.add_fallback(&primary.to_string(), &fallback.to_string());
+        let tunnel = spawn_tunnel(config).await?;
+
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", primary).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+        // relayed to the fallback
+        client.write_all(b"hello").await?;
+        let mut echoed = vec![0u8; 5];
+        timeout(Duration::from_millis(500), client.read_exact(&mut echoed)).await??;
+        assert_eq!(echoed, b"hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client_deadline() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let upstream = spawn_echo_upstream().await?;
+        let mut config = Config::new("127.0.0.1:0");
+        config.max_tunnel_lifetime = Duration::from_millis(1500);
+        config.strict_head = true;
+        let tunnel = spawn_tunnel(config).await?;
+
+        // (deadline header, expected lifetime): the client deadline is capped by the max lifetime
+        for (deadline, lifetime) in [("300", 300), ("60000", 1500)] {
+            let mut client = TcpStream::connect(tunnel).await?;
+            let start = Instant::now();
+            let request = format!("CONNECT {} HTTP/1.1\r\nX-Tunnel-Deadline-Ms: {}\r\n\r\n", upstream, deadline);
+            client.write_all(request.as_bytes()).await?;
+            let mut response = vec![0u8; 19];
+            timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+            assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+
+            // the tunnel works until the deadline, then the client sees a clean close
+            client.write_all(b"hello").await?;
+            let mut echoed = vec![0u8; 5];
+            client.read_exact(&mut echoed).await?;
+            assert_eq!(echoed, b"hello");
+            let mut rest = Vec::new();
+            timeout(Duration::from_millis(lifetime + 1000), client.read_to_end(&mut rest)).await??;
+            let elapsed = start.elapsed();
+            assert!(elapsed >= Duration::from_millis(lifetime), "{:?}", elapsed);
+            assert!(elapsed < Duration::from_millis(lifetime + 500), "{:?}", elapsed);
+        }
+
+        let mut client = TcpStream::connect(tunnel).await?;
+        let request = format!("CONNECT {} HTTP/1.1\r\nX-Tunnel-Deadline-Ms: soon\r\n\r\n", upstream);
+        client.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        timeout(Duration::from_millis(500), client.read_to_string(&mut response)).await??;
+        assert!(response.starts_with("HTTP/1.1 400 BAD_REQUEST\r\n"), "{}", response);
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_runtime() -> std::io::Result<()> {
+        let mut config = Config::new("127.0.0.1:0");
+        config.runtime_flavor = RuntimeFlavor::CurrentThread;
+        let rt = build_runtime(&config)?;
+        assert_eq!(rt.handle().runtime_flavor(), runtime::RuntimeFlavor::CurrentThread);
+        // io & time drivers are enabled
+        rt.block_on(async { tokio::time::sleep(Duration::from_millis(1)).await; TcpListener::bind("127.0.0.1:0").await })?;
+
+        config.runtime_flavor = RuntimeFlavor::MultiThread;
+        config.worker_threads = Some(3);
+        let rt = build_runtime(&config)?;
+        assert_eq!(rt.handle().runtime_flavor(), runtime::RuntimeFlavor::MultiThread);
+        assert_eq!(rt.metrics().num_workers(), 3);
+        Ok(())
+    }
+
+    // Tls upstream (test certificate for localhost) echoing back what it receives
+    async fn spawn_tls_echo_upstream() -> Result<SocketAddr, Box<dyn std::error::Error + Send + Sync>> {
+        let tls_config = load_server_config(testing::TEST_SERVER_CERT, testing::TEST_SERVER_KEY,
+                                            &Config::new("127.0.0.1:0"))?;
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tls_config));
+        let listener = TcpListener::bind("localhost:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(stream) = acceptor.accept(socket).await else { return };
+                    let (mut reader, mut writer) = tokio::io::split(stream);
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+        Ok(addr)
+    }
+
+    #[tokio::test]
+    async fn test_upstream_tls() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let upstream = spawn_tls_echo_upstream().await?;
+        let mut config = Config::new("127.0.0.1:0");
+        config.upstream_tls_hosts.push("localhost".to_string());
+        config.upstream_tls_ca = Some(testing::TEST_CA_CERT.to_string());
+        let tunnel = spawn_tunnel(config).await?;
+
+        // Plain data from the client, tls to upstream
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(format!("CONNECT localhost:{} HTTP/1.1\r\n\r\n", upstream.port()).as_bytes()).await?;
+        let mut response = [0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        assert_eq!(&response, b"HTTP/1.1 200 OK\r\n\r\n");
+
+        client.write_all(b"hello").await?;
+        let mut echoed = [0u8; 5];
+        timeout(Duration::from_millis(500), client.read_exact(&mut echoed)).await??;
+        assert_eq!(&echoed, b"hello");
+
+        // Upstream certificate not trusted: 502
+        let mut config = Config::new("127.0.0.1:0");
+        config.upstream_tls_hosts.push("localhost".to_string());
+        config.upstream_tls_ca = Some(testing::TEST_SERVER_CERT.to_string());
+        let tunnel = spawn_tunnel(config).await?;
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(format!("CONNECT localhost:{} HTTP/1.1\r\n\r\n", upstream.port()).as_bytes()).await?;
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await??;
+        assert!(response.starts_with(b"HTTP/1.1 502"), "{:?}", String::from_utf8_lossy(&response));
+        Ok(())
+    }
+
+    // Upstream echoing back what it receives, closes once the client is done (EOF)
+    async fn spawn_echo_upstream() -> std::io::Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = socket.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+        Ok(addr)
+    }
+
+    #[tokio::test]
+    async fn test_connection_counters() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let upstream = spawn_echo_upstream().await?;
+        let (tunnel, _shutdown, tunnels) = spawn_tunnel_with_shutdown(Config::new("127.0.0.1:0")).await?;
+        // counters are updated by the accept loop & the connection tasks
+        let wait_for = |active: usize, total: u64| {
+            let tunnels = tunnels.clone();
+            async move {
+                while tunnels.active_connections() != active || tunnels.total_connections() != total {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            }
+        };
+
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        // connected, no request yet
+        let idle = TcpStream::connect(tunnel).await?;
+        timeout(Duration::from_millis(500), wait_for(2, 2)).await?;
+        assert_eq!(tunnels.active().len(), 1);
+
+        drop(client);
+        timeout(Duration::from_millis(500), wait_for(1, 2)).await?;
+        drop(idle);
+        timeout(Duration::from_millis(500), wait_for(0, 2)).await?;
+        assert!(tunnels.active().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_connections_reject() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let upstream = spawn_echo_upstream().await?;
+        let mut config = Config::new("127.0.0.1:0");
+        config.max_connections = 1;
+        config.on_overload = Overload::Reject;
+        let (tunnel, _shutdown, tunnels) = spawn_tunnel_with_shutdown(config).await?;
+
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+
+        // no request needed
+        let mut second = TcpStream::connect(tunnel).await?;
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(500), second.read_to_end(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 503 SERVICE_UNAVAILABLE\r\n\r\n");
+        assert_eq!(tunnels.total_connections(), 2);
+
+        // the slot is released once the first one is closed
+        drop(client);
+        timeout(Duration::from_millis(500), async {
+            while tunnels.active_connections() != 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await?;
+        let mut third = TcpStream::connect(tunnel).await?;
+        third.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), third.read_exact(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_connections_queue() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let upstream = spawn_echo_upstream().await?;
+        let mut config = Config::new("127.0.0.1:0");
+        config.max_connections = 1;
+        config.on_overload = Overload::Queue;
+        let tunnel = spawn_tunnel(config).await?;
+
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+
+        // waiting (its request is not read yet)
+        let mut second = TcpStream::connect(tunnel).await?;
+        second.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        assert!(timeout(Duration::from_millis(200), second.read_exact(&mut response)).await.is_err());
+
+        drop(client);
+        timeout(Duration::from_millis(500), second.read_exact(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+        second.write_all(b"hello").await?;
+        let mut echoed = vec![0u8; 5];
+        timeout(Duration::from_millis(500), second.read_exact(&mut echoed)).await??;
+        assert_eq!(echoed, b"hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_summary() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let upstream = spawn_echo_upstream().await?;
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let mut config = Config::new("127.0.0.1:0");
+        config.strict_head = true; // keep the early data
+        let (tunnel, shutdown, tunnels) = spawn_tunnel_with_shutdown(config).await?;
+
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\nhello", upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 24];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\nhello");
+        drop(client);
+
+        let requests = [
+            format!("CONNECT {} HTTP/1.1\r\n\r\n", closed),
+            "CONNECT example.com:443 HTTP/1.0\r\n\r\n".to_string(),
+            "GET / HTTP/1.1\r\n\r\n".to_string(),
+        ];
+        for request in requests {
+            let mut client = TcpStream::connect(tunnel).await?;
+            client.write_all(request.as_bytes()).await?;
+            let mut response = Vec::new();
+            timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await??;
+        }
+        shutdown.cancel();
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 503 SERVICE_UNAVAILABLE\r\n\r\n");
+
+        // recorded once the tunnel task is done
+        timeout(Duration::from_millis(500), async {
+            while tunnels.active_connections() != 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await?;
+        let summary = tunnels.summary();
+        assert!(summary.starts_with("5 connection(s), 1 tunnel(s), 10 bytes relayed, average relay duration: "), "{}", summary);
+        assert!(summary.ends_with(", results: connect failed: 1, rejected (InvalidRequest): 2, \
+                                   rejected (ShuttingDown): 1, relayed: 1"), "{}", summary);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_terminate_denied() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let upstream = spawn_echo_upstream().await?;
+        let path = std::env::temp_dir().join(format!("rust_http_tunnel_terminate_denied_{}", std::process::id()));
+        std::fs::write(&path, "")?;
+        let denylist = Arc::new(TargetDenylist::load(path.to_str().unwrap())?);
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let tunnel = listener.local_addr()?;
+        let tunnels = TunnelRegistry::new();
+        let config = Config::new("127.0.0.1:0");
+        let state = ListenerState::new(&listener, &config, CancellationToken::new(), tunnels.clone(), denylist.clone())?;
+        tokio::spawn(serve_tcp(listener, Arc::new(config), SimpleDnsResolver::new(), state));
+
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+
+        // the target is now denied: the running tunnel is torn down, new ones are refused
+        std::fs::write(&path, "127.0.0.1\n")?;
+        reload_denylist(&denylist, &tunnels, true);
+        let mut rest = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut rest)).await??;
+        assert!(rest.is_empty());
+        timeout(Duration::from_millis(500), async {
+            while !tunnels.active().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await?;
+
+        let mut client = TcpStream::connect(tunnel).await?;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 403 FORBIDDEN\r\n\r\n");
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        capture::init();
+        let tunnels = TunnelRegistry::new();
+        let _connection = tunnels.connection();
+        let shutdown = CancellationToken::new();
+        let start = Instant::now();
+        let heartbeat = tokio::spawn(heartbeat(tunnels.clone(), Duration::from_millis(100), shutdown.clone()));
+
+        let find = || capture::find("Heartbeat: up 0s, 1 connection(s) open, 0 tunnel(s) running, 1 accepted since start");
+        while find().is_empty() {
+            assert!(start.elapsed() < Duration::from_millis(500), "no heartbeat after {:?}", start.elapsed());
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100), "{:?}", start.elapsed());
+
+        // stopped on shutdown
+        shutdown.cancel();
+        timeout(Duration::from_millis(500), heartbeat).await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_drain_progress() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        capture::init();
+        let upstream = spawn_echo_upstream().await?;
+        let (tunnel, shutdown, tunnels) = spawn_tunnel_with_shutdown(Config::new("127.0.0.1:0")).await?;
+
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let mut client = TcpStream::connect(tunnel).await?;
+            client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+            let mut response = vec![0u8; 19];
+            timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+            clients.push(client);
+        }
+        let oldest_peer = clients[0].local_addr()?;
+        assert_eq!(tunnels.active().len(), 2);
+
+        shutdown.cancel();
+        let drained = tokio::spawn(async move { drain(&tunnels, Duration::from_secs(5), Duration::from_secs(1)).await });
+        for client in clients {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(client);
+        }
+        timeout(Duration::from_millis(500), drained).await??;
+
+        assert_eq!(capture::find(&format!("Draining: 2 tunnel(s) running (oldest: {} ->", oldest_peer)).len(), 1);
+        let progress = capture::find(&format!(" -> {} for", upstream));
+        assert_eq!(progress.len(), 2);
+        assert!(progress[1].starts_with("Draining: 1 tunnel(s) running"), "{}", progress[1]);
+        assert!(!capture::find("Drained: all tunnels closed").is_empty());
+        Ok(())
+    }
+
+    // Resolve every target to 5 addresses
+    #[derive(Clone)]
+    struct FiveAddrsResolver {}
+
+    #[async_trait::async_trait]
+    impl DnsResolver for FiveAddrsResolver {
+        async fn resolve(&mut self, _target: &str) -> std::io::Result<SocketAddr> {
+            Ok("10.0.0.1:443".parse().unwrap())
+        }
+
+        async fn resolve_all(&mut self, _target: &str) -> std::io::Result<Vec<SocketAddr>> {
+            Ok((1..=5).map(|i| SocketAddr::from(([10, 0, 0, i], 443))).collect())
+        }
+    }
+
+    // Every connection is refused, attempts are recorded
+    #[derive(Default)]
+    struct RefusingConnector {
+        attempts: std::sync::Mutex<Vec<SocketAddr>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Connector for RefusingConnector {
+        async fn connect(&self, addr: SocketAddr) -> std::io::Result<TcpStream> {
+            self.attempts.lock().unwrap().push(addr);
+            Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused))
+        }
+    }
+
+    // Connect to addr after its delay (if any), attempts are recorded: (addr, completed), not completed once cancelled
+    #[derive(Default)]
+    struct SlowAddrsConnector {
+        delays: std::collections::HashMap<SocketAddr, Duration>,
+        attempts: Arc<std::sync::Mutex<Vec<(SocketAddr, bool)>>>,
+    }
+
+    // Record an attempt as not completed if dropped before complete() (i.e. cancelled)
+    struct AttemptGuard {
+        attempts: Arc<std::sync::Mutex<Vec<(SocketAddr, bool)>>>,
+        addr: SocketAddr,
+        completed: bool,
+    }
+
+    impl Drop for AttemptGuard {
+        fn drop(&mut self) {
+            self.attempts.lock().unwrap().push((self.addr, self.completed));
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Connector for SlowAddrsConnector {
+        async fn connect(&self, addr: SocketAddr) -> std::io::Result<TcpStream> {
+            let mut guard = AttemptGuard { attempts: self.attempts.clone(), addr, completed: false };
+            tokio::time::sleep(self.delays.get(&addr).copied().unwrap_or_default()).await;
+            let stream = TcpStream::connect(addr).await;
+            guard.completed = true;
+            stream
+        }
+    }
+
+    // Resolve every target to addrs
+    #[derive(Clone)]
+    struct StaticResolver {
+        addrs: Vec<SocketAddr>,
+    }
+
+    #[async_trait::async_trait]
+    impl DnsResolver for StaticResolver {
+        async fn resolve(&mut self, _target: &str) -> std::io::Result<SocketAddr> {
+            Ok(self.addrs[0])
+        }
+
+        async fn resolve_all(&mut self, _target: &str) -> std::io::Result<Vec<SocketAddr>> {
+            Ok(self.addrs.clone())
+        }
+    }
+
+    #[test]
+    fn test_interleave_families() {
+        let addrs: Vec<SocketAddr> = ["[::1]:1", "[::2]:2", "[::3]:3", "10.0.0.1:4", "10.0.0.2:5"].iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let interleaved: Vec<u16> = interleave_families(&addrs).iter().map(|addr| addr.port()).collect();
+        assert_eq!(interleaved, vec![1, 4, 2, 5, 3]);
+        let interleaved: Vec<u16> = interleave_families(&addrs[3..]).iter().map(|addr| addr.port()).collect();
+        assert_eq!(interleaved, vec![4, 5]);
+        assert!(interleave_families(&[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let (slow, fast) = (spawn_echo_upstream().await?, spawn_echo_upstream().await?);
+        let (events, mut received) = tokio::sync::mpsc::channel(16);
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut config = Config::new("127.0.0.1:0");
+        config.connect_timeout = Duration::from_secs(1);
+        config.happy_eyeballs_delay = Duration::from_millis(50);
+        let mut state = ListenerState::new(&listener, &config, CancellationToken::new(), TunnelRegistry::new(),
+                                           Arc::new(AllowAll::default()))?;
+        let connector = SlowAddrsConnector { delays: [(slow, Duration::from_millis(500))].into(), ..Default::default() };
+        let attempts = connector.attempts.clone();
+        state.connector = Arc::new(connector);
+        state.events = Some(events);
+
+        // the slow address first
+        let resolver = StaticResolver { addrs: vec![slow, fast] };
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(server);
+        let start = Instant::now();
+        let tunnel = tokio::spawn(tunnel_stream(reader, writer, "127.0.0.1:4000".parse()?, resolver, Arc::new(config),
+                                                state));
+        client.write_all(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n").await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(400), client.read_exact(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+        // the fast address started after the delay, without waiting for the slow one
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(50) && elapsed < Duration::from_millis(400), "{:?}", elapsed);
+        // slow attempt cancelled
+        assert_eq!(*attempts.lock().unwrap(), vec![(fast, true), (slow, false)]);
+
+        drop(client);
+        timeout(Duration::from_millis(500), tunnel).await???;
+        loop {
+            if let Some(TunnelEvent::Completed { stats, .. }) = received.recv().await {
+                assert_eq!(stats.upstream_peer, Some(fast));
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_connect_addrs() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut config = Config::new("127.0.0.1:0");
+        config.max_connect_addrs = 2;
+        let connector = Arc::new(RefusingConnector::default());
+        let state = ListenerState {
+            listen_addr: "127.0.0.1:6161".parse()?,
+            upstream_tls: None,
+            shutdown: CancellationToken::new(),
+            tunnels: TunnelRegistry::new(),
+            connector: connector.clone(),
+            policy: Arc::new(AllowAll::default()),
+            connect_hook: None,
+            events: None,
+        };
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(server);
+        let peer = "127.0.0.1:4000".parse()?;
+        let tunnel = tokio::spawn(tunnel_stream(reader, writer, peer, FiveAddrsResolver {}, Arc::new(config), state));
+
+        client.write_all(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n").await?;
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 502 BAD_GATEWAY\r\n\r\n");
+        timeout(Duration::from_millis(500), tunnel).await???;
+
+        let attempts = connector.attempts.lock().unwrap().clone();
+        assert_eq!(attempts, vec!["10.0.0.1:443".parse::<SocketAddr>()?, "10.0.0.2:443".parse()?]);
+        Ok(())
+    }
+
+    // Block one host for legal reasons
+    struct LegalPolicy {}
+
+    impl TunnelPolicy for LegalPolicy {
+        fn check(&self, target: &str, _peer: SocketAddr) -> Option<TunnelResult> {
+            (target == "blocked.example:443").then_some(TunnelResult::UnavailableForLegalReasons)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_policy() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let connector = Arc::new(RefusingConnector::default());
+        // (target, expected response)
+        let cases = [
+            ("blocked.example:443", &b"HTTP/1.1 451 UNAVAILABLE_FOR_LEGAL_REASONS\r\n\r\n"[..]),
+            ("example.com:443", &b"HTTP/1.1 502 BAD_GATEWAY\r\n\r\n"[..]),
+        ];
+        for (target, expected) in cases {
+            let state = ListenerState {
+                listen_addr: "127.0.0.1:6161".parse()?,
+                upstream_tls: None,
+                shutdown: CancellationToken::new(),
+                tunnels: TunnelRegistry::new(),
+                connector: connector.clone(),
+                policy: Arc::new(LegalPolicy {}),
+                connect_hook: None,
+                events: None,
+            };
+            let (mut client, server) = tokio::io::duplex(1024);
+            let (reader, writer) = tokio::io::split(server);
+            let peer = "127.0.0.1:4000".parse()?;
+            let tunnel = tokio::spawn(tunnel_stream(reader, writer, peer, FiveAddrsResolver {},
+                                                    Arc::new(Config::new("127.0.0.1:0")), state));
+
+            client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", target).as_bytes()).await?;
+            let mut response = Vec::new();
+            timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await??;
+            assert_eq!(response, expected, "{}", target);
+            let _ = timeout(Duration::from_millis(500), tunnel).await?;
+        }
+
+        // connection attempts (max_connect_addrs) for example.com only, never for the blocked host
+        assert_eq!(connector.attempts.lock().unwrap().len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_server_policy() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let path = std::env::temp_dir().join(format!("rust_http_tunnel_server_policy_{}", std::process::id()));
+        std::fs::write(&path, "denied.example\nblocked.example\n")?;
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let mut config = Config::new(&addr.to_string());
+        config.deny_targets = Some(path.to_str().unwrap().to_string());
+        let server = TunnelServer::new(config).with_policy(Arc::new(LegalPolicy {}));
+        tokio::spawn(async move { server.run().await });
+
+        // (target, expected response): the target denylist first, then the policy
+        let cases = [
+            ("blocked.example:443", &b"HTTP/1.1 403 FORBIDDEN\r\n\r\n"[..]),
+            ("denied.example:443", &b"HTTP/1.1 403 FORBIDDEN\r\n\r\n"[..]),
+        ];
+        for (target, expected) in cases {
+            let mut client = connect_when_listening(addr).await?;
+            client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", target).as_bytes()).await?;
+            let mut response = Vec::new();
+            timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await??;
+            assert_eq!(response, expected, "{}", target);
+        }
+        std::fs::remove_file(&path)?;
+
+        // no target denylist
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let server = TunnelServer::new(Config::new(&addr.to_string())).with_policy(Arc::new(LegalPolicy {}));
+        tokio::spawn(async move { server.run().await });
+        let mut client = connect_when_listening(addr).await?;
+        client.write_all(b"CONNECT blocked.example:443 HTTP/1.1\r\n\r\n").await?;
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 451 UNAVAILABLE_FOR_LEGAL_REASONS\r\n\r\n");
+        Ok(())
+    }
+
+    // Record the targets then resolve them to addr
+    #[derive(Clone)]
+    struct RecordingResolver {
+        addr: SocketAddr,
+        targets: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl DnsResolver for RecordingResolver {
+        async fn resolve(&mut self, target: &str) -> std::io::Result<SocketAddr> {
+            self.targets.lock().unwrap().push(target.to_string());
+            Ok(self.addr)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lowercase_host() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        assert_eq!(lowercase_host("Example.COM:443"), "example.com:443");
+        assert_eq!(lowercase_host("[::1]:443"), "[::1]:443");
+
+        let mut config = Config::new("127.0.0.1:0");
+        config.lowercase_host = true;
+        let config = Arc::new(config);
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let peer: SocketAddr = "127.0.0.1:4000".parse()?;
+
+        // policy
+        let mut state = ListenerState::new(&listener, &config, CancellationToken::new(), TunnelRegistry::new(),
+                                           Arc::new(LegalPolicy {}))?;
+        state.connector = Arc::new(RefusingConnector::default());
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(server);
+        let tunnel = tokio::spawn(tunnel_stream(reader, writer, peer, FiveAddrsResolver {}, config.clone(), state));
+        client.write_all(b"CONNECT BLOCKED.Example:443 HTTP/1.1\r\n\r\n").await?;
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 451 UNAVAILABLE_FOR_LEGAL_REASONS\r\n\r\n");
+        let _ = timeout(Duration::from_millis(500), tunnel).await?;
+
+        // same cache entry
+        let upstream = spawn_echo_upstream().await?;
+        let targets = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let resolver = CachingResolver::new(RecordingResolver { addr: upstream, targets: targets.clone() },
+                                            Duration::from_secs(60), Duration::ZERO);
+        let state = ListenerState::new(&listener, &config, CancellationToken::new(), TunnelRegistry::new(),
+                                       Arc::new(AllowAll::default()))?;
+        for host in ["Example.COM", "example.com"] {
+            let (mut client, server) = tokio::io::duplex(1024);
+            let (reader, writer) = tokio::io::split(server);
+            let tunnel = tokio::spawn(tunnel_stream(reader, writer, peer, resolver.clone(), config.clone(), state.clone()));
+            client.write_all(format!("CONNECT {}:{} HTTP/1.1\r\n\r\n", host, upstream.port()).as_bytes()).await?;
+            let mut response = vec![0u8; 19];
+            timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+            assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n", "{}", host);
+            drop(client);
+            let _ = timeout(Duration::from_millis(500), tunnel).await?;
+        }
+        assert_eq!(*targets.lock().unwrap(), vec![format!("example.com:{}", upstream.port())]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_events() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let upstream = spawn_echo_upstream().await?;
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let (events, mut received) = tokio::sync::mpsc::channel(16);
+        let server = TunnelServer::new(Config::new(&addr.to_string())).with_events(events);
+        let running = tokio::spawn(async move { server.run().await });
+
+        // successful tunnel
+        let mut client = connect_when_listening(addr).await?;
+        let peer = client.local_addr()?;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        client.write_all(b"hello").await?;
+        let mut echoed = vec![0u8; 5];
+        timeout(Duration::from_millis(500), client.read_exact(&mut echoed)).await??;
+        drop(client);
+
+        let target = upstream.to_string();
+        let event = timeout(Duration::from_millis(500), received.recv()).await?;
+        assert_eq!(event, Some(TunnelEvent::Started { peer, target: target.clone() }));
+        match timeout(Duration::from_millis(500), received.recv()).await? {
+            Some(TunnelEvent::Completed { peer: completed_peer, target: completed_target, stats }) => {
+                assert_eq!((completed_peer, completed_target), (peer, target));
+                assert_eq!(stats.client_to_upstream.bytes, 5);
+                assert_eq!(stats.upstream_to_client.bytes, 5);
+                assert_eq!(stats.upstream_peer, Some(upstream));
+                let upstream_local = stats.upstream_local.unwrap();
+                assert!(upstream_local.ip().is_loopback() && upstream_local.port() != 0, "{}", upstream_local);
+            },
+            event => panic!("unexpected event: {:?}", event),
+        }
+
+        // rejected (invalid request)
+        let mut client = TcpStream::connect(addr).await?;
+        let peer = client.local_addr()?;
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await?;
+        let event = timeout(Duration::from_millis(500), received.recv()).await?;
+        assert_eq!(event, Some(TunnelEvent::Rejected { peer, reason: RejectReason::InvalidRequest }));
+
+        // all senders (the server & its listener states) are gone once stopped
+        running.abort();
+        drop(client);
+        assert_eq!(timeout(Duration::from_millis(500), received.recv()).await?, None);
+        Ok(())
+    }
+
+    // Connect hook: veto a target (403), record the resolved addresses of every target
+    #[derive(Default)]
+    struct VetoHook {
+        vetoed: String,
+        resolved: std::sync::Mutex<Vec<(String, Vec<SocketAddr>)>>,
+    }
+
+    #[async_trait::async_trait]
+    impl ConnectHook for VetoHook {
+        async fn on_connect(&self, target: &str, resolved: &[SocketAddr], _peer: SocketAddr)
+            -> std::ops::ControlFlow<TunnelResult>
+        {
+            self.resolved.lock().unwrap().push((target.to_string(), resolved.to_vec()));
+            if target == self.vetoed {
+                return std::ops::ControlFlow::Break(TunnelResult::Forbidden);
+            }
+            std::ops::ControlFlow::Continue(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_hook() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let (vetoed, allowed) = (spawn_echo_upstream().await?, spawn_echo_upstream().await?);
+        let (events, mut received) = tokio::sync::mpsc::channel(16);
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let hook = Arc::new(VetoHook { vetoed: vetoed.to_string(), ..Default::default() });
+        let server = TunnelServer::new(Config::new(&addr.to_string()))
+            .with_connect_hook(hook.clone())
+            .with_events(events);
+        tokio::spawn(async move { server.run().await });
+
+        let mut peers = Vec::new();
+        for upstream in [vetoed, allowed] {
+            let mut client = connect_when_listening(addr).await?;
+            peers.push(client.local_addr()?);
+            client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+            let mut response = vec![0u8; 19];
+            timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+            if upstream == vetoed {
+                assert!(response.starts_with(b"HTTP/1.1 403 "), "{:?}", String::from_utf8_lossy(&response));
+            } else {
+                assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+                client.write_all(b"hello").await?;
+                let mut echoed = vec![0u8; 5];
+                timeout(Duration::from_millis(500), client.read_exact(&mut echoed)).await??;
+                assert_eq!(echoed, b"hello");
+            }
+        }
+
+        assert_eq!(*hook.resolved.lock().unwrap(), vec![
+            (vetoed.to_string(), vec![vetoed]),
+            (allowed.to_string(), vec![allowed]),
+        ]);
+        let rejected = TunnelEvent::Rejected { peer: peers[0], reason: RejectReason::Hook(TunnelResult::Forbidden) };
+        assert_eq!(timeout(Duration::from_millis(500), received.recv()).await?, Some(rejected));
+        let started = TunnelEvent::Started { peer: peers[1], target: allowed.to_string() };
+        assert_eq!(timeout(Duration::from_millis(500), received.recv()).await?, Some(started));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_request_id() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        capture::init();
+        let upstream = spawn_echo_upstream().await?;
+        let (events, mut received) = tokio::sync::mpsc::channel(16);
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut state = ListenerState::new(&listener, &Config::new("127.0.0.1:0"), CancellationToken::new(),
+                                           TunnelRegistry::new(), Arc::new(AllowAll::default()))?;
+        state.events = Some(events);
+        let mut config = Config::new("127.0.0.1:0");
+        config.strict_head = true;
+        let config = Arc::new(config);
+
+        for (header, peer) in [("X-Request-ID: abc\r\n", "127.0.0.1:4001"), ("", "127.0.0.1:4002")] {
+            let peer: SocketAddr = peer.parse()?;
+            let (mut client, server) = tokio::io::duplex(1024);
+            let (reader, writer) = tokio::io::split(server);
+            let tunnel = tokio::spawn(tunnel_stream(reader, writer, peer, SimpleDnsResolver::new(), config.clone(),
+                                                    state.clone()));
+            client.write_all(format!("CONNECT {} HTTP/1.1\r\n{}\r\n", upstream, header).as_bytes()).await?;
+            let mut response = vec![0u8; 19];
+            timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+            drop(client);
+            timeout(Duration::from_millis(500), tunnel).await???;
+        }
+
+        let mut request_ids = Vec::new();
+        while request_ids.len() < 2 {
+            if let Some(TunnelEvent::Completed { stats, .. }) = received.recv().await {
+                request_ids.push(stats.request_id);
+            }
+        }
+        // client provided, then generated
+        assert_eq!(request_ids[0], "abc");
+        assert_eq!(request_ids[1].len(), 16);
+        let logs = capture::find("Tunnel 127.0.0.1:4001 -> ");
+        assert_eq!(logs.len(), 1);
+        assert!(logs[0].contains(" closed (request id: abc, "), "{}", logs[0]);
+
+        // unusable in logs: replaced
+        assert_eq!(request_id(Some("abc-123")), "abc-123");
+        for id in ["", "a b", "a\nb", &"a".repeat(129)] {
+            assert_ne!(request_id(Some(id)), id);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_error_response_timeout() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        capture::init();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let state = ListenerState::new(&listener, &Config::new("127.0.0.1:0"), CancellationToken::new(),
+                                       TunnelRegistry::new(), Arc::new(AllowAll::default()))?;
+        let mut config = Config::new("127.0.0.1:0");
+        config.error_response_timeout = Duration::from_millis(100);
+
+        // a small pipe & a client not reading: the 405 (GET) cannot be sent
+        let (mut client, server) = tokio::io::duplex(8);
+        let (reader, writer) = tokio::io::split(server);
+        let start = Instant::now();
+        let tunnel = tokio::spawn(tunnel_stream(reader, writer, "127.0.0.1:4000".parse()?, SimpleDnsResolver::new(),
+                                                Arc::new(config), state));
+        // Note: fits in the pipe
+        client.write_all(b"GET /\r\n").await?;
+        let result = timeout(Duration::from_millis(500), tunnel).await??;
+        let e = result.unwrap_err().to_string();
+        assert!(e.contains("Timeout sending a 405 response"), "{}", e);
+        assert!(start.elapsed() >= Duration::from_millis(100), "{:?}", start.elapsed());
+        assert!(!capture::find("Timeout sending a 405 response after 100ms, closing").is_empty());
+
+        // closed: the client gets the start of the response then EOF
+        let mut response = Vec::new();
+        timeout(Duration::from_millis(500), client.read_to_end(&mut response)).await??;
+        assert_eq!(&response[..], b"HTTP/1.1");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_forward_http() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let upstream = spawn_echo_upstream().await?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let state = ListenerState::new(&listener, &Config::new("127.0.0.1:0"), CancellationToken::new(),
+                                       TunnelRegistry::new(), Arc::new(AllowAll::default()))?;
+        let mut config = Config::new("127.0.0.1:0");
+        config.forward_http = true;
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(server);
+        let tunnel = tokio::spawn(tunnel_stream(reader, writer, "127.0.0.1:4000".parse()?, SimpleDnsResolver::new(),
+                                                Arc::new(config), state));
+        let request = format!("GET http://{}/path?q=1 HTTP/1.1\r\nHost: other.example.com\r\nAccept: */*\r\n\r\n", upstream);
+        client.write_all(request.as_bytes()).await?;
+
+        // no 200 from the proxy: the upstream (echo) got the request in origin-form, with the url Host
+        let expected = format!("GET /path?q=1 HTTP/1.1\r\nHost: {}\r\nAccept: */*\r\n\r\n", upstream);
+        let mut received = vec![0u8; expected.len()];
+        timeout(Duration::from_millis(500), client.read_exact(&mut received)).await??;
+        assert_eq!(String::from_utf8_lossy(&received), expected);
+        drop(client);
+        timeout(Duration::from_millis(500), tunnel).await???;
+        Ok(())
+    }
+
+    #[test]
+    fn test_sticky_order() {
+        let addrs: Vec<SocketAddr> = ["10.0.0.1:443", "10.0.0.2:443", "10.0.0.3:443", "10.0.0.4:443"]
+            .iter().map(|addr| addr.parse().unwrap()).collect();
+        let pick = |addrs: &[SocketAddr], client: &str| {
+            let mut addrs = addrs.to_vec();
+            sticky_order(&mut addrs, client.parse().unwrap());
+            addrs
+        };
+
+        // same client, same address (whatever the resolution order), the other ones in order
+        let ordered = pick(&addrs, "192.168.1.10");
+        let mut reversed = addrs.clone();
+        reversed.reverse();
+        assert_eq!(pick(&reversed, "192.168.1.10")[0], ordered[0]);
+        assert_eq!(ordered.iter().filter(|addr| **addr != ordered[0]).collect::<Vec<_>>(),
+                   addrs.iter().filter(|addr| **addr != ordered[0]).collect::<Vec<_>>());
+
+        // other clients are spread over the addresses
+        let picked: std::collections::HashSet<SocketAddr> = (1..=32)
+            .map(|i| pick(&addrs, &format!("192.168.1.{}", i))[0])
+            .collect();
+        assert!(picked.len() > 1, "{:?}", picked);
+
+        let mut single = addrs[..1].to_vec();
+        sticky_order(&mut single, "192.168.1.10".parse().unwrap());
+        assert_eq!(single, addrs[..1]);
+    }
+
+    #[tokio::test]
+    async fn test_pause() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        capture::init();
+        let upstream = spawn_echo_upstream().await?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let tunnels = TunnelRegistry::new();
+        let state = ListenerState::new(&listener, &Config::new("127.0.0.1:0"), CancellationToken::new(),
+                                       tunnels.clone(), Arc::new(AllowAll::default()))?;
+        let connect = |state: ListenerState| async move {
+            let (mut client, server) = tokio::io::duplex(1024);
+            let (reader, writer) = tokio::io::split(server);
+            let tunnel = tokio::spawn(tunnel_stream(reader, writer, "127.0.0.1:4000".parse()?, SimpleDnsResolver::new(),
+                                                    Arc::new(Config::new("127.0.0.1:0")), state));
+            client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+            let mut response = vec![0u8; 12];
+            timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+            drop(client);
+            timeout(Duration::from_millis(500), tunnel).await???;
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(String::from_utf8(response)?)
+        };
+
+        assert!(toggle_pause(&tunnels));
+        assert!(!capture::find("Paused: new tunnels are refused (503)").is_empty());
+        assert_eq!(connect(state.clone()).await?, "HTTP/1.1 503");
+
+        assert!(!toggle_pause(&tunnels));
+        assert!(!capture::find("Resumed: new tunnels are accepted").is_empty());
+        assert_eq!(connect(state).await?, "HTTP/1.1 200");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_all_addresses_denied() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        capture::init();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let state = ListenerState::new(&listener, &Config::new("127.0.0.1:0"), CancellationToken::new(),
+                                       TunnelRegistry::new(), Arc::new(AllowAll::default()))?;
+        let mut config = Config::new("127.0.0.1:0");
+        config.upstream_denylist.add("10.0.0.0/8".parse()?);
+
+        // 10.0.0.1 to 10.0.0.5: all private
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(server);
+        let tunnel = tokio::spawn(tunnel_stream(reader, writer, "127.0.0.1:4000".parse()?, FiveAddrsResolver {},
+                                                Arc::new(config), state));
+        client.write_all(b"CONNECT internal.example.com:443 HTTP/1.1\r\n\r\n").await?;
+        timeout(Duration::from_millis(500), tunnel).await???;
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await?;
+        assert!(response.starts_with(b"HTTP/1.1 403 FORBIDDEN\r\n"), "{:?}", String::from_utf8_lossy(&response));
+        assert!(response.ends_with(b"target addresses not allowed"), "{:?}", String::from_utf8_lossy(&response));
+        assert!(!capture::find("Refused internal.example.com:443 from 127.0.0.1:4000: all resolved addresses denied \
+                                [10.0.0.1:443, 10.0.0.2:443, 10.0.0.3:443, 10.0.0.4:443, 10.0.0.5:443]").is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_early_data() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let upstream = spawn_echo_upstream().await?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut config = Config::new("127.0.0.1:0");
+        config.strict_head = true;
+        let state = ListenerState::new(&listener, &config, CancellationToken::new(),
+                                       TunnelRegistry::new(), Arc::new(AllowAll::default()))?;
+        let peer: SocketAddr = "127.0.0.1:4000".parse()?;
+
+        // request head & payload at once: the payload reaches upstream (echoed back) after the response
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(server);
+        let tunnel = tokio::spawn(tunnel_stream(reader, writer, peer, SimpleDnsResolver::new(),
+                                                Arc::new(config.clone()), state.clone()));
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\nhello", upstream, upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        assert_eq!(&response[..], b"HTTP/1.1 200 OK\r\n\r\n");
+        let mut echoed = vec![0u8; 5];
+        timeout(Duration::from_millis(500), client.read_exact(&mut echoed)).await??;
+        assert_eq!(&echoed[..], b"hello");
+        // then relayed as usual
+        client.write_all(b" world").await?;
+        let mut echoed = vec![0u8; 6];
+        timeout(Duration::from_millis(500), client.read_exact(&mut echoed)).await??;
+        assert_eq!(&echoed[..], b" world");
+        drop(client);
+        timeout(Duration::from_millis(500), tunnel).await???;
+
+        // rejected
+        config.reject_early_data = true;
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(server);
+        let tunnel = tokio::spawn(tunnel_stream(reader, writer, peer, SimpleDnsResolver::new(), Arc::new(config), state));
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\nhello", upstream).as_bytes()).await?;
+        assert!(timeout(Duration::from_millis(500), tunnel).await??.is_err());
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await?;
+        assert!(response.starts_with(b"HTTP/1.1 400 BAD_REQUEST\r\n"), "{:?}", String::from_utf8_lossy(&response));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_app_main_error() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // address in use: the startup error is returned
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let config = Config::new(&listener.local_addr()?.to_string());
+        assert!(timeout(Duration::from_millis(500), app_main(config)).await?.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_server_connections() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let server = TunnelServer::new(Config::new(&addr.to_string()));
+        let server_ = server.clone();
+        tokio::spawn(async move { server_.run().await });
+        assert_eq!((server.active_connections(), server.total_connections()), (0, 0));
+
+        let client1 = connect_when_listening(addr).await?;
+        let client2 = TcpStream::connect(addr).await?;
+        let server = &server;
+        let counted = |active, total| async move {
+            timeout(Duration::from_millis(500), async {
+                while (server.active_connections(), server.total_connections()) != (active, total) {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            }).await
+        };
+        counted(2, 2).await?;
+        drop(client1);
+        counted(1, 2).await?;
+        drop(client2);
+        counted(0, 2).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tls_only() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // no cert / key: plaintext only
+        let mut config = Config::new("127.0.0.1:0");
+        config.tls_only = true;
+        let served = TunnelServer::new(config).run().await;
+        let e = served.unwrap_err();
+        assert!(e.to_string().starts_with("Plaintext listener 127.0.0.1:"), "{}", e);
+        assert!(e.to_string().ends_with(" refused (--tls-only)"), "{}", e);
+
+        // tls, but one plaintext listener
+        // Note: refused before loading the (missing) cert & key files
+        let mut config = Config::new("127.0.0.1:0");
+        config.tls = Some(TlsFiles { cert: "missing_cert.pem".to_string(), key: "missing_key.pem".to_string() });
+        config.tls_only = true;
+        config.listen_addrs.push("127.0.0.2:0".to_string());
+        config.listener_options.push(("127.0.0.2:0".to_string(), "tls".to_string(), "false".to_string()));
+        let served = TunnelServer::new(config).run().await;
+        let e = served.unwrap_err();
+        assert!(e.to_string().starts_with("Plaintext listener 127.0.0.2:"), "{}", e);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_custom_tls_config() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let upstream = spawn_echo_upstream().await?;
+        let mut tls_config = load_server_config(testing::TEST_SERVER_CERT, testing::TEST_SERVER_KEY,
+                                                &Config::new("127.0.0.1:0"))?;
+        tls_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        // no cert / key files
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let mut config = Config::new(&addr.to_string());
+        config.tls_only = true;
+        let server = TunnelServer::new(config).with_tls_config(Arc::new(tls_config));
+        tokio::spawn(async move { server.run().await });
+
+        let mut client_config = testing::client_config();
+        client_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+        let socket = connect_when_listening(addr).await?;
+        let mut client = connector.connect(tokio_rustls::rustls::ServerName::try_from("localhost")?, socket).await?;
+        assert_eq!(client.get_ref().1.alpn_protocol(), Some(&b"http/1.1"[..]));
+
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+        client.write_all(b"hello").await?;
+        let mut echoed = vec![0u8; 5];
+        timeout(Duration::from_millis(500), client.read_exact(&mut echoed)).await??;
+        assert_eq!(echoed, b"hello");
+
+        // from cert & key files
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let mut config = Config::new(&addr.to_string());
+        config.tls_only = true;
+        assert!(TunnelServer::new(config.clone()).with_tls_files("missing_cert.pem", "missing_key.pem").is_err());
+        let server = TunnelServer::new(config).with_tls_files(testing::TEST_SERVER_CERT, testing::TEST_SERVER_KEY)?;
+        tokio::spawn(async move { server.run().await });
+        let socket = connect_when_listening(addr).await?;
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(testing::client_config()));
+        let mut client = connector.connect(tokio_rustls::rustls::ServerName::try_from("localhost")?, socket).await?;
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", upstream).as_bytes()).await?;
+        let mut response = vec![0u8; 19];
+        timeout(Duration::from_millis(500), client.read_exact(&mut response)).await??;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+        Ok(())
+    }
+}
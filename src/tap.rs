@@ -0,0 +1,85 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use log::info;
+use tokio::io::{AsyncRead, ReadBuf};
+
+// Debug tap: log (hex) the first bytes read from a stream, then get out of the way
+// Note: the data is not modified, only logged (at most limit bytes, over one or more reads)
+
+pub struct Tap<R> {
+    inner: R,
+    label: String,
+    // bytes logged so far
+    logged: usize,
+    limit: usize,
+}
+
+impl<R> Tap<R> {
+    // limit 0: nothing is logged
+    pub fn new(inner: R, limit: usize, label: String) -> Self {
+        Self { inner, label, logged: 0, limit }
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Tap<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let polled = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = polled {
+            let remaining = self.limit - self.logged;
+            let read = &buf.filled()[before..];
+            let tapped = &read[..read.len().min(remaining)];
+            if !tapped.is_empty() {
+                info!("[Tap] {} (bytes {}..{}): {}",
+                      self.label, self.logged, self.logged + tapped.len(), to_hex(tapped));
+                self.logged += tapped.len();
+            }
+        }
+        polled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Tap;
+    use crate::logger::capture;
+
+    // traits
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_tap() -> Result<(), std::io::Error> {
+        capture::init();
+        let (mut client, reader) = tokio::io::duplex(1024);
+        let mut tap = Tap::new(reader, 6, "test_tap client".to_string());
+
+        // the tapped bytes span 2 reads
+        client.write_all(b"hell").await?;
+        let mut received = vec![0u8; 4];
+        tap.read_exact(&mut received).await?;
+        client.write_all(b"o world").await?;
+        drop(client);
+        tap.read_to_end(&mut received).await?;
+        assert_eq!(received, b"hello world");
+
+        let logged = capture::find("[Tap] test_tap client");
+        assert_eq!(logged, vec![
+            "[Tap] test_tap client (bytes 0..4): 68 65 6c 6c".to_string(),
+            "[Tap] test_tap client (bytes 4..6): 6f 20".to_string(),
+        ]);
+
+        // disabled
+        let mut tap = Tap::new(&b"hello"[..], 0, "test_tap disabled".to_string());
+        let mut received = Vec::new();
+        tap.read_to_end(&mut received).await?;
+        assert_eq!(received, b"hello");
+        assert!(capture::find("[Tap] test_tap disabled").is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,349 @@
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+// Minimal distributed tracing (OpenTelemetry like spans)
+// A span is exported when dropped, only if an exporter has been installed (otherwise spans are no-op)
+// Each tunnel is a "tunnel" span with "resolve", "connect" & "relay" child spans
+// With the "otel" feature, spans can be exported to an OTLP collector (see otlp module)
+
+static EXPORTER: OnceLock<Box<dyn SpanExporter>> = OnceLock::new();
+
+pub trait SpanExporter: Send + Sync {
+    fn export(&self, span: SpanData);
+}
+
+// Install the global exporter, return false if one is already installed
+#[cfg_attr(not(feature = "otel"), allow(dead_code))]
+pub fn init(exporter: Box<dyn SpanExporter>) -> bool {
+    EXPORTER.set(exporter).is_ok()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Int(i64),
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::String(value)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(value: u64) -> Self {
+        Value::Int(value as i64)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(value: u32) -> Self {
+        Value::Int(value as i64)
+    }
+}
+
+// Identify a span (so a child span can be created from another task)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpanContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpanData {
+    pub name: &'static str,
+    pub context: SpanContext,
+    pub parent_span_id: Option<u64>,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(&'static str, Value)>,
+}
+
+#[cfg(test)]
+impl SpanData {
+    pub fn attribute(&self, key: &str) -> Option<&Value> {
+        self.attributes.iter().find(|(k, _)| *k == key).map(|(_, v)| v)
+    }
+}
+
+#[derive(Debug)]
+pub struct Span {
+    // None if no exporter is installed
+    data: Option<SpanData>,
+}
+
+impl Span {
+    // Start a new span (a root span, starting a new trace, if parent is None)
+    pub fn new(name: &'static str, parent: Option<SpanContext>) -> Self {
+        if EXPORTER.get().is_none() {
+            return Self { data: None };
+        }
+
+        let context = SpanContext {
+            trace_id: parent.map(|p| p.trace_id).unwrap_or_else(rand::random),
+            span_id: rand::random(),
+        };
+        let now = SystemTime::now();
+        Self {
+            data: Some(SpanData {
+                name,
+                context,
+                parent_span_id: parent.map(|p| p.span_id),
+                start: now,
+                end: now,
+                attributes: Vec::new(),
+            })
+        }
+    }
+
+    pub fn child(&self, name: &'static str) -> Self {
+        match self.context() {
+            Some(context) => Self::new(name, Some(context)),
+            None => Self { data: None },
+        }
+    }
+
+    pub fn context(&self) -> Option<SpanContext> {
+        self.data.as_ref().map(|d| d.context)
+    }
+
+    pub fn set_attribute<V: Into<Value>>(&mut self, key: &'static str, value: V) {
+        if let Some(data) = self.data.as_mut() {
+            match data.attributes.iter_mut().find(|(k, _)| *k == key) {
+                Some(attribute) => attribute.1 = value.into(),
+                None => data.attributes.push((key, value.into())),
+            }
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let (Some(mut data), Some(exporter)) = (self.data.take(), EXPORTER.get()) {
+            data.end = SystemTime::now();
+            exporter.export(data);
+        }
+    }
+}
+
+// OTLP/HTTP (json encoding) exporter
+// Configured with env var OTEL_EXPORTER_OTLP_ENDPOINT (e.g. http://127.0.0.1:4318), spans are posted to
+// {endpoint}/v1/traces by a background task (must be installed from within a tokio runtime)
+#[cfg(feature = "otel")]
+pub mod otlp {
+
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use log::warn;
+    use tokio::net::TcpStream;
+    use tokio::sync::mpsc;
+    // traits
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{SpanData, SpanExporter, Value};
+
+    const OTLP_ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+    const OTLP_TRACES_PATH: &str = "/v1/traces";
+    // Max spans per export request
+    const OTLP_BATCH_SIZE: usize = 256;
+
+    pub struct OtlpExporter {
+        sender: mpsc::UnboundedSender<SpanData>,
+    }
+
+    impl OtlpExporter {
+        // Return None if the endpoint env var is not set or invalid
+        pub fn from_env() -> Option<Self> {
+            let endpoint = std::env::var(OTLP_ENDPOINT_ENV).ok()?;
+            // Note: only plain http is supported
+            let Some(authority) = endpoint.strip_prefix("http://") else {
+                warn!("Invalid {} (only http:// is supported): {}", OTLP_ENDPOINT_ENV, endpoint);
+                return None;
+            };
+            let host = authority.trim_end_matches('/').to_string();
+
+            let (sender, receiver) = mpsc::unbounded_channel();
+            tokio::spawn(export_loop(host, receiver));
+            Some(Self { sender })
+        }
+    }
+
+    impl SpanExporter for OtlpExporter {
+        fn export(&self, span: SpanData) {
+            let _ = self.sender.send(span);
+        }
+    }
+
+    async fn export_loop(host: String, mut receiver: mpsc::UnboundedReceiver<SpanData>) {
+        while let Some(span) = receiver.recv().await {
+            let mut spans = vec![span];
+            while spans.len() < OTLP_BATCH_SIZE {
+                match receiver.try_recv() {
+                    Ok(span) => spans.push(span),
+                    Err(_) => break,
+                }
+            }
+            if let Err(e) = post(&host, &encode(&spans)).await {
+                warn!("Unable to export {} spans to {}: {}", spans.len(), host, e);
+            }
+        }
+    }
+
+    async fn post(host: &str, body: &str) -> std::io::Result<()> {
+        let addr = if host.contains(':') { host.to_string() } else { format!("{}:80", host) };
+        let mut stream = TcpStream::connect(addr).await?;
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            OTLP_TRACES_PATH, host, body.len(), body
+        );
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        if !(response.starts_with(b"HTTP/1.1 2") || response.starts_with(b"HTTP/1.0 2")) {
+            let status_line = response.split(|b| *b == b'\r').next().unwrap_or_default();
+            return Err(std::io::Error::other(String::from_utf8_lossy(status_line).to_string()));
+        }
+        Ok(())
+    }
+
+    fn unix_nanos(time: SystemTime) -> u128 {
+        time.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0)
+    }
+
+    fn json_string(s: &str) -> String {
+        let mut escaped = String::with_capacity(s.len() + 2);
+        escaped.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => escaped.push_str("\\\""),
+                '\\' => escaped.push_str("\\\\"),
+                c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+                c => escaped.push(c),
+            }
+        }
+        escaped.push('"');
+        escaped
+    }
+
+    fn encode_attribute(key: &str, value: &Value) -> String {
+        let value = match value {
+            Value::String(s) => format!("{{\"stringValue\":{}}}", json_string(s)),
+            // Note: 64 bits integers are encoded as strings in OTLP json
+            Value::Int(i) => format!("{{\"intValue\":\"{}\"}}", i),
+        };
+        format!("{{\"key\":{},\"value\":{}}}", json_string(key), value)
+    }
+
+    fn encode_span(span: &SpanData) -> String {
+        let attributes: Vec<String> = span.attributes.iter().map(|(k, v)| encode_attribute(k, v)).collect();
+        let parent = span.parent_span_id.map(|id| format!("\"parentSpanId\":\"{:016x}\",", id)).unwrap_or_default();
+        format!(
+            "{{\"traceId\":\"{:032x}\",\"spanId\":\"{:016x}\",{}\"name\":{},\"kind\":1,\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\"attributes\":[{}]}}",
+            span.context.trace_id, span.context.span_id, parent, json_string(span.name),
+            unix_nanos(span.start), unix_nanos(span.end), attributes.join(",")
+        )
+    }
+
+    // Encode an OTLP ExportTraceServiceRequest (json)
+    pub fn encode(spans: &[SpanData]) -> String {
+        let service = encode_attribute("service.name", &Value::from(env!("CARGO_PKG_NAME")));
+        let spans: Vec<String> = spans.iter().map(encode_span).collect();
+        format!(
+            "{{\"resourceSpans\":[{{\"resource\":{{\"attributes\":[{}]}},\"scopeSpans\":[{{\"scope\":{{\"name\":\"{}\"}},\"spans\":[{}]}}]}}]}}",
+            service, env!("CARGO_CRATE_NAME"), spans.join(",")
+        )
+    }
+
+    #[cfg(test)]
+    mod tests {
+
+        use std::time::{Duration, UNIX_EPOCH};
+        use super::encode;
+        use crate::telemetry::{SpanContext, SpanData, Value};
+
+        #[test]
+        fn test_encode() {
+            let span = SpanData {
+                name: "resolve",
+                context: SpanContext { trace_id: 1, span_id: 2 },
+                parent_span_id: Some(3),
+                start: UNIX_EPOCH + Duration::from_nanos(10),
+                end: UNIX_EPOCH + Duration::from_nanos(20),
+                attributes: vec![("target", Value::from("a\"b:80")), ("bytes", Value::Int(42))],
+            };
+            let json = encode(&[span]);
+            assert!(json.contains("\"traceId\":\"00000000000000000000000000000001\",\"spanId\":\"0000000000000002\",\"parentSpanId\":\"0000000000000003\""));
+            assert!(json.contains("\"startTimeUnixNano\":\"10\",\"endTimeUnixNano\":\"20\""));
+            assert!(json.contains("{\"key\":\"target\",\"value\":{\"stringValue\":\"a\\\"b:80\"}}"));
+            assert!(json.contains("{\"key\":\"bytes\",\"value\":{\"intValue\":\"42\"}}"));
+        }
+    }
+}
+
+// In memory exporter for tests
+// Note: the exporter is global (like the logger), filter spans by trace
+#[cfg(test)]
+pub mod capture {
+
+    use std::sync::Mutex;
+    use super::{SpanData, SpanExporter, Value};
+
+    static SPANS: Mutex<Vec<SpanData>> = Mutex::new(Vec::new());
+
+    struct CaptureExporter;
+
+    impl SpanExporter for CaptureExporter {
+        fn export(&self, span: SpanData) {
+            SPANS.lock().unwrap().push(span);
+        }
+    }
+
+    pub fn init() {
+        let _ = super::init(Box::new(CaptureExporter));
+    }
+
+    // All spans of the traces containing a span with the given attribute = value
+    pub fn find_traces(key: &str, value: &Value) -> Vec<SpanData> {
+        let spans = SPANS.lock().unwrap();
+        let trace_ids: Vec<u128> = spans
+            .iter()
+            .filter(|s| s.attribute(key) == Some(value))
+            .map(|s| s.context.trace_id)
+            .collect();
+        spans.iter().filter(|s| trace_ids.contains(&s.context.trace_id)).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{capture, Span, Value};
+
+    #[test]
+    fn test_span_export() {
+        capture::init();
+        {
+            let mut root = Span::new("test_root", None);
+            root.set_attribute("test_id", "span_export");
+            let mut child = root.child("test_child");
+            child.set_attribute("bytes", 10u64);
+            child.set_attribute("bytes", 12u64);
+        }
+
+        let spans = capture::find_traces("test_id", &Value::from("span_export"));
+        assert_eq!(spans.len(), 2);
+        // child ends (is exported) first
+        assert_eq!(spans[0].name, "test_child");
+        assert_eq!(spans[0].attribute("bytes"), Some(&Value::Int(12)));
+        assert_eq!(spans[0].parent_span_id, Some(spans[1].context.span_id));
+        assert_eq!(spans[1].name, "test_root");
+        assert_eq!(spans[1].parent_span_id, None);
+    }
+}
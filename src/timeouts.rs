@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+// Per target timeouts
+// Override the connect timeout (and optionally the establishment timeout) for targets matching a pattern:
+// a host ("api.example.com") or its subdomains ("*.slow.example.com"), whatever the port
+// e.g. "*.slow.example.com=30" for upstreams known to be slow to accept connections
+// Note: patterns are checked in order, the first matching one wins
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TargetTimeout {
+    pub connect: Duration,
+    // None: the configured establishment timeout (0: no limit)
+    pub establish: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TargetTimeouts {
+    // lowercase pattern -> timeouts
+    rules: Vec<(String, TargetTimeout)>,
+}
+
+fn parse_secs(value: &str) -> Option<Duration> {
+    value.trim().parse::<f64>().ok().and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+}
+
+impl TargetTimeouts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_rule(&mut self, pattern: &str, timeout: TargetTimeout) {
+        self.rules.push((pattern.to_ascii_lowercase(), timeout));
+    }
+
+    // Parse a rule like: "pattern=connect_secs[,establish_secs]"
+    pub fn add_rule_str(&mut self, rule: &str) -> Option<()> {
+        let (pattern, timeouts) = rule.split_once('=')?;
+        if pattern.is_empty() || pattern == "*." {
+            return None;
+        }
+        let (connect, establish) = match timeouts.split_once(',') {
+            Some((connect, establish)) => (parse_secs(connect)?, Some(parse_secs(establish)?)),
+            None => (parse_secs(timeouts)?, None),
+        };
+        self.add_rule(pattern, TargetTimeout { connect, establish });
+        Some(())
+    }
+
+    // Timeouts of target (host:port), None if no pattern matches
+    pub fn get(&self, target: &str) -> Option<TargetTimeout> {
+        let host = target.rsplit_once(':').map_or(target, |(host, _port)| host);
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+        self.rules.iter()
+            .find(|(pattern, _)| match pattern.strip_prefix("*.") {
+                Some(domain) => host.strip_suffix(domain).is_some_and(|sub| sub.ends_with('.')),
+                None => host == *pattern,
+            })
+            .map(|(_, timeout)| *timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{TargetTimeout, TargetTimeouts};
+    use std::time::Duration;
+
+    #[test]
+    fn test_target_timeouts() {
+        let mut timeouts = TargetTimeouts::new();
+        assert!(timeouts.add_rule_str("*.slow.example.com=30").is_some());
+        assert!(timeouts.add_rule_str("API.example.com=0.5,2").is_some());
+        assert!(timeouts.add_rule_str("*.example.com=1").is_some());
+
+        let slow = TargetTimeout { connect: Duration::from_secs(30), establish: None };
+        assert_eq!(timeouts.get("db.slow.example.com:443"), Some(slow));
+        assert_eq!(timeouts.get("a.b.SLOW.example.com:80"), Some(slow));
+        let api = TargetTimeout { connect: Duration::from_millis(500), establish: Some(Duration::from_secs(2)) };
+        assert_eq!(timeouts.get("api.example.com:443"), Some(api));
+        // first match
+        assert_eq!(timeouts.get("slow.example.com:443").map(|t| t.connect), Some(Duration::from_secs(1)));
+        assert_eq!(timeouts.get("example.com:443"), None);
+        assert_eq!(timeouts.get("notslow.example.org:443"), None);
+
+        for rule in ["*.example.com", "=1", "*.=1", "a.com=x", "a.com=1,", "a.com=-1"] {
+            assert!(timeouts.add_rule_str(rule).is_none(), "{}", rule);
+        }
+    }
+}
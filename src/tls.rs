@@ -1,24 +1,476 @@
-use std::fs::File;
-use std::io::BufReader;
+use std::fs;
 use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
 
 use rustls_pemfile::{certs, rsa_private_keys};
-use tokio_rustls::rustls::{Certificate, PrivateKey};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig, SupportedCipherSuite, SupportedProtocolVersion};
+use tokio_rustls::rustls::{sign, version, ClientConfig, RootCertStore, ServerName, SignatureScheme, DEFAULT_CIPHER_SUITES};
+use tokio_rustls::TlsConnector;
+
+use crate::config::Config;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+impl FromStr for TlsVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1.2" => Ok(TlsVersion::Tls12),
+            "1.3" => Ok(TlsVersion::Tls13),
+            _ => Err(format!("unsupported tls version: {} (expect 1.2 or 1.3)", s)),
+        }
+    }
+}
+
+// Certificates & keys can be PEM (text) or DER (binary) encoded
+// A DER file holds a single item, always starting with an ASN.1 SEQUENCE tag
+const PEM_BEGIN: &[u8] = b"-----BEGIN";
+const DER_SEQUENCE: u8 = 0x30;
+const DER_INTEGER: u8 = 0x02;
+
+fn is_pem(data: &[u8]) -> bool {
+    data.windows(PEM_BEGIN.len()).any(|w| w == PEM_BEGIN)
+}
+
+// Read a PEM or DER file, returning the DER encoded items
+fn load_der<F>(path: &Path, parse_pem: F, what: &str) -> std::io::Result<Vec<Vec<u8>>>
+    where F: Fn(&mut dyn std::io::BufRead) -> std::io::Result<Vec<Vec<u8>>>
+{
+    let data = fs::read(path)?;
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid {}", what));
+
+    if is_pem(&data) {
+        parse_pem(&mut data.as_slice()).map_err(|_| invalid())
+    } else if data.first() == Some(&DER_SEQUENCE) {
+        Ok(vec![data])
+    } else {
+        Err(invalid())
+    }
+}
 
 pub fn load_certs<P>(path: P) -> std::io::Result<Vec<Certificate>> where P: AsRef<Path> {
-    certs(&mut BufReader::new(File::open(path.as_ref())?))
-        .map_err(|_| {
-            std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid certificate")
-        })
+    load_der(path.as_ref(), certs, "certificate")
         .map(|mut certs| certs.drain(..).map(Certificate).collect())
 }
 
-pub fn load_keys<P>(path: P) -> std::io::Result<Vec<PrivateKey>> where P: AsRef<Path> {
+// Same as load_certs, if strict also check the chain order (see check_chain_order)
+pub fn load_cert_chain<P>(path: P, strict: bool) -> std::io::Result<Vec<Certificate>> where P: AsRef<Path> {
+    let certs = load_certs(path.as_ref())?;
+    if strict {
+        check_chain_order(&certs, path.as_ref())?;
+    }
+    Ok(certs)
+}
+
+// DER element at the start of data: (tag, whole element, content), None if malformed
+fn der_element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = data.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        // long form: the length is in the next (first & 0x7f) bytes
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 || rest.len() < n {
+            return None;
+        }
+        (rest[..n].iter().fold(0, |len, b| len << 8 | *b as usize), &rest[n..])
+    };
+    let header = data.len() - rest.len();
+    (rest.len() >= len).then(|| (tag, &data[..header + len], &rest[..len]))
+}
+
+// Issuer & subject (DER encoded names) of a certificate
+fn cert_names(cert: &Certificate) -> Option<(&[u8], &[u8])> {
+    let (_, _, cert) = der_element(&cert.0)?;
+    let (_, _, tbs) = der_element(cert)?;
+    let mut fields = Vec::new();
+    let mut rest = tbs;
+    while !rest.is_empty() {
+        let (tag, element, _) = der_element(rest)?;
+        fields.push((tag, element));
+        rest = &rest[element.len()..];
+    }
+    // optional version ([0]) then serial, signature algorithm, issuer, validity, subject...
+    let fields = if fields.first()?.0 == 0xa0 { &fields[1..] } else { &fields[..] };
+    Some((fields.get(2)?.1, fields.get(4)?.1))
+}
+
+// Check that certs is an ordered chain: leaf first, then each certificate is the issuer of the previous one
+// Note: only the names are compared (signatures are checked by clients), enough to catch a misordered bundle
+pub fn check_chain_order(certs: &[Certificate], path: &Path) -> std::io::Result<()> {
+    let invalid_input = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+
+    let names = certs
+        .iter()
+        .map(cert_names)
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| invalid_input(format!("Malformed certificate in {}", path.display())))?;
+    for (i, pair) in names.windows(2).enumerate() {
+        let ((issuer, _), (_, next_subject)) = (pair[0], pair[1]);
+        if issuer != next_subject {
+            return Err(invalid_input(format!(
+                "Certificate chain {} is out of order: certificate {} is not issued by certificate {}, \
+                 expect the leaf certificate first then each issuer", path.display(), i + 1, i + 2)));
+        }
+    }
+    Ok(())
+}
 
-    // println!("{:?}", rsa_private_keys(&mut BufReader::new(File::open(path.as_ref())?)));
-    rsa_private_keys(&mut BufReader::new(File::open(path.as_ref())?))
-        .map_err(|_| {
-            std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid key")
-        })
+pub fn load_keys<P>(path: P) -> std::io::Result<Vec<PrivateKey>> where P: AsRef<Path> {
+    load_der(path.as_ref(), rsa_private_keys, "key")
         .map(|mut keys| keys.drain(..).map(PrivateKey).collect())
-}
\ No newline at end of file
+}
+
+// RSA key size (modulus bits) of a PKCS#1 key: SEQUENCE { version, modulus, ... }
+fn rsa_key_bits(key: &PrivateKey) -> Option<usize> {
+    let (_, _, key) = der_element(&key.0)?;
+    let (_, version, _) = der_element(key)?;
+    let (tag, _, modulus) = der_element(&key[version.len()..])?;
+    if tag != DER_INTEGER {
+        return None;
+    }
+    // Note: leading zero bytes (sign) are not part of the size
+    let pos = modulus.iter().position(|b| *b != 0)?;
+    Some((modulus.len() - pos) * 8 - modulus[pos].leading_zeros() as usize)
+}
+
+// Refuse keys smaller than min_bits (e.g. 1024 bits RSA keys), no check if min_bits is 0
+pub fn check_key_size(key: &PrivateKey, min_bits: usize, path: &Path) -> std::io::Result<()> {
+    if min_bits == 0 {
+        return Ok(());
+    }
+    let invalid_input = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+    match rsa_key_bits(key) {
+        Some(bits) if bits >= min_bits => Ok(()),
+        Some(bits) => Err(invalid_input(format!(
+            "Private key {} is too small: {} bits (min: {} bits)", path.display(), bits, min_bits))),
+        None => Err(invalid_input(format!("Malformed RSA private key in {}", path.display()))),
+    }
+}
+
+// Load cert & key files then build the rustls server config
+pub fn load_server_config<P>(cert_path: P, key_path: P, config: &Config) -> std::io::Result<ServerConfig>
+    where P: AsRef<Path>
+{
+    let (cert_path, key_path) = (cert_path.as_ref(), key_path.as_ref());
+    let invalid_input = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+
+    let certs = load_cert_chain(cert_path, config.tls_strict_chain)?;
+    let key = load_keys(key_path)?
+        .pop()
+        .ok_or_else(|| invalid_input(format!("No RSA private key found in {}", key_path.display())))?;
+    check_key_size(&key, config.tls_min_key_bits, key_path)?;
+    let leaf = certs
+        .first()
+        .ok_or_else(|| invalid_input(format!("No certificate found in {}", cert_path.display())))?;
+
+    if !key_matches_cert(&key, leaf) {
+        return Err(invalid_input(format!(
+            "Certificate {} does not match private key {} (cert public key differs from the key), \
+             please check that both files belong to the same key pair",
+            cert_path.display(), key_path.display())));
+    }
+
+    build_server_config(certs, key, config)
+}
+
+// Check that key is the private key of cert: sign a message with the key & verify it with cert public key
+// Note: rustls (0.20) does not check this, a mismatch would only show up as failing handshakes
+fn key_matches_cert(key: &PrivateKey, cert: &Certificate) -> bool {
+    let message = b"rust_http_tunnel key check";
+    let schemes = [
+        (SignatureScheme::RSA_PKCS1_SHA256, &webpki::RSA_PKCS1_2048_8192_SHA256),
+        (SignatureScheme::ECDSA_NISTP256_SHA256, &webpki::ECDSA_P256_SHA256),
+        (SignatureScheme::ECDSA_NISTP384_SHA384, &webpki::ECDSA_P384_SHA384),
+        (SignatureScheme::ED25519, &webpki::ED25519),
+    ];
+
+    let signer = sign::any_supported_type(key)
+        .ok()
+        .and_then(|k| k.choose_scheme(&schemes.map(|(scheme, _)| scheme)));
+    let end_entity_cert = webpki::EndEntityCert::try_from(cert.0.as_slice());
+
+    match (signer, end_entity_cert) {
+        (Some(signer), Ok(end_entity_cert)) => {
+            let algorithm = schemes.iter().find(|(scheme, _)| *scheme == signer.scheme()).map(|(_, a)| *a);
+            match (algorithm, signer.sign(message)) {
+                (Some(algorithm), Ok(signature)) => {
+                    end_entity_cert.verify_signature(algorithm, message, &signature).is_ok()
+                },
+                _ => false,
+            }
+        },
+        _ => false,
+    }
+}
+
+// Build the rustls server config, restricted to the configured protocol versions & cipher suites
+pub fn build_server_config(certs: Vec<Certificate>, key: PrivateKey, config: &Config) -> std::io::Result<ServerConfig> {
+
+    let invalid_input = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+
+    let versions: Vec<&'static SupportedProtocolVersion> = [
+        (TlsVersion::Tls12, &version::TLS12),
+        (TlsVersion::Tls13, &version::TLS13)
+    ]
+        .into_iter()
+        .filter(|(v, _)| (config.tls_min_version..=config.tls_max_version).contains(v))
+        .map(|(_, v)| v)
+        .collect();
+
+    let cipher_suites: Vec<SupportedCipherSuite> = if config.tls_cipher_suites.is_empty() {
+        DEFAULT_CIPHER_SUITES.to_vec()
+    } else {
+        config.tls_cipher_suites
+            .iter()
+            .map(|name| {
+                DEFAULT_CIPHER_SUITES
+                    .iter()
+                    .find(|cs| format!("{:?}", cs.suite()) == *name)
+                    .copied()
+                    .ok_or_else(|| invalid_input(format!("unknown cipher suite: {}", name)))
+            })
+            .collect::<std::io::Result<_>>()?
+    };
+
+    ServerConfig::builder()
+        .with_cipher_suites(&cipher_suites)
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&versions)
+        .map_err(|e| invalid_input(e.to_string()))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid_input(e.to_string()))
+}
+
+// Upstream tls: for the configured hosts, the tunnel re-originates tls to upstream
+// (the proxy client sends plain data through the tunnel, e.g. to inspect or forward it)
+#[derive(Clone)]
+pub struct UpstreamTls {
+    connector: TlsConnector,
+    hosts: Arc<Vec<String>>,
+}
+
+impl UpstreamTls {
+    // None if no upstream tls host is configured
+    pub fn from_config(config: &Config) -> std::io::Result<Option<Self>> {
+        if config.upstream_tls_hosts.is_empty() {
+            return Ok(None);
+        }
+        let invalid_input = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+
+        let ca_path = config.upstream_tls_ca
+            .as_ref()
+            .ok_or_else(|| invalid_input("--upstream-tls requires --upstream-tls-ca".to_string()))?;
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(ca_path)? {
+            roots.add(&cert).map_err(|e| invalid_input(format!("Invalid CA certificate in {}: {}", ca_path, e)))?;
+        }
+        let client_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        Ok(Some(Self {
+            connector: TlsConnector::from(Arc::new(client_config)),
+            hosts: Arc::new(config.upstream_tls_hosts.clone()),
+        }))
+    }
+
+    // Server name (SNI) for the target host, None if not an upstream tls host
+    pub fn server_name(&self, host: &str) -> Option<ServerName> {
+        if !self.hosts.iter().any(|h| h.eq_ignore_ascii_case(host)) {
+            return None;
+        }
+        ServerName::try_from(host).ok()
+    }
+
+    pub async fn connect(&self, server_name: ServerName, stream: TcpStream) -> std::io::Result<TlsStream<TcpStream>> {
+        self.connector.connect(server_name, stream).await
+    }
+}
+
+// Test certificates (see test_data/gen.sh) & client config trusting them
+#[cfg(test)]
+pub mod testing {
+
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore, SupportedProtocolVersion, ALL_VERSIONS};
+    use super::load_certs;
+
+    pub const TEST_CA_CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/ca.crt");
+    pub const TEST_SERVER_CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/server.crt");
+    pub const TEST_SERVER_KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/server.key");
+    pub const TEST_OTHER_KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/other.key");
+    pub const TEST_WEAK_KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/weak.key");
+    pub const TEST_SERVER_CERT_DER: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/server.der");
+    pub const TEST_SERVER_KEY_DER: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/server.key.der");
+    // server cert then CA cert, and the other way around
+    pub const TEST_CHAIN: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/chain.crt");
+    pub const TEST_CHAIN_REVERSED: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/chain_reversed.crt");
+
+    pub fn client_config_with_versions(versions: &[&'static SupportedProtocolVersion]) -> ClientConfig {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(TEST_CA_CERT).unwrap() {
+            roots.add(&cert).unwrap();
+        }
+        ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(versions)
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth()
+    }
+
+    pub fn client_config() -> ClientConfig {
+        client_config_with_versions(ALL_VERSIONS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::Arc;
+
+    use tokio_rustls::rustls::{version, ServerName, SupportedProtocolVersion};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    use super::{build_server_config, check_key_size, load_cert_chain, load_certs, load_keys, load_server_config, TlsVersion};
+    use super::testing::{client_config_with_versions, TEST_OTHER_KEY, TEST_SERVER_CERT, TEST_SERVER_KEY, TEST_WEAK_KEY};
+    use super::testing::{TEST_CHAIN, TEST_CHAIN_REVERSED, TEST_SERVER_CERT_DER, TEST_SERVER_KEY_DER};
+    use crate::config::Config;
+
+    type TResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    // Handshake between a client with the given protocol versions and a server built from config
+    async fn handshake(config: &Config, client_versions: &[&'static SupportedProtocolVersion]) -> std::io::Result<()> {
+        let certs = load_certs(TEST_SERVER_CERT)?;
+        let key = load_keys(TEST_SERVER_KEY)?.remove(0);
+        let acceptor = TlsAcceptor::from(Arc::new(build_server_config(certs, key, config)?));
+        let connector = TlsConnector::from(Arc::new(client_config_with_versions(client_versions)));
+
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let (client_result, server_result) = tokio::join!(
+            connector.connect(server_name, client),
+            acceptor.accept(server)
+        );
+        client_result?;
+        server_result?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tls13_only_rejects_tls12_client() -> TResult {
+        let mut config = Config::new("127.0.0.1:0");
+        config.tls_min_version = TlsVersion::Tls13;
+
+        assert!(handshake(&config, &[&version::TLS12]).await.is_err());
+        handshake(&config, &[&version::TLS13]).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cipher_suites() -> TResult {
+        let mut config = Config::new("127.0.0.1:0");
+        config.tls_max_version = TlsVersion::Tls12;
+        config.tls_cipher_suites = vec!["TLS13_AES_128_GCM_SHA256".to_string()];
+        // no cipher suite matching the allowed protocol versions
+        assert!(handshake(&config, &[&version::TLS12]).await.is_err());
+
+        config.tls_cipher_suites = vec!["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".to_string()];
+        handshake(&config, &[&version::TLS12]).await?;
+
+        config.tls_cipher_suites = vec!["FOO".to_string()];
+        assert!(handshake(&config, &[&version::TLS12]).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_server_config() -> TResult {
+        let config = Config::new("127.0.0.1:0");
+        load_server_config(TEST_SERVER_CERT, TEST_SERVER_KEY, &config)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_certs_pem_der() -> TResult {
+        let pem = load_certs(TEST_SERVER_CERT)?;
+        let der = load_certs(TEST_SERVER_CERT_DER)?;
+        assert_eq!(pem.len(), 1);
+        assert_eq!(pem, der);
+        for cert in pem.iter().chain(der.iter()) {
+            webpki::EndEntityCert::try_from(cert.0.as_slice())?;
+        }
+
+        assert_eq!(load_keys(TEST_SERVER_KEY)?, load_keys(TEST_SERVER_KEY_DER)?);
+        let config = Config::new("127.0.0.1:0");
+        load_server_config(TEST_SERVER_CERT_DER, TEST_SERVER_KEY_DER, &config)?;
+
+        // neither PEM nor DER
+        let err = load_certs(concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/gen.sh")).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_cert_chain_strict() -> TResult {
+        assert_eq!(load_cert_chain(TEST_CHAIN, true)?.len(), 2);
+        assert_eq!(load_cert_chain(TEST_SERVER_CERT, true)?.len(), 1);
+
+        // reversed: only refused if strict
+        assert_eq!(load_cert_chain(TEST_CHAIN_REVERSED, false)?.len(), 2);
+        let err = load_cert_chain(TEST_CHAIN_REVERSED, true).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        let message = err.to_string();
+        assert!(message.contains("out of order: certificate 1 is not issued by certificate 2"), "{}", message);
+
+        let mut config = Config::new("127.0.0.1:0");
+        load_server_config(TEST_CHAIN, TEST_SERVER_KEY, &config)?;
+        config.tls_strict_chain = true;
+        load_server_config(TEST_CHAIN, TEST_SERVER_KEY, &config)?;
+        assert!(load_server_config(TEST_CHAIN_REVERSED, TEST_SERVER_KEY, &config).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_key_size() -> TResult {
+        let path = std::path::Path::new(TEST_WEAK_KEY);
+        let strong = load_keys(TEST_SERVER_KEY)?.remove(0);
+        let weak = load_keys(TEST_WEAK_KEY)?.remove(0);
+        check_key_size(&strong, 2048, path)?;
+        check_key_size(&load_keys(TEST_SERVER_KEY_DER)?.remove(0), 2048, path)?;
+        assert!(check_key_size(&strong, 4096, path).is_err());
+
+        let err = check_key_size(&weak, 2048, path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("too small: 1024 bits (min: 2048 bits)"), "{}", err);
+        check_key_size(&weak, 1024, path)?;
+        check_key_size(&weak, 0, path)?;
+
+        // refused at load (the key does not match the cert either, the size is checked first)
+        let config = Config::new("127.0.0.1:0");
+        let err = load_server_config(TEST_SERVER_CERT, TEST_WEAK_KEY, &config).unwrap_err();
+        assert!(err.to_string().contains("too small"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_server_config_mismatch() {
+        let config = Config::new("127.0.0.1:0");
+        let err = load_server_config(TEST_SERVER_CERT, TEST_OTHER_KEY, &config).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        let message = err.to_string();
+        assert!(message.contains("does not match"), "{}", message);
+        assert!(message.contains("server.crt") && message.contains("other.key"), "{}", message);
+    }
+}
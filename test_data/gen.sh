@@ -0,0 +1,32 @@
+#!/bin/sh
+# Regenerate the test certificates & keys used by the unit tests
+set -e
+cd "$(dirname "$0")"
+
+# CA
+openssl req -x509 -newkey rsa:2048 -nodes -keyout ca.key -out ca.crt -days 3650 -sha256 \
+    -subj '/CN=rust_http_tunnel test CA/' \
+    -addext 'basicConstraints=critical,CA:TRUE' -addext 'keyUsage=critical,keyCertSign,cRLSign'
+
+# Server cert for localhost signed by the CA (key in PKCS#1 "RSA PRIVATE KEY" format)
+openssl req -newkey rsa:2048 -nodes -keyout server.pkcs8.key -out server.csr -subj '/CN=localhost/'
+openssl rsa -in server.pkcs8.key -out server.key -traditional
+printf 'basicConstraints=CA:FALSE\nsubjectAltName=DNS:localhost,IP:127.0.0.1\nextendedKeyUsage=serverAuth\n' > server.ext
+openssl x509 -req -in server.csr -CA ca.crt -CAkey ca.key -CAcreateserial -out server.crt -days 3650 \
+    -sha256 -extfile server.ext
+
+# Unrelated key (does not match server.crt)
+openssl genrsa -traditional -out other.key 2048
+
+# Weak (1024 bits) key, rejected by the minimum key size check
+openssl genrsa -traditional -out weak.key 1024
+
+# DER encoded copies of the server cert & key
+openssl x509 -in server.crt -outform der -out server.der
+openssl rsa -in server.key -outform der -out server.key.der -traditional
+
+# Full chain (leaf first) & misordered chain
+cat server.crt ca.crt > chain.crt
+cat ca.crt server.crt > chain_reversed.crt
+
+rm -f server.pkcs8.key server.csr server.ext ca.srl